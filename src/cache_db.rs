@@ -1,24 +1,80 @@
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    Key, XChaCha20Poly1305, XNonce,
+};
 use heed::{
     Database, Env, Error,
     types::{Bytes, Str},
 };
+use rand::{rngs::OsRng, Rng};
 use serde::{Serialize, de::DeserializeOwned};
 use std::path::Path;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use crate::types::Cache;
 
 pub const DB_PROFILES: &str = "profiles";
 pub const DB_FOLLOWED: &str = "followed_pubkeys";
 pub const DB_RELAYS: &str = "nip65_relays";
-pub const DB_TIMELINE: &str = "timeline_posts";
 pub const DB_NOTIFICATIONS: &str = "notification_posts";
 pub const DB_IMAGES: &str = "images";
-pub const DB_SELF_POSTS: &str = "self_posts";
+// Decrypted direct messages. Values are sealed with a per-account key, see `write_encrypted`.
+pub const DB_MESSAGES: &str = "direct_messages";
+// NIP-05 verification results, with the time they were checked
+pub const DB_NIP05: &str = "nip05";
+// Size and last access time of each entry of `DB_IMAGES`, see `ImageRecord`
+pub const DB_IMAGE_INDEX: &str = "image_index";
+// 以前タイムラインと自分の投稿を丸ごと保存していた DB。今はイベントデータベースから組むので空にする
+const LEGACY_POST_DBS: [&str; 2] = ["timeline_posts", "self_posts"];
+
+// 画像キャッシュの既定の上限。設定で変えられる
+const DEFAULT_IMAGE_BUDGET: u64 = 256 * 1024 * 1024;
+
+// 読むたびに書き込みトランザクションを開かないよう、これより新しい記録は更新しない
+const IMAGE_TOUCH_INTERVAL_MS: u64 = 60 * 1000;
+
+const NONCE_LEN: usize = 24;
+
+type RawDb = Database<Str, Bytes>;
+
+// DB_IMAGE_INDEX の値。最後に読み書きした時刻 (ミリ秒) と画像のバイト数
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct ImageRecord {
+    last_used: u64,
+    size: u64,
+}
+
+impl ImageRecord {
+    fn to_bytes(self) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&self.last_used.to_be_bytes());
+        bytes[8..].copy_from_slice(&self.size.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes: [u8; 16] = bytes.try_into().ok()?;
+        Some(Self {
+            last_used: u64::from_be_bytes(bytes[..8].try_into().ok()?),
+            size: u64::from_be_bytes(bytes[8..].try_into().ok()?),
+        })
+    }
+}
+
+/// Bytes stored in the image cache and how many images that is.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ImageCacheUsage {
+    pub bytes: u64,
+    pub entries: u64,
+}
 
 #[derive(Clone)]
 pub struct LmdbCache {
     env: Arc<Env>,
+    // 画像キャッシュの合計バイト数と上限
+    image_usage: Arc<AtomicU64>,
+    image_budget: Arc<AtomicU64>,
 }
 
 impl LmdbCache {
@@ -26,20 +82,32 @@ impl LmdbCache {
         std::fs::create_dir_all(path)?;
         let mut options = heed::EnvOpenOptions::new();
         options.map_size(1024 * 1024 * 1024); // 1 GB
-        options.max_dbs(9);
+        options.max_dbs(16);
         let env = unsafe { options.open(path)? };
 
         let mut txn = env.write_txn()?;
         let _: Database<Str, Bytes> = env.create_database(&mut txn, Some(DB_PROFILES))?;
         let _: Database<Str, Bytes> = env.create_database(&mut txn, Some(DB_FOLLOWED))?;
         let _: Database<Str, Bytes> = env.create_database(&mut txn, Some(DB_RELAYS))?;
-        let _: Database<Str, Bytes> = env.create_database(&mut txn, Some(DB_TIMELINE))?;
         let _: Database<Str, Bytes> = env.create_database(&mut txn, Some(DB_NOTIFICATIONS))?;
         let _: Database<Str, Bytes> = env.create_database(&mut txn, Some(DB_IMAGES))?;
-        let _: Database<Str, Bytes> = env.create_database(&mut txn, Some(DB_SELF_POSTS))?;
+        let _: Database<Str, Bytes> = env.create_database(&mut txn, Some(DB_MESSAGES))?;
+        let _: Database<Str, Bytes> = env.create_database(&mut txn, Some(DB_NIP05))?;
+        let _: Database<Str, Bytes> = env.create_database(&mut txn, Some(DB_IMAGE_INDEX))?;
+        for name in LEGACY_POST_DBS {
+            if let Some(db) = env.open_database::<Str, Bytes>(&txn, Some(name))? {
+                db.clear(&mut txn)?;
+            }
+        }
         txn.commit()?;
 
-        Ok(Self { env: Arc::new(env) })
+        let cache = Self {
+            env: Arc::new(env),
+            image_usage: Arc::new(AtomicU64::new(0)),
+            image_budget: Arc::new(AtomicU64::new(DEFAULT_IMAGE_BUDGET)),
+        };
+        cache.index_images()?;
+        Ok(cache)
     }
 
     pub fn read_cache<T: DeserializeOwned>(
@@ -83,26 +151,286 @@ impl LmdbCache {
         Ok(())
     }
 
+    fn image_dbs(&self, txn: &heed::RoTxn) -> Result<(RawDb, RawDb), Error> {
+        let images = self.env.open_database(txn, Some(DB_IMAGES))?.ok_or(Error::DatabaseClosing)?;
+        let index = self.env.open_database(txn, Some(DB_IMAGE_INDEX))?.ok_or(Error::DatabaseClosing)?;
+        Ok((images, index))
+    }
+
+    // 記録のない画像 (古い版で保存したもの) にも大きさを記録し、合計を数える。
+    // 時刻は 0 にして、最初に捨てられるようにする
+    fn index_images(&self) -> Result<(), Error> {
+        let mut wtxn = self.env.write_txn()?;
+        let (images, index) = self.image_dbs(&wtxn)?;
+        let mut missing = Vec::new();
+        let mut total = 0;
+        for item in images.iter(&wtxn)? {
+            let (key, data) = item?;
+            match index.get(&wtxn, key)?.and_then(ImageRecord::from_bytes) {
+                Some(record) => total += record.size,
+                None => missing.push((key.to_string(), data.len() as u64)),
+            }
+        }
+        for (key, size) in missing {
+            index.put(&mut wtxn, &key, &ImageRecord { last_used: 0, size }.to_bytes())?;
+            total += size;
+        }
+        wtxn.commit()?;
+        self.image_usage.store(total, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Reads a cached image and records the access for LRU eviction.
     pub fn read_image_cache(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        let (data, last_used) = {
+            let rtxn = self.env.read_txn()?;
+            let (images, index) = self.image_dbs(&rtxn)?;
+            let data = images.get(&rtxn, key)?.map(|data| data.to_vec());
+            let last_used = index.get(&rtxn, key)?.and_then(ImageRecord::from_bytes).map_or(0, |record| record.last_used);
+            (data, last_used)
+        };
+        let now = now_millis();
+        if let Some(data) = &data
+            && now.saturating_sub(last_used) >= IMAGE_TOUCH_INTERVAL_MS
+        {
+            let mut wtxn = self.env.write_txn()?;
+            let (_, index) = self.image_dbs(&wtxn)?;
+            let record = ImageRecord { last_used: now, size: data.len() as u64 };
+            index.put(&mut wtxn, key, &record.to_bytes())?;
+            wtxn.commit()?;
+        }
+        Ok(data)
+    }
+
+    /// Stores an image, then evicts the least recently used ones if the cache is over budget.
+    pub fn write_image_cache(&self, key: &str, data: &[u8]) -> Result<(), Error> {
+        let mut wtxn = self.env.write_txn()?;
+        let (images, index) = self.image_dbs(&wtxn)?;
+        let replaced = index.get(&wtxn, key)?.and_then(ImageRecord::from_bytes).map_or(0, |record| record.size);
+        let record = ImageRecord { last_used: now_millis(), size: data.len() as u64 };
+        images.put(&mut wtxn, key, data)?;
+        index.put(&mut wtxn, key, &record.to_bytes())?;
+        let mut usage = (self.image_usage.load(Ordering::Relaxed) + record.size).saturating_sub(replaced);
+        let budget = self.image_budget.load(Ordering::Relaxed);
+        if usage > budget {
+            // 書くたびに消さないよう、上限の 9 割まで減らす
+            usage -= evict_images(&mut wtxn, images, index, usage, budget / 10 * 9)?;
+        }
+        // 書き込みトランザクションは同時に一つだけなので、コミット前に合計を更新すれば次の書き込みとずれない
+        self.image_usage.store(usage, Ordering::Relaxed);
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    /// Sets the disk budget of the image cache and evicts down to it right away.
+    pub fn set_image_budget(&self, budget: u64) -> Result<(), Error> {
+        self.image_budget.store(budget, Ordering::Relaxed);
+        let mut wtxn = self.env.write_txn()?;
+        let (images, index) = self.image_dbs(&wtxn)?;
+        let usage = self.image_usage.load(Ordering::Relaxed);
+        let freed = evict_images(&mut wtxn, images, index, usage, budget)?;
+        self.image_usage.store(usage - freed, Ordering::Relaxed);
+        wtxn.commit()?;
+        Ok(())
+    }
+
+    pub fn image_cache_usage(&self) -> Result<ImageCacheUsage, Error> {
+        let rtxn = self.env.read_txn()?;
+        let (images, _) = self.image_dbs(&rtxn)?;
+        Ok(ImageCacheUsage {
+            bytes: self.image_usage.load(Ordering::Relaxed),
+            entries: images.len(&rtxn)?,
+        })
+    }
+
+    pub fn clear_image_cache(&self) -> Result<(), Error> {
+        let mut wtxn = self.env.write_txn()?;
+        let (images, index) = self.image_dbs(&wtxn)?;
+        images.clear(&mut wtxn)?;
+        index.clear(&mut wtxn)?;
+        wtxn.commit()?;
+        self.image_usage.store(0, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn read_bytes(&self, db_name: &str, key: &str) -> Result<Option<Vec<u8>>, Error> {
         let rtxn = self.env.read_txn()?;
         let db: Database<Str, Bytes> = self
             .env
-            .open_database(&rtxn, Some(DB_IMAGES))?
+            .open_database(&rtxn, Some(db_name))?
             .ok_or(Error::DatabaseClosing)?;
-        match db.get(&rtxn, key)? {
-            Some(data) => Ok(Some(data.to_vec())),
-            None => Ok(None),
-        }
+        Ok(db.get(&rtxn, key)?.map(|data| data.to_vec()))
     }
 
-    pub fn write_image_cache(&self, key: &str, data: &[u8]) -> Result<(), Error> {
+    pub fn write_bytes(&self, db_name: &str, key: &str, data: &[u8]) -> Result<(), Error> {
         let mut wtxn = self.env.write_txn()?;
         let db: Database<Str, Bytes> = self
             .env
-            .open_database(&wtxn, Some(DB_IMAGES))?
+            .open_database(&wtxn, Some(db_name))?
             .ok_or(Error::DatabaseClosing)?;
         db.put(&mut wtxn, key, data)?;
         wtxn.commit()?;
         Ok(())
     }
+
+    /// Stores `data` as plain JSON. Unlike `write_cache`, the entry does not expire.
+    pub fn write_state<T: Serialize>(
+        &self,
+        db_name: &str,
+        key: &str,
+        data: &T,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.write_bytes(db_name, key, &serde_json::to_vec(data)?)?;
+        Ok(())
+    }
+
+    /// Reads an entry written by `write_state`. `Ok(None)` if there is none.
+    pub fn read_state<T: DeserializeOwned>(
+        &self,
+        db_name: &str,
+        key: &str,
+    ) -> Result<Option<T>, Box<dyn std::error::Error + Send + Sync>> {
+        match self.read_bytes(db_name, key)? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Stores `data` as JSON sealed with XChaCha20-Poly1305 under `secret`.
+    ///
+    /// Unlike `write_cache`, entries do not expire. The entry key is authenticated so a
+    /// value cannot be moved to another key.
+    pub fn write_encrypted<T: Serialize>(
+        &self,
+        db_name: &str,
+        key: &str,
+        data: &T,
+        secret: &[u8; 32],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let plaintext = serde_json::to_vec(data)?;
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill(&mut nonce);
+        let cipher = XChaCha20Poly1305::new(Key::from_slice(secret));
+        let ciphertext = cipher
+            .encrypt(XNonce::from_slice(&nonce), Payload { msg: &plaintext, aad: key.as_bytes() })
+            .map_err(|e| format!("Encryption error: {e:?}"))?;
+
+        let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        self.write_bytes(db_name, key, &sealed)?;
+        Ok(())
+    }
+
+    /// Reads an entry written by `write_encrypted`. `Ok(None)` if there is none.
+    pub fn read_encrypted<T: DeserializeOwned>(
+        &self,
+        db_name: &str,
+        key: &str,
+        secret: &[u8; 32],
+    ) -> Result<Option<T>, Box<dyn std::error::Error + Send + Sync>> {
+        let Some(sealed) = self.read_bytes(db_name, key)? else {
+            return Ok(None);
+        };
+        if sealed.len() < NONCE_LEN {
+            return Err("Corrupted encrypted entry".into());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let cipher = XChaCha20Poly1305::new(Key::from_slice(secret));
+        let plaintext = cipher
+            .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad: key.as_bytes() })
+            .map_err(|_| "Wrong key or corrupted encrypted entry")?;
+        Ok(Some(serde_json::from_slice(&plaintext)?))
+    }
+}
+
+fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+// 最後に使われた時刻が古いものから消して、合計を `target` 以下にする。消したバイト数を返す
+fn evict_images(
+    wtxn: &mut heed::RwTxn,
+    images: RawDb,
+    index: RawDb,
+    usage: u64,
+    target: u64,
+) -> Result<u64, Error> {
+    if usage <= target {
+        return Ok(0);
+    }
+    let mut records = Vec::new();
+    for item in index.iter(wtxn)? {
+        let (key, bytes) = item?;
+        let record = ImageRecord::from_bytes(bytes).unwrap_or(ImageRecord { last_used: 0, size: 0 });
+        records.push((record.last_used, key.to_string(), record.size));
+    }
+    records.sort_unstable();
+    let mut freed = 0;
+    for (_, key, size) in records {
+        if usage - freed <= target {
+            break;
+        }
+        images.delete(wtxn, &key)?;
+        index.delete(wtxn, &key)?;
+        freed += size;
+    }
+    Ok(freed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used_images() {
+        let path = std::env::temp_dir().join(format!("rustr-image-lru-{}", std::process::id()));
+        let cache = LmdbCache::new(&path).unwrap();
+        cache.set_image_budget(100).unwrap();
+        for key in ["a", "b", "c"] {
+            cache.write_image_cache(key, &[0; 30]).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        // 書いた直後に読んでも記録は変わらない
+        let written = image_record(&cache, "a");
+        assert!(cache.read_image_cache("a").unwrap().is_some());
+        assert_eq!(image_record(&cache, "a"), written);
+        // しばらく経ってから a を読むと b が最も古くなる
+        set_last_used(&cache, "a", 0);
+        assert!(cache.read_image_cache("a").unwrap().is_some());
+        // 100 バイトを超えたら 90 バイトまで減らす
+        cache.write_image_cache("d", &[0; 30]).unwrap();
+
+        assert!(cache.read_image_cache("b").unwrap().is_none());
+        for key in ["a", "c", "d"] {
+            assert!(cache.read_image_cache(key).unwrap().is_some());
+        }
+        assert_eq!(cache.image_cache_usage().unwrap().bytes, 90);
+
+        cache.set_image_budget(40).unwrap();
+        let usage = cache.image_cache_usage().unwrap();
+        assert_eq!((usage.bytes, usage.entries), (30, 1));
+        assert!(cache.read_image_cache("d").unwrap().is_some());
+
+        cache.clear_image_cache().unwrap();
+        assert_eq!(cache.image_cache_usage().unwrap().entries, 0);
+        drop(cache);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    fn image_record(cache: &LmdbCache, key: &str) -> ImageRecord {
+        let rtxn = cache.env.read_txn().unwrap();
+        let (_, index) = cache.image_dbs(&rtxn).unwrap();
+        ImageRecord::from_bytes(index.get(&rtxn, key).unwrap().unwrap()).unwrap()
+    }
+
+    fn set_last_used(cache: &LmdbCache, key: &str, last_used: u64) {
+        let record = ImageRecord { last_used, ..image_record(cache, key) };
+        let mut wtxn = cache.env.write_txn().unwrap();
+        let (_, index) = cache.image_dbs(&wtxn).unwrap();
+        index.put(&mut wtxn, key, &record.to_bytes()).unwrap();
+        wtxn.commit().unwrap();
+    }
 }
@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use nostr::Timestamp;
+use tokio::sync::OnceCell;
+use zbus::zvariant::Value;
+
+use crate::types::NostrPostAppInternal;
+
+const APP_NAME: &str = "rustr";
+const DBUS_DESTINATION: &str = "org.freedesktop.Notifications";
+const DBUS_PATH: &str = "/org/freedesktop/Notifications";
+// これより古いイベントは、再接続で遅れて届いたものとみなして通知しない
+const MAX_ALERT_AGE_SECS: u64 = 10 * 60;
+const MAX_BODY_CHARS: usize = 120;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AlertKind {
+    Mention,
+    Message,
+    Zap,
+}
+
+#[derive(Clone)]
+enum Backend {
+    // 最初の通知のときにセッションバスへ接続する
+    Session {
+        address: Option<String>,
+        connection: Arc<OnceCell<zbus::Connection>>,
+    },
+    Connection(zbus::Connection),
+    Log,
+}
+
+/// Sends desktop notifications through `org.freedesktop.Notifications`.
+#[derive(Clone)]
+pub struct Notifier {
+    backend: Backend,
+}
+
+impl Notifier {
+    /// Picks the backend from the environment.
+    ///
+    /// `RUSTR_NOTIFIER=log` only prints notifications. `RUSTR_NOTIFIER_DBUS_ADDRESS`
+    /// points the D-Bus backend at another bus than the session bus, e.g. a mock one.
+    pub fn from_env() -> Self {
+        if std::env::var("RUSTR_NOTIFIER").is_ok_and(|v| v == "log") {
+            return Self::log();
+        }
+        Self {
+            backend: Backend::Session {
+                address: std::env::var("RUSTR_NOTIFIER_DBUS_ADDRESS").ok(),
+                connection: Arc::new(OnceCell::new()),
+            },
+        }
+    }
+
+    pub fn log() -> Self {
+        Self { backend: Backend::Log }
+    }
+
+    /// Uses an already open connection, e.g. a peer-to-peer one to a test server.
+    pub fn with_connection(connection: zbus::Connection) -> Self {
+        Self { backend: Backend::Connection(connection) }
+    }
+
+    /// Shows a notification and returns the id the notification server assigned to it.
+    pub async fn notify(&self, summary: &str, body: &str) -> Result<u32, Box<dyn std::error::Error + Send + Sync>> {
+        let connection = match &self.backend {
+            Backend::Log => {
+                println!("[notification] {summary}: {body}");
+                return Ok(0);
+            }
+            Backend::Connection(connection) => connection,
+            Backend::Session { address, connection } => {
+                connection
+                    .get_or_try_init(|| async {
+                        match address {
+                            Some(address) => zbus::connection::Builder::address(address.as_str())?.build().await,
+                            None => zbus::Connection::session().await,
+                        }
+                    })
+                    .await?
+            }
+        };
+        let actions: Vec<&str> = Vec::new();
+        let hints: HashMap<&str, Value> = HashMap::new();
+        let reply = connection
+            .call_method(
+                Some(DBUS_DESTINATION),
+                DBUS_PATH,
+                Some(DBUS_DESTINATION),
+                "Notify",
+                &(APP_NAME, 0u32, "", summary, body, actions, hints, -1i32),
+            )
+            .await?;
+        Ok(reply.body().deserialize()?)
+    }
+}
+
+/// Shows a desktop notification for something received while the window is unfocused,
+/// if that kind of notification is enabled in the settings.
+pub fn alert(app_data: &NostrPostAppInternal, kind: AlertKind, created_at: Timestamp, summary: String, body: &str) {
+    if app_data.window_focused || !app_data.desktop_notifications.allows(kind) {
+        return;
+    }
+    if created_at.as_u64() + MAX_ALERT_AGE_SECS < Timestamp::now().as_u64() {
+        return;
+    }
+    let mut text: String = body.chars().take(MAX_BODY_CHARS).collect();
+    if body.chars().count() > MAX_BODY_CHARS {
+        text.push('…');
+    }
+    let notifier = app_data.notifier.clone();
+    tokio::spawn(async move {
+        if let Err(e) = notifier.notify(&summary, &text).await {
+            eprintln!("Failed to show desktop notification: {e}");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use std::sync::Mutex;
+    use zbus::zvariant::OwnedValue;
+
+    // org.freedesktop.Notifications の代わりに、届いた通知を記録するだけのサーバー
+    struct MockNotifications {
+        received: Arc<Mutex<Vec<(String, String, String)>>>,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.Notifications")]
+    impl MockNotifications {
+        #[allow(clippy::too_many_arguments)]
+        fn notify(
+            &self,
+            app_name: String,
+            _replaces_id: u32,
+            _app_icon: String,
+            summary: String,
+            body: String,
+            _actions: Vec<String>,
+            _hints: HashMap<String, OwnedValue>,
+            _expire_timeout: i32,
+        ) -> u32 {
+            let mut received = self.received.lock().unwrap();
+            received.push((app_name, summary, body));
+            received.len() as u32
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_notify_call_over_dbus() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let (server_stream, client_stream) = UnixStream::pair().unwrap();
+        let guid = zbus::Guid::generate();
+        let server = zbus::connection::Builder::unix_stream(server_stream)
+            .server(guid)
+            .unwrap()
+            .p2p()
+            .serve_at(DBUS_PATH, MockNotifications { received: received.clone() })
+            .unwrap()
+            .build();
+        let client = zbus::connection::Builder::unix_stream(client_stream).p2p().build();
+        let (server, client) = futures::join!(server, client);
+        let _server = server.unwrap();
+
+        let notifier = Notifier::with_connection(client.unwrap());
+        let id = notifier.notify("Alice からのメンション", "こんにちは").await.unwrap();
+
+        assert_eq!(id, 1);
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![(APP_NAME.to_string(), "Alice からのメンション".to_string(), "こんにちは".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn log_backend_needs_no_bus() {
+        assert_eq!(Notifier::log().notify("summary", "body").await.unwrap(), 0);
+    }
+}
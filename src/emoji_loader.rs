@@ -129,46 +129,45 @@ pub async fn fetch_from_relay(
                 if let Some(msg_type) = parsed.get(0).and_then(|v| v.as_str()) {
                     match msg_type {
                         "EVENT" => {
-                            if let (Some(sid), Some(event_json)) = (parsed.get(1).and_then(|v| v.as_str()), parsed.get(2)) {
-                                if sid == sub_id {
-                                    if let Ok(event) = serde_json::from_value::<RawNostrEvent>(event_json.clone()) {
-                                        let mut is_emoji_list = false;
-                                        let mut is_pointer_list = false;
-
-                                        if event.kind == 30030 {
-                                            is_emoji_list = true;
-                                        } else if event.kind == 10030 {
-                                            if event.tags.iter().any(|t| t.get(0).map_or(false, |v| v == "d") && t.get(1).map_or(false, |v| v == "emojis")) {
-                                                is_emoji_list = true;
-                                            } else if event.tags.iter().any(|t| t.get(0).map_or(false, |v| v == "a")) {
-                                                is_pointer_list = true;
-                                            }
-                                        }
+                            if let (Some(sid), Some(event_json)) = (parsed.get(1).and_then(|v| v.as_str()), parsed.get(2))
+                                && sid == sub_id
+                                && let Ok(event) = serde_json::from_value::<RawNostrEvent>(event_json.clone())
+                            {
+                                let mut is_emoji_list = false;
+                                let mut is_pointer_list = false;
+
+                                if event.kind == 30030 {
+                                    is_emoji_list = true;
+                                } else if event.kind == 10030 {
+                                    if event.tags.iter().any(|t| t.first().is_some_and(|v| v == "d") && t.get(1).is_some_and(|v| v == "emojis")) {
+                                        is_emoji_list = true;
+                                    } else if event.tags.iter().any(|t| t.first().is_some_and(|v| v == "a")) {
+                                        is_pointer_list = true;
+                                    }
+                                }
 
-                                        if is_emoji_list {
-                                            for tag in &event.tags {
-                                                if tag.len() >= 3 && tag[0] == "emoji" {
-                                                    let shortcode = &tag[1];
-                                                    let image_url = tag[2].clone();
-                                                    let shortcode_key = shortcode.trim_matches(':').to_string();
-                                                    if !shortcode_key.is_empty() {
-                                                        emojis.insert(shortcode_key, image_url);
-                                                    }
-                                                }
+                                if is_emoji_list {
+                                    for tag in &event.tags {
+                                        if tag.len() >= 3 && tag[0] == "emoji" {
+                                            let shortcode = &tag[1];
+                                            let image_url = tag[2].clone();
+                                            let shortcode_key = shortcode.trim_matches(':').to_string();
+                                            if !shortcode_key.is_empty() {
+                                                emojis.insert(shortcode_key, image_url);
                                             }
                                         }
+                                    }
+                                }
 
-                                        if is_pointer_list {
-                                            for tag in &event.tags {
-                                                if tag.len() >= 2 && tag[0] == "a" {
-                                                    let parts: Vec<&str> = tag[1].split(':').collect();
-                                                    if parts.len() == 3 && parts[0] == "30030" {
-                                                        if let Ok(pubkey) = PublicKey::from_str(parts[1]) {
-                                                            let d_identifier = parts[2].to_string();
-                                                            pointers.push(EventPointer { pubkey, d_identifier });
-                                                        }
-                                                    }
-                                                }
+                                if is_pointer_list {
+                                    for tag in &event.tags {
+                                        if tag.len() >= 2 && tag[0] == "a" {
+                                            let parts: Vec<&str> = tag[1].split(':').collect();
+                                            if parts.len() == 3 && parts[0] == "30030"
+                                                && let Ok(pubkey) = PublicKey::from_str(parts[1])
+                                            {
+                                                let d_identifier = parts[2].to_string();
+                                                pointers.push(EventPointer { pubkey, d_identifier });
                                             }
                                         }
                                     }
@@ -176,9 +175,8 @@ pub async fn fetch_from_relay(
                             }
                         },
                         "EOSE" => {
-                            if let Some(sid) = parsed.get(1).and_then(|v| v.as_str()) {
-                                if sid == sub_id { break; }
-                            }
+                            if let Some(sid) = parsed.get(1).and_then(|v| v.as_str())
+                                && sid == sub_id { break; }
                         },
                         _ => {}
                     }
@@ -0,0 +1,131 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+
+use nostr::{Event, EventId, Filter, Kind, PublicKey};
+use nostr_sdk::NostrLMDB;
+use nostr_sdk::prelude::{IntoNostrDatabase, NostrDatabase};
+
+use crate::{
+    nostr_client::event_to_timeline_post,
+    types::{ProfileMetadata, TimelinePost},
+};
+
+// cache_db (heed) とは別の LMDB 環境にする
+pub const EVENT_DB_PATH: &str = "event_db";
+
+/// The signed events the relay pool received or sent, queryable by `Filter`.
+pub type EventDb = Arc<dyn NostrDatabase>;
+
+/// Opens the event database. Every session's client shares the one handle, since an
+/// LMDB environment must not be opened twice in a process.
+pub fn open(path: &Path) -> Result<EventDb, Box<dyn std::error::Error + Send + Sync>> {
+    std::fs::create_dir_all(path)?;
+    Ok(NostrLMDB::open(path)?.into_nostr_database())
+}
+
+/// Stored events matching any of `filters`, newest first, without duplicates.
+pub async fn query(db: &EventDb, filters: Vec<Filter>) -> Vec<Event> {
+    let mut seen = HashSet::new();
+    let mut events = Vec::new();
+    for filter in filters {
+        match db.query(filter).await {
+            Ok(found) => events.extend(found.into_iter().filter(|event| seen.insert(event.id))),
+            Err(e) => eprintln!("Failed to query event store: {e}"),
+        }
+    }
+    events.sort_by_key(|event| Reverse(event.created_at));
+    events
+}
+
+/// The stored kind 0 metadata of `authors`.
+pub async fn profiles(db: &EventDb, authors: HashSet<PublicKey>) -> HashMap<PublicKey, ProfileMetadata> {
+    if authors.is_empty() {
+        return HashMap::new();
+    }
+    query(db, vec![Filter::new().authors(authors).kind(Kind::Metadata)])
+        .await
+        .into_iter()
+        .filter_map(|event| Some((event.pubkey, serde_json::from_str(&event.content).ok()?)))
+        .collect()
+}
+
+/// Stored events as timeline posts, with their authors' stored metadata.
+pub async fn posts(db: &EventDb, filters: Vec<Filter>) -> Vec<TimelinePost> {
+    let events = query(db, filters).await;
+    let profiles = profiles(db, events.iter().map(|event| event.pubkey).collect()).await;
+    events
+        .iter()
+        .map(|event| event_to_timeline_post(event, profiles.get(&event.pubkey).cloned().unwrap_or_default()))
+        .collect()
+}
+
+/// Merges a page fetched from relays into posts read from the store. Fetched posts win,
+/// since they carry fresher author metadata. Stored posts older than the page are dropped,
+/// so paging back from the oldest post doesn't skip anything.
+pub fn merge_fetched(posts: &mut Vec<TimelinePost>, fetched: Vec<TimelinePost>) {
+    if let Some(oldest) = fetched.iter().map(|post| post.created_at).min() {
+        posts.retain(|post| post.created_at >= oldest);
+    }
+    let ids: HashSet<EventId> = fetched.iter().map(|post| post.id).collect();
+    posts.retain(|post| !ids.contains(&post.id));
+    posts.extend(fetched);
+    posts.sort_by_key(|post| Reverse(post.created_at));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr::{EventBuilder, Keys, Timestamp};
+
+    #[tokio::test]
+    async fn builds_posts_from_stored_events() {
+        let path = std::env::temp_dir().join(format!("rustr-event-store-{}", std::process::id()));
+        let db = open(&path).unwrap();
+        let alice = Keys::generate();
+        let metadata = EventBuilder::new(Kind::Metadata, r#"{"name":"alice"}"#).sign_with_keys(&alice).unwrap();
+        let older = EventBuilder::text_note("1")
+            .custom_created_at(Timestamp::from(1_700_000_000))
+            .sign_with_keys(&alice)
+            .unwrap();
+        let newer = EventBuilder::text_note("2")
+            .custom_created_at(Timestamp::from(1_700_000_100))
+            .sign_with_keys(&alice)
+            .unwrap();
+        for event in [&metadata, &older, &newer] {
+            db.save_event(event).await.unwrap();
+        }
+
+        let filter = Filter::new().author(alice.public_key()).kind(Kind::TextNote);
+        let posts = posts(&db, vec![filter.clone(), filter]).await;
+
+        assert_eq!(posts.iter().map(|p| p.id).collect::<Vec<_>>(), vec![newer.id, older.id]);
+        assert_eq!(posts[0].author_metadata.name, "alice");
+        drop(db);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn merges_fetched_page_into_stored_posts() {
+        let keys = Keys::generate();
+        let post = |content: &str, at: u64, name: &str| {
+            let event = EventBuilder::text_note(content)
+                .custom_created_at(Timestamp::from(at))
+                .sign_with_keys(&keys)
+                .unwrap();
+            event_to_timeline_post(&event, ProfileMetadata { name: name.to_string(), ..Default::default() })
+        };
+        let shared = post("b", 200, "new");
+        let mut posts = vec![
+            post("a", 250, "old"),
+            TimelinePost { author_metadata: Default::default(), ..shared.clone() },
+            post("z", 100, "old"),
+        ];
+
+        merge_fetched(&mut posts, vec![shared, post("c", 300, "new")]);
+
+        assert_eq!(posts.iter().map(|p| p.content.as_str()).collect::<Vec<_>>(), vec!["c", "a", "b"]);
+        assert_eq!(posts[2].author_metadata.name, "new");
+    }
+}
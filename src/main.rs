@@ -1,9 +1,23 @@
 mod cache_db;
+mod desktop_notify;
 mod emoji_loader;
+mod event_store;
+mod nip02;
+mod nip05;
+mod nip06;
+mod nip46;
 mod nip49;
+mod nip51;
+mod nip10;
+mod nip17;
+mod nip18;
+mod nip65;
+mod nip92;
+mod notifications;
 mod nostr_client;
 mod ui;
 mod types;
+mod upload;
 
 use eframe::egui;
 use std::collections::{HashMap, HashSet};
@@ -18,14 +32,18 @@ mod theme;
 
 use crate::cache_db::{LmdbCache, DB_FOLLOWED, DB_PROFILES};
 use crate::types::*;
+use crate::ui::image_cache::ImageCache;
 
 
 const CONFIG_FILE: &str = "config.json"; // 設定ファイル名
+// 1: 空のリレー設定を既定のリレーに置き換え済み
+const CONFIG_VERSION: u32 = 1;
 
 const DB_PATH: &str = "cache_db";
 const CACHE_DIR: &str = "cache"; // Re-added for migration
 
 const MAX_POST_LENGTH: usize = 140; // 投稿の最大文字数
+const READ_ONLY_HINT: &str = "閲覧専用モードでは署名できないため使えません";
 
 async fn migrate_data_from_files(
     cache_db: &LmdbCache,
@@ -43,15 +61,14 @@ async fn migrate_data_from_files(
     for entry in fs::read_dir(cache_path)? {
         let entry = entry?;
         let path = entry.path();
-        if path.is_file() {
-            if let Some(captures) = re.captures(path.file_name().unwrap().to_str().unwrap()) {
-                if let Some(pubkey) = captures.get(1) {
-                    files_by_pubkey
-                        .entry(pubkey.as_str().to_string())
-                        .or_default()
-                        .push(path);
-                }
-            }
+        if path.is_file()
+            && let Some(captures) = re.captures(path.file_name().unwrap().to_str().unwrap())
+            && let Some(pubkey) = captures.get(1)
+        {
+            files_by_pubkey
+                .entry(pubkey.as_str().to_string())
+                .or_default()
+                .push(path);
         }
     }
 
@@ -88,11 +105,15 @@ pub struct NostrPostApp {
 }
 
 // --- Config ---
-fn load_config() -> (Config, RelayConfig) {
-    if Path::new(CONFIG_FILE).exists() {
-        let config_str = fs::read_to_string(CONFIG_FILE).unwrap_or_default();
-        let config: Config = serde_json::from_str(&config_str).unwrap_or_default();
+pub fn load_config() -> Config {
+    if !Path::new(CONFIG_FILE).exists() {
+        return Config { version: CONFIG_VERSION, ..Default::default() };
+    }
+    let config_str = fs::read_to_string(CONFIG_FILE).unwrap_or_default();
+    let mut config: Config = serde_json::from_str(&config_str).unwrap_or_default();
 
+    // Migrate single-account configs into the account list.
+    if config.accounts.is_empty() && !config.encrypted_secret_key.is_empty() {
         // Migrate from old `Vec<String>` relay format if necessary
         let relay_config = serde_json::from_value::<RelayConfig>(config.relays.clone())
             .unwrap_or_else(|_| {
@@ -100,36 +121,66 @@ fn load_config() -> (Config, RelayConfig) {
                     serde_json::from_value(config.relays.clone()).unwrap_or_default();
                 RelayConfig {
                     aggregator: old_relays,
-                    self_hosted: vec![],
-                    search: vec![],
+                    ..Default::default()
                 }
             });
-
-        (config, relay_config)
-    } else {
-        (Config::default(), RelayConfig::default())
+        config.accounts.push(Account {
+            name: String::new(),
+            pubkey: String::new(),
+            encrypted_secret_key: std::mem::take(&mut config.encrypted_secret_key),
+            salt: std::mem::take(&mut config.salt),
+            encrypted_nwc_uri: config.encrypted_nwc_uri.take(),
+            relays: relay_config,
+            remote_signer: None,
+            read_only: false,
+            blur_unfollowed_media: false,
+        });
+        config.active_account = 0;
+    }
+    if config.active_account >= config.accounts.len() {
+        config.active_account = 0;
+    }
+    // 以前は起動時に既定のリレーで上書きしていたので、リレーが空のまま保存された設定がある。
+    // 一度だけ既定のリレーを入れ、それ以降は空にしたリレー設定をそのまま使う
+    if config.version < 1 {
+        for account in &mut config.accounts {
+            if account.relays.is_empty() {
+                account.relays = RelayConfig::with_defaults();
+            }
+        }
     }
+    config.version = CONFIG_VERSION;
+    config
 }
 
+pub fn write_config(config: &Config) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    fs::write(CONFIG_FILE, serde_json::to_string_pretty(config)?)?;
+    Ok(())
+}
 
 pub fn save_config(app_data: &mut NostrPostAppInternal) {
     // Load the existing config to preserve sensitive fields like the secret key.
-    let mut current_config: Config = if Path::new(CONFIG_FILE).exists() {
-        let config_str = fs::read_to_string(CONFIG_FILE).unwrap_or_default();
-        serde_json::from_str(&config_str).unwrap_or_default()
-    } else {
-        Config::default()
-    };
+    let mut current_config = load_config();
 
     // Update the fields from the current app state.
-    current_config.relays = serde_json::to_value(app_data.relays.clone()).unwrap();
+    let active_account = app_data.active_account;
+    if let Some(account) = current_config.accounts.get_mut(active_account) {
+        account.relays = app_data.relays.clone();
+        if let Some(current) = app_data.accounts.get(active_account) {
+            account.blur_unfollowed_media = current.blur_unfollowed_media;
+        }
+    }
+    // アカウント切り替えで古いリレー設定に戻らないよう、メモリ上の一覧も更新する
+    if let Some(account) = app_data.accounts.get_mut(active_account) {
+        account.relays = app_data.relays.clone();
+    }
     current_config.theme = Some(app_data.current_theme);
+    current_config.desktop_notifications = app_data.desktop_notifications;
+    current_config.media_server = app_data.media_server.clone();
+    current_config.image_cache = app_data.image_cache_config;
 
     // Write the updated config back.
-    match fs::write(
-        CONFIG_FILE,
-        serde_json::to_string_pretty(&current_config).unwrap(),
-    ) {
+    match write_config(&current_config) {
         Ok(_) => println!("Config saved successfully."),
         Err(e) => eprintln!("Failed to save config: {}", e),
     }
@@ -141,7 +192,8 @@ impl NostrPostApp {
         let runtime = Runtime::new().expect("Failed to create Tokio runtime");
 
         // --- 設定ファイルの読み込み ---
-        let (_config, relay_config) = load_config();
+        let _config = load_config();
+        let relay_config = _config.active().map(|a| a.relays.clone()).unwrap_or_default();
         let theme = _config.theme.unwrap_or(AppTheme::Light);
 
         // egui のスタイル設定
@@ -213,28 +265,64 @@ impl NostrPostApp {
 
         let lmdb_cache =
             LmdbCache::new(Path::new(DB_PATH)).expect("Failed to initialize LMDB cache");
-
-        let mut initial_relays = relay_config;
-        initial_relays.aggregator = vec!["wss://yabu.me".to_string()];
-        initial_relays.search = vec!["wss://search.nos.today".to_string()];
+        if let Err(e) = lmdb_cache.set_image_budget(_config.image_cache.budget_bytes()) {
+            eprintln!("Failed to trim image cache: {}", e);
+        }
+        let event_db = event_store::open(Path::new(event_store::EVENT_DB_PATH))
+            .expect("Failed to open event database");
 
         let app_data_internal = NostrPostAppInternal {
+            accounts: _config.accounts.clone(),
+            active_account: _config.active_account,
+            account_name_input: String::new(),
+            show_add_account: false,
+            register_mode: RegisterMode::ImportKey,
+            mnemonic_word_count: 12,
+            generated_mnemonic: None,
+            mnemonic_quiz: Vec::new(),
+            restore_mnemonic_input: String::new(),
+            restore_bip39_passphrase_input: String::new(),
+            restore_account_index: 0,
+            bunker_uri_input: String::new(),
+            nostrconnect_uri: None,
+            nostrconnect_app_keys: None,
+            nip46_auth_url: None,
+            read_only_input: String::new(),
             nwc_uri_input: String::new(),
             cache_db: lmdb_cache,
+            event_db,
             is_logged_in: false,
             post_input: String::new(),
             show_post_dialog: false,
             show_emoji_picker: false,
+            attachments: Vec::new(),
+            uploads_in_progress: 0,
+            upload_status: String::new(),
+            media_server: _config.media_server.clone(),
+            image_cache_config: _config.image_cache,
             my_emojis: HashMap::new(),
             secret_key_input: String::new(),
             passphrase_input: String::new(),
             confirm_passphrase_input: String::new(),
             nostr_client: None,
-            my_keys: None,
+            my_signer: None,
+            my_pubkey: None,
             followed_pubkeys: HashSet::new(),
             followed_pubkeys_display: String::new(),
+            timeline_feed: TimelineFeed::Following,
             timeline_posts: Vec::new(),
+            timeline_pagination: Default::default(),
             notification_posts: Vec::new(),
+            notification_pagination: Default::default(),
+            new_timeline_posts: Vec::new(),
+            new_notification_posts: Vec::new(),
+            notifications_read_until: None,
+            desktop_notifications: _config.desktop_notifications,
+            notifier: desktop_notify::Notifier::from_env(),
+            nip05_resolver: nip05::Resolver::from_env(),
+            nip05_checks: HashMap::new(),
+            window_focused: true,
+            live: Default::default(),
             should_repaint: false,
             is_loading: false,
             current_tab: AppTab::Home,
@@ -243,7 +331,9 @@ impl NostrPostApp {
             editable_profile: ProfileMetadata::default(),
             profile_fetch_status: "Fetching profile...".to_string(),
             current_theme: theme,
-            image_cache: HashMap::new(),
+            image_cache: ImageCache::default(),
+            revealed_media: HashSet::new(),
+            lightbox: None,
             nwc_passphrase_input: String::new(),
             nwc: None,
             nwc_client: None,
@@ -253,20 +343,39 @@ impl NostrPostApp {
             is_fetching_zap_history: false,
             show_zap_dialog: false,
             zap_amount_input: String::new(),
-            zap_target_post: None,
+            zap_target: None,
             show_reply_dialog: false,
             reply_input: String::new(),
             reply_target_post: None,
-            relays: initial_relays,
+            relays: relay_config,
             aggregator_relay_input: String::new(),
             self_hosted_relay_input: String::new(),
             search_relay_input: String::new(),
+            relay_list_status: String::new(),
+            dm_relay_input: String::new(),
+            dm_relays_status: String::new(),
+            follows: Default::default(),
+            mute_list: Default::default(),
+            mute_input: String::new(),
+            mute_privately: true,
+            mute_status: String::new(),
+            relay_panel_error: None,
+            relay_statuses: HashMap::new(),
+            relay_monitor: None,
             search_input: String::new(),
             search_results: Vec::new(),
+            search_query: String::new(),
+            search_pagination: Default::default(),
+            search_error: None,
             quoted_posts_cache: HashMap::new(),
+            thread: None,
+            user_profile: None,
+            nav_history: Vec::new(),
+            messages: Default::default(),
             profile_cache: HashMap::new(),
             posts_to_fetch: Arc::new(Mutex::new(HashSet::new())),
             profile_posts: Vec::new(),
+            profile_posts_pagination: Default::default(),
             is_fetching_profile_posts: false,
         };
         let data = Arc::new(Mutex::new(app_data_internal));
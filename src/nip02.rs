@@ -0,0 +1,235 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use nostr::{Event, EventBuilder, Filter, Kind, NostrSigner, PublicKey, Tag, Timestamp};
+use nostr_sdk::Client;
+
+use crate::cache_db::{LmdbCache, DB_FOLLOWED};
+use crate::nip65;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+// ロールバック用に残しておく過去のコンタクトリストの数
+const MAX_HISTORY: usize = 20;
+// これ以上フォローしているときだけ「大幅に減った」かを見る
+const SHRINK_CHECK_MIN: usize = 10;
+
+/// A change to our follows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContactChange {
+    Follow(Vec<PublicKey>),
+    Unfollow(HashSet<PublicKey>),
+}
+
+/// The pubkeys in the `p` tags of a kind 3 event.
+pub fn followed_pubkeys(event: &Event) -> HashSet<PublicKey> {
+    event.tags.public_keys().copied().collect()
+}
+
+fn tag_pubkey(tag: &Tag) -> Option<PublicKey> {
+    match tag.as_slice() {
+        [name, pubkey, ..] if name == "p" => PublicKey::from_hex(pubkey).ok(),
+        _ => None,
+    }
+}
+
+/// The tags of the next contact list: `base` with `change` applied.
+///
+/// Existing `p` tags are kept as they are, with their relay hints and petnames, and
+/// tags other than `p` are left untouched.
+pub fn apply_change(base: &[Tag], change: &ContactChange) -> Vec<Tag> {
+    let mut tags: Vec<Tag> = base.to_vec();
+    match change {
+        ContactChange::Follow(pubkeys) => {
+            let mut present: HashSet<PublicKey> = tags.iter().filter_map(tag_pubkey).collect();
+            for pubkey in pubkeys {
+                if present.insert(*pubkey) {
+                    tags.push(Tag::public_key(*pubkey));
+                }
+            }
+        }
+        ContactChange::Unfollow(pubkeys) => {
+            tags.retain(|tag| tag_pubkey(tag).is_none_or(|pubkey| !pubkeys.contains(&pubkey)));
+        }
+    }
+    tags
+}
+
+/// Refuses to publish when the relays' list looks stale or the result lost more follows
+/// than were asked to be removed.
+///
+/// `known` is how many people we believed we follow before the change, `base` how many
+/// the freshest kind 3 on the relays holds (`None` if none was found).
+pub fn check_shrink(known: usize, base: Option<usize>, next: usize, removed: usize) -> Result<(), String> {
+    let Some(base) = base else {
+        if known > 0 {
+            return Err("リレーから最新のフォローリストを取得できなかったため、公開を中止しました".to_string());
+        }
+        return Ok(());
+    };
+    if known >= SHRINK_CHECK_MIN && base * 2 < known {
+        return Err(format!(
+            "リレー上のフォローリスト ({base}人) が手元の記録 ({known}人) より大幅に少ないため、公開を中止しました"
+        ));
+    }
+    if next + removed < base {
+        return Err(format!(
+            "フォローが {}人 減る変更になるため、公開を中止しました",
+            base - next
+        ));
+    }
+    Ok(())
+}
+
+/// The newest kind 3 of `me` on the relays.
+pub async fn fetch_latest(
+    client: &Client,
+    me: PublicKey,
+) -> Result<Option<Event>, Box<dyn std::error::Error + Send + Sync>> {
+    let filter = Filter::new().author(me).kind(Kind::ContactList).limit(1);
+    let events = client.fetch_events(filter, FETCH_TIMEOUT).await?;
+    Ok(events.into_iter().max_by_key(|e| e.created_at))
+}
+
+fn history_key(me: &PublicKey) -> String {
+    format!("{}:contact_history", me.to_hex())
+}
+
+/// Contact lists we replaced, newest first.
+pub fn load_history(cache_db: &LmdbCache, me: &PublicKey) -> Vec<Event> {
+    cache_db
+        .read_state::<Vec<Event>>(DB_FOLLOWED, &history_key(me))
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to read contact list history: {e}");
+            None
+        })
+        .unwrap_or_default()
+}
+
+fn remember(cache_db: &LmdbCache, me: &PublicKey, event: &Event) -> Vec<Event> {
+    let mut history = load_history(cache_db, me);
+    if !history.iter().any(|e| e.id == event.id) {
+        history.insert(0, event.clone());
+        history.sort_by_key(|e| std::cmp::Reverse(e.created_at));
+        history.truncate(MAX_HISTORY);
+        if let Err(e) = cache_db.write_state(DB_FOLLOWED, &history_key(me), &history) {
+            eprintln!("Failed to save contact list history: {e}");
+        }
+    }
+    history
+}
+
+async fn publish_tags(
+    client: &Client,
+    signer: &Arc<dyn NostrSigner>,
+    previous: Option<&Event>,
+    content: String,
+    tags: Vec<Tag>,
+) -> Result<Event, Box<dyn std::error::Error + Send + Sync>> {
+    let mut builder = EventBuilder::new(Kind::ContactList, content).tags(tags);
+    // 置き換え可能イベントなので、前のリストより新しい created_at にする
+    if let Some(previous) = previous
+        && previous.created_at >= Timestamp::now()
+    {
+        builder = builder.custom_created_at(previous.created_at + 1);
+    }
+    let event = builder.sign(signer).await?;
+    nip65::publish_own(client, &event).await?;
+    Ok(event)
+}
+
+/// Fetches the freshest contact list, applies `change` and publishes the result.
+///
+/// The replaced list is kept in the local history. Returns the new event and history.
+pub async fn publish_change(
+    client: &Client,
+    cache_db: &LmdbCache,
+    signer: &Arc<dyn NostrSigner>,
+    me: PublicKey,
+    known: usize,
+    change: ContactChange,
+) -> Result<(Event, Vec<Event>), Box<dyn std::error::Error + Send + Sync>> {
+    let latest = fetch_latest(client, me).await?;
+    let base_tags: Vec<Tag> = latest.as_ref().map(|e| e.tags.as_slice().to_vec()).unwrap_or_default();
+    let base_count = latest.as_ref().map(|e| followed_pubkeys(e).len());
+    let tags = apply_change(&base_tags, &change);
+    let next_count = tags.iter().filter_map(tag_pubkey).collect::<HashSet<_>>().len();
+    let removed = match &change {
+        ContactChange::Follow(_) => 0,
+        ContactChange::Unfollow(pubkeys) => pubkeys.len(),
+    };
+    check_shrink(known, base_count, next_count, removed)?;
+
+    // 古いクライアントは content にリレーの JSON を入れているので、そのまま引き継ぐ
+    let content = latest.as_ref().map(|e| e.content.clone()).unwrap_or_default();
+    let event = publish_tags(client, signer, latest.as_ref(), content, tags).await?;
+    let history = match &latest {
+        Some(latest) => remember(cache_db, &me, latest),
+        None => load_history(cache_db, &me),
+    };
+    Ok((event, history))
+}
+
+/// Publishes the follows of an earlier contact list from the history again.
+pub async fn restore(
+    client: &Client,
+    cache_db: &LmdbCache,
+    signer: &Arc<dyn NostrSigner>,
+    me: PublicKey,
+    snapshot: &Event,
+) -> Result<(Event, Vec<Event>), Box<dyn std::error::Error + Send + Sync>> {
+    let latest = fetch_latest(client, me).await?;
+    let event = publish_tags(
+        client,
+        signer,
+        latest.as_ref(),
+        snapshot.content.clone(),
+        snapshot.tags.as_slice().to_vec(),
+    )
+    .await?;
+    let history = match &latest {
+        Some(latest) => remember(cache_db, &me, latest),
+        None => load_history(cache_db, &me),
+    };
+    Ok((event, history))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr::Keys;
+
+    #[test]
+    fn changes_keep_petnames_and_other_tags() {
+        let alice = Keys::generate().public_key();
+        let bob = Keys::generate().public_key();
+        let carol = Keys::generate().public_key();
+        let base = vec![
+            Tag::parse(["p", &alice.to_hex(), "wss://alice.example", "alice"]).unwrap(),
+            Tag::parse(["p", &bob.to_hex()]).unwrap(),
+            Tag::parse(["t", "nostr"]).unwrap(),
+        ];
+
+        let followed = apply_change(&base, &ContactChange::Follow(vec![alice, carol]));
+        assert_eq!(followed.len(), 4);
+        assert_eq!(followed[0], base[0]);
+        assert_eq!(followed[3], Tag::public_key(carol));
+
+        let unfollowed = apply_change(&followed, &ContactChange::Unfollow(HashSet::from([bob])));
+        assert_eq!(unfollowed, vec![base[0].clone(), base[2].clone(), Tag::public_key(carol)]);
+    }
+
+    #[test]
+    fn refuses_lists_that_shrink_unexpectedly() {
+        // リレーから取れなかった
+        assert!(check_shrink(120, None, 1, 0).is_err());
+        // 手元より大幅に少ない古いリスト
+        assert!(check_shrink(120, Some(3), 4, 0).is_err());
+        // 頼んだ分より多く減る
+        assert!(check_shrink(120, Some(120), 100, 1).is_err());
+
+        assert!(check_shrink(0, None, 1, 0).is_ok());
+        assert!(check_shrink(120, Some(118), 119, 0).is_ok());
+        assert!(check_shrink(120, Some(120), 70, 50).is_ok());
+    }
+}
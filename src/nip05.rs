@@ -0,0 +1,258 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use nostr::nips::nip05::{Nip05Address, Nip05Profile};
+use nostr::{PublicKey, Timestamp};
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Handle;
+use tokio::sync::Semaphore;
+
+use crate::cache_db::{LmdbCache, DB_NIP05};
+use crate::types::NostrPostAppInternal;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+// 一致・不一致の結果はしばらく使い回す
+const CHECKED_TTL_SECS: u64 = 24 * 60 * 60;
+// 取得に失敗したときは早めに確認し直す
+const UNREACHABLE_TTL_SECS: u64 = 30 * 60;
+// 同時に問い合わせる .well-known/nostr.json の数
+const MAX_CONCURRENT_LOOKUPS: usize = 4;
+
+/// The result of checking a profile's `nip05` against its pubkey.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Nip05Status {
+    Pending,
+    Verified,
+    // nostr.json が別の公開鍵を指している、または名前が載っていない
+    Mismatch,
+    // nostr.json を取得できなかった
+    Unreachable,
+}
+
+// NIP-05: /.well-known/nostr.json からのリダイレクトには従わない
+#[derive(Debug)]
+struct Redirected(u16);
+
+impl std::fmt::Display for Redirected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "nostr.json redirected with HTTP {}", self.0)
+    }
+}
+
+impl std::error::Error for Redirected {}
+
+#[derive(Serialize, Deserialize)]
+struct CachedCheck {
+    status: Nip05Status,
+    checked_at: u64,
+}
+
+impl CachedCheck {
+    fn is_fresh(&self, now: u64) -> bool {
+        let ttl = match self.status {
+            Nip05Status::Unreachable | Nip05Status::Pending => UNREACHABLE_TTL_SECS,
+            Nip05Status::Verified | Nip05Status::Mismatch => CHECKED_TTL_SECS,
+        };
+        now < self.checked_at + ttl
+    }
+}
+
+/// Fetches `.well-known/nostr.json` documents.
+#[derive(Clone)]
+pub struct Resolver {
+    // ドメインの代わりに問い合わせる先 (テスト用のローカルサーバーなど)
+    base_url: Option<String>,
+    agent: ureq::Agent,
+    permits: Arc<Semaphore>,
+}
+
+impl Resolver {
+    /// `RUSTR_NIP05_BASE_URL` sends every lookup to that server instead of the
+    /// identifier's domain, e.g. a local stand-in.
+    pub fn from_env() -> Self {
+        Self::new(std::env::var("RUSTR_NIP05_BASE_URL").ok())
+    }
+
+    pub fn new(base_url: Option<String>) -> Self {
+        Self {
+            base_url,
+            agent: ureq::AgentBuilder::new().redirects(0).timeout(FETCH_TIMEOUT).build(),
+            permits: Arc::new(Semaphore::new(MAX_CONCURRENT_LOOKUPS)),
+        }
+    }
+
+    fn url(&self, address: &Nip05Address) -> String {
+        match &self.base_url {
+            Some(base) => format!(
+                "{}/.well-known/nostr.json?name={}",
+                base.trim_end_matches('/'),
+                urlencoding::encode(address.name())
+            ),
+            None => address.url().to_string(),
+        }
+    }
+
+    /// Resolves `name@domain` to its pubkey and the relays the document lists for it.
+    pub async fn lookup(&self, identifier: &str) -> Result<Nip05Profile, Box<dyn std::error::Error + Send + Sync>> {
+        let address = Nip05Address::parse(identifier.trim())?;
+        let url = self.url(&address);
+        let agent = self.agent.clone();
+        let _permit = self.permits.acquire().await?;
+        let body = tokio::task::spawn_blocking(move || -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+            let res = agent.get(&url).call()?;
+            if (300..400).contains(&res.status()) {
+                return Err(Redirected(res.status()).into());
+            }
+            Ok(res.into_string()?)
+        })
+        .await??;
+        Ok(Nip05Profile::from_raw_json(&address, &body)?)
+    }
+
+    /// Checks that `identifier` points at `pubkey`. Results are kept in `DB_NIP05` for a while.
+    pub async fn verify(&self, cache_db: &LmdbCache, identifier: &str, pubkey: PublicKey) -> Nip05Status {
+        let key = format!("{}:{}", pubkey.to_hex(), identifier.trim().to_lowercase());
+        let now = Timestamp::now().as_u64();
+        match cache_db.read_state::<CachedCheck>(DB_NIP05, &key) {
+            Ok(Some(cached)) if cached.is_fresh(now) => return cached.status,
+            Ok(_) => {}
+            Err(e) => eprintln!("Failed to read NIP-05 cache: {e}"),
+        }
+        let status = match self.lookup(identifier).await {
+            Ok(profile) if profile.public_key == pubkey => Nip05Status::Verified,
+            Ok(_) => Nip05Status::Mismatch,
+            // 名前が載っていない・リダイレクトされたのは不一致、通信の失敗は到達不能として扱う
+            Err(e) if e.downcast_ref::<nostr::nips::nip05::Error>().is_some() || e.is::<Redirected>() => {
+                Nip05Status::Mismatch
+            }
+            Err(e) => {
+                eprintln!("Failed to look up {identifier}: {e}");
+                Nip05Status::Unreachable
+            }
+        };
+        if let Err(e) = cache_db.write_state(DB_NIP05, &key, &CachedCheck { status, checked_at: now }) {
+            eprintln!("Failed to cache NIP-05 check: {e}");
+        }
+        status
+    }
+}
+
+/// The verification state of `pubkey`'s `identifier`. Starts checking it the first time
+/// it is asked for and returns `Pending` until the check is done.
+pub fn status(
+    app_data: &mut NostrPostAppInternal,
+    pubkey: PublicKey,
+    identifier: &str,
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) -> Nip05Status {
+    if let Some((checked, status)) = app_data.nip05_checks.get(&pubkey)
+        && checked == identifier
+    {
+        return *status;
+    }
+    app_data
+        .nip05_checks
+        .insert(pubkey, (identifier.to_string(), Nip05Status::Pending));
+    let resolver = app_data.nip05_resolver.clone();
+    let cache_db = app_data.cache_db.clone();
+    let identifier = identifier.to_string();
+    let arc = app_data_arc.clone();
+    runtime_handle.spawn(async move {
+        let status = resolver.verify(&cache_db, &identifier, pubkey).await;
+        let mut app_data = arc.lock().unwrap();
+        // 確認中にプロフィールが変わっていたら結果は捨てる
+        if let Some(entry) = app_data.nip05_checks.get_mut(&pubkey).filter(|(checked, _)| *checked == identifier) {
+            entry.1 = status;
+            app_data.should_repaint = true;
+        }
+    });
+    Nip05Status::Pending
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr::Keys;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // 決まった応答を返すだけのローカルサーバー。リクエストの数を数える
+    async fn serve(response: String) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        (base_url, requests)
+    }
+
+    async fn serve_nostr_json(json: String) -> (String, Arc<AtomicUsize>) {
+        serve(format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{json}",
+            json.len()
+        ))
+        .await
+    }
+
+    #[tokio::test]
+    async fn lookup_reads_pubkey_and_relay_hints() {
+        let bob = Keys::generate().public_key();
+        let json = format!(
+            r#"{{"names":{{"bob":"{bob}"}},"relays":{{"{bob}":["wss://relay.example.com"]}}}}"#,
+            bob = bob.to_hex()
+        );
+        let (base_url, _) = serve_nostr_json(json).await;
+
+        let profile = Resolver::new(Some(base_url)).lookup("bob@example.com").await.unwrap();
+
+        assert_eq!(profile.public_key, bob);
+        assert_eq!(profile.relays.len(), 1);
+        assert_eq!(profile.relays[0].as_str_without_trailing_slash(), "wss://relay.example.com");
+    }
+
+    #[tokio::test]
+    async fn verify_caches_results() {
+        let bob = Keys::generate().public_key();
+        let mallory = Keys::generate().public_key();
+        let json = format!(r#"{{"names":{{"bob":"{}"}}}}"#, bob.to_hex());
+        let (base_url, requests) = serve_nostr_json(json).await;
+        let path = std::env::temp_dir().join(format!("rustr-nip05-test-{}", Keys::generate().public_key().to_hex()));
+        let cache_db = LmdbCache::new(&path).unwrap();
+        let resolver = Resolver::new(Some(base_url));
+
+        assert_eq!(resolver.verify(&cache_db, "bob@example.com", bob).await, Nip05Status::Verified);
+        assert_eq!(resolver.verify(&cache_db, "bob@example.com", bob).await, Nip05Status::Verified);
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert_eq!(resolver.verify(&cache_db, "bob@example.com", mallory).await, Nip05Status::Mismatch);
+        assert_eq!(resolver.verify(&cache_db, "alice@example.com", bob).await, Nip05Status::Mismatch);
+
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn redirects_are_not_followed() {
+        let bob = Keys::generate().public_key();
+        let (elsewhere, followed) = serve_nostr_json(format!(r#"{{"names":{{"bob":"{}"}}}}"#, bob.to_hex())).await;
+        let (base_url, _) = serve(format!(
+            "HTTP/1.1 301 Moved Permanently\r\nLocation: {elsewhere}/.well-known/nostr.json?name=bob\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        ))
+        .await;
+        let path = std::env::temp_dir().join(format!("rustr-nip05-test-{}", Keys::generate().public_key().to_hex()));
+        let cache_db = LmdbCache::new(&path).unwrap();
+
+        let status = Resolver::new(Some(base_url)).verify(&cache_db, "bob@example.com", bob).await;
+
+        assert_eq!(status, Nip05Status::Mismatch);
+        assert_eq!(followed.load(Ordering::SeqCst), 0);
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
@@ -0,0 +1,74 @@
+use bip39::Mnemonic;
+use nostr::nips::nip06::FromMnemonic;
+use nostr::Keys;
+use rand::rngs::OsRng;
+use rand::seq::index::sample;
+use rand::Rng;
+
+/// Number of words the user has to re-enter to confirm a new mnemonic.
+pub const QUIZ_WORDS: usize = 3;
+
+/// Generates a new English BIP-39 mnemonic with 12 or 24 words.
+pub fn generate_mnemonic(
+    word_count: usize,
+) -> Result<Mnemonic, Box<dyn std::error::Error + Send + Sync>> {
+    let entropy_len = match word_count {
+        12 => 16,
+        24 => 32,
+        _ => return Err(format!("Unsupported word count: {word_count}").into()),
+    };
+    let mut entropy = vec![0u8; entropy_len];
+    OsRng.fill(entropy.as_mut_slice());
+    Ok(Mnemonic::from_entropy(&entropy)?)
+}
+
+/// Parses and validates a mnemonic typed or pasted by the user.
+pub fn parse_mnemonic(words: &str) -> Result<Mnemonic, Box<dyn std::error::Error + Send + Sync>> {
+    let normalized = words.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase();
+    Ok(Mnemonic::parse_normalized(&normalized)?)
+}
+
+/// Derives keys at `m/44'/1237'/<account>'/0/0` as specified by NIP-06.
+pub fn derive_keys(
+    mnemonic: &Mnemonic,
+    passphrase: &str,
+    account: u32,
+) -> Result<Keys, Box<dyn std::error::Error + Send + Sync>> {
+    let phrase = mnemonic.to_string();
+    let passphrase = (!passphrase.is_empty()).then_some(passphrase);
+    Ok(Keys::from_mnemonic_with_account(phrase.as_str(), passphrase, Some(account))?)
+}
+
+/// Picks distinct, sorted word positions (0-based) for the confirmation quiz.
+pub fn quiz_positions(word_count: usize) -> Vec<usize> {
+    let mut positions = sample(&mut OsRng, word_count, QUIZ_WORDS.min(word_count)).into_vec();
+    positions.sort_unstable();
+    positions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derives_spec_vectors() {
+        // NIP-06 のテストベクタ。大文字や余分な空白があっても同じ鍵になる
+        let vectors = [
+            (
+                "  Leader monkey parrot ring guide accident before fence cannon height naive bean ",
+                "7f7ff03d123792d6ac594bfa67bf6d0c0ab55b6b1fdb6249303fe861f1ccba9a",
+                "17162c921dc4d2518f9a101db33695df1afb56ab82f5ff3e5da6eec3ca5cd917",
+            ),
+            (
+                "what bleak badge arrange retreat wolf trade produce cricket blur garlic valid proud rude strong choose busy staff weather area salt hollow arm fade",
+                "c15d739894c81a2fcfd3a2df85a0d2c0dbc47a280d092799f144d73d7ae78add",
+                "d41b22899549e1f3d335a31002cfd382174006e166d3e658e3a5eecdb6463573",
+            ),
+        ];
+        for (words, secret_key, public_key) in vectors {
+            let keys = derive_keys(&parse_mnemonic(words).unwrap(), "", 0).unwrap();
+            assert_eq!(keys.secret_key().to_secret_hex(), secret_key);
+            assert_eq!(keys.public_key().to_hex(), public_key);
+        }
+    }
+}
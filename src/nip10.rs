@@ -0,0 +1,273 @@
+use nostr::nips::nip10::Marker;
+use nostr::{EventId, PublicKey, RelayUrl, Tag, TagStandard};
+
+/// One `e` tag of a kind 1 note, with its optional relay hint and author.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventRef {
+    pub id: EventId,
+    pub relay: Option<RelayUrl>,
+    pub author: Option<PublicKey>,
+}
+
+/// Where a note sits in a thread, read from its `e` tags (NIP-10).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ThreadRefs {
+    pub root: Option<EventRef>,
+    pub reply: Option<EventRef>,
+    pub mentions: Vec<EventRef>,
+}
+
+impl ThreadRefs {
+    /// Reads marked `e` tags, or the deprecated positional form when no tag has a marker.
+    pub fn parse(tags: &[Tag]) -> Self {
+        // "mention" などの未知のマーカーは nostr の TagStandard では読めないので、生の配列を見る
+        let mut entries: Vec<(EventRef, Option<&str>)> = Vec::new();
+        for tag in tags {
+            let values = tag.as_slice();
+            if values.first().map(|s| s.as_str()) != Some("e") {
+                continue;
+            }
+            let Some(Ok(id)) = values.get(1).map(|s| EventId::from_hex(s)) else { continue };
+            let relay = values
+                .get(2)
+                .filter(|s| !s.is_empty())
+                .and_then(|s| RelayUrl::parse(s).ok());
+            let marker = values.get(3).map(|s| s.as_str()).filter(|s| !s.is_empty());
+            let author = values.get(4).and_then(|s| PublicKey::from_hex(s).ok());
+            entries.push((EventRef { id, relay, author }, marker));
+        }
+
+        let mut refs = Self::default();
+        // "mention" だけのタグでもマーカー付きの形式として読む
+        let marked = entries.iter().any(|(_, m)| m.is_some());
+        if marked {
+            for (event_ref, marker) in entries {
+                match marker {
+                    Some("root") if refs.root.is_none() => refs.root = Some(event_ref),
+                    Some("reply") if refs.reply.is_none() => refs.reply = Some(event_ref),
+                    _ => refs.mentions.push(event_ref),
+                }
+            }
+        } else {
+            // 旧形式: 先頭がルート、末尾が返信先、その間は言及
+            let count = entries.len();
+            for (i, (event_ref, _)) in entries.into_iter().enumerate() {
+                if i == 0 {
+                    refs.root = Some(event_ref);
+                } else if i == count - 1 {
+                    refs.reply = Some(event_ref);
+                } else {
+                    refs.mentions.push(event_ref);
+                }
+            }
+        }
+        refs
+    }
+
+    /// The note this one directly replies to. A reply to the root only has a root tag.
+    pub fn parent(&self) -> Option<&EventRef> {
+        self.reply.as_ref().or(self.root.as_ref())
+    }
+}
+
+fn event_tag(event_ref: EventRef, marker: Marker) -> Tag {
+    Tag::from_standardized(TagStandard::Event {
+        event_id: event_ref.id,
+        relay_url: event_ref.relay,
+        marker: Some(marker),
+        public_key: event_ref.author,
+        uppercase: false,
+    })
+}
+
+/// Tags for a kind 1 reply to the note `parent_id` by `parent_author`.
+///
+/// Emits marked `root` / `reply` `e` tags (only `root` when the parent starts the
+/// thread) and `p` tags for the parent's author plus every `p` of the parent, without
+/// duplicates and without `me`. Relay hints already on the parent's tags are kept;
+/// missing ones come from `relay_hint`.
+pub fn reply_tags(
+    parent_id: EventId,
+    parent_author: PublicKey,
+    parent_tags: &[Tag],
+    me: PublicKey,
+    relay_hint: impl Fn(&PublicKey) -> Option<RelayUrl>,
+) -> Vec<Tag> {
+    let refs = ThreadRefs::parse(parent_tags);
+    let parent = EventRef {
+        id: parent_id,
+        relay: relay_hint(&parent_author),
+        author: Some(parent_author),
+    };
+
+    let mut tags = Vec::new();
+    match refs.root {
+        Some(mut root) if root.id != parent_id => {
+            if root.relay.is_none() {
+                root.relay = root.author.as_ref().and_then(&relay_hint);
+            }
+            tags.push(event_tag(root, Marker::Root));
+            tags.push(event_tag(parent, Marker::Reply));
+        }
+        _ => tags.push(event_tag(parent, Marker::Root)),
+    }
+
+    let mut mentioned: Vec<(PublicKey, Option<RelayUrl>)> = vec![(parent_author, None)];
+    for tag in parent_tags {
+        let values = tag.as_slice();
+        if values.first().map(|s| s.as_str()) != Some("p") {
+            continue;
+        }
+        let Some(Ok(public_key)) = values.get(1).map(|s| PublicKey::from_hex(s)) else { continue };
+        let relay = values
+            .get(2)
+            .filter(|s| !s.is_empty())
+            .and_then(|s| RelayUrl::parse(s).ok());
+        match mentioned.iter_mut().find(|(pk, _)| *pk == public_key) {
+            Some((_, existing)) => {
+                if existing.is_none() {
+                    *existing = relay;
+                }
+            }
+            None => mentioned.push((public_key, relay)),
+        }
+    }
+    for (public_key, relay) in mentioned {
+        if public_key == me {
+            continue;
+        }
+        tags.push(Tag::from_standardized(TagStandard::PublicKey {
+            public_key,
+            relay_url: relay.or_else(|| relay_hint(&public_key)),
+            alias: None,
+            uppercase: false,
+        }));
+    }
+    tags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr::Keys;
+
+    fn id(n: u8) -> EventId {
+        EventId::from_byte_array([n; 32])
+    }
+
+    fn raw(values: &[&str]) -> Tag {
+        Tag::parse(values.iter().copied()).unwrap()
+    }
+
+    fn values(tags: &[Tag]) -> Vec<Vec<String>> {
+        tags.iter().map(|t| t.as_slice().to_vec()).collect()
+    }
+
+    fn no_hint(_: &PublicKey) -> Option<RelayUrl> {
+        None
+    }
+
+    #[test]
+    fn reply_to_root_marks_parent_as_root() {
+        let me = Keys::generate().public_key();
+        let alice = Keys::generate().public_key();
+        let hint = RelayUrl::parse("wss://alice.example").unwrap();
+
+        let tags = reply_tags(id(1), alice, &[], me, |pk| (*pk == alice).then(|| hint.clone()));
+
+        assert_eq!(
+            values(&tags),
+            vec![
+                vec!["e".to_string(), id(1).to_hex(), hint.to_string(), "root".to_string(), alice.to_hex()],
+                vec!["p".to_string(), alice.to_hex(), hint.to_string()],
+            ]
+        );
+        let refs = ThreadRefs::parse(&tags);
+        assert_eq!(refs.root.map(|r| r.id), Some(id(1)));
+        assert!(refs.reply.is_none());
+    }
+
+    #[test]
+    fn reply_mid_thread_carries_root_and_deduplicates_p_tags() {
+        let me = Keys::generate().public_key();
+        let alice = Keys::generate().public_key();
+        let bob = Keys::generate().public_key();
+        let carol = Keys::generate().public_key();
+        // alice のルート (id 1) から続くスレッドで、carol の投稿 (id 3) に返信する
+        let parent_tags = vec![
+            raw(&["e", &id(1).to_hex(), "wss://root.example", "root"]),
+            raw(&["e", &id(2).to_hex(), "", "reply"]),
+            raw(&["p", &alice.to_hex(), "wss://alice.example"]),
+            raw(&["p", &bob.to_hex()]),
+            raw(&["p", &me.to_hex()]),
+            raw(&["p", &alice.to_hex()]),
+        ];
+
+        let tags = reply_tags(id(3), carol, &parent_tags, me, no_hint);
+
+        let refs = ThreadRefs::parse(&tags);
+        let root = refs.root.unwrap();
+        assert_eq!(root.id, id(1));
+        assert_eq!(root.relay, Some(RelayUrl::parse("wss://root.example").unwrap()));
+        assert_eq!(refs.reply.map(|r| r.id), Some(id(3)));
+        assert!(refs.mentions.is_empty());
+
+        let p_tags: Vec<Vec<String>> = values(&tags).into_iter().filter(|v| v[0] == "p").collect();
+        assert_eq!(
+            p_tags,
+            vec![
+                vec!["p".to_string(), carol.to_hex()],
+                vec!["p".to_string(), alice.to_hex(), "wss://alice.example".to_string()],
+                vec!["p".to_string(), bob.to_hex()],
+            ]
+        );
+    }
+
+    #[test]
+    fn reply_to_legacy_positional_event() {
+        let me = Keys::generate().public_key();
+        let alice = Keys::generate().public_key();
+        let bob = Keys::generate().public_key();
+        // 旧形式: [ルート, 言及, 返信先] の順で、マーカーなし
+        let parent_tags = vec![
+            raw(&["e", &id(1).to_hex()]),
+            raw(&["e", &id(5).to_hex()]),
+            raw(&["e", &id(2).to_hex()]),
+            raw(&["p", &alice.to_hex()]),
+        ];
+        let parent_refs = ThreadRefs::parse(&parent_tags);
+        assert_eq!(parent_refs.root.as_ref().map(|r| r.id), Some(id(1)));
+        assert_eq!(parent_refs.parent().map(|r| r.id), Some(id(2)));
+        assert_eq!(parent_refs.mentions.iter().map(|r| r.id).collect::<Vec<_>>(), vec![id(5)]);
+
+        let tags = reply_tags(id(3), bob, &parent_tags, me, no_hint);
+
+        let refs = ThreadRefs::parse(&tags);
+        assert_eq!(refs.root.map(|r| r.id), Some(id(1)));
+        assert_eq!(refs.reply.map(|r| r.id), Some(id(3)));
+        assert_eq!(
+            values(&tags).into_iter().filter(|v| v[0] == "p").map(|v| v[1].clone()).collect::<Vec<_>>(),
+            vec![bob.to_hex(), alice.to_hex()]
+        );
+    }
+
+    #[test]
+    fn single_positional_tag_is_the_root() {
+        let tags = vec![raw(&["e", &id(1).to_hex()])];
+        let refs = ThreadRefs::parse(&tags);
+        assert_eq!(refs.root.as_ref().map(|r| r.id), Some(id(1)));
+        assert_eq!(refs.parent().map(|r| r.id), Some(id(1)));
+    }
+
+    #[test]
+    fn mention_only_tags_are_not_a_reply() {
+        let tags = vec![
+            raw(&["e", &id(1).to_hex(), "", "mention"]),
+            raw(&["e", &id(2).to_hex(), "", "mention"]),
+        ];
+        let refs = ThreadRefs::parse(&tags);
+        assert!(refs.root.is_none());
+        assert!(refs.parent().is_none());
+        assert_eq!(refs.mentions.iter().map(|r| r.id).collect::<Vec<_>>(), vec![id(1), id(2)]);
+    }
+}
@@ -0,0 +1,338 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use nostr::nips::nip59;
+use nostr::{Event, EventBuilder, EventId, Filter, Kind, NostrSigner, PublicKey, Tag, Timestamp};
+use nostr_sdk::prelude::{FlagCheck, RelayServiceFlags};
+use nostr_sdk::Client;
+use rand::{rngs::OsRng, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::cache_db::{LmdbCache, DB_MESSAGES, DB_RELAYS};
+use crate::nip65;
+use crate::types::RelayConfig;
+
+// ギフトラップの created_at は最大 2 日前までずらされる (NIP-59)
+const WRAP_TIMESTAMP_TWEAK: u64 = 2 * 24 * 60 * 60;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// One decrypted message of a conversation.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DirectMessage {
+    // NIP-17 ならラップの中の rumor (kind 14) の id、NIP-04 ならイベントの id
+    pub id: EventId,
+    pub sender: PublicKey,
+    pub content: String,
+    pub created_at: Timestamp,
+    // 旧形式 (NIP-04, kind 4) で受け取ったメッセージ
+    #[serde(default)]
+    pub legacy: bool,
+}
+
+/// Decrypted conversations of one account, kept encrypted in `DB_MESSAGES`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct MessageStore {
+    // 相手ごとの会話。古い順。
+    pub conversations: HashMap<PublicKey, Vec<DirectMessage>>,
+    // 復号済みのイベント。同じラップを何度も復号しないようにする。
+    #[serde(default)]
+    seen: HashSet<EventId>,
+    #[serde(default)]
+    newest: Option<Timestamp>,
+}
+
+impl MessageStore {
+    fn entry_key(me: &PublicKey) -> String {
+        format!("{}:messages", me.to_hex())
+    }
+
+    pub fn load(
+        cache_db: &LmdbCache,
+        me: &PublicKey,
+        secret: &[u8; 32],
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(cache_db
+            .read_encrypted(DB_MESSAGES, &Self::entry_key(me), secret)?
+            .unwrap_or_default())
+    }
+
+    pub fn save(
+        &self,
+        cache_db: &LmdbCache,
+        me: &PublicKey,
+        secret: &[u8; 32],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        cache_db.write_encrypted(DB_MESSAGES, &Self::entry_key(me), self, secret)
+    }
+
+    pub fn is_seen(&self, event: &Event) -> bool {
+        self.seen.contains(&event.id)
+    }
+
+    /// Records that `event` was processed, whether or not it held a message for us.
+    pub fn mark_seen(&mut self, event: &Event) {
+        self.seen.insert(event.id);
+        if self.newest.is_none_or(|newest| event.created_at > newest) {
+            self.newest = Some(event.created_at);
+        }
+    }
+
+    /// Adds a message to the conversation with `peer`. Returns false if it was already there.
+    pub fn insert(&mut self, peer: PublicKey, message: DirectMessage) -> bool {
+        let conversation = self.conversations.entry(peer).or_default();
+        if conversation.iter().any(|m| m.id == message.id) {
+            return false;
+        }
+        let index = conversation.partition_point(|m| m.created_at <= message.created_at);
+        conversation.insert(index, message);
+        true
+    }
+
+    /// `since` for the next fetch, leaving room for the randomized gift wrap timestamps.
+    pub fn since(&self) -> Option<Timestamp> {
+        self.newest
+            .map(|newest| Timestamp::from(newest.as_u64().saturating_sub(WRAP_TIMESTAMP_TWEAK)))
+    }
+}
+
+/// Returns the key that seals this account's messages, creating it on first use.
+///
+/// The key is stored NIP-44 encrypted to our own pubkey, so it only opens with the
+/// account's signer (local keys or NIP-46 alike).
+pub async fn unlock_store_key(
+    cache_db: &LmdbCache,
+    signer: &Arc<dyn NostrSigner>,
+    me: PublicKey,
+) -> Result<[u8; 32], Box<dyn std::error::Error + Send + Sync>> {
+    let entry_key = format!("{}:key", me.to_hex());
+    if let Some(stored) = cache_db.read_bytes(DB_MESSAGES, &entry_key)? {
+        let payload = String::from_utf8(stored)?;
+        let decrypted = signer.nip44_decrypt(&me, &payload).await?;
+        let bytes = hex::decode(decrypted.trim())?;
+        return bytes
+            .try_into()
+            .map_err(|_| "Invalid message store key".into());
+    }
+
+    let mut secret = [0u8; 32];
+    OsRng.fill(&mut secret);
+    let payload = signer.nip44_encrypt(&me, &hex::encode(secret)).await?;
+    cache_db.write_bytes(DB_MESSAGES, &entry_key, payload.as_bytes())?;
+    Ok(secret)
+}
+
+/// Filters for messages to and from `me`: gift wraps (kind 1059) and legacy kind 4 DMs.
+pub fn message_filters(me: PublicKey, since: Option<Timestamp>) -> Vec<Filter> {
+    let filters = vec![
+        Filter::new().kind(Kind::GiftWrap).pubkey(me),
+        Filter::new().kind(Kind::EncryptedDirectMessage).pubkey(me),
+        Filter::new().kind(Kind::EncryptedDirectMessage).author(me),
+    ];
+    match since {
+        Some(since) => filters.into_iter().map(|f| f.since(since)).collect(),
+        None => filters,
+    }
+}
+
+// 相手側の pubkey。自分が送ったものは p タグの宛先になる。
+fn counterparty(author: PublicKey, tags: &nostr::Tags, me: PublicKey) -> Option<PublicKey> {
+    if author != me {
+        return Some(author);
+    }
+    tags.public_keys().copied().find(|pk| *pk != me).or(Some(me))
+}
+
+/// Decrypts a kind 1059 gift wrap or a kind 4 DM into the conversation peer and the message.
+///
+/// Returns `Ok(None)` for wraps that do not hold a kind 14 chat message.
+pub async fn open_event(
+    signer: &Arc<dyn NostrSigner>,
+    me: PublicKey,
+    event: &Event,
+) -> Result<Option<(PublicKey, DirectMessage)>, Box<dyn std::error::Error + Send + Sync>> {
+    if event.kind == Kind::GiftWrap {
+        let unwrapped = nip59::extract_rumor(signer, event).await?;
+        let mut rumor = unwrapped.rumor;
+        if rumor.kind != Kind::PrivateDirectMessage {
+            return Ok(None);
+        }
+        // seal の署名者と rumor の著者が違うものはなりすましなので捨てる
+        if rumor.pubkey != unwrapped.sender {
+            return Err("Rumor author does not match the seal".into());
+        }
+        let Some(peer) = counterparty(rumor.pubkey, &rumor.tags, me) else {
+            return Ok(None);
+        };
+        let message = DirectMessage {
+            id: rumor.id(),
+            sender: rumor.pubkey,
+            content: rumor.content.clone(),
+            created_at: rumor.created_at,
+            legacy: false,
+        };
+        Ok(Some((peer, message)))
+    } else if event.kind == Kind::EncryptedDirectMessage {
+        let Some(peer) = counterparty(event.pubkey, &event.tags, me) else {
+            return Ok(None);
+        };
+        let content = signer.nip04_decrypt(&peer, &event.content).await?;
+        let message = DirectMessage {
+            id: event.id,
+            sender: event.pubkey,
+            content,
+            created_at: event.created_at,
+            legacy: true,
+        };
+        Ok(Some((peer, message)))
+    } else {
+        Ok(None)
+    }
+}
+
+fn dm_cache_key(pubkey: &PublicKey) -> String {
+    format!("dm:{}", pubkey.to_hex())
+}
+
+/// The relays where `pubkey` receives DMs: their kind 10050 list, empty when they have
+/// none. NIP-17 asks not to send to other relays. Cached in `DB_RELAYS` under `dm:<hex>`.
+pub async fn inbox_relays(
+    client: &Client,
+    cache_db: &LmdbCache,
+    pubkey: PublicKey,
+) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+    let cache_key = dm_cache_key(&pubkey);
+    // 空の結果はキャッシュを使わず、後から公開されたリストを取りに行く
+    if let Ok(cache) = cache_db.read_cache::<Vec<String>>(DB_RELAYS, &cache_key)
+        && !cache.data.is_empty()
+    {
+        return Ok(cache.data);
+    }
+    let filter = Filter::new().author(pubkey).kind(Kind::InboxRelays).limit(1);
+    let relays: Vec<String> = client
+        .fetch_events(filter, FETCH_TIMEOUT)
+        .await?
+        .into_iter()
+        .max_by_key(|event| event.created_at)
+        .map(|event| {
+            event
+                .tags
+                .iter()
+                .filter_map(|tag| match tag.as_slice() {
+                    [name, url, ..] if name == "relay" => Some(url.clone()),
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    if let Err(e) = cache_db.write_cache(DB_RELAYS, &cache_key, &relays) {
+        eprintln!("Failed to cache DM relays: {e}");
+    }
+    Ok(relays)
+}
+
+/// The relays we list in our kind 10050: the ones set in the settings, or our read
+/// relays when none are set.
+pub fn own_dm_relays(config: &RelayConfig) -> Vec<String> {
+    if !config.dm.is_empty() {
+        return config.dm.clone();
+    }
+    nip65::RelayList::from_config(config).read
+}
+
+/// Signs and publishes our kind 10050 DM relay list.
+pub async fn publish_dm_relays(
+    client: &Client,
+    cache_db: &LmdbCache,
+    signer: &Arc<dyn NostrSigner>,
+    config: &RelayConfig,
+) -> Result<EventId, Box<dyn std::error::Error + Send + Sync>> {
+    let relays = own_dm_relays(config);
+    if relays.is_empty() {
+        return Err("公開するリレーがありません".into());
+    }
+    let tags = relays
+        .iter()
+        .map(|url| Tag::parse(["relay", url.as_str()]))
+        .collect::<Result<Vec<Tag>, _>>()?;
+    let event = EventBuilder::new(Kind::InboxRelays, "").tags(tags).sign(signer).await?;
+    let event_id = nip65::publish_own(client, &event).await?;
+    cache_db.write_cache(DB_RELAYS, &dm_cache_key(&event.pubkey), &relays)?;
+    Ok(event_id)
+}
+
+// ラップを宛先の DM リレーに届ける。相手が kind 10050 を出していなければ送らない。
+// 自分宛てのコピーだけは、メッセージ画面が読んでいる読み込みリレーに置く。
+async fn deliver(
+    client: &Client,
+    cache_db: &LmdbCache,
+    recipient: PublicKey,
+    me: PublicKey,
+    wrap: &Event,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut relays = inbox_relays(client, cache_db, recipient).await?;
+    if relays.is_empty() {
+        if recipient != me {
+            return Err("相手が DM 用のリレー (kind 10050) を公開していないため送信できません".into());
+        }
+        relays = client
+            .pool()
+            .relays_with_flag(RelayServiceFlags::READ, FlagCheck::All)
+            .await
+            .into_keys()
+            .map(|url| url.to_string())
+            .collect();
+    }
+    nip65::ensure_relays(client, &relays).await;
+    let output = client.send_event_to(relays, wrap).await?;
+    if output.success.is_empty() {
+        return Err("どのリレーにも届きませんでした".into());
+    }
+    Ok(())
+}
+
+/// Sends `content` to `peer` as a NIP-17 chat message: a kind 14 rumor, sealed (kind 13)
+/// and gift wrapped (kind 1059) once for the peer and once for ourselves.
+pub async fn send_message(
+    client: &Client,
+    cache_db: &LmdbCache,
+    signer: &Arc<dyn NostrSigner>,
+    me: PublicKey,
+    peer: PublicKey,
+    content: String,
+) -> Result<DirectMessage, Box<dyn std::error::Error + Send + Sync>> {
+    let mut rumor = EventBuilder::private_msg_rumor(peer, content.clone()).build(me);
+    let message = DirectMessage {
+        id: rumor.id(),
+        sender: me,
+        content,
+        created_at: rumor.created_at,
+        legacy: false,
+    };
+
+    let wrap_for_peer = EventBuilder::gift_wrap(signer, &peer, rumor.clone(), Vec::<Tag>::new()).await?;
+    deliver(client, cache_db, peer, me, &wrap_for_peer).await?;
+    // 自分宛てのコピー。他の端末でも送信済みメッセージが見えるようにする。
+    if peer != me {
+        let wrap_for_me = EventBuilder::gift_wrap(signer, &me, rumor, Vec::<Tag>::new()).await?;
+        if let Err(e) = deliver(client, cache_db, me, me, &wrap_for_me).await {
+            eprintln!("Failed to store own copy of message: {e}");
+        }
+    }
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dm_relays_default_to_read_relays() {
+        // 検索リレーは入れない
+        let mut config = RelayConfig::with_defaults();
+        assert_eq!(own_dm_relays(&config), vec!["wss://yabu.me".to_string()]);
+
+        config.dm = vec!["wss://dm.example.com".to_string()];
+        assert_eq!(own_dm_relays(&config), config.dm);
+    }
+}
@@ -0,0 +1,79 @@
+use nostr::nips::nip19::{FromBech32, Nip19Event};
+use nostr::{Event, EventId, JsonUtil, Kind, Tag, TagStandard};
+use regex::Regex;
+
+/// Kind 6 (repost of a kind 1) or kind 16 (generic repost).
+pub fn is_repost(kind: Kind) -> bool {
+    kind == Kind::Repost || kind == Kind::GenericRepost
+}
+
+/// The id of the reposted event, from the repost's `e` tag.
+pub fn reposted_id(tags: &[Tag]) -> Option<EventId> {
+    tags.iter().find_map(|tag| match tag.as_standardized() {
+        Some(TagStandard::Event { event_id, .. }) => Some(*event_id),
+        _ => None,
+    })
+}
+
+/// The original event embedded in a repost's content.
+///
+/// Returns `None` for empty content, for events whose id or signature does not check out,
+/// and for events other than the one the repost's `e` tag points at.
+pub fn embedded_event(content: &str, tags: &[Tag]) -> Option<Event> {
+    if content.trim().is_empty() {
+        return None;
+    }
+    let event = Event::from_json(content).ok()?;
+    if reposted_id(tags) != Some(event.id) {
+        return None;
+    }
+    event.verify().ok()?;
+    Some(event)
+}
+
+/// `q` tags for every `nostr:note1` / `nostr:nevent1` quoted in `content`.
+///
+/// Relay hints and authors come from the `nevent` when it carries them.
+pub fn quote_tags(content: &str) -> Vec<Tag> {
+    let re_nostr = Regex::new(r"nostr:((?:note|nevent)1[a-z0-9]+)").unwrap();
+    let mut quoted: Vec<EventId> = Vec::new();
+    let mut tags = Vec::new();
+    for cap in re_nostr.captures_iter(content) {
+        let bech32 = &cap[1];
+        let (event_id, relay_url, public_key) = if let Ok(nevent) = Nip19Event::from_bech32(bech32) {
+            (nevent.event_id, nevent.relays.first().cloned(), nevent.author)
+        } else if let Ok(event_id) = EventId::from_bech32(bech32) {
+            (event_id, None, None)
+        } else {
+            continue;
+        };
+        if quoted.contains(&event_id) {
+            continue;
+        }
+        quoted.push(event_id);
+        tags.push(Tag::from_standardized(TagStandard::Quote {
+            event_id,
+            relay_url,
+            public_key,
+        }));
+    }
+    tags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr::{EventBuilder, Keys};
+
+    #[test]
+    fn embedded_event_must_match_the_e_tag() {
+        let keys = Keys::generate();
+        let original = EventBuilder::text_note("original").sign_with_keys(&keys).unwrap();
+        let other = EventBuilder::text_note("other").sign_with_keys(&keys).unwrap();
+        let tags = [Tag::event(original.id)];
+
+        assert_eq!(embedded_event(&original.as_json(), &tags).map(|e| e.id), Some(original.id));
+        assert!(embedded_event(&other.as_json(), &tags).is_none());
+        assert!(embedded_event(&original.as_json(), &[]).is_none());
+    }
+}
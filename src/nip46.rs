@@ -0,0 +1,197 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use nostr::nips::nip46::NostrConnectURI;
+use nostr::util::BoxedFuture;
+use nostr::{Keys, NostrSigner, PublicKey, RelayUrl, Url};
+use nostr_connect::client::{AuthUrlHandler, NostrConnect};
+
+use crate::types::NostrPostAppInternal;
+
+/// How long to wait for the remote signer to answer a request (including user approval).
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+
+const APP_NAME: &str = "N";
+
+/// A connected NIP-46 remote signer.
+pub struct RemoteSession {
+    pub signer: Arc<dyn NostrSigner>,
+    pub user_pubkey: PublicKey,
+    /// `bunker://` URI to persist so the session can be resumed on the next login.
+    pub bunker_uri: String,
+}
+
+// auth_url を受け取ったらログイン画面にリンクとして表示する。
+struct ShowAuthUrl(Arc<Mutex<NostrPostAppInternal>>);
+
+impl fmt::Debug for ShowAuthUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ShowAuthUrl")
+    }
+}
+
+impl AuthUrlHandler for ShowAuthUrl {
+    fn on_auth_url(&self, auth_url: Url) -> BoxedFuture<'_, nostr::Result<()>> {
+        Box::pin(async move {
+            println!("Remote signer requested authorization: {auth_url}");
+            let mut app_data = self.0.lock().unwrap();
+            app_data.nip46_auth_url = Some(auth_url.to_string());
+            app_data.should_repaint = true;
+            Ok(())
+        })
+    }
+}
+
+/// Builds a `nostrconnect://` URI for the user to paste into their signer app.
+pub fn client_uri(
+    app_keys: &Keys,
+    relays: &[String],
+) -> Result<NostrConnectURI, Box<dyn std::error::Error + Send + Sync>> {
+    let relays = relays
+        .iter()
+        .map(|r| RelayUrl::parse(r))
+        .collect::<Result<Vec<_>, _>>()?;
+    if relays.is_empty() {
+        return Err("At least one relay is required for nostrconnect://".into());
+    }
+    Ok(NostrConnectURI::client(app_keys.public_key(), relays, APP_NAME))
+}
+
+/// Connects to a remote signer using a `bunker://` or `nostrconnect://` URI.
+///
+/// For `nostrconnect://`, `app_keys` must be the keys the URI was created with.
+/// Waits until the signer has answered `get_public_key`.
+pub async fn connect(
+    uri: &str,
+    app_keys: Keys,
+    timeout: Duration,
+    app_data: Option<Arc<Mutex<NostrPostAppInternal>>>,
+) -> Result<RemoteSession, Box<dyn std::error::Error + Send + Sync>> {
+    let uri = NostrConnectURI::parse(uri.trim())?;
+    let mut signer = NostrConnect::new(uri, app_keys, timeout, None)?;
+    if let Some(app_data) = app_data {
+        signer.auth_url_handler(ShowAuthUrl(app_data));
+    }
+
+    let user_pubkey = signer.get_public_key().await?;
+    let bunker_uri = signer.bunker_uri().await?.to_string();
+    println!("Connected to remote signer for {}", user_pubkey.to_hex());
+
+    Ok(RemoteSession {
+        signer: Arc::new(signer),
+        user_pubkey,
+        bunker_uri,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::{SinkExt, StreamExt};
+    use nostr::filter::MatchEventOptions;
+    use nostr::nips::nip46::NostrConnectRequest;
+    use nostr::{ClientMessage, Event, EventBuilder, Filter, JsonUtil, RelayMessage, SubscriptionId};
+    use nostr_connect::signer::{NostrConnectKeys, NostrConnectRemoteSigner, NostrConnectSignerActions};
+    use tokio::net::TcpListener;
+    use tokio::sync::broadcast;
+    use tokio_tungstenite::tungstenite::Message;
+
+    // 受け取ったイベントを購読中の接続に流すだけのローカルリレー。REQ の数を数える
+    async fn local_relay() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let reqs = Arc::new(AtomicUsize::new(0));
+        let counter = reqs.clone();
+        let (events, _) = broadcast::channel::<Event>(64);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let events = events.clone();
+                let counter = counter.clone();
+                tokio::spawn(async move {
+                    let Ok(mut ws) = tokio_tungstenite::accept_async(stream).await else { return };
+                    let mut received = events.subscribe();
+                    let mut subscriptions: HashMap<SubscriptionId, Vec<Filter>> = HashMap::new();
+                    loop {
+                        tokio::select! {
+                            message = ws.next() => {
+                                let text = match message {
+                                    Some(Ok(Message::Text(text))) => text,
+                                    Some(Ok(_)) => continue,
+                                    _ => break,
+                                };
+                                let reply = match ClientMessage::from_json(text.as_str()) {
+                                    Ok(ClientMessage::Event(event)) => {
+                                        let _ = events.send(event.clone().into_owned());
+                                        RelayMessage::ok(event.id, true, "")
+                                    }
+                                    Ok(ClientMessage::Req { subscription_id, filter }) => {
+                                        counter.fetch_add(1, Ordering::SeqCst);
+                                        subscriptions.insert(subscription_id.clone().into_owned(), vec![filter.into_owned()]);
+                                        RelayMessage::eose(subscription_id.into_owned())
+                                    }
+                                    Ok(ClientMessage::ReqMultiFilter { subscription_id, filters }) => {
+                                        counter.fetch_add(1, Ordering::SeqCst);
+                                        subscriptions.insert(subscription_id.clone().into_owned(), filters);
+                                        RelayMessage::eose(subscription_id.into_owned())
+                                    }
+                                    Ok(ClientMessage::Close(id)) => {
+                                        subscriptions.remove(&id);
+                                        continue;
+                                    }
+                                    _ => continue,
+                                };
+                                if ws.send(Message::text(reply.as_json())).await.is_err() {
+                                    break;
+                                }
+                            }
+                            Ok(event) = received.recv() => {
+                                for (id, filters) in &subscriptions {
+                                    if filters.iter().any(|f| f.match_event(&event, MatchEventOptions::new())) {
+                                        let message = RelayMessage::event(id.clone(), event.clone());
+                                        let _ = ws.send(Message::text(message.as_json())).await;
+                                    }
+                                }
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        (url, reqs)
+    }
+
+    struct ApproveAll;
+
+    impl NostrConnectSignerActions for ApproveAll {
+        fn approve(&self, _public_key: &PublicKey, _req: &NostrConnectRequest) -> bool {
+            true
+        }
+    }
+
+    #[tokio::test]
+    async fn connects_to_bunker_and_signs() {
+        let (relay, reqs) = local_relay().await;
+        let user = Keys::generate();
+        let keys = NostrConnectKeys { signer: Keys::generate(), user: user.clone() };
+        let bunker = NostrConnectRemoteSigner::new(keys, [relay.as_str()], Some("secret".to_string()), None).unwrap();
+        let uri = bunker.bunker_uri().to_string();
+        tokio::spawn(async move { bunker.serve(ApproveAll).await });
+        // bunker の購読が張られてから接続する
+        while reqs.load(Ordering::SeqCst) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let session = connect(&uri, Keys::generate(), Duration::from_secs(10), None).await.unwrap();
+
+        assert_eq!(session.user_pubkey, user.public_key());
+        assert_eq!(session.signer.get_public_key().await.unwrap(), user.public_key());
+        let unsigned = EventBuilder::text_note("hello").build(user.public_key());
+        let event = session.signer.sign_event(unsigned).await.unwrap();
+        assert_eq!(event.pubkey, user.public_key());
+        event.verify().unwrap();
+    }
+}
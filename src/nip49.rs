@@ -1,95 +1,194 @@
+use bech32::{FromBase32, ToBase32, Variant};
 use chacha20poly1305::{
-    aead::{Aead, KeyInit},
-    ChaCha20Poly1305, Key, Nonce,
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce, XChaCha20Poly1305, XNonce,
 };
 use pbkdf2::pbkdf2_hmac;
 use sha2::Sha256;
 use base64::{Engine as _, engine::general_purpose};
 use rand::Rng;
 use rand::rngs::OsRng;
+use unicode_normalization::UnicodeNormalization;
+
+/// bech32 HRP for NIP-49 encrypted secret keys.
+pub const NCRYPTSEC_HRP: &str = "ncryptsec";
+/// scrypt cost used for newly encrypted data (64 MiB, ~0.1s on a desktop).
+pub const DEFAULT_LOG_N: u8 = 16;
+// 復号するときに受け付ける上限 (1 GiB)。それ以上はメモリを確保できずに落ちかねないので読まない
+const MAX_LOG_N: u8 = 20;
+
+const VERSION: u8 = 0x02;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+// version + log_n + salt + nonce + key security byte
+const HEADER_LEN: usize = 2 + SALT_LEN + NONCE_LEN + 1;
+
+// Pre-NIP-49 format written by older versions of this app.
+const LEGACY_PREFIX: &str = "#nip49:";
+const LEGACY_PBKDF2_ROUNDS: u32 = 100_000;
+
+/// The key-security byte defined by NIP-49. It is authenticated as associated data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeySecurity {
+    /// The key is known to have been handled insecurely.
+    Weak = 0x00,
+    /// The key is not known to have been handled insecurely.
+    Medium = 0x01,
+    /// The client does not track this information.
+    Unknown = 0x02,
+}
 
-const PBKDF2_ROUNDS: u32 = 100_000;
+impl TryFrom<u8> for KeySecurity {
+    type Error = Box<dyn std::error::Error + Send + Sync>;
 
-/// Encrypts plaintext using NIP-49 spec.
-/// Returns a tuple of `(encrypted_base64_string, salt_base64_string)`.
-pub fn encrypt(
-    plaintext: &[u8],
-    passphrase: &str,
-) -> Result<(String, String), Box<dyn std::error::Error + Send + Sync>> {
-    let mut salt_bytes = [0u8; 16];
-    OsRng.fill(&mut salt_bytes);
-    let salt_base64 = general_purpose::STANDARD.encode(salt_bytes);
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(KeySecurity::Weak),
+            0x01 => Ok(KeySecurity::Medium),
+            0x02 => Ok(KeySecurity::Unknown),
+            _ => Err(format!("Unknown NIP-49 key security byte: {value:#04x}").into()),
+        }
+    }
+}
 
-    let mut derived_key_bytes = [0u8; 32];
-    pbkdf2_hmac::<Sha256>(
-        passphrase.as_bytes(),
-        &salt_bytes,
-        PBKDF2_ROUNDS,
-        &mut derived_key_bytes,
-    );
-    let cipher_key = Key::from_slice(&derived_key_bytes);
-    let cipher = ChaCha20Poly1305::new(cipher_key);
+// NIP-49 requires the password to be NFKC-normalized before key derivation.
+fn derive_key(
+    passphrase: &str,
+    salt: &[u8],
+    log_n: u8,
+) -> Result<[u8; 32], Box<dyn std::error::Error + Send + Sync>> {
+    let normalized: String = passphrase.nfkc().collect();
+    let params = scrypt::Params::new(log_n, 8, 1, 32)
+        .map_err(|e| format!("Invalid scrypt parameters: {e}"))?;
+    let mut key = [0u8; 32];
+    scrypt::scrypt(normalized.as_bytes(), salt, &params, &mut key)
+        .map_err(|e| format!("scrypt failed: {e}"))?;
+    Ok(key)
+}
 
-    let mut nonce_bytes: [u8; 12] = [0u8; 12];
+fn seal(
+    plaintext: &[u8],
+    passphrase: &str,
+    log_n: u8,
+    key_security: KeySecurity,
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill(&mut salt);
+    let mut nonce_bytes = [0u8; NONCE_LEN];
     OsRng.fill(&mut nonce_bytes);
-    let nonce = Nonce::from_slice(&nonce_bytes);
 
-    let ciphertext_with_tag = cipher
-        .encrypt(nonce, plaintext)
+    let key = derive_key(passphrase, &salt, log_n)?;
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(&key));
+    let ad = [key_security as u8];
+    let ciphertext = cipher
+        .encrypt(XNonce::from_slice(&nonce_bytes), Payload { msg: plaintext, aad: &ad })
         .map_err(|e| format!("NIP-49 encryption error: {e:?}"))?;
 
-    let mut encoded_data = ciphertext_with_tag;
-    encoded_data.extend_from_slice(nonce_bytes.as_ref());
-
-    let nip49_encoded = format!("#nip49:{}", general_purpose::STANDARD.encode(&encoded_data));
+    let mut out = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+    out.push(VERSION);
+    out.push(log_n);
+    out.extend_from_slice(&salt);
+    out.extend_from_slice(&nonce_bytes);
+    out.push(key_security as u8);
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
 
-    Ok((nip49_encoded, salt_base64))
+fn open(
+    data: &[u8],
+    passphrase: &str,
+) -> Result<(Vec<u8>, KeySecurity), Box<dyn std::error::Error + Send + Sync>> {
+    if data.len() <= HEADER_LEN {
+        return Err("Invalid NIP-49 payload".into());
+    }
+    if data[0] != VERSION {
+        return Err(format!("Unsupported NIP-49 version: {:#04x}", data[0]).into());
+    }
+    let log_n = data[1];
+    if log_n > MAX_LOG_N {
+        return Err(format!("NIP-49 scrypt cost too high: log_n {log_n} (the maximum is {MAX_LOG_N})").into());
+    }
+    let salt = &data[2..2 + SALT_LEN];
+    let nonce_bytes = &data[2 + SALT_LEN..2 + SALT_LEN + NONCE_LEN];
+    let key_security = KeySecurity::try_from(data[HEADER_LEN - 1])?;
+    let ciphertext = &data[HEADER_LEN..];
+
+    let key = derive_key(passphrase, salt, log_n)?;
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(&key));
+    let ad = [key_security as u8];
+    let plaintext = cipher
+        .decrypt(XNonce::from_slice(nonce_bytes), Payload { msg: ciphertext, aad: &ad })
+        .map_err(|_| "Incorrect passphrase or corrupted data")?;
+    Ok((plaintext, key_security))
 }
 
-/// Encrypts plaintext using NIP-49 spec with a pre-existing salt.
-/// Returns the encrypted_base64_string.
-pub fn encrypt_with_salt(
-    plaintext: &[u8],
+/// Encrypts a 32-byte secret key into a NIP-49 `ncryptsec1...` string.
+pub fn encrypt_secret_key(
+    secret_key: &[u8; 32],
     passphrase: &str,
-    salt_base64: &str,
+    log_n: u8,
+    key_security: KeySecurity,
 ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
-    let salt_bytes = general_purpose::STANDARD.decode(salt_base64)?;
-
-    let mut derived_key_bytes = [0u8; 32];
-    pbkdf2_hmac::<Sha256>(
-        passphrase.as_bytes(),
-        &salt_bytes,
-        PBKDF2_ROUNDS,
-        &mut derived_key_bytes,
-    );
-    let cipher_key = Key::from_slice(&derived_key_bytes);
-    let cipher = ChaCha20Poly1305::new(cipher_key);
-
-    let mut nonce_bytes: [u8; 12] = [0u8; 12];
-    OsRng.fill(&mut nonce_bytes);
-    let nonce = Nonce::from_slice(&nonce_bytes);
+    let data = seal(secret_key, passphrase, log_n, key_security)?;
+    Ok(bech32::encode(NCRYPTSEC_HRP, data.to_base32(), Variant::Bech32)?)
+}
 
-    let ciphertext_with_tag = cipher
-        .encrypt(nonce, plaintext)
-        .map_err(|e| format!("NIP-49 encryption error: {e:?}"))?;
+/// Decrypts a NIP-49 `ncryptsec1...` string and returns the raw secret key bytes.
+pub fn decrypt_secret_key(
+    ncryptsec: &str,
+    passphrase: &str,
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let (hrp, data, _variant) = bech32::decode(ncryptsec.trim())?;
+    if hrp != NCRYPTSEC_HRP {
+        return Err(format!("Expected an {NCRYPTSEC_HRP} string, got prefix '{hrp}'").into());
+    }
+    let data = Vec::<u8>::from_base32(&data)?;
+    let (secret_key, _) = open(&data, passphrase)?;
+    if secret_key.len() != 32 {
+        return Err("Invalid NIP-49 secret key length".into());
+    }
+    Ok(secret_key)
+}
 
-    let mut encoded_data = ciphertext_with_tag;
-    encoded_data.extend_from_slice(nonce_bytes.as_ref());
+/// Returns true if the string looks like an `ncryptsec1...` value.
+pub fn is_ncryptsec(s: &str) -> bool {
+    s.trim().to_lowercase().starts_with("ncryptsec1")
+}
 
-    let nip49_encoded = format!("#nip49:{}", general_purpose::STANDARD.encode(&encoded_data));
+/// Encrypts arbitrary data (e.g. the NWC URI) with the same scheme as
+/// `encrypt_secret_key`, base64-encoded instead of bech32.
+pub fn encrypt(
+    plaintext: &[u8],
+    passphrase: &str,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let data = seal(plaintext, passphrase, DEFAULT_LOG_N, KeySecurity::Unknown)?;
+    Ok(general_purpose::STANDARD.encode(data))
+}
 
-    Ok(nip49_encoded)
+/// Decrypts data produced by `encrypt`.
+pub fn decrypt(
+    encoded: &str,
+    passphrase: &str,
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let data = general_purpose::STANDARD.decode(encoded)?;
+    let (plaintext, _) = open(&data, passphrase)?;
+    Ok(plaintext)
 }
 
+/// Returns true if the value was written in the old PBKDF2/ChaCha20 format.
+pub fn is_legacy(encoded: &str) -> bool {
+    encoded.starts_with(LEGACY_PREFIX)
+}
 
-/// Decrypts a NIP-49 encoded string.
-pub fn decrypt(
+/// Decrypts the old `#nip49:` PBKDF2-SHA256 + ChaCha20Poly1305 format.
+/// Only used to migrate existing configs.
+pub fn decrypt_legacy(
     nip49_encoded: &str,
     passphrase: &str,
     salt_base64: &str,
 ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
-    if !nip49_encoded.starts_with("#nip49:") {
-        return Err("Invalid NIP-49 format".into());
+    if !is_legacy(nip49_encoded) {
+        return Err("Invalid legacy format".into());
     }
 
     let salt_bytes = general_purpose::STANDARD.decode(salt_base64)?;
@@ -98,15 +197,15 @@ pub fn decrypt(
     pbkdf2_hmac::<Sha256>(
         passphrase.as_bytes(),
         &salt_bytes,
-        PBKDF2_ROUNDS,
+        LEGACY_PBKDF2_ROUNDS,
         &mut derived_key_bytes,
     );
     let cipher_key = Key::from_slice(&derived_key_bytes);
     let cipher = ChaCha20Poly1305::new(cipher_key);
 
-    let decoded_bytes = general_purpose::STANDARD.decode(&nip49_encoded[7..])?;
+    let decoded_bytes = general_purpose::STANDARD.decode(&nip49_encoded[LEGACY_PREFIX.len()..])?;
     if decoded_bytes.len() < 12 {
-        return Err("Invalid NIP-49 payload".into());
+        return Err("Invalid legacy payload".into());
     }
 
     let (ciphertext_and_tag, nonce_bytes) = decoded_bytes.split_at(decoded_bytes.len() - 12);
@@ -118,3 +217,27 @@ pub fn decrypt(
 
     Ok(decrypted_bytes)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decrypts_spec_vector() {
+        let ncryptsec = "ncryptsec1qgg9947rlpvqu76pj5ecreduf9jxhselq2nae2kghhvd5g7dgjtcxfqtd67p9m0w57lspw8gsq6yphnm8623nsl8xn9j4jdzz84zm3frztj3z7s35vpzmqf6ksu8r89qk5z2zxfmu5gv8th8wclt0h4p";
+        let secret_key = decrypt_secret_key(ncryptsec, "nostr").unwrap();
+        assert_eq!(hex::encode(secret_key), "3501454135014541350145413501453fefb02227e449e57cf4d3a3ce05378683");
+        assert!(decrypt_secret_key(ncryptsec, "nostr!").is_err());
+    }
+
+    #[test]
+    fn round_trips_and_rejects_excessive_cost() {
+        let mut data = seal(b"secret", "パスワード", 4, KeySecurity::Medium).unwrap();
+        assert_eq!(open(&data, "パスワード").unwrap(), (b"secret".to_vec(), KeySecurity::Medium));
+
+        data[1] = 40;
+        assert!(open(&data, "パスワード").unwrap_err().to_string().contains("too high"));
+        data[1] = MAX_LOG_N + 1;
+        assert!(open(&data, "パスワード").unwrap_err().to_string().contains("too high"));
+    }
+}
@@ -0,0 +1,378 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use nostr::{Event, EventBuilder, EventId, Filter, Kind, NostrSigner, PublicKey, Tag};
+use nostr_sdk::Client;
+
+use crate::cache_db::{LmdbCache, DB_FOLLOWED};
+use crate::nip18;
+use crate::nip65;
+use crate::types::TimelinePost;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// One entry of a NIP-51 mute list.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MuteItem {
+    Pubkey(PublicKey),
+    Hashtag(String),
+    Word(String),
+    // スレッドのルート (または単体の投稿) の id
+    Thread(EventId),
+}
+
+impl MuteItem {
+    fn from_tag(values: &[String]) -> Option<Self> {
+        match values {
+            [name, value, ..] if name == "p" => PublicKey::from_hex(value).ok().map(Self::Pubkey),
+            [name, value, ..] if name == "t" && !value.is_empty() => Some(Self::Hashtag(value.to_lowercase())),
+            [name, value, ..] if name == "word" && !value.is_empty() => Some(Self::Word(value.to_lowercase())),
+            [name, value, ..] if name == "e" => EventId::from_hex(value).ok().map(Self::Thread),
+            _ => None,
+        }
+    }
+
+    fn to_tag(&self) -> Vec<String> {
+        match self {
+            Self::Pubkey(pubkey) => vec!["p".to_string(), pubkey.to_hex()],
+            Self::Hashtag(hashtag) => vec!["t".to_string(), hashtag.clone()],
+            Self::Word(word) => vec!["word".to_string(), word.clone()],
+            Self::Thread(id) => vec!["e".to_string(), id.to_hex()],
+        }
+    }
+}
+
+/// A change to the mute list. It is applied to the newest list on the relays when published.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MuteChange {
+    // true なら非公開の項目として追加する
+    Add(MuteItem, bool),
+    Remove(MuteItem),
+}
+
+/// The user's mute list (kind 10000): public entries in the tags and private entries
+/// NIP-44 encrypted to ourselves in the content.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MuteList {
+    pub public: Vec<MuteItem>,
+    pub private: Vec<MuteItem>,
+    // 非公開の項目を復号できなかった。このまま公開すると消えてしまうので書き込まない。
+    pub private_locked: bool,
+}
+
+impl MuteList {
+    pub fn is_empty(&self) -> bool {
+        self.public.is_empty() && self.private.is_empty()
+    }
+
+    pub fn contains(&self, item: &MuteItem) -> bool {
+        self.public.contains(item) || self.private.contains(item)
+    }
+
+    /// Adds `item`, moving it between the public and private parts if it is already listed.
+    pub fn add(&mut self, item: MuteItem, private: bool) {
+        self.remove(&item);
+        if private {
+            self.private.push(item);
+        } else {
+            self.public.push(item);
+        }
+    }
+
+    pub fn remove(&mut self, item: &MuteItem) {
+        self.public.retain(|i| i != item);
+        self.private.retain(|i| i != item);
+    }
+
+    pub fn apply(&mut self, change: &MuteChange) {
+        match change {
+            MuteChange::Add(item, private) => self.add(item.clone(), *private),
+            MuteChange::Remove(item) => self.remove(item),
+        }
+    }
+
+    fn items(&self) -> impl Iterator<Item = &MuteItem> {
+        self.public.iter().chain(self.private.iter())
+    }
+
+    /// Whether `post` is hidden by the list: by its author, a hashtag, a word in the
+    /// content, or the thread it belongs to. Reposts are also hidden by the reposted author.
+    pub fn hides(&self, post: &TimelinePost) -> bool {
+        self.hides_parts(post.id, post.kind, post.author_pubkey, &post.content, &post.tags)
+    }
+
+    /// Same as `hides`, for an event that is not converted to a post yet.
+    pub fn hides_event(&self, event: &Event) -> bool {
+        self.hides_parts(event.id, event.kind, event.pubkey, &event.content, event.tags.as_slice())
+    }
+
+    fn hides_parts(&self, id: EventId, kind: Kind, author: PublicKey, content: &str, tags: &[Tag]) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+        let has_tag = |tag_name: &str, expected: &str| {
+            tags.iter()
+                .any(|tag| matches!(tag.as_slice(), [name, value, ..] if name == tag_name && value.eq_ignore_ascii_case(expected)))
+        };
+        let content = content.to_lowercase();
+        self.items().any(|item| match item {
+            MuteItem::Pubkey(pubkey) => {
+                author == *pubkey || (nip18::is_repost(kind) && has_tag("p", &pubkey.to_hex()))
+            }
+            MuteItem::Hashtag(hashtag) => has_tag("t", hashtag),
+            MuteItem::Word(word) => content.contains(word.as_str()),
+            MuteItem::Thread(thread) => id == *thread || has_tag("e", &thread.to_hex()),
+        })
+    }
+
+    /// Drops every post the list hides.
+    pub fn retain_visible(&self, posts: &mut Vec<TimelinePost>) {
+        if !self.is_empty() {
+            posts.retain(|post| !self.hides(post));
+        }
+    }
+}
+
+fn parse_items(tags: impl IntoIterator<Item = Vec<String>>) -> Vec<MuteItem> {
+    let mut items = Vec::new();
+    for item in tags.into_iter().filter_map(|values| MuteItem::from_tag(&values)) {
+        if !items.contains(&item) {
+            items.push(item);
+        }
+    }
+    items
+}
+
+/// Reads a kind 10000 event. Private entries are only read when `signer` can decrypt them.
+pub async fn parse(event: &Event, signer: Option<&Arc<dyn NostrSigner>>, me: PublicKey) -> MuteList {
+    let mut list = MuteList {
+        public: parse_items(event.tags.iter().map(|tag| tag.as_slice().to_vec())),
+        ..Default::default()
+    };
+    if event.content.is_empty() {
+        return list;
+    }
+    let Some(signer) = signer else {
+        list.private_locked = true;
+        return list;
+    };
+    // 古いクライアントは NIP-04 で暗号化している
+    let decrypted = if event.content.contains("?iv=") {
+        signer.nip04_decrypt(&me, &event.content).await
+    } else {
+        signer.nip44_decrypt(&me, &event.content).await
+    };
+    match decrypted.map_err(|e| e.to_string()).and_then(|json| {
+        serde_json::from_str::<Vec<Vec<String>>>(&json).map_err(|e| e.to_string())
+    }) {
+        Ok(tags) => list.private = parse_items(tags),
+        Err(e) => {
+            eprintln!("Failed to decrypt private mute list: {e}");
+            list.private_locked = true;
+        }
+    }
+    list
+}
+
+fn cache_key(me: &PublicKey) -> String {
+    format!("{}:mute_list", me.to_hex())
+}
+
+fn read_cached(cache_db: &LmdbCache, me: &PublicKey) -> Option<Event> {
+    cache_db.read_state(DB_FOLLOWED, &cache_key(me)).unwrap_or_else(|e| {
+        eprintln!("Failed to read cached mute list: {e}");
+        None
+    })
+}
+
+fn newest(cached: Option<Event>, fetched: Option<Event>) -> Option<Event> {
+    match (cached, fetched) {
+        (Some(cached), Some(fetched)) if fetched.created_at <= cached.created_at => Some(cached),
+        (cached, fetched) => fetched.or(cached),
+    }
+}
+
+/// The newest kind 10000 of `me` on the relays.
+pub async fn fetch_latest(
+    client: &Client,
+    me: PublicKey,
+) -> Result<Option<Event>, Box<dyn std::error::Error + Send + Sync>> {
+    let filter = Filter::new().author(me).kind(Kind::MuteList).limit(1);
+    let events = client.fetch_events(filter, FETCH_TIMEOUT).await?;
+    Ok(events.into_iter().max_by_key(|e| e.created_at))
+}
+
+/// Loads the newest mute list of `me` from the relays or, when they have none, from the
+/// copy kept in `DB_FOLLOWED`. The raw event is cached, so private entries stay encrypted.
+pub async fn load(
+    client: &Client,
+    cache_db: &LmdbCache,
+    signer: Option<&Arc<dyn NostrSigner>>,
+    me: PublicKey,
+) -> MuteList {
+    let cached = read_cached(cache_db, &me);
+    let fetched = fetch_latest(client, me).await.unwrap_or_else(|e| {
+        eprintln!("Failed to fetch mute list: {e}");
+        None
+    });
+    let Some(event) = newest(cached, fetched) else {
+        return MuteList::default();
+    };
+    if let Err(e) = cache_db.write_state(DB_FOLLOWED, &cache_key(&me), &event) {
+        eprintln!("Failed to cache mute list: {e}");
+    }
+    parse(&event, signer, me).await
+}
+
+/// Fetches the newest mute list, applies `change` and publishes the result.
+///
+/// `known` is the list we loaded at login. It is only used when neither the relays nor
+/// the cache have a list. Returns the published list.
+pub async fn publish_change(
+    client: &Client,
+    cache_db: &LmdbCache,
+    signer: &Arc<dyn NostrSigner>,
+    me: PublicKey,
+    known: &MuteList,
+    change: &MuteChange,
+) -> Result<MuteList, Box<dyn std::error::Error + Send + Sync>> {
+    let fetched = match fetch_latest(client, me).await {
+        Ok(fetched) => fetched,
+        // 何も分からないまま公開すると、リレー上のミュートリストを丸ごと消してしまう
+        Err(e) if known.is_empty() => {
+            return Err(format!("リレーから最新のミュートリストを取得できなかったため、公開を中止しました: {e}").into());
+        }
+        Err(e) => {
+            eprintln!("Failed to fetch mute list: {e}");
+            None
+        }
+    };
+    let mut list = match newest(read_cached(cache_db, &me), fetched) {
+        Some(event) => parse(&event, Some(signer), me).await,
+        None => known.clone(),
+    };
+    list.apply(change);
+    publish(client, cache_db, signer, me, &list).await?;
+    Ok(list)
+}
+
+/// Signs and publishes `list` as our kind 10000 event, replacing the previous one.
+async fn publish(
+    client: &Client,
+    cache_db: &LmdbCache,
+    signer: &Arc<dyn NostrSigner>,
+    me: PublicKey,
+    list: &MuteList,
+) -> Result<Event, Box<dyn std::error::Error + Send + Sync>> {
+    if list.private_locked {
+        return Err("非公開のミュートを復号できなかったため、リストを更新できません".into());
+    }
+    let content = if list.private.is_empty() {
+        String::new()
+    } else {
+        let tags: Vec<Vec<String>> = list.private.iter().map(MuteItem::to_tag).collect();
+        signer.nip44_encrypt(&me, &serde_json::to_string(&tags)?).await?
+    };
+    let tags = list
+        .public
+        .iter()
+        .map(|item| Tag::parse(item.to_tag()))
+        .collect::<Result<Vec<Tag>, _>>()?;
+    let event = EventBuilder::new(Kind::MuteList, content)
+        .tags(tags)
+        .sign(signer)
+        .await?;
+    nip65::publish_own(client, &event).await?;
+    cache_db.write_state(DB_FOLLOWED, &cache_key(&me), &event)?;
+    Ok(event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr::{Keys, Timestamp};
+
+    fn post(author: PublicKey, content: &str, tags: &[&[&str]]) -> TimelinePost {
+        TimelinePost {
+            id: EventId::from_byte_array([1; 32]),
+            kind: Kind::TextNote,
+            author_pubkey: author,
+            author_metadata: Default::default(),
+            content: content.to_string(),
+            created_at: Timestamp::from(1),
+            emojis: Default::default(),
+            tags: tags.iter().map(|t| Tag::parse(t.iter().copied()).unwrap()).collect(),
+        }
+    }
+
+    #[test]
+    fn hides_by_author_hashtag_word_and_thread() {
+        let alice = Keys::generate().public_key();
+        let bob = Keys::generate().public_key();
+        let root = EventId::from_byte_array([9; 32]);
+        let mut list = MuteList::default();
+        list.add(MuteItem::Pubkey(alice), false);
+        list.add(MuteItem::Hashtag("spoiler".to_string()), true);
+        list.add(MuteItem::Word("crypto".to_string()), true);
+        list.add(MuteItem::Thread(root), false);
+
+        assert!(list.hides(&post(alice, "hello", &[])));
+        assert!(list.hides(&post(bob, "hi", &[&["t", "Spoiler"]])));
+        assert!(list.hides(&post(bob, "Buy CRYPTO now", &[])));
+        assert!(list.hides(&post(bob, "reply", &[&["e", &root.to_hex(), "", "root"]])));
+        assert!(!list.hides(&post(bob, "hello", &[&["p", &alice.to_hex()]])));
+    }
+
+    #[tokio::test]
+    async fn public_entries_are_read_from_tags() {
+        let keys = Keys::generate();
+        let alice = Keys::generate().public_key();
+        let event = EventBuilder::new(Kind::MuteList, "")
+            .tags([
+                Tag::public_key(alice),
+                Tag::hashtag("NSFW"),
+                Tag::parse(["word", "spam"]).unwrap(),
+                Tag::public_key(alice),
+            ])
+            .sign_with_keys(&keys)
+            .unwrap();
+
+        let list = parse(&event, None, keys.public_key()).await;
+
+        assert_eq!(
+            list.public,
+            vec![
+                MuteItem::Pubkey(alice),
+                MuteItem::Hashtag("nsfw".to_string()),
+                MuteItem::Word("spam".to_string()),
+            ]
+        );
+        assert!(!list.private_locked);
+    }
+
+    #[tokio::test]
+    async fn changes_apply_to_the_newest_list() {
+        let keys = Keys::generate();
+        let alice = Keys::generate().public_key();
+        let bob = Keys::generate().public_key();
+        let list_event = |pubkeys: &[PublicKey], at: u64| {
+            EventBuilder::new(Kind::MuteList, "")
+                .tags(pubkeys.iter().map(|pubkey| Tag::public_key(*pubkey)))
+                .custom_created_at(Timestamp::from(at))
+                .sign_with_keys(&keys)
+                .unwrap()
+        };
+        let cached = list_event(&[alice], 100);
+        // 別のクライアントで bob をミュートした
+        let fetched = list_event(&[alice, bob], 200);
+
+        let event = newest(Some(cached.clone()), Some(fetched.clone())).unwrap();
+        assert_eq!(event.id, fetched.id);
+        assert_eq!(newest(Some(fetched.clone()), Some(cached)).unwrap().id, fetched.id);
+        let mut list = parse(&event, None, keys.public_key()).await;
+        list.apply(&MuteChange::Remove(MuteItem::Pubkey(alice)));
+        list.apply(&MuteChange::Add(MuteItem::Word("spam".to_string()), false));
+
+        assert_eq!(list.public, vec![MuteItem::Pubkey(bob), MuteItem::Word("spam".to_string())]);
+    }
+}
@@ -0,0 +1,409 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use nostr::nips::nip65::{self, RelayMetadata};
+use nostr::{Event, EventBuilder, EventId, Filter, Kind, NostrSigner, PublicKey, RelayUrl};
+use nostr_sdk::prelude::{FlagCheck, RelayOptions, RelayServiceFlags};
+use nostr_sdk::Client;
+use serde::{Deserialize, Serialize};
+
+use crate::cache_db::{LmdbCache, DB_RELAYS};
+use crate::types::RelayConfig;
+
+/// Upper bound on the extra relays connected to read the home timeline.
+pub const MAX_OUTBOX_RELAYS: usize = 8;
+// 1 人の著者について、書き込みリレーを何か所まで読みに行くか
+const RELAYS_PER_AUTHOR: usize = 2;
+// 宛先ユーザー 1 人あたりに届ける読み込みリレー数と、その合計の上限
+const INBOX_RELAYS_PER_USER: usize = 2;
+const MAX_INBOX_RELAYS: usize = 8;
+const AUTHORS_PER_REQ: usize = 250;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A user's NIP-65 (kind 10002) relay list. Cached in `DB_RELAYS` by hex pubkey.
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
+pub struct RelayList {
+    #[serde(default)]
+    pub read: Vec<String>,
+    #[serde(default)]
+    pub write: Vec<String>,
+}
+
+impl RelayList {
+    pub fn from_event(event: &Event) -> Self {
+        let mut list = Self::default();
+        for (url, metadata) in nip65::extract_relay_list(event) {
+            let url = url.to_string();
+            match metadata {
+                Some(RelayMetadata::Read) => list.read.push(url),
+                Some(RelayMetadata::Write) => list.write.push(url),
+                None => {
+                    list.read.push(url.clone());
+                    list.write.push(url);
+                }
+            }
+        }
+        list
+    }
+
+    // 自分のリレーリスト。アグリゲーターと自前のリレーを、設定した読み書きの向きで載せる。
+    // 検索リレーは NIP-50 専用なので含めない。
+    pub fn from_config(config: &RelayConfig) -> Self {
+        let mut list = Self::default();
+        for url in config.aggregator.iter().chain(config.self_hosted.iter()) {
+            let Ok(relay_url) = RelayUrl::parse(url) else { continue };
+            let relay_url = relay_url.to_string();
+            let access = config.access(url);
+            if access.read && !list.read.contains(&relay_url) {
+                list.read.push(relay_url.clone());
+            }
+            if access.write && !list.write.contains(&relay_url) {
+                list.write.push(relay_url);
+            }
+        }
+        list
+    }
+
+    /// Builds the kind 10002 event. Relays in both lists get no marker.
+    pub fn to_event_builder(&self) -> EventBuilder {
+        let mut entries: Vec<(RelayUrl, Option<RelayMetadata>)> = Vec::new();
+        for url in self.write.iter().chain(self.read.iter()) {
+            let Ok(relay_url) = RelayUrl::parse(url) else { continue };
+            if entries.iter().any(|(u, _)| u == &relay_url) {
+                continue;
+            }
+            let metadata = match (self.read.contains(url), self.write.contains(url)) {
+                (true, true) => None,
+                (true, false) => Some(RelayMetadata::Read),
+                _ => Some(RelayMetadata::Write),
+            };
+            entries.push((relay_url, metadata));
+        }
+        EventBuilder::relay_list(entries)
+    }
+}
+
+/// Returns the relay lists of `pubkeys`, fetching the ones not in the cache.
+///
+/// Authors without a kind 10002 get an empty list so they are not looked up again
+/// until the cache entry expires.
+pub async fn fetch_relay_lists(
+    client: &Client,
+    pubkeys: &HashSet<PublicKey>,
+    cache_db: &LmdbCache,
+) -> HashMap<PublicKey, RelayList> {
+    let mut lists = HashMap::new();
+    let mut missing = Vec::new();
+    for pubkey in pubkeys {
+        match cache_db.read_cache::<RelayList>(DB_RELAYS, &pubkey.to_hex()) {
+            Ok(cache) => {
+                lists.insert(*pubkey, cache.data);
+            }
+            Err(_) => missing.push(*pubkey),
+        }
+    }
+    if missing.is_empty() {
+        return lists;
+    }
+
+    println!("Fetching NIP-65 relay lists for {} authors", missing.len());
+    let chunks: Vec<Vec<PublicKey>> = missing.chunks(AUTHORS_PER_REQ).map(|c| c.to_vec()).collect();
+    let results = futures::future::join_all(chunks.iter().map(|chunk| {
+        let filter = Filter::new().authors(chunk.clone()).kind(Kind::RelayList);
+        client.fetch_events(filter, Duration::from_secs(10))
+    }))
+    .await;
+
+    for (chunk, result) in chunks.iter().zip(results) {
+        let events = match result {
+            Ok(events) => events,
+            Err(e) => {
+                // 取得に失敗した分はキャッシュせず、次回また問い合わせる
+                eprintln!("Failed to fetch relay lists: {e}");
+                continue;
+            }
+        };
+        let mut newest: HashMap<PublicKey, Event> = HashMap::new();
+        for event in events {
+            match newest.get(&event.pubkey) {
+                Some(current) if current.created_at >= event.created_at => {}
+                _ => {
+                    newest.insert(event.pubkey, event);
+                }
+            }
+        }
+        for pubkey in chunk {
+            let list = newest.get(pubkey).map(RelayList::from_event).unwrap_or_default();
+            if let Err(e) = cache_db.write_cache(DB_RELAYS, &pubkey.to_hex(), &list) {
+                eprintln!("Failed to cache relay list: {e}");
+            }
+            lists.insert(*pubkey, list);
+        }
+    }
+    lists
+}
+
+/// Write relays of `pubkey` from the `DB_RELAYS` cache only, for use on the UI thread.
+pub fn cached_write_relays(cache_db: &LmdbCache, pubkey: &PublicKey) -> Vec<RelayUrl> {
+    cache_db
+        .read_cache::<RelayList>(DB_RELAYS, &pubkey.to_hex())
+        .map(|cache| cache.data.write.iter().filter_map(|url| RelayUrl::parse(url).ok()).collect())
+        .unwrap_or_default()
+}
+
+/// A relay where events by `pubkey` can be found: the first of their write relays.
+pub async fn relay_hint(client: &Client, cache_db: &LmdbCache, pubkey: &PublicKey) -> Option<RelayUrl> {
+    let pubkeys = HashSet::from([*pubkey]);
+    let lists = fetch_relay_lists(client, &pubkeys, cache_db).await;
+    lists.get(pubkey)?.write.iter().find_map(|url| RelayUrl::parse(url).ok())
+}
+
+/// Picks at most [`MAX_OUTBOX_RELAYS`] write relays that cover the most authors.
+///
+/// Greedy set cover: each round takes the relay listed by the most authors that
+/// still want another relay, until every author has up to `RELAYS_PER_AUTHOR`.
+/// Returns the chosen relays with the authors to ask them for, and the authors
+/// left uncovered (no relay list, or only relays that were not picked).
+pub fn select_outbox_relays(
+    authors: &HashSet<PublicKey>,
+    lists: &HashMap<PublicKey, RelayList>,
+) -> (Vec<(String, HashSet<PublicKey>)>, HashSet<PublicKey>) {
+    let mut wanted: HashMap<PublicKey, usize> = HashMap::new();
+    let mut candidates: HashMap<String, HashSet<PublicKey>> = HashMap::new();
+    for author in authors {
+        let Some(list) = lists.get(author) else { continue };
+        let mut count = 0;
+        for url in &list.write {
+            if let Ok(url) = RelayUrl::parse(url)
+                && candidates.entry(url.to_string()).or_default().insert(*author)
+            {
+                count += 1;
+            }
+        }
+        if count > 0 {
+            wanted.insert(*author, RELAYS_PER_AUTHOR.min(count));
+        }
+    }
+
+    let mut selected: Vec<(String, HashSet<PublicKey>)> = Vec::new();
+    while selected.len() < MAX_OUTBOX_RELAYS {
+        let best = candidates
+            .iter()
+            .map(|(url, listed)| {
+                let gain = listed.iter().filter(|a| wanted.get(*a).is_some_and(|n| *n > 0)).count();
+                (gain, url)
+            })
+            .filter(|(gain, _)| *gain > 0)
+            // 同数なら URL 順にして結果を安定させる
+            .max_by(|a, b| a.0.cmp(&b.0).then_with(|| b.1.cmp(a.1)))
+            .map(|(_, url)| url.clone());
+        let Some(url) = best else { break };
+
+        let listed = candidates.remove(&url).unwrap_or_default();
+        for author in &listed {
+            if let Some(n) = wanted.get_mut(author) {
+                *n = n.saturating_sub(1);
+            }
+        }
+        selected.push((url, listed));
+    }
+
+    let covered: HashSet<PublicKey> = selected.iter().flat_map(|(_, a)| a.iter().copied()).collect();
+    let uncovered = authors.difference(&covered).copied().collect();
+    (selected, uncovered)
+}
+
+// 宛先ユーザーの読み込みリレー (inbox)。人数が多くても MAX_INBOX_RELAYS までにする。
+fn inbox_relays(pubkeys: &HashSet<PublicKey>, lists: &HashMap<PublicKey, RelayList>) -> Vec<RelayUrl> {
+    let mut relays: Vec<RelayUrl> = Vec::new();
+    for pubkey in pubkeys {
+        let Some(list) = lists.get(pubkey) else { continue };
+        let urls = list.read.iter().filter_map(|url| RelayUrl::parse(url).ok());
+        for url in urls.take(INBOX_RELAYS_PER_USER) {
+            if relays.len() >= MAX_INBOX_RELAYS {
+                return relays;
+            }
+            if !relays.contains(&url) {
+                relays.push(url);
+            }
+        }
+    }
+    relays
+}
+
+/// Adds relays that are not in the pool yet and waits briefly for them to connect.
+///
+/// They are added without the READ / WRITE flags, so plain `fetch_events` and
+/// `send_event` keep using the relays from the user's settings only.
+pub async fn ensure_relays(client: &Client, urls: &[String]) {
+    let mut added = Vec::new();
+    for url in urls {
+        let opts = RelayOptions::new().flags(RelayServiceFlags::PING | RelayServiceFlags::GOSSIP);
+        match client.pool().add_relay(url.as_str(), opts).await {
+            Ok(true) => added.push(url.clone()),
+            Ok(false) => {}
+            Err(e) => eprintln!("Failed to add relay {url}: {e}"),
+        }
+    }
+    futures::future::join_all(added.iter().map(|url| async move {
+        if let Err(e) = client.try_connect_relay(url.as_str(), CONNECT_TIMEOUT).await {
+            eprintln!("Failed to connect to {url}: {e}");
+        }
+    }))
+    .await;
+}
+
+/// Our read relays plus `hints` (relays a tag or a NIP-05 document pointed at), connected.
+pub async fn read_relays_with_hints(client: &Client, hints: &[String]) -> Vec<String> {
+    ensure_relays(client, hints).await;
+    let mut relays: Vec<String> = client
+        .pool()
+        .relays_with_flag(RelayServiceFlags::READ, FlagCheck::All)
+        .await
+        .into_keys()
+        .map(|url| url.to_string())
+        .collect();
+    for hint in hints {
+        if !relays.contains(hint) {
+            relays.push(hint.clone());
+        }
+    }
+    relays
+}
+
+// 一つのリレーにも保存されなければ失敗にする
+async fn send_to(
+    client: &Client,
+    targets: HashSet<RelayUrl>,
+    event: &Event,
+) -> Result<EventId, Box<dyn std::error::Error + Send + Sync>> {
+    let output = client.send_event_to(targets, event).await?;
+    if output.success.is_empty() {
+        return Err("どのリレーにも保存されませんでした".into());
+    }
+    if !output.failed.is_empty() {
+        eprintln!("Event {} was rejected by {} relays", output.val, output.failed.len());
+    }
+    Ok(output.val)
+}
+
+async fn write_relays(client: &Client) -> HashSet<RelayUrl> {
+    client
+        .pool()
+        .relays_with_flag(RelayServiceFlags::WRITE, FlagCheck::All)
+        .await
+        .into_keys()
+        .collect()
+}
+
+/// Publishes an event to our write relays and to the read relays of every user it tags.
+pub async fn publish(
+    client: &Client,
+    cache_db: &LmdbCache,
+    event: &Event,
+) -> Result<EventId, Box<dyn std::error::Error + Send + Sync>> {
+    let mut targets = write_relays(client).await;
+
+    let tagged: HashSet<PublicKey> = event
+        .tags
+        .public_keys()
+        .copied()
+        .filter(|pubkey| *pubkey != event.pubkey)
+        .collect();
+    if !tagged.is_empty() {
+        let lists = fetch_relay_lists(client, &tagged, cache_db).await;
+        let inbox = inbox_relays(&tagged, &lists);
+        let inbox_urls: Vec<String> = inbox.iter().map(|u| u.to_string()).collect();
+        ensure_relays(client, &inbox_urls).await;
+        targets.extend(inbox);
+    }
+
+    send_to(client, targets, event).await
+}
+
+/// Publishes an event to our write relays only.
+///
+/// For our own lists (mutes, contacts): their `p` tags are not mentions, so the tagged
+/// users' inboxes must not get them.
+pub async fn publish_own(client: &Client, event: &Event) -> Result<EventId, Box<dyn std::error::Error + Send + Sync>> {
+    send_to(client, write_relays(client).await, event).await
+}
+
+/// Signs and publishes our kind 10002 built from `RelayConfig`.
+pub async fn publish_own_relay_list(
+    client: &Client,
+    cache_db: &LmdbCache,
+    signer: &Arc<dyn NostrSigner>,
+    config: &RelayConfig,
+) -> Result<EventId, Box<dyn std::error::Error + Send + Sync>> {
+    let list = RelayList::from_config(config);
+    if list.write.is_empty() && list.read.is_empty() {
+        return Err("公開するリレーがありません".into());
+    }
+    let event = list.to_event_builder().sign(signer).await?;
+    let event_id = publish(client, cache_db, &event).await?;
+    cache_db.write_cache(DB_RELAYS, &event.pubkey.to_hex(), &list)?;
+    Ok(event_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr::Keys;
+
+    fn writes_to(urls: &[&str]) -> RelayList {
+        RelayList { read: Vec::new(), write: urls.iter().map(|url| url.to_string()).collect() }
+    }
+
+    fn url(url: &str) -> String {
+        RelayUrl::parse(url).unwrap().to_string()
+    }
+
+    #[test]
+    fn outbox_relays_cover_each_author_twice_with_the_fewest_relays() {
+        let (alice, bob, carol) = (Keys::generate().public_key(), Keys::generate().public_key(), Keys::generate().public_key());
+        let lists = HashMap::from([
+            (alice, writes_to(&["wss://shared.example", "wss://a.example"])),
+            (bob, writes_to(&["wss://shared.example", "wss://b.example"])),
+            (carol, writes_to(&["wss://shared.example"])),
+        ]);
+
+        let (selected, uncovered) = select_outbox_relays(&HashSet::from([alice, bob, carol]), &lists);
+
+        let urls: Vec<&String> = selected.iter().map(|(url, _)| url).collect();
+        assert_eq!(urls, vec![&url("wss://shared.example"), &url("wss://a.example"), &url("wss://b.example")]);
+        assert_eq!(selected[0].1, HashSet::from([alice, bob, carol]));
+        assert!(uncovered.is_empty());
+    }
+
+    #[test]
+    fn outbox_relays_are_capped() {
+        let authors: Vec<PublicKey> = (0..MAX_OUTBOX_RELAYS + 2).map(|_| Keys::generate().public_key()).collect();
+        let lists: HashMap<PublicKey, RelayList> = authors
+            .iter()
+            .enumerate()
+            .map(|(i, author)| (*author, writes_to(&[&format!("wss://relay{i}.example")])))
+            .collect();
+
+        let (selected, uncovered) = select_outbox_relays(&authors.iter().copied().collect(), &lists);
+
+        assert_eq!(selected.len(), MAX_OUTBOX_RELAYS);
+        assert_eq!(uncovered.len(), 2);
+        assert!(selected.iter().all(|(_, listed)| listed.is_disjoint(&uncovered)));
+    }
+
+    #[test]
+    fn authors_without_usable_write_relays_are_uncovered() {
+        let (alice, bob, carol) = (Keys::generate().public_key(), Keys::generate().public_key(), Keys::generate().public_key());
+        let lists = HashMap::from([
+            (alice, writes_to(&["wss://a.example"])),
+            (bob, writes_to(&["not a relay"])),
+        ]);
+
+        let (selected, uncovered) = select_outbox_relays(&HashSet::from([alice, bob, carol]), &lists);
+
+        assert_eq!(selected, vec![(url("wss://a.example"), HashSet::from([alice]))]);
+        assert_eq!(uncovered, HashSet::from([bob, carol]));
+    }
+}
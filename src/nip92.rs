@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use nostr::Tag;
+use regex::Regex;
+
+static URL_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"https?://[^\s<>"]+"#).unwrap());
+
+const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "webp", "avif", "apng"];
+const VIDEO_EXTENSIONS: &[&str] = &["mp4", "webm", "mov", "m4v", "ogv"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaKind {
+    Image,
+    Video,
+}
+
+/// An image or video URL in a note, with what its `imeta` tag says about it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Media {
+    pub url: String,
+    pub kind: MediaKind,
+    pub dim: Option<(u32, u32)>,
+    pub blurhash: Option<String>,
+    // 動画のプレビュー画像 (imeta の image か thumb)
+    pub poster: Option<String>,
+    pub alt: Option<String>,
+}
+
+/// A piece of note text: plain text or a media URL.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Segment<'a> {
+    Text(&'a str),
+    Media(Media),
+}
+
+// imeta の "key value" を url ごとにまとめる
+fn imeta_entries(tags: &[Tag]) -> HashMap<&str, HashMap<&str, &str>> {
+    let mut entries = HashMap::new();
+    for tag in tags {
+        let [name, fields @ ..] = tag.as_slice() else {
+            continue;
+        };
+        if name != "imeta" {
+            continue;
+        }
+        let fields: HashMap<&str, &str> = fields.iter().filter_map(|field| field.split_once(' ')).collect();
+        if let Some(url) = fields.get("url") {
+            entries.insert(*url, fields);
+        }
+    }
+    entries
+}
+
+fn kind_from_extension(url: &str) -> Option<MediaKind> {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    let ext = path.rsplit_once('.').map(|(_, ext)| ext.to_lowercase())?;
+    if IMAGE_EXTENSIONS.contains(&ext.as_str()) {
+        Some(MediaKind::Image)
+    } else if VIDEO_EXTENSIONS.contains(&ext.as_str()) {
+        Some(MediaKind::Video)
+    } else {
+        None
+    }
+}
+
+fn parse_dim(value: &str) -> Option<(u32, u32)> {
+    let (width, height) = value.split_once('x')?;
+    Some((width.parse().ok()?, height.parse().ok()?)).filter(|&(w, h)| w > 0 && h > 0)
+}
+
+/// Splits `text` into plain text and media URLs. A URL is media when its `imeta`
+/// mime type or its file extension says it is an image or a video.
+pub fn split_media<'a>(text: &'a str, tags: &[Tag]) -> Vec<Segment<'a>> {
+    let entries = imeta_entries(tags);
+    let mut segments = Vec::new();
+    let mut last_end = 0;
+    for mat in URL_RE.find_iter(text) {
+        let url = mat.as_str();
+        let entry = entries.get(url);
+        let kind = match entry.and_then(|fields| fields.get("m")) {
+            Some(mime) if mime.starts_with("image/") => Some(MediaKind::Image),
+            Some(mime) if mime.starts_with("video/") => Some(MediaKind::Video),
+            _ => kind_from_extension(url),
+        };
+        let Some(kind) = kind else {
+            continue;
+        };
+        if mat.start() > last_end {
+            segments.push(Segment::Text(&text[last_end..mat.start()]));
+        }
+        let field = |key: &str| entry.and_then(|fields| fields.get(key)).map(|value| value.to_string());
+        segments.push(Segment::Media(Media {
+            url: url.to_string(),
+            kind,
+            dim: entry.and_then(|fields| fields.get("dim")).and_then(|value| parse_dim(value)),
+            blurhash: field("blurhash"),
+            poster: field("image").or_else(|| field("thumb")),
+            alt: field("alt"),
+        }));
+        last_end = mat.end();
+    }
+    if last_end < text.len() {
+        segments.push(Segment::Text(&text[last_end..]));
+    }
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_media_by_extension() {
+        let text = "見て https://example.com/cat.JPG?w=1 と https://example.com/page と https://example.com/a.mp4";
+        let segments = split_media(text, &[]);
+
+        assert_eq!(segments.len(), 4);
+        assert_eq!(segments[0], Segment::Text("見て "));
+        assert!(matches!(&segments[1], Segment::Media(m) if m.kind == MediaKind::Image && m.dim.is_none()));
+        assert_eq!(segments[2], Segment::Text(" と https://example.com/page と "));
+        assert!(matches!(&segments[3], Segment::Media(m) if m.kind == MediaKind::Video));
+    }
+
+    #[test]
+    fn reads_imeta_fields() {
+        let tags = vec![
+            Tag::parse([
+                "imeta",
+                "url https://cdn.example.com/abc",
+                "m video/mp4",
+                "dim 1920x1080",
+                "image https://cdn.example.com/abc.jpg",
+            ])
+            .unwrap(),
+            Tag::parse(["imeta", "url https://example.com/x.png", "blurhash LEHV6nWB2yk8", "alt a cat"]).unwrap(),
+        ];
+        let segments = split_media("https://cdn.example.com/abc\nhttps://example.com/x.png", &tags);
+
+        let Segment::Media(video) = &segments[0] else { panic!("not media") };
+        assert_eq!(video.kind, MediaKind::Video);
+        assert_eq!(video.dim, Some((1920, 1080)));
+        assert_eq!(video.poster.as_deref(), Some("https://cdn.example.com/abc.jpg"));
+        let Segment::Media(image) = &segments[2] else { panic!("not media") };
+        assert_eq!(image.blurhash.as_deref(), Some("LEHV6nWB2yk8"));
+        assert_eq!(image.alt.as_deref(), Some("a cat"));
+    }
+}
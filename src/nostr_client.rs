@@ -1,10 +1,14 @@
-use nostr::{Filter, Kind, PublicKey};
+use nostr::{Event, Filter, Kind, PublicKey, Timestamp};
 use nostr_sdk::{Client, SubscribeAutoCloseOptions};
 use std::collections::{HashMap, HashSet};
 use std::time::Duration;
 
 use crate::{
     cache_db::{LmdbCache, DB_PROFILES},
+    event_store::{self, EventDb},
+    nip02, nip05, nip65,
+    nip51::MuteList,
+    notifications,
     types::{ProfileMetadata, TimelinePost},
 };
 
@@ -33,12 +37,12 @@ pub async fn fetch_nip01_profile(
         _ = async {
             let mut notifications = client.notifications();
             while let Ok(notification) = notifications.recv().await {
-                if let nostr_sdk::RelayPoolNotification::Event { event, .. } = notification {
-                    if event.kind == Kind::Metadata && event.pubkey == public_key {
-                        profile_json_string = event.content.clone();
-                        received_nip01 = true;
-                        break;
-                    }
+                if let nostr_sdk::RelayPoolNotification::Event { event, .. } = notification
+                    && event.kind == Kind::Metadata && event.pubkey == public_key
+                {
+                    profile_json_string = event.content.clone();
+                    received_nip01 = true;
+                    break;
                 }
             }
         } => {},
@@ -85,30 +89,189 @@ pub async fn get_profile_metadata(
     }
 }
 
+// フォローのタイムラインで 1 つの REQ に入れる著者数の上限
+const AUTHORS_PER_REQ: usize = 250;
+const TIMELINE_LIMIT: usize = 20;
+// タイムラインに流すのは投稿とリポスト (NIP-18)
+const TIMELINE_KINDS: [Kind; 3] = [Kind::TextNote, Kind::Repost, Kind::GenericRepost];
+const NOTIFICATION_LIMIT: usize = 50;
+const SEARCH_LIMIT: usize = 50;
+// プロフィールに 1 ページで出す投稿の数
+pub const AUTHOR_POSTS_LIMIT: usize = 20;
+// 通知になる種類: 返信・メンション、リアクション、リポスト、Zap レシート
+const NOTIFICATION_KINDS: [Kind; 5] = [
+    Kind::TextNote,
+    Kind::Reaction,
+    Kind::Repost,
+    Kind::GenericRepost,
+    Kind::ZapReceipt,
+];
+// 新しいフォロワーを探すとき・フォロワー数を数えるときに見るコンタクトリストの数
+pub const FOLLOWER_LIMIT: usize = 500;
+
+// until を指定すると、その時刻以前の (古い) ページを取得する
+fn with_until(filter: Filter, until: Option<Timestamp>) -> Filter {
+    match until {
+        Some(until) => filter.until(until),
+        None => filter,
+    }
+}
+
+/// Posts fetched for one page of a list.
+pub struct Page {
+    pub posts: Vec<TimelinePost>,
+    /// `until` for the next page: the oldest event the relays returned, including ones
+    /// filtered out of `posts`. `None` if they returned less than a full page.
+    pub next_until: Option<Timestamp>,
+}
+
+// limit 件そろって返ってきたら、その最古の created_at から次のページを読む。
+// 足りなければそれより古いものはない。
+pub fn next_until<'a>(events: impl IntoIterator<Item = &'a Event>, limit: usize) -> Option<Timestamp> {
+    let created_at: Vec<Timestamp> = events.into_iter().map(|e| e.created_at).collect();
+    if created_at.len() < limit {
+        return None;
+    }
+    created_at.into_iter().min()
+}
+
+// authors が None ならグローバルフィード、Some ならその著者だけのフィルター。
+// フォローが多いときは著者を分割して複数のフィルターにする。limit は呼び出し側で付ける。
+pub fn timeline_filters(authors: Option<&HashSet<PublicKey>>) -> Vec<Filter> {
+    match authors {
+        None => vec![Filter::new().kinds(TIMELINE_KINDS)],
+        Some(authors) => {
+            let authors: Vec<PublicKey> = authors.iter().copied().collect();
+            authors
+                .chunks(AUTHORS_PER_REQ)
+                .map(|chunk| Filter::new().authors(chunk.to_vec()).kinds(TIMELINE_KINDS))
+                .collect()
+        }
+    }
+}
+
+// Replies, mentions, reactions, reposts and zap receipts that tag the user's pubkey
+pub fn notification_filter(my_pubkey: PublicKey) -> Filter {
+    Filter::new().kinds(NOTIFICATION_KINDS).pubkey(my_pubkey)
+}
+
+// 自分を含むコンタクトリスト。前回からの差分を新しいフォロワーとして通知する
+pub fn follower_filter(my_pubkey: PublicKey) -> Filter {
+    Filter::new().kind(Kind::ContactList).pubkey(my_pubkey)
+}
+
+// 1 つのリレー群と、そこに問い合わせる著者 (None なら著者で絞らない)
+#[derive(Debug, Clone)]
+pub struct TimelineRoute {
+    pub relays: Vec<String>,
+    pub authors: Option<HashSet<PublicKey>>,
+}
+
 pub async fn fetch_timeline_events(
     client: &Client,
-    aggregator_relays: Vec<String>,
-) -> Result<Vec<TimelinePost>, Box<dyn std::error::Error + Send + Sync>> {
-    let mut timeline_posts = Vec::new();
+    routes: &[TimelineRoute],
+    until: Option<Timestamp>,
+    mutes: &MuteList,
+) -> Result<Page, Box<dyn std::error::Error + Send + Sync>> {
+    let mut requests: Vec<(Vec<String>, Filter)> = Vec::new();
+    for route in routes {
+        if route.relays.is_empty() {
+            continue;
+        }
+        for filter in timeline_filters(route.authors.as_ref()) {
+            requests.push((route.relays.clone(), with_until(filter.limit(TIMELINE_LIMIT), until)));
+        }
+    }
+    if requests.is_empty() {
+        return Ok(Page { posts: Vec::new(), next_until: None });
+    }
 
-    if aggregator_relays.is_empty() {
-        return Ok(timeline_posts);
+    let relays: HashSet<&String> = requests.iter().flat_map(|(relays, _)| relays).collect();
+    println!(
+        "Fetching timeline from {} relays ({} REQ)",
+        relays.len(),
+        requests.len()
+    );
+    let results = futures::future::join_all(requests.into_iter().map(|(relays, filter)| {
+        client.fetch_events_from(relays, filter, Duration::from_secs(10))
+    }))
+    .await;
+
+    let mut seen = HashSet::new();
+    let mut note_events = Vec::new();
+    let mut last_error = None;
+    let mut any_ok = false;
+    let mut page_is_full = false;
+    for result in results {
+        match result {
+            Ok(events) => {
+                any_ok = true;
+                page_is_full |= events.len() >= TIMELINE_LIMIT;
+                for event in events {
+                    if seen.insert(event.id) {
+                        note_events.push(event);
+                    }
+                }
+            }
+            // 一部のリレーが落ちていても、取れた分でタイムラインを組む
+            Err(e) => {
+                eprintln!("Timeline REQ failed: {e}");
+                last_error = Some(e);
+            }
+        }
     }
+    if let (false, Some(e)) = (any_ok, last_error) {
+        return Err(e.into());
+    }
+    // 各 REQ の上位を合わせたものから、全体で新しい順に TIMELINE_LIMIT 件を残す。
+    // 次のページの until は、ミュートで隠す前の最古の投稿で決める。
+    note_events.sort_by_key(|e| std::cmp::Reverse(e.created_at));
+    page_is_full |= note_events.len() > TIMELINE_LIMIT;
+    note_events.truncate(TIMELINE_LIMIT);
+    let next_until = note_events.last().filter(|_| page_is_full).map(|e| e.created_at);
+    note_events.retain(|e| !mutes.hides_event(e));
+
+    let posts = events_to_timeline_posts(client, note_events).await?;
+    Ok(Page { posts, next_until })
+}
 
-    let timeline_filter = Filter::new()
-        .kind(Kind::TextNote)
-        .limit(20);
+pub fn event_to_timeline_post(event: &Event, author_metadata: ProfileMetadata) -> TimelinePost {
+    let emojis = event
+        .tags
+        .iter()
+        .filter_map(|tag| {
+            if let Some(nostr::TagStandard::Emoji { shortcode, url }) = tag.as_standardized() {
+                Some((shortcode.to_string(), url.to_string()))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    TimelinePost {
+        id: event.id,
+        kind: event.kind,
+        author_pubkey: event.pubkey,
+        author_metadata,
+        content: event.content.clone(),
+        created_at: event.created_at,
+        emojis,
+        tags: event.tags.clone().to_vec(),
+    }
+}
 
-    println!("Fetching timeline from: {:?}", aggregator_relays);
-    let note_events = client
-        .fetch_events_from(aggregator_relays, timeline_filter, Duration::from_secs(10))
-        .await?;
+// 著者のメタデータをまとめて取得して TimelinePost に変換する (新しい順)
+pub async fn events_to_timeline_posts(
+    client: &Client,
+    note_events: Vec<Event>,
+) -> Result<Vec<TimelinePost>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut timeline_posts = Vec::new();
 
     if !note_events.is_empty() {
         let author_pubkeys: HashSet<PublicKey> =
             note_events.iter().map(|e| e.pubkey).collect();
         let metadata_filter = Filter::new()
-            .authors(author_pubkeys.into_iter())
+            .authors(author_pubkeys)
             .kind(Kind::Metadata);
         let metadata_events = client
             .fetch_events(metadata_filter, Duration::from_secs(5))
@@ -121,116 +284,145 @@ pub async fn fetch_timeline_events(
         }
 
         for event in note_events {
-            let emojis = event
-                .tags
-                .iter()
-                .filter_map(|tag| {
-                    if let Some(nostr::TagStandard::Emoji { shortcode, url }) =
-                        tag.as_standardized()
-                    {
-                        Some((shortcode.to_string(), url.to_string()))
-                    } else {
-                        None
-                    }
-                })
-                .collect();
-
-            timeline_posts.push(TimelinePost {
-                id: event.id,
-                kind: event.kind,
-                author_pubkey: event.pubkey,
-                author_metadata: profiles.get(&event.pubkey).cloned().unwrap_or_default(),
-                content: event.content.clone(),
-                created_at: event.created_at,
-                emojis,
-                tags: event.tags.to_vec(),
-            });
+            let author_metadata = profiles.get(&event.pubkey).cloned().unwrap_or_default();
+            timeline_posts.push(event_to_timeline_post(&event, author_metadata));
         }
         timeline_posts.sort_by_key(|p| std::cmp::Reverse(p.created_at));
     }
     Ok(timeline_posts)
 }
 
-pub async fn fetch_notification_events(
+// 通知イベントを、送り主 (Zap なら zap リクエストの著者) のプロフィール付きの投稿にする
+pub async fn notification_events_to_posts(
     client: &Client,
-    my_pubkey: PublicKey,
+    events: Vec<Event>,
 ) -> Result<Vec<TimelinePost>, Box<dyn std::error::Error + Send + Sync>> {
-    let mut notification_posts = Vec::new();
-    let notification_relays = vec!["wss://yabu.me".to_string()];
-
-    // Filter for replies (Kind 1) and reactions (Kind 7) that tag the user's pubkey
-    let notifications_filter = Filter::new()
-        .kinds(vec![Kind::TextNote, Kind::Reaction])
-        .pubkey(my_pubkey)
-        .limit(20);
-
-    println!("Fetching notifications from: {:?}", notification_relays);
-    let notification_events = client
-        .fetch_events_from(
-            notification_relays,
-            notifications_filter,
-            Duration::from_secs(10),
-        )
+    if events.is_empty() {
+        return Ok(Vec::new());
+    }
+    let actors: HashSet<PublicKey> = events.iter().map(notifications::actor).collect();
+    let metadata_filter = Filter::new().authors(actors).kind(Kind::Metadata);
+    let metadata_events = client
+        .fetch_events(metadata_filter, Duration::from_secs(5))
         .await?;
+    let mut profiles: HashMap<PublicKey, ProfileMetadata> = HashMap::new();
+    for event in metadata_events {
+        if let Ok(metadata) = serde_json::from_str::<ProfileMetadata>(&event.content) {
+            profiles.insert(event.pubkey, metadata);
+        }
+    }
 
-    if !notification_events.is_empty() {
-        let author_pubkeys: HashSet<PublicKey> =
-            notification_events.iter().map(|e| e.pubkey).collect();
-
-        if !author_pubkeys.is_empty() {
-            let metadata_filter = Filter::new()
-                .authors(author_pubkeys.into_iter())
-                .kind(Kind::Metadata);
-
-            let metadata_events = client
-                .fetch_events(metadata_filter, Duration::from_secs(5))
-                .await?;
-            let mut profiles: HashMap<PublicKey, ProfileMetadata> = HashMap::new();
-            for event in metadata_events {
-                if let Ok(metadata) = serde_json::from_str::<ProfileMetadata>(&event.content) {
-                    profiles.insert(event.pubkey, metadata);
-                }
-            }
+    let mut posts: Vec<TimelinePost> = events
+        .iter()
+        .map(|event| {
+            let metadata = profiles.get(&notifications::actor(event)).cloned().unwrap_or_default();
+            notifications::to_post(event, metadata)
+        })
+        .collect();
+    posts.sort_by_key(|p| std::cmp::Reverse(p.created_at));
+    Ok(posts)
+}
 
-            for event in notification_events {
-                let emojis = event
-                    .tags
-                    .iter()
-                    .filter_map(|tag| {
-                        if let Some(nostr::TagStandard::Emoji { shortcode, url }) =
-                            tag.as_standardized()
-                        {
-                            Some((shortcode.to_string(), url.to_string()))
-                        } else {
-                            None
-                        }
-                    })
-                    .collect();
-
-                notification_posts.push(TimelinePost {
-                    id: event.id,
-                    kind: event.kind,
-                    author_pubkey: event.pubkey,
-                    author_metadata: profiles.get(&event.pubkey).cloned().unwrap_or_default(),
-                    content: event.content.clone(),
-                    created_at: event.created_at,
-                    emojis,
-                    tags: event.tags.to_vec(),
-                });
+// 通知は自分の読み込みリレー (設定で READ にしたもの) に問い合わせる
+pub async fn fetch_notification_events(
+    client: &Client,
+    cache_db: &LmdbCache,
+    my_pubkey: PublicKey,
+    until: Option<Timestamp>,
+    mutes: &MuteList,
+) -> Result<Page, Box<dyn std::error::Error + Send + Sync>> {
+    let notifications_filter =
+        with_until(notification_filter(my_pubkey).limit(NOTIFICATION_LIMIT), until);
+
+    println!("Fetching notifications from read relays");
+    let zapper = notifications::load_zapper(cache_db, &my_pubkey);
+    let events = client
+        .fetch_events(notifications_filter, Duration::from_secs(10))
+        .await?;
+    // 1 ページ分返ってきたら、それより古い通知は次のページで読む
+    let next_until = next_until(events.iter(), NOTIFICATION_LIMIT);
+    let notification_events: Vec<Event> = events
+        .into_iter()
+        .filter(|event| notifications::is_relevant(event, my_pubkey, zapper))
+        .collect();
+    let mut posts = notification_events_to_posts(client, notification_events).await?;
+
+    // フォロワーの差分は先頭ページを取るときだけ見る
+    let mut new_follows = Vec::new();
+    if until.is_none() {
+        let filter = follower_filter(my_pubkey).limit(FOLLOWER_LIMIT);
+        match client.fetch_events(filter, Duration::from_secs(10)).await {
+            Ok(contact_lists) => {
+                new_follows = notifications::new_followers(cache_db, &my_pubkey, contact_lists);
             }
-            notification_posts.sort_by_key(|p| std::cmp::Reverse(p.created_at));
+            Err(e) => eprintln!("Failed to fetch followers: {e}"),
         }
     }
+    let new_follows = notification_events_to_posts(client, new_follows).await?;
+    // 保存済みのフォロー通知のうち、このページの期間に入るものを混ぜる
+    let follows = notifications::remember_follows(cache_db, &my_pubkey, new_follows)
+        .into_iter()
+        .filter(|p| until.is_none_or(|until| p.created_at < until))
+        .filter(|p| next_until.is_none_or(|next| p.created_at >= next));
+    posts.extend(follows);
+    // 送り主 (Zap ならリクエストした人) や対象のスレッドでミュートを判定する
+    mutes.retain_visible(&mut posts);
+    posts.sort_by_key(|p| std::cmp::Reverse(p.created_at));
+    Ok(Page { posts, next_until })
+}
+
+// 保存済みのイベントだけで組んだタイムライン。ネットワークの結果を待たずに先に出す
+pub async fn stored_timeline_posts(
+    db: &EventDb,
+    authors: Option<&HashSet<PublicKey>>,
+    mutes: &MuteList,
+) -> Vec<TimelinePost> {
+    let filters = timeline_filters(authors).into_iter().map(|f| f.limit(TIMELINE_LIMIT)).collect();
+    let mut posts = event_store::posts(db, filters).await;
+    mutes.retain_visible(&mut posts);
+    posts.truncate(TIMELINE_LIMIT);
+    posts
+}
+
+// 保存済みの通知と、覚えているフォロー通知
+pub async fn stored_notification_posts(
+    db: &EventDb,
+    cache_db: &LmdbCache,
+    my_pubkey: PublicKey,
+    mutes: &MuteList,
+) -> Vec<TimelinePost> {
+    let zapper = notifications::load_zapper(cache_db, &my_pubkey);
+    let events: Vec<Event> = event_store::query(db, vec![notification_filter(my_pubkey).limit(NOTIFICATION_LIMIT)])
+        .await
+        .into_iter()
+        .filter(|event| notifications::is_relevant(event, my_pubkey, zapper))
+        .collect();
+    let profiles = event_store::profiles(db, events.iter().map(notifications::actor).collect()).await;
+    let mut posts: Vec<TimelinePost> = events
+        .iter()
+        .map(|event| {
+            let metadata = profiles.get(&notifications::actor(event)).cloned().unwrap_or_default();
+            notifications::to_post(event, metadata)
+        })
+        .collect();
+    posts.extend(notifications::remember_follows(cache_db, &my_pubkey, Vec::new()));
+    mutes.retain_visible(&mut posts);
+    posts.sort_by_key(|p| std::cmp::Reverse(p.created_at));
+    posts
+}
 
-    Ok(notification_posts)
+pub async fn stored_posts_by_author(db: &EventDb, author_pubkey: PublicKey) -> Vec<TimelinePost> {
+    event_store::posts(db, vec![Filter::new().author(author_pubkey).kind(Kind::TextNote).limit(AUTHOR_POSTS_LIMIT)]).await
 }
 
 pub async fn search_events(
     search_relays: Vec<String>,
     query: String,
-) -> Result<Vec<TimelinePost>, Box<dyn std::error::Error + Send + Sync>> {
+    until: Option<Timestamp>,
+    mutes: &MuteList,
+) -> Result<Page, Box<dyn std::error::Error + Send + Sync>> {
     if search_relays.is_empty() || query.is_empty() {
-        return Ok(Vec::new());
+        return Ok(Page { posts: Vec::new(), next_until: None });
     }
 
     let client = Client::new(nostr::Keys::generate());
@@ -241,16 +433,23 @@ pub async fn search_events(
     }
     client.connect().await;
 
-    let search_filter = Filter::new().search(query).kind(Kind::TextNote).limit(50);
+    let search_filter = with_until(Filter::new().search(query).kind(Kind::TextNote).limit(SEARCH_LIMIT), until);
 
-    let events = client.fetch_events_from(search_relays, search_filter, Duration::from_secs(10)).await?;
+    let events = client
+        .fetch_events_from(search_relays, search_filter, Duration::from_secs(10))
+        .await?;
+    let next_until = next_until(events.iter(), SEARCH_LIMIT);
+    let events: Vec<Event> = events
+        .into_iter()
+        .filter(|e| !mutes.hides_event(e))
+        .collect();
 
     let mut timeline_posts = Vec::new();
     if !events.is_empty() {
         let author_pubkeys: HashSet<PublicKey> =
             events.iter().map(|e| e.pubkey).collect();
         let metadata_filter = Filter::new()
-            .authors(author_pubkeys.into_iter())
+            .authors(author_pubkeys)
             .kind(Kind::Metadata);
 
         // Fetch metadata from the same search relays
@@ -279,24 +478,31 @@ pub async fn search_events(
     }
 
     client.disconnect().await;
-    Ok(timeline_posts)
+    Ok(Page { posts: timeline_posts, next_until })
 }
 
+// relay_hints があれば、読み込みリレーに加えてそこからも取る (NIP-05 で見つけた相手など)
 pub async fn fetch_posts_by_author(
     client: &Client,
     author_pubkey: PublicKey,
-) -> Result<Vec<TimelinePost>, Box<dyn std::error::Error + Send + Sync>> {
+    relay_hints: &[String],
+    until: Option<Timestamp>,
+) -> Result<Page, Box<dyn std::error::Error + Send + Sync>> {
     let mut timeline_posts = Vec::new();
 
-    let timeline_filter = Filter::new()
-        .author(author_pubkey)
-        .kind(Kind::TextNote)
-        .limit(20);
+    let timeline_filter = with_until(
+        Filter::new().author(author_pubkey).kind(Kind::TextNote).limit(AUTHOR_POSTS_LIMIT),
+        until,
+    );
 
     println!("Fetching posts for author: {}", author_pubkey.to_hex());
-    let note_events = client
-        .fetch_events(timeline_filter, Duration::from_secs(10))
-        .await?;
+    let note_events = if relay_hints.is_empty() {
+        client.fetch_events(timeline_filter, Duration::from_secs(10)).await?
+    } else {
+        let relays = nip65::read_relays_with_hints(client, relay_hints).await;
+        client.fetch_events_from(relays, timeline_filter, Duration::from_secs(10)).await?
+    };
+    let next_until = next_until(note_events.iter(), AUTHOR_POSTS_LIMIT);
 
     if !note_events.is_empty() {
         // Since we are fetching for a single author, we can fetch their metadata once.
@@ -330,6 +536,45 @@ pub async fn fetch_posts_by_author(
         }
         timeline_posts.sort_by_key(|p| std::cmp::Reverse(p.created_at));
     }
-    Ok(timeline_posts)
+    Ok(Page { posts: timeline_posts, next_until })
+}
+
+/// How many people `pubkey` follows, from their newest contact list, and how many
+/// contact lists include them. Followers are counted up to `FOLLOWER_LIMIT`.
+pub async fn fetch_follow_counts(
+    client: &Client,
+    pubkey: PublicKey,
+) -> Result<(Option<usize>, usize), Box<dyn std::error::Error + Send + Sync>> {
+    let following_filter = Filter::new().author(pubkey).kind(Kind::ContactList).limit(1);
+    let (following, followers) = futures::join!(
+        client.fetch_events(following_filter, Duration::from_secs(10)),
+        client.fetch_events(follower_filter(pubkey).limit(FOLLOWER_LIMIT), Duration::from_secs(10)),
+    );
+    let following = following?
+        .into_iter()
+        .max_by_key(|e| e.created_at)
+        .map(|e| nip02::followed_pubkeys(&e).len());
+    let followers: HashSet<PublicKey> = followers?.into_iter().map(|e| e.pubkey).collect();
+    Ok((following, followers.len()))
 }
 
+
+// NIP-05 識別子 (name@domain) を公開鍵に解決する
+pub async fn resolve_nip05(
+    identifier: &str,
+) -> Result<PublicKey, Box<dyn std::error::Error + Send + Sync>> {
+    let profile = nip05::Resolver::from_env().lookup(identifier).await?;
+    Ok(profile.public_key)
+}
+
+// npub / hex 公開鍵 / NIP-05 識別子のいずれかを公開鍵に解決する
+pub async fn resolve_public_key(
+    input: &str,
+) -> Result<PublicKey, Box<dyn std::error::Error + Send + Sync>> {
+    let input = input.trim();
+    if input.contains('@') {
+        resolve_nip05(input).await
+    } else {
+        Ok(PublicKey::parse(input)?)
+    }
+}
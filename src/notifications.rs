@@ -0,0 +1,436 @@
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+use lightning_invoice::Bolt11Invoice;
+use nostr::{Event, EventId, JsonUtil, Kind, PublicKey, Timestamp};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache_db::{LmdbCache, DB_NOTIFICATIONS},
+    nip10::ThreadRefs,
+    nip18,
+    nostr_client::event_to_timeline_post,
+    types::{ProfileMetadata, TimelinePost},
+};
+
+// 新しいフォロワーの通知を何件まで保存しておくか
+const MAX_RECENT_FOLLOWS: usize = 100;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NotificationKind {
+    Mention,
+    Reply,
+    Reaction,
+    Repost,
+    Zap,
+    Follow,
+}
+
+impl NotificationKind {
+    pub fn of(post: &TimelinePost) -> Option<Self> {
+        match post.kind {
+            Kind::TextNote if ThreadRefs::parse(&post.tags).parent().is_some() => Some(Self::Reply),
+            Kind::TextNote => Some(Self::Mention),
+            Kind::Reaction => Some(Self::Reaction),
+            kind if nip18::is_repost(kind) => Some(Self::Repost),
+            Kind::ZapReceipt => Some(Self::Zap),
+            Kind::ContactList => Some(Self::Follow),
+            _ => None,
+        }
+    }
+
+    pub fn icon(self) -> &'static str {
+        match self {
+            Self::Mention => "＠",
+            Self::Reply => "💬",
+            Self::Reaction => "❤",
+            Self::Repost => "🔁",
+            Self::Zap => "⚡",
+            Self::Follow => "👤",
+        }
+    }
+
+    // 返信とメンションは本文があるので、1 件ずつ表示する
+    fn is_grouped(self) -> bool {
+        !matches!(self, Self::Mention | Self::Reply)
+    }
+}
+
+// Zap レシートの description に入っている zap リクエスト (kind 9734)
+fn zap_request(event: &Event) -> Option<Event> {
+    if event.kind != Kind::ZapReceipt {
+        return None;
+    }
+    let description = event.tags.iter().find_map(|tag| match tag.as_slice() {
+        [name, value, ..] if name == "description" => Some(value),
+        _ => None,
+    })?;
+    let request = Event::from_json(description).ok()?;
+    request.verify().ok()?;
+    Some(request)
+}
+
+/// The account a notification comes from.
+///
+/// Zap receipts are signed by the recipient's LNURL server, so for them this is the
+/// author of the embedded zap request.
+pub fn actor(event: &Event) -> PublicKey {
+    zap_request(event).map_or(event.pubkey, |request| request.pubkey)
+}
+
+/// Whether `event` is worth notifying `me` about. `zapper` is the key of our LNURL
+/// server (see `load_zapper`); zap receipts signed by anyone else are fakes.
+pub fn is_relevant(event: &Event, me: PublicKey, zapper: Option<PublicKey>) -> bool {
+    if actor(event) == me {
+        return false;
+    }
+    match event.kind {
+        // NIP-57: レシートは受け取り側の LNURL サーバーの nostrPubkey が署名する
+        Kind::ZapReceipt => zapper == Some(event.pubkey),
+        // リアクションとリポストは、最後の p タグが対象の投稿の著者 (NIP-25 / NIP-18)。
+        // スレッドの参加者として p に入っているだけのものは除く。
+        Kind::Reaction | Kind::Repost | Kind::GenericRepost => {
+            event.tags.public_keys().last() == Some(&me)
+        }
+        Kind::ContactList => event.tags.public_keys().any(|pk| *pk == me),
+        _ => true,
+    }
+}
+
+/// Converts a notification event to a post attributed to its `actor`.
+pub fn to_post(event: &Event, actor_metadata: ProfileMetadata) -> TimelinePost {
+    let mut post = event_to_timeline_post(event, actor_metadata);
+    post.author_pubkey = actor(event);
+    match event.kind {
+        // 本文には zap リクエストのコメントを出す
+        Kind::ZapReceipt => post.content = zap_request(event).map(|r| r.content).unwrap_or_default(),
+        // コンタクトリストは大きいので、誰がいつフォローしたかだけ残す
+        Kind::ContactList => {
+            post.content.clear();
+            post.tags.clear();
+        }
+        _ => {}
+    }
+    post
+}
+
+/// The post a reaction, repost or zap refers to.
+pub fn target(post: &TimelinePost) -> Option<EventId> {
+    match post.kind {
+        // NIP-25 / NIP-57: 対象は最後の e タグ
+        Kind::Reaction | Kind::ZapReceipt => post.tags.iter().rev().find_map(|tag| match tag.as_slice() {
+            [name, id, ..] if name == "e" => EventId::from_hex(id).ok(),
+            _ => None,
+        }),
+        kind if nip18::is_repost(kind) => nip18::reposted_id(&post.tags),
+        _ => None,
+    }
+}
+
+fn reaction_label(post: &TimelinePost) -> String {
+    match post.content.trim() {
+        "" | "+" => "❤".to_string(),
+        "-" => "👎".to_string(),
+        content => content.to_string(),
+    }
+}
+
+pub fn zap_sats(post: &TimelinePost) -> u64 {
+    post.tags
+        .iter()
+        .find_map(|tag| match tag.as_slice() {
+            [name, invoice, ..] if name == "bolt11" => Bolt11Invoice::from_str(invoice).ok(),
+            _ => None,
+        })
+        .and_then(|invoice| invoice.amount_milli_satoshis())
+        .map_or(0, |msats| msats / 1000)
+}
+
+/// One row of the notification list.
+#[derive(Debug, Clone)]
+pub struct NotificationGroup {
+    pub kind: NotificationKind,
+    pub target: Option<EventId>,
+    /// Index into the notification list of one post per actor, newest first.
+    pub actors: Vec<usize>,
+    pub latest: Timestamp,
+    /// Distinct reactions, newest first.
+    pub reactions: Vec<String>,
+    pub sats: u64,
+}
+
+/// Groups notifications (newest first) into rows: reactions, reposts and zaps by the
+/// post they refer to, new followers by day. Replies and mentions get a row each.
+pub fn group(posts: &[TimelinePost]) -> Vec<NotificationGroup> {
+    let mut groups: Vec<NotificationGroup> = Vec::new();
+    let mut by_key: HashMap<(NotificationKind, Option<EventId>, u64), usize> = HashMap::new();
+    for (i, post) in posts.iter().enumerate() {
+        let Some(kind) = NotificationKind::of(post) else { continue };
+        let target = target(post);
+        let existing = if kind.is_grouped() {
+            let day = match kind {
+                NotificationKind::Follow => post.created_at.as_u64() / SECONDS_PER_DAY,
+                _ => 0,
+            };
+            let next = groups.len();
+            let index = *by_key.entry((kind, target, day)).or_insert(next);
+            (index < next).then_some(index)
+        } else {
+            None
+        };
+        let group = match existing {
+            Some(index) => &mut groups[index],
+            None => {
+                groups.push(NotificationGroup {
+                    kind,
+                    target,
+                    actors: Vec::new(),
+                    latest: post.created_at,
+                    reactions: Vec::new(),
+                    sats: 0,
+                });
+                groups.last_mut().unwrap()
+            }
+        };
+        if !group.actors.iter().any(|&a| posts[a].author_pubkey == post.author_pubkey) {
+            group.actors.push(i);
+        }
+        match kind {
+            NotificationKind::Reaction => {
+                let label = reaction_label(post);
+                if !group.reactions.contains(&label) {
+                    group.reactions.push(label);
+                }
+            }
+            NotificationKind::Zap => group.sats += zap_sats(post),
+            _ => {}
+        }
+    }
+    groups
+}
+
+// --- 既読位置 ---
+
+fn read_key(me: &PublicKey) -> String {
+    format!("{}:read_until", me.to_hex())
+}
+
+/// Everything up to this time has been read. `None` for an account seen for the first time.
+pub fn load_read_until(cache_db: &LmdbCache, me: &PublicKey) -> Option<Timestamp> {
+    cache_db
+        .read_state(DB_NOTIFICATIONS, &read_key(me))
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to read notification state: {e}");
+            None
+        })
+}
+
+pub fn save_read_until(cache_db: &LmdbCache, me: &PublicKey, read_until: Timestamp) {
+    if let Err(e) = cache_db.write_state(DB_NOTIFICATIONS, &read_key(me), &read_until) {
+        eprintln!("Failed to save notification state: {e}");
+    }
+}
+
+// --- Zap ---
+
+fn zapper_key(me: &PublicKey) -> String {
+    format!("{}:zapper", me.to_hex())
+}
+
+/// The `nostrPubkey` of our lightning address's LNURL server, saved by `save_zapper`.
+pub fn load_zapper(cache_db: &LmdbCache, me: &PublicKey) -> Option<PublicKey> {
+    cache_db
+        .read_state(DB_NOTIFICATIONS, &zapper_key(me))
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to read zapper: {e}");
+            None
+        })
+        .flatten()
+}
+
+pub fn save_zapper(cache_db: &LmdbCache, me: &PublicKey, zapper: Option<PublicKey>) {
+    if let Err(e) = cache_db.write_state(DB_NOTIFICATIONS, &zapper_key(me), &zapper) {
+        eprintln!("Failed to save zapper: {e}");
+    }
+}
+
+// --- 新しいフォロワー ---
+
+// 自分をフォローしているアカウントと、これまでに通知したフォロー
+#[derive(Serialize, Deserialize, Default)]
+struct FollowerState {
+    followers: HashSet<PublicKey>,
+    // 最後に調べた時刻。初回 (None) は今のフォロワーを覚えるだけで通知しない
+    #[serde(default)]
+    checked_at: Option<Timestamp>,
+    recent: Vec<TimelinePost>,
+}
+
+fn followers_key(me: &PublicKey) -> String {
+    format!("{}:followers", me.to_hex())
+}
+
+fn load_followers(cache_db: &LmdbCache, me: &PublicKey) -> FollowerState {
+    cache_db
+        .read_state(DB_NOTIFICATIONS, &followers_key(me))
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to read follower state: {e}");
+            None
+        })
+        .unwrap_or_default()
+}
+
+fn save_followers(cache_db: &LmdbCache, me: &PublicKey, state: &FollowerState) {
+    if let Err(e) = cache_db.write_state(DB_NOTIFICATIONS, &followers_key(me), state) {
+        eprintln!("Failed to save follower state: {e}");
+    }
+}
+
+/// Records the authors of `contact_lists` that include `me` and returns the lists of
+/// the ones not seen before that were published after the previous call. The first
+/// call only records the current followers.
+pub fn new_followers(
+    cache_db: &LmdbCache,
+    me: &PublicKey,
+    contact_lists: impl IntoIterator<Item = Event>,
+) -> Vec<Event> {
+    let mut state = load_followers(cache_db, me);
+    let checked_at = state.checked_at;
+    let mut new = Vec::new();
+    for event in contact_lists {
+        if !is_relevant(&event, *me, None) {
+            continue;
+        }
+        // 覚えているのは新しい FOLLOWER_LIMIT 件だけなので、知らない人でも
+        // 前回より古いリストは前からのフォロワーとみなす
+        if state.followers.insert(event.pubkey) && checked_at.is_some_and(|t| event.created_at > t) {
+            new.push(event);
+        }
+    }
+    state.checked_at = Some(Timestamp::now());
+    save_followers(cache_db, me, &state);
+    new
+}
+
+/// Adds `follows` to the saved follow notifications and returns all of them, newest first.
+pub fn remember_follows(cache_db: &LmdbCache, me: &PublicKey, follows: Vec<TimelinePost>) -> Vec<TimelinePost> {
+    let mut state = load_followers(cache_db, me);
+    if !follows.is_empty() {
+        state.recent.extend(follows);
+        state.recent.sort_by_key(|p| std::cmp::Reverse(p.created_at));
+        state.recent.truncate(MAX_RECENT_FOLLOWS);
+        save_followers(cache_db, me, &state);
+    }
+    state.recent
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr::{EventBuilder, Keys, Tag};
+
+    fn post(n: u8, kind: Kind, author: PublicKey, content: &str, tags: Vec<Tag>) -> TimelinePost {
+        TimelinePost {
+            id: EventId::from_byte_array([n; 32]),
+            kind,
+            author_pubkey: author,
+            author_metadata: ProfileMetadata::default(),
+            content: content.to_string(),
+            created_at: Timestamp::from(1_700_000_000 - n as u64),
+            emojis: HashMap::new(),
+            tags,
+        }
+    }
+
+    fn e_tag(target: &EventId) -> Tag {
+        Tag::parse(["e", &target.to_hex()]).unwrap()
+    }
+
+    #[test]
+    fn reactions_to_the_same_post_share_a_row() {
+        let alice = Keys::generate().public_key();
+        let bob = Keys::generate().public_key();
+        let mine = EventId::from_byte_array([100; 32]);
+        let other = EventId::from_byte_array([101; 32]);
+        // 新しい順
+        let posts = vec![
+            post(1, Kind::Reaction, alice, "+", vec![e_tag(&mine)]),
+            post(2, Kind::Reaction, bob, "⚡", vec![e_tag(&mine)]),
+            post(3, Kind::Reaction, alice, "-", vec![e_tag(&other)]),
+            post(4, Kind::Reaction, alice, "⚡", vec![e_tag(&mine)]),
+        ];
+
+        let groups = group(&posts);
+
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].target, Some(mine));
+        assert_eq!(groups[0].actors, vec![0, 1]);
+        assert_eq!(groups[0].reactions, vec!["❤".to_string(), "⚡".to_string()]);
+        assert_eq!(groups[0].latest, posts[0].created_at);
+        assert_eq!(groups[1].target, Some(other));
+    }
+
+    #[test]
+    fn replies_and_mentions_are_not_grouped() {
+        let alice = Keys::generate().public_key();
+        let root = EventId::from_byte_array([100; 32]);
+        let posts = vec![
+            post(1, Kind::TextNote, alice, "reply", vec![e_tag(&root)]),
+            post(2, Kind::TextNote, alice, "reply again", vec![e_tag(&root)]),
+            post(3, Kind::TextNote, alice, "hello", vec![]),
+        ];
+
+        let kinds: Vec<NotificationKind> = group(&posts).iter().map(|g| g.kind).collect();
+
+        assert_eq!(
+            kinds,
+            vec![NotificationKind::Reply, NotificationKind::Reply, NotificationKind::Mention]
+        );
+    }
+
+    #[test]
+    fn only_contact_lists_newer_than_the_last_check_are_new() {
+        let me = Keys::generate().public_key();
+        let contact_list = |created_at: Timestamp| {
+            EventBuilder::new(Kind::ContactList, "")
+                .tag(Tag::public_key(me))
+                .custom_created_at(created_at)
+                .sign_with_keys(&Keys::generate())
+                .unwrap()
+        };
+        let now = Timestamp::now();
+        let path = std::env::temp_dir().join(format!("rustr-followers-test-{}", me.to_hex()));
+        let cache_db = LmdbCache::new(&path).unwrap();
+
+        // 初回は覚えるだけ
+        assert!(new_followers(&cache_db, &me, [contact_list(now - 60)]).is_empty());
+        // 上限の外にいた古いフォロワーは通知しない
+        let old = contact_list(now - 3600);
+        let followed = contact_list(now + 60);
+        let new = new_followers(&cache_db, &me, [old, followed.clone()]);
+
+        assert_eq!(new.iter().map(|e| e.id).collect::<Vec<_>>(), vec![followed.id]);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn zap_receipts_must_be_signed_by_our_lnurl_server() {
+        let me = Keys::generate().public_key();
+        let server = Keys::generate();
+        let request = EventBuilder::new(Kind::ZapRequest, "")
+            .tag(Tag::public_key(me))
+            .sign_with_keys(&Keys::generate())
+            .unwrap();
+        let receipt = |signer: &Keys| {
+            EventBuilder::new(Kind::ZapReceipt, "")
+                .tags([Tag::public_key(me), Tag::parse(["description", &request.as_json()]).unwrap()])
+                .sign_with_keys(signer)
+                .unwrap()
+        };
+
+        assert!(is_relevant(&receipt(&server), me, Some(server.public_key())));
+        assert!(!is_relevant(&receipt(&Keys::generate()), me, Some(server.public_key())));
+        assert!(!is_relevant(&receipt(&server), me, None));
+    }
+}
@@ -1,5 +1,5 @@
 use eframe::egui;
-use nostr::{nips::nip47::NostrWalletConnectURI, PublicKey, Timestamp, Keys, EventId, Kind};
+use nostr::{nips::nip47::NostrWalletConnectURI, PublicKey, Timestamp, Keys, EventId, Kind, NostrSigner};
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use chrono::{DateTime, Utc};
@@ -7,6 +7,12 @@ use nostr_sdk::Client;
 use std::sync::{Arc, Mutex};
 
 use crate::cache_db::LmdbCache;
+use crate::event_store::EventDb;
+use crate::desktop_notify::{AlertKind, Notifier};
+use crate::nip05::{Nip05Status, Resolver};
+use crate::nip51::MuteList;
+use crate::upload::Uploaded;
+use crate::ui::{follow_panel::FollowState, image_cache::ImageCache, live::LiveState, messages_view::MessagesState, navigation::NavEntry, pagination::Pagination, relay_panel::RelayStatusInfo, thread_view::ThreadState, user_profile_view::UserProfile, zap::ZapTarget};
 
 // --- Pub-used structs and enums ---
 
@@ -18,23 +24,257 @@ pub struct RelayConfig {
     pub self_hosted: Vec<String>,
     #[serde(default)]
     pub search: Vec<String>,
+    // Relays for our NIP-17 kind 10050 list. Empty means our read relays.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dm: Vec<String>,
+    // Read/write overrides keyed by relay URL. Relays not listed use `default_access`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub access: HashMap<String, RelayAccess>,
 }
 
-#[derive(Serialize, Deserialize, Default, Debug)]
-pub struct Config {
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RelayAccess {
+    pub read: bool,
+    pub write: bool,
+}
+
+impl RelayConfig {
+    /// Relays used for newly registered accounts.
+    pub fn with_defaults() -> Self {
+        Self {
+            aggregator: vec!["wss://yabu.me".to_string()],
+            self_hosted: vec![],
+            search: vec!["wss://search.nos.today".to_string()],
+            dm: vec![],
+            access: HashMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.aggregator.is_empty() && self.self_hosted.is_empty() && self.search.is_empty()
+    }
+
+    /// Every configured relay once, in category order.
+    pub fn all_urls(&self) -> Vec<String> {
+        let mut urls: Vec<String> = Vec::new();
+        for url in self.aggregator.iter().chain(self.self_hosted.iter()).chain(self.search.iter()) {
+            if !urls.contains(url) {
+                urls.push(url.clone());
+            }
+        }
+        urls
+    }
+
+    // 検索リレーは NIP-50 の問い合わせ専用なので、既定では投稿を送らない
+    fn default_access(&self, url: &str) -> RelayAccess {
+        let general = self.aggregator.iter().chain(self.self_hosted.iter()).any(|u| u == url);
+        RelayAccess { read: true, write: general }
+    }
+
+    pub fn access(&self, url: &str) -> RelayAccess {
+        self.access.get(url).copied().unwrap_or_else(|| self.default_access(url))
+    }
+
+    pub fn set_access(&mut self, url: &str, access: RelayAccess) {
+        if access == self.default_access(url) {
+            self.access.remove(url);
+        } else {
+            self.access.insert(url.to_string(), access);
+        }
+    }
+
+    // どのカテゴリからも消えたリレーの設定を捨てる
+    pub fn prune_access(&mut self) {
+        let urls = self.all_urls();
+        self.access.retain(|url, _| urls.contains(url));
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct Account {
     #[serde(default)]
-    pub encrypted_secret_key: String,
+    pub name: String,
+    // Hex public key. Lets us list accounts and read their caches without
+    // decrypting the key. Filled in on first unlock for migrated configs.
     #[serde(default)]
+    pub pubkey: String,
+    /// NIP-49 `ncryptsec1...` string.
+    #[serde(default)]
+    pub encrypted_secret_key: String,
+    // Only present for keys written before NIP-49 support. Cleared once the
+    // key has been re-encrypted on the next successful login.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub salt: String,
     #[serde(default)]
     pub encrypted_nwc_uri: Option<String>,
-    // The `relays` field is now a struct, but we want to be able to deserialize
-    // old configs where it was a `Vec<String>`. We handle this in `main.rs:load_config`.
-    // For new configs, it will be a `RelayConfig`.
     #[serde(default)]
+    pub relays: RelayConfig,
+    // Set for accounts whose key lives in a NIP-46 remote signer. Such
+    // accounts have no encrypted_secret_key and need no passphrase.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote_signer: Option<RemoteSignerConfig>,
+    // Watch-only account added from an npub / NIP-05. Nothing can be signed.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub read_only: bool,
+    // フォローしていない人の画像・動画をクリックするまでぼかす
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub blur_unfollowed_media: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RemoteSignerConfig {
+    /// `bunker://` URI returned by the signer after the first connection.
+    pub bunker_uri: String,
+    // Hex secret of the per-app key the signer has authorized. It can only
+    // talk to the signer, not sign as the user, so it is stored unencrypted.
+    pub app_secret_key: String,
+}
+
+impl Account {
+    pub fn display_name(&self) -> String {
+        if !self.name.is_empty() {
+            self.name.clone()
+        } else if self.pubkey.len() >= 8 {
+            format!("{}...", &self.pubkey[0..8])
+        } else {
+            "アカウント".to_string()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Config {
+    // Bumped by `main.rs:load_config` once it has migrated an older config.
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default)]
+    pub accounts: Vec<Account>,
+    #[serde(default)]
+    pub active_account: usize,
+    // Single-account fields from older configs. `main.rs:load_config` moves
+    // them into `accounts`, so they are never written back.
+    #[serde(default, skip_serializing)]
+    pub encrypted_secret_key: String,
+    #[serde(default, skip_serializing)]
+    pub salt: String,
+    #[serde(default, skip_serializing)]
+    pub encrypted_nwc_uri: Option<String>,
+    // This used to be a `Vec<String>`, then a `RelayConfig`.
+    #[serde(default, skip_serializing)]
     pub relays: serde_json::Value,
     #[serde(default)]
     pub theme: Option<AppTheme>,
+    #[serde(default)]
+    pub desktop_notifications: DesktopNotificationConfig,
+    #[serde(default)]
+    pub media_server: MediaServerConfig,
+    #[serde(default)]
+    pub image_cache: ImageCacheConfig,
+}
+
+// ディスクの画像キャッシュの上限
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageCacheConfig {
+    pub budget_mb: u64,
+}
+
+impl Default for ImageCacheConfig {
+    fn default() -> Self {
+        Self { budget_mb: 256 }
+    }
+}
+
+impl ImageCacheConfig {
+    pub fn budget_bytes(&self) -> u64 {
+        self.budget_mb * 1024 * 1024
+    }
+}
+
+// 添付ファイルのアップロード先の種類
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MediaServerKind {
+    #[default]
+    Nip96,
+    Blossom,
+}
+
+impl MediaServerKind {
+    pub fn default_url(self) -> &'static str {
+        match self {
+            MediaServerKind::Nip96 => "https://nostr.build",
+            MediaServerKind::Blossom => "https://blossom.primal.net",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MediaServerConfig {
+    #[serde(default)]
+    pub kind: MediaServerKind,
+    #[serde(default = "default_media_server_url")]
+    pub url: String,
+}
+
+fn default_media_server_url() -> String {
+    MediaServerKind::Nip96.default_url().to_string()
+}
+
+impl Default for MediaServerConfig {
+    fn default() -> Self {
+        Self {
+            kind: MediaServerKind::Nip96,
+            url: default_media_server_url(),
+        }
+    }
+}
+
+// OS のデスクトップ通知。全体のスイッチと種類ごとのスイッチ。
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DesktopNotificationConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_true")]
+    pub mentions: bool,
+    #[serde(default = "default_true")]
+    pub messages: bool,
+    #[serde(default = "default_true")]
+    pub zaps: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Default for DesktopNotificationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            mentions: true,
+            messages: true,
+            zaps: true,
+        }
+    }
+}
+
+impl DesktopNotificationConfig {
+    pub fn allows(&self, kind: AlertKind) -> bool {
+        self.enabled
+            && match kind {
+                AlertKind::Mention => self.mentions,
+                AlertKind::Message => self.messages,
+                AlertKind::Zap => self.zaps,
+            }
+    }
+}
+
+impl Config {
+    pub fn active(&self) -> Option<&Account> {
+        self.accounts.get(self.active_account)
+    }
+
+    pub fn active_mut(&mut self) -> Option<&mut Account> {
+        self.accounts.get_mut(self.active_account)
+    }
 }
 
 #[derive(Serialize, Deserialize, Default, Debug)]
@@ -86,11 +326,14 @@ pub struct ProfileMetadata {
     pub extra: HashMap<String, serde_json::Value>,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum ImageKind {
     Avatar,
     Emoji,
     ProfilePicture,
+    Banner,
+    // 本文中の画像 (アニメーションも再生する)
+    Media,
 }
 
 #[derive(Clone)]
@@ -112,6 +355,8 @@ pub struct ZapReceipt {
     pub zapped_event_id: Option<EventId>,
 }
 
+/// A note as the views show it, built from a stored or fetched event and its author's
+/// metadata. The signed event itself lives in the event database.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TimelinePost {
     pub id: EventId,
@@ -131,11 +376,29 @@ pub enum AppTab {
     Home,
     Notifications,
     Search,
+    Messages,
     Wallet,
     Profile,
     Settings,
 }
 
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum TimelineFeed {
+    // Followed accounts (NIP-02 contact list) plus self
+    Following,
+    // Latest notes on the aggregator relays
+    Global,
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum RegisterMode {
+    ImportKey,
+    Generate,
+    Restore,
+    RemoteSigner,
+    ReadOnly,
+}
+
 #[derive(Debug, PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
 pub enum AppTheme {
     Light,
@@ -173,22 +436,71 @@ impl AppTheme {
 }
 
 pub struct NostrPostAppInternal {
+    // Accounts
+    pub accounts: Vec<Account>,
+    pub active_account: usize,
+    pub account_name_input: String,
+    pub show_add_account: bool,
+
+    // Register (NIP-06)
+    pub register_mode: RegisterMode,
+    pub mnemonic_word_count: usize,
+    pub generated_mnemonic: Option<String>,
+    pub mnemonic_quiz: Vec<(usize, String)>,
+    pub restore_mnemonic_input: String,
+    pub restore_bip39_passphrase_input: String,
+    pub restore_account_index: u32,
+
+    // Remote signer (NIP-46)
+    pub bunker_uri_input: String,
+    pub nostrconnect_uri: Option<String>,
+    pub nostrconnect_app_keys: Option<Keys>,
+    pub nip46_auth_url: Option<String>,
+
+    // Read-only (npub / NIP-05) login
+    pub read_only_input: String,
+
     pub nwc_uri_input: String,
     pub cache_db: LmdbCache,
+    pub event_db: EventDb,
     pub is_logged_in: bool,
     pub post_input: String,
     pub show_post_dialog: bool,
     pub show_emoji_picker: bool,
+    // Files uploaded from the composer, added as `imeta` tags while their URL is in the post
+    pub attachments: Vec<Uploaded>,
+    pub uploads_in_progress: usize,
+    pub upload_status: String,
+    pub media_server: MediaServerConfig,
+    pub image_cache_config: ImageCacheConfig,
     pub my_emojis: HashMap<String, String>,
     pub secret_key_input: String,
     pub passphrase_input: String,
     pub confirm_passphrase_input: String,
     pub nostr_client: Option<Client>,
-    pub my_keys: Option<Keys>,
+    // Local keys or a NIP-46 remote signer. All events are signed through this.
+    pub my_signer: Option<Arc<dyn NostrSigner>>,
+    pub my_pubkey: Option<PublicKey>,
     pub followed_pubkeys: HashSet<PublicKey>,
     pub followed_pubkeys_display: String,
+    pub timeline_feed: TimelineFeed,
     pub timeline_posts: Vec<TimelinePost>,
+    pub timeline_pagination: Pagination,
     pub notification_posts: Vec<TimelinePost>,
+    pub notification_pagination: Pagination,
+    // Notifications up to this time have been read (persisted per account)
+    pub notifications_read_until: Option<Timestamp>,
+    pub desktop_notifications: DesktopNotificationConfig,
+    pub notifier: Notifier,
+    // NIP-05 checks of displayed profiles: the identifier checked and its result
+    pub nip05_resolver: Resolver,
+    pub nip05_checks: HashMap<PublicKey, (String, Nip05Status)>,
+    // デスクトップ通知はウィンドウが非アクティブのときだけ出す
+    pub window_focused: bool,
+    // Live events received since the list was last shown, for the "N new posts" banner
+    pub new_timeline_posts: Vec<TimelinePost>,
+    pub new_notification_posts: Vec<TimelinePost>,
+    pub live: LiveState,
     pub should_repaint: bool,
     pub is_loading: bool,
     pub current_tab: AppTab,
@@ -197,7 +509,10 @@ pub struct NostrPostAppInternal {
     pub editable_profile: ProfileMetadata,
     pub profile_fetch_status: String,
     pub current_theme: AppTheme,
-    pub image_cache: HashMap<String, ImageState>,
+    pub image_cache: ImageCache,
+    // Blurred media the user clicked to show
+    pub revealed_media: HashSet<String>,
+    pub lightbox: Option<String>,
 
     // NWC
     pub nwc_passphrase_input: String,
@@ -210,7 +525,7 @@ pub struct NostrPostAppInternal {
     // ZAP
     pub show_zap_dialog: bool,
     pub zap_amount_input: String,
-    pub zap_target_post: Option<TimelinePost>,
+    pub zap_target: Option<ZapTarget>,
 
     // Reply
     pub show_reply_dialog: bool,
@@ -222,17 +537,45 @@ pub struct NostrPostAppInternal {
     pub aggregator_relay_input: String,
     pub self_hosted_relay_input: String,
     pub search_relay_input: String,
+    pub relay_list_status: String,
+    pub dm_relay_input: String,
+    pub dm_relays_status: String,
+    pub relay_panel_error: Option<String>,
+    pub follows: FollowState,
+    // Mute list (NIP-51 kind 10000)
+    pub mute_list: MuteList,
+    pub mute_input: String,
+    // 投稿メニューからミュートするとき、非公開 (暗号化) の項目にする
+    pub mute_privately: bool,
+    pub mute_status: String,
+    // Live state of the relays in the pool, keyed by normalized URL
+    pub relay_statuses: HashMap<String, RelayStatusInfo>,
+    pub relay_monitor: Option<tokio::task::JoinHandle<()>>,
 
     // Search
     pub search_input: String,
     pub search_results: Vec<TimelinePost>,
+    // Query of the current results, used when loading older pages
+    pub search_query: String,
+    pub search_pagination: Pagination,
+    // name@domain を公開鍵に解決できなかったときなど
+    pub search_error: Option<String>,
 
     // Quote
     pub quoted_posts_cache: HashMap<EventId, Arc<TimelinePost>>,
+    // Thread opened from a post, shown in place of the home timeline
+    pub thread: Option<ThreadState>,
+    // Another user's profile, shown in place of the current tab
+    pub user_profile: Option<UserProfile>,
+    // Pages to go back to (threads, profiles and tabs), oldest first
+    pub nav_history: Vec<NavEntry>,
+    // Direct messages (NIP-17, plus legacy NIP-04 read)
+    pub messages: MessagesState,
     pub profile_cache: HashMap<PublicKey, ProfileMetadata>,
     pub posts_to_fetch: Arc<Mutex<HashSet<EventId>>>,
 
     // Profile
     pub profile_posts: Vec<TimelinePost>,
+    pub profile_posts_pagination: Pagination,
     pub is_fetching_profile_posts: bool,
 }
@@ -1,12 +1,24 @@
+pub mod accounts;
+pub mod attachments;
 pub mod home_view;
 pub mod notifications_view;
+pub mod pagination;
 pub mod image_cache;
+pub mod live;
+pub mod media;
 pub mod events;
+pub mod follow_panel;
 pub mod login_view;
+pub mod messages_view;
+pub mod mute_panel;
+pub mod navigation;
 pub mod post;
 pub mod profile_view;
+pub mod relay_panel;
 pub mod search_view;
 pub mod settings_view;
+pub mod thread_view;
+pub mod user_profile_view;
 pub mod wallet_view;
 pub mod zap;
 
@@ -21,10 +33,13 @@ use crate::{
 impl eframe::App for NostrPostApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         let mut app_data = self.data.lock().unwrap();
+        app_data.window_focused = ctx.input(|i| i.focused);
+        app_data.image_cache.begin_frame();
 
         let home_tab_text = "ホーム";
         let notifications_tab_text = "通知";
         let search_tab_text = "検索";
+        let messages_tab_text = "メッセージ";
         let wallet_tab_text = "ウォレット";
         let profile_tab_text = "プロフィール";
         let settings_tab_text = "設定";
@@ -37,6 +52,9 @@ impl eframe::App for NostrPostApp {
             .inner_margin(Margin::same(15))
             .fill(ctx.style().visuals.panel_fill);
 
+        // 通知タブから離れたら、見た分を既読にする
+        let previous_tab = app_data.current_tab;
+
         egui::SidePanel::left("side_panel")
             .frame(panel_frame)
             .min_width(220.0)
@@ -63,36 +81,42 @@ impl eframe::App for NostrPostApp {
 
                 ui.add_space(15.0);
 
+                if app_data.is_logged_in && !app_data.accounts.is_empty() {
+                    accounts::draw_account_switcher(ui, &mut app_data, &runtime_handle);
+                    ui.add_space(15.0);
+                }
+
                 ui.with_layout(egui::Layout::top_down_justified(egui::Align::LEFT), |ui| {
                     ui.style_mut().spacing.item_spacing.y = 12.0; // ボタン間の垂直スペース
 
-                    ui.selectable_value(&mut app_data.current_tab, AppTab::Home, home_tab_text);
+                    // タブを押したらスレッドやプロフィールを閉じてタブの一覧に戻す。閉じたページは戻るで開き直せる。
+                    let tab_button = |ui: &mut egui::Ui, app_data: &mut NostrPostAppInternal, tab: AppTab, label: String| {
+                        let selected = app_data.current_tab == tab && app_data.user_profile.is_none();
+                        if ui.selectable_label(selected, label).clicked() {
+                            navigation::open_tab(app_data, tab);
+                        }
+                    };
+                    tab_button(ui, &mut app_data, AppTab::Home, home_tab_text.to_string());
                     if app_data.is_logged_in {
-                        ui.selectable_value(
-                            &mut app_data.current_tab,
-                            AppTab::Notifications,
-                            notifications_tab_text,
-                        );
-                        ui.selectable_value(
-                            &mut app_data.current_tab,
-                            AppTab::Search,
-                            search_tab_text,
-                        );
-                        ui.selectable_value(
-                            &mut app_data.current_tab,
-                            AppTab::Wallet,
-                            wallet_tab_text,
-                        );
-                        ui.selectable_value(
-                            &mut app_data.current_tab,
-                            AppTab::Profile,
-                            profile_tab_text,
-                        );
-                        ui.selectable_value(
-                            &mut app_data.current_tab,
-                            AppTab::Settings,
-                            settings_tab_text,
-                        );
+                        let unread = notifications_view::unread_count(&app_data);
+                        let notifications_label = if unread > 0 {
+                            format!("{notifications_tab_text} ({unread})")
+                        } else {
+                            notifications_tab_text.to_string()
+                        };
+                        tab_button(ui, &mut app_data, AppTab::Notifications, notifications_label);
+                        tab_button(ui, &mut app_data, AppTab::Search, search_tab_text.to_string());
+                        tab_button(ui, &mut app_data, AppTab::Messages, messages_tab_text.to_string());
+                        tab_button(ui, &mut app_data, AppTab::Wallet, wallet_tab_text.to_string());
+                        tab_button(ui, &mut app_data, AppTab::Profile, profile_tab_text.to_string());
+                        tab_button(ui, &mut app_data, AppTab::Settings, settings_tab_text.to_string());
+
+                        if ui
+                            .add_enabled(navigation::can_go_back(&app_data), egui::Button::new("← 戻る"))
+                            .clicked()
+                        {
+                            navigation::back(&mut app_data, &app_data_arc_clone, &runtime_handle);
+                        }
                     }
                 });
 
@@ -105,12 +129,21 @@ impl eframe::App for NostrPostApp {
                         .min_size(egui::vec2(ui.available_width(), 40.0))
                         .corner_radius(egui::CornerRadius::from(8.0));
 
-                    if ui.add(button).clicked() {
+                    let can_sign = app_data.my_signer.is_some();
+                    if ui
+                        .add_enabled(can_sign, button)
+                        .on_disabled_hover_text(crate::READ_ONLY_HINT)
+                        .clicked()
+                    {
                         app_data.show_post_dialog = true;
                     }
                 }
             });
 
+        if previous_tab == AppTab::Notifications && app_data.current_tab != AppTab::Notifications {
+            notifications_view::mark_all_read(&mut app_data);
+        }
+
         egui::CentralPanel::default()
             .frame(panel_frame)
             .show(ctx, |ui| {
@@ -120,6 +153,8 @@ impl eframe::App for NostrPostApp {
                     if app_data.current_tab == AppTab::Home {
                         login_view::draw_login_view(ui, &mut app_data, app_data_arc_clone, runtime_handle);
                     }
+                } else if app_data.user_profile.is_some() {
+                    user_profile_view::draw_user_profile_view(ui, ctx, &mut app_data, app_data_arc_clone, &runtime_handle);
                 } else {
                     match app_data.current_tab {
                         AppTab::Home => {
@@ -131,6 +166,9 @@ impl eframe::App for NostrPostApp {
                         AppTab::Search => {
                             search_view::draw_search_view(ui, ctx, &mut app_data, app_data_arc_clone, runtime_handle);
                         },
+                        AppTab::Messages => {
+                            messages_view::draw_messages_view(ui, &mut app_data, app_data_arc_clone, &runtime_handle);
+                        },
                         AppTab::Wallet => {
                             wallet_view::draw_wallet_view(ui, &mut app_data, app_data_arc_clone, runtime_handle);
                         },
@@ -138,13 +176,21 @@ impl eframe::App for NostrPostApp {
                             profile_view::draw_profile_view(ui, ctx, &mut app_data, app_data_arc_clone, runtime_handle);
                         },
                         AppTab::Settings => {
-                            settings_view::draw_settings_view(ui, ctx, &mut app_data, app_data_arc_clone, runtime_handle);
+                            // リレーパネルが入って縦に長くなったのでスクロールさせる
+                            egui::ScrollArea::vertical().show(ui, |ui| {
+                                settings_view::draw_settings_view(ui, ctx, &mut app_data, app_data_arc_clone, runtime_handle);
+                            });
                         }
                     }
                 }
             // }); // この閉じ括弧も削除
         });
 
+        if app_data.is_logged_in {
+            zap::draw_zap_dialog(ctx, &mut app_data, &self.data, &self.runtime.handle().clone());
+            media::draw_lightbox(ctx, &mut app_data);
+        }
+
         // update メソッドの最後に should_repaint をチェックし、再描画をリクエスト
         if app_data.should_repaint {
             ctx.request_repaint();
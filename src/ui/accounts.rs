@@ -0,0 +1,125 @@
+use eframe::egui;
+use tokio::runtime::Handle;
+
+use crate::types::{AppTab, NostrPostAppInternal, ProfileMetadata};
+use crate::ui::{attachments, navigation};
+
+// ログイン中のセッションを破棄する。ログアウトとアカウント切り替えで共通。
+pub fn end_session(app_data: &mut NostrPostAppInternal, runtime_handle: &Handle) {
+    let client_to_shutdown = app_data.nostr_client.take();
+    let nwc_client_to_shutdown = app_data.nwc_client.take();
+
+    app_data.is_logged_in = false;
+    app_data.my_signer = None;
+    app_data.my_pubkey = None;
+    app_data.nip46_auth_url = None;
+    app_data.followed_pubkeys.clear();
+    app_data.followed_pubkeys_display.clear();
+    app_data.timeline_posts.clear();
+    app_data.notification_posts.clear();
+    app_data.new_timeline_posts.clear();
+    app_data.new_notification_posts.clear();
+    app_data.live.stop();
+    app_data.timeline_pagination.reset();
+    app_data.notification_pagination.reset();
+    app_data.notifications_read_until = None;
+    app_data.profile_posts_pagination.reset();
+    app_data.search_pagination.reset();
+    app_data.search_query.clear();
+    app_data.search_error = None;
+    attachments::clear(app_data);
+    app_data.relay_list_status.clear();
+    app_data.dm_relay_input.clear();
+    app_data.dm_relays_status.clear();
+    app_data.follows = Default::default();
+    app_data.mute_list = Default::default();
+    app_data.mute_input.clear();
+    app_data.mute_status.clear();
+    app_data.relay_panel_error = None;
+    if let Some(monitor) = app_data.relay_monitor.take() {
+        monitor.abort();
+    }
+    app_data.relay_statuses.clear();
+    app_data.profile_posts.clear();
+    app_data.search_results.clear();
+    app_data.quoted_posts_cache.clear();
+    navigation::clear(app_data);
+    app_data.revealed_media.clear();
+    app_data.lightbox = None;
+    app_data.messages.stop();
+    app_data.my_emojis.clear();
+    app_data.post_input.clear();
+    app_data.passphrase_input.clear();
+    app_data.confirm_passphrase_input.clear();
+    app_data.secret_key_input.clear();
+    app_data.current_tab = AppTab::Home;
+    app_data.nip01_profile_display.clear();
+    app_data.editable_profile = ProfileMetadata::default();
+    app_data.profile_fetch_status = "ログインしてください".to_string();
+    app_data.nwc = None;
+    app_data.nwc_error = None;
+    app_data.zap_history.clear();
+    app_data.show_post_dialog = false;
+    app_data.show_reply_dialog = false;
+    app_data.reply_target_post = None;
+    app_data.show_zap_dialog = false;
+    app_data.zap_target = None;
+    app_data.should_repaint = true;
+
+    if client_to_shutdown.is_some() || nwc_client_to_shutdown.is_some() {
+        runtime_handle.spawn(async move {
+            if let Some(client) = client_to_shutdown {
+                client.shutdown().await;
+            }
+            if let Some(client) = nwc_client_to_shutdown {
+                client.shutdown().await;
+            }
+        });
+    }
+}
+
+// サイドパネルのアカウント切り替え。切り替え先はログイン画面でパスフレーズを入力する。
+pub fn draw_account_switcher(
+    ui: &mut egui::Ui,
+    app_data: &mut NostrPostAppInternal,
+    runtime_handle: &Handle,
+) {
+    let add_account_text = "＋ アカウントを追加";
+
+    let selected_text = app_data
+        .accounts
+        .get(app_data.active_account)
+        .map(|a| a.display_name())
+        .unwrap_or_default();
+
+    let mut switch_to = None;
+    let mut add_account = false;
+    egui::ComboBox::from_id_salt("account_switcher")
+        .selected_text(selected_text)
+        .width(ui.available_width())
+        .show_ui(ui, |ui| {
+            for (i, account) in app_data.accounts.iter().enumerate() {
+                if ui
+                    .selectable_label(i == app_data.active_account, account.display_name())
+                    .clicked()
+                    && i != app_data.active_account
+                {
+                    switch_to = Some(i);
+                }
+            }
+            ui.separator();
+            if ui.selectable_label(false, add_account_text).clicked() {
+                add_account = true;
+            }
+        });
+
+    if let Some(index) = switch_to {
+        println!("Switching to account {}", app_data.accounts[index].display_name());
+        end_session(app_data, runtime_handle);
+        app_data.active_account = index;
+        app_data.relays = app_data.accounts[index].relays.clone();
+    } else if add_account {
+        end_session(app_data, runtime_handle);
+        app_data.show_add_account = true;
+    }
+}
@@ -0,0 +1,151 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use eframe::egui;
+use nostr::Tag;
+use rfd::FileDialog;
+use tokio::runtime::Handle;
+
+use crate::{
+    types::NostrPostAppInternal,
+    upload::{self, MediaFile},
+};
+
+type LoadResult = Result<MediaFile, Box<dyn std::error::Error + Send + Sync>>;
+
+const MEDIA_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "webp", "avif", "mp4", "webm", "mov"];
+
+// クリップボードの画像を PNG にする
+fn clipboard_image() -> LoadResult {
+    let image = arboard::Clipboard::new()?.get_image()?;
+    let rgba = image::RgbaImage::from_raw(image.width as u32, image.height as u32, image.bytes.into_owned())
+        .ok_or("クリップボードの画像を読み取れませんでした")?;
+    let mut bytes = std::io::Cursor::new(Vec::new());
+    rgba.write_to(&mut bytes, image::ImageFormat::Png)?;
+    Ok(MediaFile {
+        name: "clipboard.png".to_string(),
+        bytes: bytes.into_inner(),
+    })
+}
+
+/// Reads a file on a blocking thread, uploads it and appends its URL to the post.
+fn start_upload(
+    app_data: &mut NostrPostAppInternal,
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+    load: impl FnOnce() -> LoadResult + Send + 'static,
+) {
+    let Some(signer) = app_data.my_signer.clone() else {
+        return;
+    };
+    let server = app_data.media_server.clone();
+    app_data.uploads_in_progress += 1;
+    app_data.upload_status = format!("アップロード中... ({}件)", app_data.uploads_in_progress);
+    let arc = app_data_arc.clone();
+    runtime_handle.spawn(async move {
+        let result = match tokio::task::spawn_blocking(load).await {
+            Ok(Ok(file)) => upload::upload(&server, &signer, file).await,
+            Ok(Err(e)) => Err(e),
+            Err(e) => Err(e.into()),
+        };
+        let mut app_data = arc.lock().unwrap();
+        app_data.uploads_in_progress -= 1;
+        app_data.should_repaint = true;
+        match result {
+            // 投稿をやめていたら URL は入れない
+            Ok(_) if !app_data.show_post_dialog => {}
+            Ok(uploaded) => {
+                let input = &mut app_data.post_input;
+                if !input.is_empty() && !input.ends_with(char::is_whitespace) {
+                    input.push('\n');
+                }
+                input.push_str(&uploaded.url);
+                app_data.attachments.push(uploaded);
+                app_data.upload_status.clear();
+            }
+            Err(e) => {
+                eprintln!("Failed to upload media: {e}");
+                app_data.upload_status = format!("アップロードに失敗しました: {e}");
+            }
+        }
+        if app_data.uploads_in_progress > 0 {
+            app_data.upload_status = format!("アップロード中... ({}件)", app_data.uploads_in_progress);
+        }
+    });
+}
+
+fn upload_paths(
+    app_data: &mut NostrPostAppInternal,
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+    paths: Vec<PathBuf>,
+) {
+    for path in paths {
+        start_upload(app_data, app_data_arc, runtime_handle, move || Ok(MediaFile::read(&path)?));
+    }
+}
+
+/// The attach (📎) and paste image (📋) buttons of the composer.
+pub fn draw_attach_buttons(
+    ui: &mut egui::Ui,
+    app_data: &mut NostrPostAppInternal,
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    if ui.button("📎").on_hover_text("ファイルを添付").clicked()
+        && let Some(paths) = FileDialog::new()
+            .add_filter("画像・動画", MEDIA_EXTENSIONS)
+            .add_filter("すべてのファイル", &["*"])
+            .pick_files()
+    {
+        upload_paths(app_data, app_data_arc, runtime_handle, paths);
+    }
+    if ui.button("📋").on_hover_text("クリップボードの画像を添付").clicked() {
+        start_upload(app_data, app_data_arc, runtime_handle, clipboard_image);
+    }
+    if app_data.uploads_in_progress > 0 {
+        ui.spinner();
+    }
+}
+
+/// Uploads files dropped onto the window while the composer is open.
+pub fn handle_dropped_files(
+    ctx: &egui::Context,
+    app_data: &mut NostrPostAppInternal,
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    let dropped = ctx.input(|i| i.raw.dropped_files.clone());
+    for file in dropped {
+        match (file.path, file.bytes) {
+            (Some(path), _) => upload_paths(app_data, app_data_arc, runtime_handle, vec![path]),
+            // Web などパスがなく中身だけ渡されるとき
+            (None, Some(bytes)) => {
+                let name = file.name;
+                start_upload(app_data, app_data_arc, runtime_handle, move || {
+                    Ok(MediaFile { name, bytes: bytes.to_vec() })
+                });
+            }
+            (None, None) => {}
+        }
+    }
+}
+
+pub fn is_hovering_files(ctx: &egui::Context) -> bool {
+    ctx.input(|i| !i.raw.hovered_files.is_empty())
+}
+
+/// `imeta` tags of the attachments whose URL is still in `content`.
+pub fn imeta_tags(app_data: &NostrPostAppInternal, content: &str) -> Vec<Tag> {
+    app_data
+        .attachments
+        .iter()
+        .filter(|attachment| content.contains(&attachment.url))
+        .map(|attachment| attachment.imeta.clone())
+        .collect()
+}
+
+pub fn clear(app_data: &mut NostrPostAppInternal) {
+    app_data.attachments.clear();
+    app_data.upload_status.clear();
+}
@@ -1,14 +1,20 @@
 use std::collections::HashSet;
 use std::time::Duration;
-use nostr::{Filter, Keys, Kind, PublicKey};
+use nostr::{Filter, Kind, PublicKey};
 use nostr_sdk::{Client, SubscribeAutoCloseOptions};
 
 use crate::{
-    types::{ProfileMetadata, RelayConfig, TimelinePost},
-    cache_db::{LmdbCache, DB_FOLLOWED, DB_PROFILES, DB_TIMELINE, DB_NOTIFICATIONS, DB_SELF_POSTS},
-    nostr_client::{fetch_nip01_profile, fetch_timeline_events, fetch_notification_events, fetch_posts_by_author}
+    types::{ProfileMetadata, RelayConfig, TimelineFeed, TimelinePost},
+    cache_db::{LmdbCache, DB_FOLLOWED, DB_PROFILES},
+    event_store,
+    nostr_client::{fetch_nip01_profile, fetch_timeline_events, fetch_notification_events, stored_notification_posts, stored_timeline_posts, TimelineRoute},
+    nip51::MuteList,
+    nip65,
+    notifications,
 };
 
+use crate::ui::zap;
+
 pub struct FreshData {
     pub followed_pubkeys: HashSet<PublicKey>,
     pub timeline_posts: Vec<TimelinePost>,
@@ -19,17 +25,19 @@ pub struct FreshData {
 
 pub async fn refresh_all_data(
     client: &Client,
-    keys: &Keys,
+    pubkey: PublicKey,
+    feed: TimelineFeed,
     cache_db: &LmdbCache,
     relay_config: &RelayConfig,
+    mutes: &MuteList,
 ) -> Result<FreshData, Box<dyn std::error::Error + Send + Sync>> {
-    let pubkey_hex = keys.public_key().to_string();
+    let pubkey_hex = pubkey.to_string();
 
     println!("Refreshing all data from network...");
 
     // Fetch NIP-02 contact list
     let nip02_filter = Filter::new()
-        .authors(vec![keys.public_key()])
+        .authors(vec![pubkey])
         .kind(Kind::ContactList)
         .limit(1);
     let nip02_filter_id = client
@@ -44,16 +52,16 @@ pub async fn refresh_all_data(
         _ = async {
             let mut notifications = client.notifications();
             while let Ok(notification) = notifications.recv().await {
-                if let nostr_sdk::RelayPoolNotification::Event { event, .. } = notification {
-                    if event.kind == Kind::ContactList && event.pubkey == keys.public_key() {
-                        for tag in event.tags.iter() {
-                            if let Some(nostr::TagStandard::PublicKey { public_key, .. }) = tag.as_standardized() {
-                                followed_pubkeys.insert(*public_key);
-                            }
+                if let nostr_sdk::RelayPoolNotification::Event { event, .. } = notification
+                    && event.kind == Kind::ContactList && event.pubkey == pubkey
+                {
+                    for tag in event.tags.iter() {
+                        if let Some(nostr::TagStandard::PublicKey { public_key, .. }) = tag.as_standardized() {
+                            followed_pubkeys.insert(*public_key);
                         }
-                        received_nip02 = true;
-                        break;
                     }
+                    received_nip02 = true;
+                    break;
                 }
             }
         } => {},
@@ -62,20 +70,37 @@ pub async fn refresh_all_data(
 
     if received_nip02 {
         cache_db.write_cache(DB_FOLLOWED, &pubkey_hex, &followed_pubkeys)?;
+    } else if let Ok(cache) = cache_db.read_cache::<HashSet<PublicKey>>(DB_FOLLOWED, &pubkey_hex) {
+        // リレーから取れなかったときは前回のフォローリストでタイムラインを組む
+        followed_pubkeys = cache.data;
     }
 
     // Fetch timeline, notifications, and profile in parallel
-    let (timeline_result, notification_result, profile_result) = tokio::join!(
-        fetch_timeline_events(client, relay_config.aggregator.clone()),
-        fetch_notification_events(client, keys.public_key()),
-        fetch_nip01_profile(client, keys.public_key(), cache_db)
+    let routes = timeline_routes(client, pubkey, &followed_pubkeys, feed, cache_db, relay_config).await;
+    // Zap レシートの署名者を確かめられるよう、通知の前にプロフィールの lud16 から
+    // LNURL サーバーの nostrPubkey を調べておく
+    let profile_and_notifications = async {
+        let profile = fetch_nip01_profile(client, pubkey, cache_db).await;
+        if let Ok((metadata, _)) = &profile {
+            match zap::lnurl_nostr_pubkey(&metadata.lud16).await {
+                Ok(zapper) => notifications::save_zapper(cache_db, &pubkey, zapper),
+                Err(e) => eprintln!("Failed to fetch LNURL pay parameters: {e}"),
+            }
+        }
+        (profile, fetch_notification_events(client, cache_db, pubkey, None, mutes).await)
+    };
+    let (timeline_result, (profile_result, notification_result)) = tokio::join!(
+        fetch_timeline_events(client, &routes, None, mutes),
+        profile_and_notifications
     );
 
-    let timeline_posts = timeline_result?;
-    cache_db.write_cache(DB_TIMELINE, &pubkey_hex, &timeline_posts)?;
+    // 取得したイベントはクライアントのデータベースに保存されているので、保存済みの分と合わせる
+    let authors = feed_authors(feed, pubkey, &followed_pubkeys);
+    let mut timeline_posts = stored_timeline_posts(client.database(), authors.as_ref(), mutes).await;
+    event_store::merge_fetched(&mut timeline_posts, timeline_result?.posts);
 
-    let notification_posts = notification_result?;
-    cache_db.write_cache(DB_NOTIFICATIONS, &pubkey_hex, &notification_posts)?;
+    let mut notification_posts = stored_notification_posts(client.database(), cache_db, pubkey, mutes).await;
+    event_store::merge_fetched(&mut notification_posts, notification_result?.posts);
 
     let (profile_metadata, profile_json_string) = profile_result?;
     cache_db.write_cache(DB_PROFILES, &pubkey_hex, &profile_metadata)?;
@@ -91,37 +116,68 @@ pub async fn refresh_all_data(
     })
 }
 
-pub async fn refresh_timeline(
+// フィードに流す著者 (自分を含む)。グローバルフィードは著者で絞らないので None
+pub fn feed_authors(feed: TimelineFeed, pubkey: PublicKey, followed_pubkeys: &HashSet<PublicKey>) -> Option<HashSet<PublicKey>> {
+    match feed {
+        TimelineFeed::Global => None,
+        TimelineFeed::Following => {
+            let mut authors = followed_pubkeys.clone();
+            authors.insert(pubkey);
+            Some(authors)
+        }
+    }
+}
+
+// ホームのフィードをどのリレーに問い合わせるか。
+// フォロー中は NIP-65 の書き込みリレー (outbox) から読み、リレーリストが無い人や
+// 選ばれなかったリレーにしか書かない人と自分はアグリゲーターから読む。
+// グローバルフィードは著者で絞らずアグリゲーターだけを見る。
+pub async fn timeline_routes(
     client: &Client,
-    keys: &Keys,
+    pubkey: PublicKey,
+    followed_pubkeys: &HashSet<PublicKey>,
+    feed: TimelineFeed,
     cache_db: &LmdbCache,
     relay_config: &RelayConfig,
-) -> Result<Vec<TimelinePost>, Box<dyn std::error::Error + Send + Sync>> {
-    let pubkey_hex = keys.public_key().to_string();
+) -> Vec<TimelineRoute> {
+    let aggregator = relay_config.aggregator.clone();
+    if feed == TimelineFeed::Global {
+        return vec![TimelineRoute { relays: aggregator, authors: None }];
+    }
 
-    println!("Refreshing timeline from network...");
+    let lists = nip65::fetch_relay_lists(client, followed_pubkeys, cache_db).await;
+    let (outbox, mut uncovered) = nip65::select_outbox_relays(followed_pubkeys, &lists);
+    uncovered.insert(pubkey);
 
-    let timeline_posts = fetch_timeline_events(client, relay_config.aggregator.clone()).await?;
-    cache_db.write_cache(DB_TIMELINE, &pubkey_hex, &timeline_posts)?;
+    let outbox_urls: Vec<String> = outbox.iter().map(|(url, _)| url.clone()).collect();
+    nip65::ensure_relays(client, &outbox_urls).await;
 
-    println!("Finished refreshing timeline.");
-
-    Ok(timeline_posts)
+    let mut routes: Vec<TimelineRoute> = outbox
+        .into_iter()
+        .map(|(url, authors)| TimelineRoute { relays: vec![url], authors: Some(authors) })
+        .collect();
+    routes.push(TimelineRoute { relays: aggregator, authors: Some(uncovered) });
+    routes
 }
 
-pub async fn fetch_and_cache_self_posts(
+pub async fn refresh_timeline(
     client: &Client,
-    keys: &Keys,
+    pubkey: PublicKey,
+    followed_pubkeys: &HashSet<PublicKey>,
+    feed: TimelineFeed,
     cache_db: &LmdbCache,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let pubkey_hex = keys.public_key().to_string();
-
-    println!("Fetching self posts from network...");
+    relay_config: &RelayConfig,
+    mutes: &MuteList,
+) -> Result<Vec<TimelinePost>, Box<dyn std::error::Error + Send + Sync>> {
+    println!("Refreshing {feed:?} timeline from network...");
 
-    let self_posts = fetch_posts_by_author(client, keys.public_key()).await?;
-    cache_db.write_cache(DB_SELF_POSTS, &pubkey_hex, &self_posts)?;
+    let routes = timeline_routes(client, pubkey, followed_pubkeys, feed, cache_db, relay_config).await;
+    let fetched = fetch_timeline_events(client, &routes, None, mutes).await?.posts;
+    let authors = feed_authors(feed, pubkey, followed_pubkeys);
+    let mut timeline_posts = stored_timeline_posts(client.database(), authors.as_ref(), mutes).await;
+    event_store::merge_fetched(&mut timeline_posts, fetched);
 
-    println!("Finished fetching self posts.");
+    println!("Finished refreshing timeline.");
 
-    Ok(())
+    Ok(timeline_posts)
 }
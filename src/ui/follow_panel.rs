@@ -0,0 +1,317 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use eframe::egui;
+use nostr::nips::nip19::{FromBech32, Nip19Profile, ToBech32};
+use nostr::{Event, EventId, PublicKey};
+use tokio::runtime::Handle;
+
+use crate::{
+    cache_db::DB_FOLLOWED,
+    nip02::{self, ContactChange},
+    types::NostrPostAppInternal,
+    ui::live,
+    READ_ONLY_HINT,
+};
+
+// 設定のフォロー管理の状態
+#[derive(Default)]
+pub struct FollowState {
+    pub search: String,
+    pub input: String,
+    pub selected: HashSet<PublicKey>,
+    // 一括解除の確認中
+    pub confirm_bulk: bool,
+    // ロールバックの確認中の履歴
+    pub confirm_restore: Option<EventId>,
+    pub status: String,
+    pub is_publishing: bool,
+    // 置き換えた過去のコンタクトリスト (新しい順)
+    pub history: Vec<Event>,
+}
+
+pub fn is_following(app_data: &NostrPostAppInternal, pubkey: &PublicKey) -> bool {
+    app_data.followed_pubkeys.contains(pubkey)
+}
+
+fn can_publish(app_data: &NostrPostAppInternal) -> bool {
+    app_data.my_signer.is_some() && !app_data.follows.is_publishing
+}
+
+// 公開が終わったコンタクトリストを反映して、タイムラインの購読を張り直す
+fn finish(
+    arc: &Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+    result: Result<(Event, Vec<Event>), Box<dyn std::error::Error + Send + Sync>>,
+    done: &str,
+) {
+    let follows_changed = {
+        let mut app_data = arc.lock().unwrap();
+        app_data.follows.is_publishing = false;
+        app_data.should_repaint = true;
+        match result {
+            Ok((event, history)) => {
+                let followed = nip02::followed_pubkeys(&event);
+                if let Err(e) = app_data.cache_db.write_cache(DB_FOLLOWED, &event.pubkey.to_hex(), &followed) {
+                    eprintln!("Failed to cache follows: {e}");
+                }
+                let changed = app_data.followed_pubkeys != followed;
+                app_data.followed_pubkeys = followed;
+                app_data.follows.history = history;
+                app_data.follows.status = done.to_string();
+                changed
+            }
+            Err(e) => {
+                eprintln!("Failed to publish contact list: {e}");
+                app_data.follows.status = format!("フォローリストを更新できませんでした: {e}");
+                false
+            }
+        }
+    };
+    if follows_changed {
+        live::resubscribe_timeline(arc.clone(), runtime_handle);
+    }
+}
+
+/// Publishes `change` on top of the freshest contact list on the relays.
+pub fn publish_change(
+    app_data: &mut NostrPostAppInternal,
+    change: ContactChange,
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    let (Some(client), Some(signer), Some(me)) =
+        (app_data.nostr_client.clone(), app_data.my_signer.clone(), app_data.my_pubkey)
+    else {
+        return;
+    };
+    if app_data.follows.is_publishing {
+        return;
+    }
+    app_data.follows.is_publishing = true;
+    app_data.follows.status = "フォローリストを公開中...".to_string();
+    let known = app_data.followed_pubkeys.len();
+    let cache_db = app_data.cache_db.clone();
+    let arc = app_data_arc.clone();
+    let handle = runtime_handle.clone();
+    runtime_handle.spawn(async move {
+        let done = match &change {
+            ContactChange::Follow(_) => "フォローしました",
+            ContactChange::Unfollow(_) => "フォローを解除しました",
+        };
+        let result = nip02::publish_change(&client, &cache_db, &signer, me, known, change).await;
+        finish(&arc, &handle, result, done);
+    });
+}
+
+fn restore(
+    app_data: &mut NostrPostAppInternal,
+    snapshot: Event,
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    let (Some(client), Some(signer), Some(me)) =
+        (app_data.nostr_client.clone(), app_data.my_signer.clone(), app_data.my_pubkey)
+    else {
+        return;
+    };
+    app_data.follows.is_publishing = true;
+    app_data.follows.status = "フォローリストを戻しています...".to_string();
+    let cache_db = app_data.cache_db.clone();
+    let arc = app_data_arc.clone();
+    let handle = runtime_handle.clone();
+    runtime_handle.spawn(async move {
+        let result = nip02::restore(&client, &cache_db, &signer, me, &snapshot).await;
+        finish(&arc, &handle, result, "フォローリストを戻しました");
+    });
+}
+
+fn short_npub(pubkey: &PublicKey) -> String {
+    let npub = pubkey.to_bech32().unwrap_or_default();
+    format!("{}...{}", &npub[0..8], &npub[npub.len() - 4..])
+}
+
+fn pubkey_name(app_data: &NostrPostAppInternal, pubkey: &PublicKey) -> String {
+    match app_data.profile_cache.get(pubkey) {
+        Some(metadata) if !metadata.name.is_empty() => metadata.name.clone(),
+        _ => short_npub(pubkey),
+    }
+}
+
+fn parse_pubkey(input: &str) -> Option<PublicKey> {
+    let input = input.trim();
+    let bech32 = input.strip_prefix("nostr:").unwrap_or(input);
+    PublicKey::from_bech32(bech32)
+        .ok()
+        .or_else(|| Nip19Profile::from_bech32(bech32).ok().map(|p| p.public_key))
+        .or_else(|| PublicKey::from_hex(input).ok())
+}
+
+/// A "フォロー" / "フォロー解除" button for `pubkey`. Hidden for our own pubkey.
+pub fn follow_button(
+    ui: &mut egui::Ui,
+    app_data: &mut NostrPostAppInternal,
+    pubkey: PublicKey,
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) -> bool {
+    if app_data.my_pubkey == Some(pubkey) {
+        return false;
+    }
+    let following = is_following(app_data, &pubkey);
+    let text = if following { "フォロー解除" } else { "フォロー" };
+    let clicked = ui
+        .add_enabled(can_publish(app_data), egui::Button::new(text))
+        .on_disabled_hover_text(READ_ONLY_HINT)
+        .clicked();
+    if clicked {
+        let change = if following {
+            ContactChange::Unfollow(HashSet::from([pubkey]))
+        } else {
+            ContactChange::Follow(vec![pubkey])
+        };
+        publish_change(app_data, change, app_data_arc, runtime_handle);
+    }
+    clicked
+}
+
+/// The follow list manager in the settings: search, bulk removal and rollback.
+pub fn draw_follow_panel(
+    ui: &mut egui::Ui,
+    app_data: &mut NostrPostAppInternal,
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    ui.heading(format!("フォロー ({}人)", app_data.followed_pubkeys.len()));
+    ui.add_space(10.0);
+    ui.label("公開する前にリレーから最新のフォローリストを取り直し、大幅に減るような変更は中止します。");
+    ui.add_space(10.0);
+
+    let enabled = can_publish(app_data);
+    ui.horizontal(|ui| {
+        ui.add(
+            egui::TextEdit::singleline(&mut app_data.follows.input)
+                .hint_text("npub")
+                .desired_width(260.0),
+        );
+        if ui
+            .add_enabled(enabled, egui::Button::new("フォローする"))
+            .on_disabled_hover_text(READ_ONLY_HINT)
+            .clicked()
+        {
+            match parse_pubkey(&app_data.follows.input) {
+                Some(pubkey) => {
+                    app_data.follows.input.clear();
+                    publish_change(app_data, ContactChange::Follow(vec![pubkey]), app_data_arc, runtime_handle);
+                }
+                None => app_data.follows.status = "公開鍵の形式が正しくありません".to_string(),
+            }
+        }
+    });
+
+    ui.add_space(10.0);
+    ui.horizontal(|ui| {
+        ui.label("検索:");
+        ui.add(egui::TextEdit::singleline(&mut app_data.follows.search).desired_width(200.0));
+    });
+    let query = app_data.follows.search.trim().to_lowercase();
+    let mut rows: Vec<(PublicKey, String)> = app_data
+        .followed_pubkeys
+        .iter()
+        .map(|pubkey| (*pubkey, pubkey_name(app_data, pubkey)))
+        .filter(|(pubkey, name)| {
+            query.is_empty()
+                || name.to_lowercase().contains(&query)
+                || pubkey.to_bech32().is_ok_and(|npub| npub.contains(&query))
+        })
+        .collect();
+    rows.sort_by_cached_key(|(_, name)| name.to_lowercase());
+
+    egui::ScrollArea::vertical()
+        .id_salt("follow_list_scroll_area")
+        .max_height(240.0)
+        .show(ui, |ui| {
+            for (pubkey, name) in &rows {
+                let mut checked = app_data.follows.selected.contains(pubkey);
+                if ui.checkbox(&mut checked, name).changed() {
+                    if checked {
+                        app_data.follows.selected.insert(*pubkey);
+                    } else {
+                        app_data.follows.selected.remove(pubkey);
+                    }
+                }
+            }
+        });
+
+    // フォローしていない人の選択は残さない
+    let followed = &app_data.followed_pubkeys;
+    app_data.follows.selected.retain(|pubkey| followed.contains(pubkey));
+    let selected = app_data.follows.selected.len();
+    ui.horizontal(|ui| {
+        if ui.button("表示中をすべて選択").clicked() {
+            app_data.follows.selected.extend(rows.iter().map(|(pubkey, _)| *pubkey));
+        }
+        if ui.button("選択を解除").clicked() {
+            app_data.follows.selected.clear();
+            app_data.follows.confirm_bulk = false;
+        }
+        if ui
+            .add_enabled(enabled && selected > 0, egui::Button::new(format!("選択した {selected}人のフォローを解除")))
+            .clicked()
+        {
+            app_data.follows.confirm_bulk = true;
+        }
+    });
+    if app_data.follows.confirm_bulk && selected > 0 {
+        ui.horizontal(|ui| {
+            ui.colored_label(ui.visuals().warn_fg_color, format!("{selected}人のフォローを解除しますか？"));
+            if ui.add_enabled(enabled, egui::Button::new("解除する")).clicked() {
+                let pubkeys = std::mem::take(&mut app_data.follows.selected);
+                app_data.follows.confirm_bulk = false;
+                publish_change(app_data, ContactChange::Unfollow(pubkeys), app_data_arc, runtime_handle);
+            }
+            if ui.button("キャンセル").clicked() {
+                app_data.follows.confirm_bulk = false;
+            }
+        });
+    }
+
+    if !app_data.follows.status.is_empty() {
+        ui.label(&app_data.follows.status);
+    }
+
+    ui.add_space(10.0);
+    egui::CollapsingHeader::new(format!("以前のフォローリスト ({}件)", app_data.follows.history.len()))
+        .id_salt("contact_list_history")
+        .show(ui, |ui| {
+            if app_data.follows.history.is_empty() {
+                ui.label("このアプリで置き換えたフォローリストはまだありません。");
+            }
+            let mut to_restore = None;
+            let history = app_data.follows.history.clone();
+            for snapshot in &history {
+                let datetime = chrono::DateTime::from_timestamp(snapshot.created_at.as_u64() as i64, 0).unwrap_or_default();
+                ui.horizontal(|ui| {
+                    ui.label(format!(
+                        "{}  {}人",
+                        datetime.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M"),
+                        nip02::followed_pubkeys(snapshot).len()
+                    ));
+                    if app_data.follows.confirm_restore == Some(snapshot.id) {
+                        if ui.add_enabled(enabled, egui::Button::new("この内容で公開する")).clicked() {
+                            to_restore = Some(snapshot.clone());
+                        }
+                        if ui.button("キャンセル").clicked() {
+                            app_data.follows.confirm_restore = None;
+                        }
+                    } else if ui.add_enabled(enabled, egui::Button::new("この時点に戻す")).clicked() {
+                        app_data.follows.confirm_restore = Some(snapshot.id);
+                    }
+                });
+            }
+            if let Some(snapshot) = to_restore {
+                app_data.follows.confirm_restore = None;
+                restore(app_data, snapshot, app_data_arc, runtime_handle);
+            }
+        });
+}
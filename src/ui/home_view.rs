@@ -1,13 +1,15 @@
 use eframe::egui;
-use nostr::{nips::nip19::ToBech32, EventBuilder, Kind, Tag, Filter, TagStandard};
+use nostr::{nips::nip19::ToBech32, EventBuilder, Kind, Tag, Filter, TagStandard, PublicKey, RelayUrl};
 use regex::Regex;
 use std::sync::{Arc, Mutex};
 use std::collections::{HashSet, HashMap};
 
 
 use crate::{
+    event_store, nip10, nip18, nip65,
+    nostr_client::{fetch_timeline_events, stored_timeline_posts},
     types::*,
-    ui::{image_cache, post, zap, events},
+    ui::{attachments, image_cache, post, events, live, pagination, thread_view},
     MAX_POST_LENGTH,
 };
 
@@ -18,98 +20,7 @@ pub fn draw_home_view(
     app_data_arc: Arc<Mutex<NostrPostAppInternal>>,
     runtime_handle: tokio::runtime::Handle,
 ) {
-    // --- Quote Fetching Logic ---
-    let mut items_to_fetch = HashSet::new();
-    if let Ok(mut posts_to_fetch) = app_data.posts_to_fetch.lock() {
-        if !posts_to_fetch.is_empty() {
-            items_to_fetch = posts_to_fetch.clone();
-            posts_to_fetch.clear();
-        }
-    }
-
-    if !items_to_fetch.is_empty() {
-        if let Some(client) = app_data.nostr_client.as_ref() {
-            let client = client.clone();
-            let app_data_clone = app_data_arc.clone();
-            let profile_cache_clone = app_data.profile_cache.clone();
-
-            runtime_handle.spawn(async move {
-                // 1. Fetch event content for all items that need fetching.
-                let event_ids_to_fetch: HashSet<nostr::EventId> = items_to_fetch.iter().map(|item| *item).collect();
-                let events = if !event_ids_to_fetch.is_empty() {
-                    let events_filter = Filter::new().ids(event_ids_to_fetch);
-                    client.fetch_events(events_filter, std::time::Duration::from_secs(10)).await.unwrap_or_default().into_iter().collect()
-                } else {
-                    Vec::<nostr::Event>::new()
-                };
-
-                if events.is_empty() {
-                    return;
-                }
-
-                // 2. Determine which author profiles we need to fetch.
-                let mut profiles_to_fetch = HashSet::new();
-                for event in &events {
-                    if !profile_cache_clone.contains_key(&event.pubkey) {
-                        profiles_to_fetch.insert(event.pubkey);
-                    }
-                }
-
-                // 3. Fetch the missing profiles.
-                let mut new_profiles = HashMap::new();
-                if !profiles_to_fetch.is_empty() {
-                    let metadata_filter = Filter::new().authors(profiles_to_fetch).kind(Kind::Metadata);
-                    if let Ok(metadata_events) = client.fetch_events(metadata_filter, std::time::Duration::from_secs(5)).await {
-                        for event in metadata_events {
-                            if let Ok(metadata) = serde_json::from_str(&event.content) {
-                                new_profiles.insert(event.pubkey, metadata);
-                            }
-                        }
-                    }
-                }
-
-                // 4. Combine existing cache, new profiles, and fetched events to create the final posts.
-                let mut profiles = profile_cache_clone;
-                profiles.extend(new_profiles.clone());
-
-                let fetched_posts: HashMap<nostr::EventId, Arc<TimelinePost>> = events.into_iter()
-                    .filter(|e| e.kind == Kind::TextNote)
-                    .map(|event| {
-                        let author_metadata = profiles.get(&event.pubkey).cloned().unwrap_or_default();
-                        let emojis = event.tags.iter().filter_map(|tag| {
-                            if let Some(TagStandard::Emoji { shortcode, url }) = tag.as_standardized() {
-                                Some((shortcode.to_string(), url.to_string()))
-                            } else {
-                                None
-                            }
-                        }).collect();
-
-                        let timeline_post = Arc::new(TimelinePost {
-                            id: event.id,
-                            kind: event.kind,
-                            author_pubkey: event.pubkey,
-                            author_metadata,
-                            content: event.content.clone(),
-                            created_at: event.created_at,
-                            emojis,
-                            tags: event.tags.to_vec(),
-                        });
-                        (event.id, timeline_post)
-                    })
-                    .collect();
-
-                // 5. Update the application state with the newly fetched data.
-                if !fetched_posts.is_empty() {
-                    let mut data = app_data_clone.lock().unwrap();
-                    data.quoted_posts_cache.extend(fetched_posts);
-                    if !new_profiles.is_empty() {
-                        data.profile_cache.extend(new_profiles);
-                    }
-                    data.should_repaint = true;
-                }
-            });
-        }
-    }
+    fetch_queued_posts(app_data, &app_data_arc, &runtime_handle);
 
 
     let mut urls_to_load: Vec<(String, ImageKind)> = Vec::new();
@@ -119,205 +30,137 @@ pub fn draw_home_view(
     let cancel_button_text = "キャンセル";
     let timeline_heading_text = "ホーム";
     let fetch_latest_button_text = "最新の投稿を取得";
+    let following_feed_text = "フォロー中";
+    let global_feed_text = "グローバル";
     let no_timeline_message_text = "タイムラインに投稿はまだありません。";
+    let new_posts_banner_text = "件の新しい投稿";
 
 
-    // --- ZAP Dialog ---
-    if app_data.show_zap_dialog {
-        if let Some(post_to_zap) = app_data.zap_target_post.clone() {
-            let mut close_dialog = false;
-            egui::Window::new("ZAPを送る")
-                .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0.0, 0.0))
-                .collapsible(false)
-                .resizable(false)
-                .show(ctx, |ui| {
-                    ui.vertical_centered_justified(|ui| {
-                        ui.add_space(10.0);
-                        let display_name = if !post_to_zap.author_metadata.name.is_empty() {
-                            post_to_zap.author_metadata.name.clone()
-                        } else {
-                            let pubkey = post_to_zap.author_pubkey.to_bech32().unwrap_or_default();
-                            format!("{}...{}", &pubkey[0..8], &pubkey[pubkey.len()-4..])
-                        };
-                        ui.label(format!("{} にZAPします", display_name));
-                        ui.add_space(10.0);
-                        ui.horizontal(|ui| {
-                            ui.label("金額 (sats):");
-                            ui.add(egui::TextEdit::singleline(&mut app_data.zap_amount_input)
-                                .desired_width(120.0));
-                        });
-                        ui.add_space(10.0);
-                    });
-
-                    ui.separator();
-                    ui.add_space(5.0);
-
-                    ui.horizontal(|ui| {
-                        if ui.button("キャンセル").clicked() {
-                           close_dialog = true;
-                        }
-                        ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
-                            if ui.button("ZAP").clicked() {
-                                if let (Some(nwc), Some(nwc_client), Some(my_keys)) =
-                                    (app_data.nwc.as_ref(), app_data.nwc_client.as_ref(), app_data.my_keys.as_ref())
-                                {
-                                    if let Ok(amount_sats) = app_data.zap_amount_input.parse::<u64>() {
-                                        let nwc_clone = nwc.clone();
-                                        let nwc_client_clone = nwc_client.clone();
-                                        let my_keys_clone = my_keys.clone();
-                                        let app_data_clone = app_data_arc.clone();
-
-                                        runtime_handle.spawn(async move {
-                                            {
-                                                let mut data = app_data_clone.lock().unwrap();
-                                                data.should_repaint = true;
-                                            } // Lock is dropped here
-
-                                            let result = zap::send_zap_request(
-                                                &nwc_clone,
-                                                &nwc_client_clone,
-                                                &my_keys_clone,
-                                                post_to_zap.author_pubkey,
-                                                &post_to_zap.author_metadata.lud16,
-                                                amount_sats,
-                                                Some(post_to_zap.id),
-                                                Some(post_to_zap.kind),
-                                            ).await;
-
-                                            let mut data = app_data_clone.lock().unwrap();
-                                            match result {
-                                                Ok(_) => {
-                                                    // ZAPリクエストを送信しました。ウォレットの確認を待っています...
-                                                }
-                                                Err(e) => {
-                                                    eprintln!("ZAPエラー: {}", e);
-                                                }
-                                            }
-                                            data.should_repaint = true;
-                                        });
-
-                                        close_dialog = true;
+    // --- Reply Dialog ---
+    if app_data.show_reply_dialog
+        && let Some(post_to_reply) = app_data.reply_target_post.clone()
+    {
+        let mut close_dialog = false;
+        let author_name = if !post_to_reply.author_metadata.name.is_empty() {
+            post_to_reply.author_metadata.name.clone()
+        } else {
+            let pubkey = post_to_reply.author_pubkey.to_bech32().unwrap_or_default();
+            format!("{}...{}", &pubkey[0..8], &pubkey[pubkey.len() - 4..])
+        };
 
-                                    } else {
-                                        eprintln!("無効な金額です");
-                                    }
-                                } else {
-                                    eprintln!("ZAPにはNWCの接続が必要です");
-                                }
-                            }
-                        });
-                    });
+        egui::Window::new(format!("Replying to {}", author_name))
+            .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0.0, 0.0))
+            .collapsible(false)
+            .resizable(true)
+            .show(ctx, |ui| {
+                ui.add_space(10.0);
+                ui.label("Original post:");
+                let original_post_frame = egui::Frame {
+                    inner_margin: egui::Margin::same(10),
+                    corner_radius: 8.0.into(),
+                    shadow: eframe::epaint::Shadow::NONE,
+                    fill: ui.style().visuals.widgets.inactive.bg_fill,
+                    ..Default::default()
+                };
+                original_post_frame.show(ui, |ui| {
+                    ui.label(
+                        egui::RichText::new(post_to_reply.content.clone())
+                            .color(egui::Color32::GRAY)
+                            .italics(),
+                    );
                 });
-            if close_dialog {
-                app_data.show_zap_dialog = false;
-                app_data.zap_target_post = None;
-            }
-        }
-    }
 
+                ui.add_space(10.0);
+                ui.separator();
+                ui.add_space(10.0);
 
-    // --- Reply Dialog ---
-    if app_data.show_reply_dialog {
-        if let Some(post_to_reply) = app_data.reply_target_post.clone() {
-            let mut close_dialog = false;
-            let author_name = if !post_to_reply.author_metadata.name.is_empty() {
-                post_to_reply.author_metadata.name.clone()
-            } else {
-                let pubkey = post_to_reply.author_pubkey.to_bech32().unwrap_or_default();
-                format!("{}...{}", &pubkey[0..8], &pubkey[pubkey.len() - 4..])
-            };
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    ui.add(
+                        egui::TextEdit::multiline(&mut app_data.reply_input)
+                            .desired_rows(3)
+                            .desired_width(f32::INFINITY)
+                            .hint_text("Write your reply..."),
+                    );
+                });
 
-            egui::Window::new(format!("Replying to {}", author_name))
-                .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0.0, 0.0))
-                .collapsible(false)
-                .resizable(true)
-                .show(ctx, |ui| {
-                    ui.add_space(10.0);
-                    ui.label("Original post:");
-                    let original_post_frame = egui::Frame {
-                        inner_margin: egui::Margin::same(10),
-                        corner_radius: 8.0.into(),
-                        shadow: eframe::epaint::Shadow::NONE,
-                        fill: ui.style().visuals.widgets.inactive.bg_fill,
-                        ..Default::default()
-                    };
-                    original_post_frame.show(ui, |ui| {
-                        ui.label(
-                            egui::RichText::new(post_to_reply.content.clone())
-                                .color(egui::Color32::GRAY)
-                                .italics(),
-                        );
-                    });
 
-                    ui.add_space(10.0);
-                    ui.separator();
-                    ui.add_space(10.0);
+                ui.add_space(10.0);
 
-                    egui::ScrollArea::vertical().show(ui, |ui| {
-                        ui.add(
-                            egui::TextEdit::multiline(&mut app_data.reply_input)
-                                .desired_rows(3)
-                                .desired_width(f32::INFINITY)
-                                .hint_text("Write your reply..."),
-                        );
-                    });
+                ui.horizontal(|ui| {
+                    if ui.button("Cancel").clicked() {
+                        close_dialog = true;
+                    }
 
+                    ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                        if ui.button("Publish Reply").clicked()
+                            && let (Some(client), Some(signer)) = (
+                                app_data.nostr_client.as_ref(),
+                                app_data.my_signer.as_ref(),
+                            )
+                        {
+                            let client = client.clone();
+                            let signer = signer.clone();
+                            let reply_content = app_data.reply_input.clone();
+                            let cache_db = app_data.cache_db.clone();
+                            let my_pubkey = app_data.my_pubkey;
+                            let cloned_app_data_arc = app_data_arc.clone();
+
+                            runtime_handle.spawn(async move {
+                                let Some(my_pubkey) = my_pubkey else { return };
+                                // スレッドの参加者が使っている書き込みリレーをヒントとして付ける
+                                let mut participants: HashSet<PublicKey> = post_to_reply
+                                    .tags
+                                    .iter()
+                                    .filter_map(|tag| match tag.as_standardized() {
+                                        Some(TagStandard::PublicKey { public_key, .. }) => Some(*public_key),
+                                        Some(TagStandard::Event { public_key: Some(public_key), .. }) => Some(*public_key),
+                                        _ => None,
+                                    })
+                                    .collect();
+                                participants.insert(post_to_reply.author_pubkey);
+                                let relay_lists = nip65::fetch_relay_lists(&client, &participants, &cache_db).await;
+                                let tags = nip10::reply_tags(
+                                    post_to_reply.id,
+                                    post_to_reply.author_pubkey,
+                                    &post_to_reply.tags,
+                                    my_pubkey,
+                                    |pubkey| {
+                                        relay_lists
+                                            .get(pubkey)
+                                            .and_then(|list| list.write.first())
+                                            .and_then(|url| RelayUrl::parse(url).ok())
+                                    },
+                                );
+                                let event_result =
+                                    EventBuilder::new(Kind::TextNote, reply_content)
+                                        .tags(tags)
+                                        .sign(&signer)
+                                        .await;
+
+                                match event_result {
+                                    Ok(event) => match nip65::publish(&client, &cache_db, &event).await {
+                                        Ok(event_id) => {
+                                            println!("Reply published with event id: {:?}", event_id);
+                                        }
+                                        Err(e) => eprintln!("Failed to publish reply: {}", e),
+                                    },
+                                    Err(e) => eprintln!("Failed to create reply event: {}", e),
+                                }
 
-                    ui.add_space(10.0);
+                                let mut data = cloned_app_data_arc.lock().unwrap();
+                                data.should_repaint = true;
+                            });
 
-                    ui.horizontal(|ui| {
-                        if ui.button("Cancel").clicked() {
                             close_dialog = true;
                         }
-
-                        ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
-                            if ui.button("Publish Reply").clicked() {
-                                if let (Some(client), Some(keys)) = (
-                                    app_data.nostr_client.as_ref(),
-                                    app_data.my_keys.as_ref(),
-                                ) {
-                                    let client = client.clone();
-                                    let keys = keys.clone();
-                                    let reply_content = app_data.reply_input.clone();
-                                    let cloned_app_data_arc = app_data_arc.clone();
-
-                                    runtime_handle.spawn(async move {
-                                        let tags = vec![
-                                            Tag::event(post_to_reply.id),
-                                            Tag::public_key(post_to_reply.author_pubkey),
-                                        ];
-                                        let event_result =
-                                            EventBuilder::new(Kind::TextNote, reply_content)
-                                                .tags(tags)
-                                                .sign(&keys)
-                                                .await;
-
-                                        match event_result {
-                                            Ok(event) => match client.send_event(&event).await {
-                                                Ok(event_id) => {
-                                                    println!("Reply published with event id: {:?}", event_id);
-                                                }
-                                                Err(e) => eprintln!("Failed to publish reply: {}", e),
-                                            },
-                                            Err(e) => eprintln!("Failed to create reply event: {}", e),
-                                        }
-
-                                        let mut data = cloned_app_data_arc.lock().unwrap();
-                                        data.should_repaint = true;
-                                    });
-
-                                    close_dialog = true;
-                                }
-                            }
-                        });
                     });
                 });
+            });
 
-            if close_dialog {
-                app_data.show_reply_dialog = false;
-                app_data.reply_target_post = None;
-                app_data.reply_input.clear();
-            }
+        if close_dialog {
+            app_data.show_reply_dialog = false;
+            app_data.reply_target_post = None;
+            app_data.reply_input.clear();
         }
     }
 
@@ -326,6 +169,7 @@ pub fn draw_home_view(
         let painter = ctx.layer_painter(egui::LayerId::new(egui::Order::Background, "dim_layer".into()));
         let screen_rect = ctx.screen_rect();
         painter.add(egui::Shape::rect_filled(screen_rect, 0.0, egui::Color32::from_black_alpha(128)));
+        attachments::handle_dropped_files(ctx, app_data, &app_data_arc, &runtime_handle);
 
         egui::Window::new(new_post_window_title_text)
             .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0.0, 0.0))
@@ -339,6 +183,7 @@ pub fn draw_home_view(
                             if ui.button("😀").clicked() {
                                 app_data.show_emoji_picker = !app_data.show_emoji_picker;
                             }
+                            attachments::draw_attach_buttons(ui, app_data, &app_data_arc, &runtime_handle);
 
                             let re_nostr = Regex::new(r"nostr:(?:note|nevent)1[a-z0-9]+\s*").unwrap();
                             let user_text = re_nostr.replace_all(&app_data.post_input, "");
@@ -356,11 +201,14 @@ pub fn draw_home_view(
                                 if ui.button(cancel_button_text).clicked() {
                                     app_data.show_post_dialog = false;
                                     app_data.post_input.clear();
+                                    attachments::clear(app_data);
                                 }
-                                if ui.button(publish_button_text).clicked() && !app_data.is_loading {
+                                // アップロード中は URL がまだ入っていないので公開できない
+                                let can_publish = app_data.uploads_in_progress == 0;
+                                if ui.add_enabled(can_publish, egui::Button::new(publish_button_text)).clicked() && !app_data.is_loading {
                                     let post_content = app_data.post_input.clone();
                                     let client_clone = app_data.nostr_client.as_ref().unwrap().clone();
-                                    let keys_clone = app_data.my_keys.clone().unwrap();
+                                    let signer_clone = app_data.my_signer.clone().unwrap();
 
                                     app_data.is_loading = true;
                                     app_data.should_repaint = true;
@@ -378,6 +226,8 @@ pub fn draw_home_view(
                                     }
 
                                     let my_emojis = app_data.my_emojis.clone();
+                                    let imeta_tags = attachments::imeta_tags(app_data, &post_content);
+                                    let cache_db = app_data.cache_db.clone();
                                     let cloned_app_data_arc = app_data_arc.clone();
                                     runtime_handle.spawn(async move {
                                         let mut tags: Vec<Tag> = Vec::new();
@@ -391,25 +241,32 @@ pub fn draw_home_view(
                                             }
                                         }
                                         for shortcode in used_emojis {
-                                            if let Some(url) = my_emojis.get(&shortcode) {
-                                                if let Ok(tag) = Tag::parse(["emoji", &shortcode, url]) {
-                                                    tags.push(tag);
-                                                }
+                                            if let Some(url) = my_emojis.get(&shortcode)
+                                                && let Ok(tag) = Tag::parse(["emoji", &shortcode, url])
+                                            {
+                                                tags.push(tag);
                                             }
                                         }
 
+                                        // --- Quote Tags (NIP-18) ---
+                                        tags.extend(nip18::quote_tags(&post_content));
+
+                                        // --- Media Tags (NIP-92) ---
+                                        tags.extend(imeta_tags);
+
                                         let event_result = EventBuilder::new(Kind::TextNote, post_content.clone())
                                             .tags(tags)
-                                            .sign(&keys_clone)
+                                            .sign(&signer_clone)
                                             .await;
 
                                         match event_result {
-                                            Ok(event) => match client_clone.send_event(&event).await {
+                                            Ok(event) => match nip65::publish(&client_clone, &cache_db, &event).await {
                                                 Ok(event_id) => {
                                                     println!("Post published with event id: {event_id:?}");
                                                     let mut data = cloned_app_data_arc.lock().unwrap();
                                                     data.post_input.clear();
                                                     data.show_post_dialog = false;
+                                                    attachments::clear(&mut data);
                                                 }
                                                 Err(e) => {
                                                     eprintln!("Failed to publish post: {e}");
@@ -438,6 +295,11 @@ pub fn draw_home_view(
                                 .hint_text(post_input_hint_text),
                         );
                     });
+                    if attachments::is_hovering_files(ctx) {
+                        ui.label("ドロップして添付");
+                    } else if !app_data.upload_status.is_empty() {
+                        ui.label(&app_data.upload_status);
+                    }
                 });
             });
 
@@ -464,7 +326,7 @@ pub fn draw_home_view(
                                         ui.painter().rect_filled(rect.expand(2.0), egui::CornerRadius::from(4.0), ui.visuals().widgets.hovered.bg_fill);
                                     }
 
-                                    match app_data.image_cache.get(&url_key) {
+                                    match app_data.image_cache.get(&url_key, ImageKind::Emoji) {
                                         Some(ImageState::Loaded(texture_handle)) => {
                                             let image = egui::Image::new(texture_handle).fit_to_exact_size(emoji_size);
                                             image.paint_at(ui, rect);
@@ -487,7 +349,7 @@ pub fn draw_home_view(
                                         app_data.post_input.push_str(&format!(":{}:", shortcode));
                                         app_data.show_emoji_picker = false;
                                     }
-                                    response.on_hover_text(&format!(":{}:", shortcode));
+                                    response.on_hover_text(format!(":{}:", shortcode));
                                 }
                             }
                         });
@@ -507,172 +369,282 @@ pub fn draw_home_view(
         ..Default::default()
     };
     card_frame.show(ui, |ui| {
-        ui.horizontal(|ui| {
-            ui.heading(timeline_heading_text);
-
-            let fetch_button = egui::Button::new(egui::RichText::new(fetch_latest_button_text).strong());
-            if ui.add_enabled(!app_data.is_loading, fetch_button).clicked() {
-                if let (Some(client), Some(keys)) = (
-                    app_data.nostr_client.as_ref(),
-                    app_data.my_keys.as_ref(),
-                ) {
+        if app_data.thread.is_some() {
+            thread_view::draw_thread_view(ui, app_data, &mut urls_to_load, app_data_arc.clone(), &runtime_handle);
+        } else {
+            ui.horizontal(|ui| {
+                ui.heading(timeline_heading_text);
+
+                // --- フィード切り替え ---
+                let previous_feed = app_data.timeline_feed;
+                ui.add_enabled_ui(!app_data.is_loading, |ui| {
+                    ui.selectable_value(&mut app_data.timeline_feed, TimelineFeed::Following, following_feed_text);
+                    ui.selectable_value(&mut app_data.timeline_feed, TimelineFeed::Global, global_feed_text);
+                });
+                let feed_changed = app_data.timeline_feed != previous_feed;
+                if feed_changed {
+                    app_data.timeline_posts.clear();
+                    app_data.new_timeline_posts.clear();
+                    app_data.timeline_pagination.reset();
+                }
+
+                let fetch_button = egui::Button::new(egui::RichText::new(fetch_latest_button_text).strong());
+                if (ui.add_enabled(!app_data.is_loading, fetch_button).clicked() || feed_changed)
+                    && let (Some(client), Some(pubkey)) = (
+                        app_data.nostr_client.as_ref(),
+                        app_data.my_pubkey,
+                    ) {
                     let client = client.clone();
-                    let keys = keys.clone();
                     let cache_db = app_data.cache_db.clone();
                     let relay_config = app_data.relays.clone();
+                    let followed_pubkeys = app_data.followed_pubkeys.clone();
+                    let feed = app_data.timeline_feed;
+                    let mutes = app_data.mute_list.clone();
                     let cloned_app_data_arc = app_data_arc.clone();
+                    let handle = runtime_handle.clone();
 
                     app_data.is_loading = true;
                     app_data.should_repaint = true;
 
                     runtime_handle.spawn(async move {
-                        match events::refresh_timeline(&client, &keys, &cache_db, &relay_config).await {
+                        // 取得が終わるまでは保存済みのイベントでフィードを表示しておく
+                        if feed_changed {
+                            let authors = events::feed_authors(feed, pubkey, &followed_pubkeys);
+                            let stored = stored_timeline_posts(client.database(), authors.as_ref(), &mutes).await;
+                            let mut app_data = cloned_app_data_arc.lock().unwrap();
+                            if app_data.timeline_feed == feed {
+                                app_data.timeline_posts = stored;
+                                app_data.should_repaint = true;
+                            }
+                        }
+                        match events::refresh_timeline(&client, pubkey, &followed_pubkeys, feed, &cache_db, &relay_config, &mutes).await {
                             Ok(timeline_posts) => {
                                 let mut app_data = cloned_app_data_arc.lock().unwrap();
-                                app_data.timeline_posts = timeline_posts;
+                                if app_data.timeline_feed == feed {
+                                    app_data.timeline_posts = timeline_posts;
+                                    app_data.timeline_pagination.reset();
+                                    live::drop_shown_pending(&mut app_data);
+                                }
                                 println!("Refreshed timeline from home view.");
                             }
                             Err(e) => {
                                 eprintln!("Failed to refresh timeline: {}", e);
                             }
                         }
-                        let mut app_data = cloned_app_data_arc.lock().unwrap();
-                        app_data.is_loading = false;
-                        app_data.should_repaint = true;
+                        {
+                            let mut app_data = cloned_app_data_arc.lock().unwrap();
+                            app_data.is_loading = false;
+                            app_data.should_repaint = true;
+                        }
+                        // フィードを切り替えたら新着の購読もそのフィードに張り替える
+                        if feed_changed {
+                            live::resubscribe_timeline(cloned_app_data_arc, &handle);
+                        }
                     });
                 }
-            }
 
-            if app_data.is_loading {
-                ui.add_space(10.0);
-                ui.spinner();
-                ui.label("更新中...");
+                if app_data.is_loading {
+                    ui.add_space(10.0);
+                    ui.spinner();
+                    ui.label("更新中...");
+                }
+            });
+            ui.add_space(10.0);
+
+            // 新着はすぐには差し込まず、バナーを押したときに一覧へ反映する
+            let mut scroll_to_top = false;
+            let new_posts_count = app_data.new_timeline_posts.len();
+            if new_posts_count > 0 {
+                let banner = egui::Button::new(
+                    egui::RichText::new(format!("↑ {new_posts_count}{new_posts_banner_text}")).strong(),
+                );
+                if ui.add_sized([ui.available_width(), 28.0], banner).clicked() {
+                    live::show_new_posts(app_data, live::LiveTarget::Timeline);
+                    scroll_to_top = true;
+                }
+                ui.add_space(5.0);
             }
-        });
-        ui.add_space(10.0);
 
-        if app_data.timeline_posts.is_empty() {
-            ui.label(no_timeline_message_text);
-        } else {
-            let num_posts = app_data.timeline_posts.len();
-            let row_height = 90.0;
-
-            let card_frame = egui::Frame {
-                inner_margin: egui::Margin::same(0),
-                corner_radius: 8.0.into(),
-                shadow: eframe::epaint::Shadow::NONE,
-                fill: app_data.current_theme.card_background_color(),
-                ..Default::default()
-            };
-            card_frame.show(ui, |ui| {
-                egui::ScrollArea::vertical()
-                    .id_salt("timeline_scroll_area")
-                    .max_height(ui.available_height() - 100.0)
-                    .show_rows(ui, row_height, num_posts, |ui, row_range| {
-                        for i in row_range {
-                            let post_data = app_data.timeline_posts[i].clone();
-                            post::render_post(
-                                ui,
-                                app_data,
-                                &post_data,
-                                &mut urls_to_load,
-                                app_data_arc.clone(),
-                                runtime_handle.clone(),
-                            );
-                            ui.add_space(5.0);
-                        }
-                    });
-            });
+            if app_data.timeline_posts.is_empty() {
+                ui.label(no_timeline_message_text);
+            } else {
+                let num_posts = app_data.timeline_posts.len();
+                let row_height = 90.0;
+
+                let card_frame = egui::Frame {
+                    inner_margin: egui::Margin::same(0),
+                    corner_radius: 8.0.into(),
+                    shadow: eframe::epaint::Shadow::NONE,
+                    fill: app_data.current_theme.card_background_color(),
+                    ..Default::default()
+                };
+                card_frame.show(ui, |ui| {
+                    let mut scroll_area = egui::ScrollArea::vertical()
+                        .id_salt("timeline_scroll_area")
+                        .max_height(ui.available_height() - 100.0);
+                    if scroll_to_top {
+                        scroll_area = scroll_area.vertical_scroll_offset(0.0);
+                    }
+                    let output = scroll_area
+                        .show_rows(ui, row_height, num_posts, |ui, row_range| {
+                            for i in row_range {
+                                let post_data = app_data.timeline_posts[i].clone();
+                                post::render_post(
+                                    ui,
+                                    app_data,
+                                    &post_data,
+                                    &mut urls_to_load,
+                                    app_data_arc.clone(),
+                                    runtime_handle.clone(),
+                                );
+                                ui.add_space(5.0);
+                            }
+                        });
+                    if pagination::near_bottom(&output, row_height) {
+                        load_older_timeline(app_data, app_data_arc.clone(), &runtime_handle);
+                    }
+                    pagination::draw_footer(ui, &mut app_data.timeline_pagination);
+                });
+            }
         }
 
-        // --- Image Loading Logic ---
-
-        // First, try to load images from the LMDB cache for URLs not in memory.
-        let cache_db = app_data.cache_db.clone();
-        let mut still_to_load = Vec::new();
-        for (url_key, kind) in urls_to_load {
-            if let Some(image_bytes) = image_cache::load_from_lmdb(&cache_db, &url_key) {
-                // Image found in cache, process it directly.
-                // This is a simplification; for a smoother UI, this should be async.
-                if let Ok(mut dynamic_image) = image::load_from_memory(&image_bytes) {
-                    let (width, height) = match kind {
-                        ImageKind::Avatar => (32, 32),
-                        ImageKind::Emoji => (20, 20),
-                    _ => (32, 32), // Default for Banner, ProfilePicture, etc.
-                    };
-                    dynamic_image = dynamic_image.thumbnail(width, height);
-                    let color_image = egui::ColorImage::from_rgba_unmultiplied(
-                        [dynamic_image.width() as usize, dynamic_image.height() as usize],
-                        dynamic_image.to_rgba8().as_flat_samples().as_slice(),
-                    );
-                    let texture_handle = ctx.load_texture(
-                        &url_key,
-                        color_image,
-                        Default::default()
-                    );
-                    app_data.image_cache.insert(url_key, ImageState::Loaded(texture_handle));
-                } else {
-                    // Failed to decode, mark as failed.
-                    app_data.image_cache.insert(url_key, ImageState::Failed);
+        image_cache::load_images(ctx, app_data, &app_data_arc, &runtime_handle, urls_to_load);
+    });
+
+}
+
+// 一覧の末尾に近づいたら、いま表示している最古の投稿より前のページを読み込む
+fn load_older_timeline(
+    app_data: &mut NostrPostAppInternal,
+    app_data_arc: Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &tokio::runtime::Handle,
+) {
+    let (Some(client), Some(pubkey)) = (app_data.nostr_client.clone(), app_data.my_pubkey) else {
+        return;
+    };
+    let Some((until, generation)) = app_data.timeline_pagination.begin(&app_data.timeline_posts) else {
+        return;
+    };
+    let feed = app_data.timeline_feed;
+    let followed_pubkeys = app_data.followed_pubkeys.clone();
+    let relay_config = app_data.relays.clone();
+    let cache_db = app_data.cache_db.clone();
+    let mutes = app_data.mute_list.clone();
+
+    runtime_handle.spawn(async move {
+        let routes = events::timeline_routes(&client, pubkey, &followed_pubkeys, feed, &cache_db, &relay_config).await;
+        let result = fetch_timeline_events(&client, &routes, Some(until), &mutes).await;
+        let mut guard = app_data_arc.lock().unwrap();
+        let app_data = &mut *guard;
+        app_data.timeline_pagination.finish(generation, &mut app_data.timeline_posts, result);
+        app_data.should_repaint = true;
+    });
+}
+
+// --- Quote Fetching Logic ---
+// posts_to_fetch に積まれた引用・リポスト元・通知の対象をまとめて取得する
+pub fn fetch_queued_posts(
+    app_data: &mut NostrPostAppInternal,
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &tokio::runtime::Handle,
+) {
+    let mut items_to_fetch = HashSet::new();
+    if let Ok(mut posts_to_fetch) = app_data.posts_to_fetch.lock()
+        && !posts_to_fetch.is_empty()
+    {
+        items_to_fetch = posts_to_fetch.clone();
+        posts_to_fetch.clear();
+    }
+
+    if !items_to_fetch.is_empty()
+        && let Some(client) = app_data.nostr_client.as_ref()
+    {
+        let client = client.clone();
+        let app_data_clone = app_data_arc.clone();
+        let profile_cache_clone = app_data.profile_cache.clone();
+        let mutes = app_data.mute_list.clone();
+
+        runtime_handle.spawn(async move {
+            // 1. Fetch event content for all items that need fetching.
+            // 保存済みのイベントを先に探し、無いものだけリレーに問い合わせる。
+            let event_ids_to_fetch: HashSet<nostr::EventId> = items_to_fetch.iter().copied().collect();
+            let mut events = event_store::query(client.database(), vec![Filter::new().ids(event_ids_to_fetch.clone())]).await;
+            let missing_ids: HashSet<nostr::EventId> = event_ids_to_fetch
+                .into_iter()
+                .filter(|id| !events.iter().any(|e| e.id == *id))
+                .collect();
+            if !missing_ids.is_empty() {
+                let events_filter = Filter::new().ids(missing_ids);
+                events.extend(client.fetch_events(events_filter, std::time::Duration::from_secs(10)).await.unwrap_or_default());
+            }
+
+            if events.is_empty() {
+                return;
+            }
+
+            // 2. Determine which author profiles we need to fetch.
+            // ミュート中の投稿は中身を出さないので、著者のプロフィールも取らない。
+            // 投稿自体はキャッシュに入れて、同じ引用を何度も取りに行かないようにする。
+            let mut profiles_to_fetch = HashSet::new();
+            for event in &events {
+                if !profile_cache_clone.contains_key(&event.pubkey) && !mutes.hides_event(event) {
+                    profiles_to_fetch.insert(event.pubkey);
                 }
-            } else {
-                // Not on disk, queue for network download.
-                still_to_load.push((url_key, kind));
             }
-        }
 
-        // Fetch remaining images from the network.
-        let data_clone = app_data_arc.clone();
-        for (url_key, kind) in still_to_load {
-            app_data.image_cache.insert(url_key.clone(), ImageState::Loading);
-            app_data.should_repaint = true;
-
-            let app_data_clone = data_clone.clone();
-            let ctx_clone = ctx.clone();
-            let cache_db_for_fetch = app_data.cache_db.clone();
-            let request = ehttp::Request::get(&url_key);
-
-            ehttp::fetch(request, move |result| {
-                let new_state = match result {
-                    Ok(response) => {
-                        if response.ok {
-                            // Save to LMDB cache first.
-                            image_cache::save_to_lmdb(&cache_db_for_fetch, &response.url, &response.bytes);
-
-                            match image::load_from_memory(&response.bytes) {
-                                Ok(mut dynamic_image) => {
-                                    let (width, height) = match kind {
-                                        ImageKind::Avatar => (32, 32),
-                                        ImageKind::Emoji => (20, 20),
-                                    _ => (32, 32), // Default for Banner, ProfilePicture, etc.
-                                    };
-                                    dynamic_image = dynamic_image.thumbnail(width, height);
-
-                                    let color_image = egui::ColorImage::from_rgba_unmultiplied(
-                                        [dynamic_image.width() as usize, dynamic_image.height() as usize],
-                                        dynamic_image.to_rgba8().as_flat_samples().as_slice(),
-                                    );
-                                    let texture_handle = ctx_clone.load_texture(
-                                        &response.url,
-                                        color_image,
-                                        Default::default()
-                                    );
-                                    ImageState::Loaded(texture_handle)
-                                }
-                                Err(_) => ImageState::Failed,
-                            }
-                        } else {
-                            ImageState::Failed
+            // 3. Fetch the missing profiles, from the event database first.
+            let mut new_profiles = event_store::profiles(client.database(), profiles_to_fetch.clone()).await;
+            profiles_to_fetch.retain(|pk| !new_profiles.contains_key(pk));
+            if !profiles_to_fetch.is_empty() {
+                let metadata_filter = Filter::new().authors(profiles_to_fetch).kind(Kind::Metadata);
+                if let Ok(metadata_events) = client.fetch_events(metadata_filter, std::time::Duration::from_secs(5)).await {
+                    for event in metadata_events {
+                        if let Ok(metadata) = serde_json::from_str(&event.content) {
+                            new_profiles.insert(event.pubkey, metadata);
                         }
                     }
-                    Err(_) => ImageState::Failed,
-                };
-
-                let mut app_data = app_data_clone.lock().unwrap();
-                app_data.image_cache.insert(url_key, new_state);
-                ctx_clone.request_repaint();
-            });
-        }
-    });
+                }
+            }
 
+            // 4. Combine existing cache, new profiles, and fetched events to create the final posts.
+            let mut profiles = profile_cache_clone;
+            profiles.extend(new_profiles.clone());
+
+            let fetched_posts: HashMap<nostr::EventId, Arc<TimelinePost>> = events.into_iter()
+                .filter(|e| e.kind == Kind::TextNote)
+                .map(|event| {
+                    let author_metadata = profiles.get(&event.pubkey).cloned().unwrap_or_default();
+                    let emojis = event.tags.iter().filter_map(|tag| {
+                        if let Some(TagStandard::Emoji { shortcode, url }) = tag.as_standardized() {
+                            Some((shortcode.to_string(), url.to_string()))
+                        } else {
+                            None
+                        }
+                    }).collect();
+
+                    let timeline_post = Arc::new(TimelinePost {
+                        id: event.id,
+                        kind: event.kind,
+                        author_pubkey: event.pubkey,
+                        author_metadata,
+                        content: event.content.clone(),
+                        created_at: event.created_at,
+                        emojis,
+                        tags: event.tags.to_vec(),
+                    });
+                    (event.id, timeline_post)
+                })
+                .collect();
+
+            // 5. Update the application state with the newly fetched data.
+            if !fetched_posts.is_empty() {
+                let mut data = app_data_clone.lock().unwrap();
+                data.quoted_posts_cache.extend(fetched_posts);
+                if !new_profiles.is_empty() {
+                    data.profile_cache.extend(new_profiles);
+                }
+                data.should_repaint = true;
+            }
+        });
+    }
 }
@@ -1,16 +1,50 @@
 use crate::cache_db::LmdbCache;
+use crate::types::{ImageKind, ImageState, NostrPostAppInternal};
+use eframe::egui;
+use image::codecs::{gif::GifDecoder, jpeg::JpegEncoder, png::PngDecoder, webp::WebPDecoder};
+use image::{AnimationDecoder, DynamicImage, ImageFormat, RgbaImage};
 use sha2::{Digest, Sha256};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+use tokio::sync::Semaphore;
 
-// Hashes the URL to create a stable key for the DB.
-fn url_to_key(url: &str) -> String {
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+// 同時にダウンロードする数
+const MAX_DOWNLOADS: usize = 6;
+// 読み込み中にできる数。あふれた分は次のフレームでまた頼まれる
+const MAX_PENDING: usize = 256;
+// メモリに置くテクスチャの合計 (バイト)
+const MAX_TEXTURE_BYTES: usize = 256 * 1024 * 1024;
+// 失敗したら 5秒, 10秒, 20秒... と間を空けて取り直す
+const RETRY_BASE: Duration = Duration::from_secs(5);
+const RETRY_MAX: Duration = Duration::from_secs(60 * 60);
+// これより大きいファイルは表示しない
+const MAX_BYTES: usize = 20 * 1024 * 1024;
+// アニメーションはフレーム数が多いので小さめにする
+const MAX_FRAMES: usize = 300;
+const MAX_ANIMATED_SIDE: u32 = 480;
+// 縮小後のフレームの合計 (バイト)。超えた分のフレームは捨てる
+const MAX_ANIMATION_BYTES: usize = 64 * 1024 * 1024;
+// 遅延 0 や 10ms の GIF はブラウザと同じく 100ms で再生する
+const MIN_FRAME_DELAY: Duration = Duration::from_millis(20);
+const DEFAULT_FRAME_DELAY: Duration = Duration::from_millis(100);
+
+// Hashes the URL to create a stable key for the DB. Each kind has its own thumbnail.
+fn url_to_key(url: &str, kind: ImageKind) -> String {
     let mut hasher = Sha256::new();
     hasher.update(url.as_bytes());
+    hasher.update(format!("#{kind:?}").as_bytes());
     let result = hasher.finalize();
     format!("{:x}", result)
 }
 
-pub fn load_from_lmdb(cache_db: &LmdbCache, url: &str) -> Option<Vec<u8>> {
-    let key = url_to_key(url);
+fn load_from_lmdb(cache_db: &LmdbCache, url: &str, kind: ImageKind) -> Option<Vec<u8>> {
+    let key = url_to_key(url, kind);
     match cache_db.read_image_cache(&key) {
         Ok(Some(data)) => Some(data),
         Ok(None) => None,
@@ -22,9 +56,515 @@ pub fn load_from_lmdb(cache_db: &LmdbCache, url: &str) -> Option<Vec<u8>> {
     }
 }
 
-pub fn save_to_lmdb(cache_db: &LmdbCache, url: &str, data: &[u8]) {
-    let key = url_to_key(url);
+fn save_to_lmdb(cache_db: &LmdbCache, url: &str, kind: ImageKind, data: &[u8]) {
+    let key = url_to_key(url, kind);
     if let Err(e) = cache_db.write_image_cache(&key, data) {
         eprintln!("Failed to write image to LMDB cache: {}", e);
     }
 }
+
+/// The largest texture made for each kind of image.
+fn thumbnail_size(kind: ImageKind) -> (u32, u32) {
+    match kind {
+        ImageKind::Avatar => (32, 32),
+        ImageKind::Emoji => (20, 20),
+        ImageKind::ProfilePicture => (100, 100),
+        ImageKind::Banner => (600, 200),
+        ImageKind::Media => (1280, 1280),
+    }
+}
+
+fn retry_delay(failures: u32) -> Duration {
+    RETRY_BASE.saturating_mul(1 << failures.saturating_sub(1).min(16)).min(RETRY_MAX)
+}
+
+fn is_svg(bytes: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(&bytes[..bytes.len().min(1024)]);
+    head.trim_start().starts_with('<') && head.contains("<svg")
+}
+
+// 縦横比を保ったまま (max_width, max_height) に収める
+fn fit(image: RgbaImage, (max_width, max_height): (u32, u32)) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    if width <= max_width && height <= max_height {
+        return image;
+    }
+    let scale = (max_width as f32 / width as f32).min(max_height as f32 / height as f32);
+    let (w, h) = (((width as f32 * scale) as u32).max(1), ((height as f32 * scale) as u32).max(1));
+    image::imageops::resize(&image, w, h, image::imageops::FilterType::Triangle)
+}
+
+/// An image decoded on a worker thread, not yet uploaded as textures.
+struct Decoded {
+    // 元画像の大きさ (縦横比に使う)
+    size: (u32, u32),
+    frames: Vec<(egui::ColorImage, Duration)>,
+    // 縮小した静止画。元のファイルの代わりにディスクに保存する
+    thumbnail: Option<RgbaImage>,
+}
+
+fn render_svg(bytes: &[u8], (max_width, max_height): (u32, u32), kind: ImageKind) -> Result<Decoded, Error> {
+    let tree = usvg::Tree::from_data(bytes, &usvg::Options::default())?;
+    let size = tree.size();
+    let mut scale = (max_width as f32 / size.width()).min(max_height as f32 / size.height());
+    // 本文のアイコンのような小さな SVG を大きく描きすぎない
+    if matches!(kind, ImageKind::Media) {
+        scale = scale.min(2.0);
+    }
+    let (width, height) = (((size.width() * scale).ceil() as u32).max(1), ((size.height() * scale).ceil() as u32).max(1));
+    let mut pixmap = resvg::tiny_skia::Pixmap::new(width, height).ok_or("invalid SVG size")?;
+    resvg::render(&tree, resvg::tiny_skia::Transform::from_scale(scale, scale), &mut pixmap.as_mut());
+    let color_image = egui::ColorImage::from_rgba_premultiplied([width as usize, height as usize], pixmap.data());
+    Ok(Decoded {
+        size: (size.width().round() as u32, size.height().round() as u32),
+        frames: vec![(color_image, Duration::ZERO)],
+        thumbnail: None,
+    })
+}
+
+// 元画像の大きさと、縮小したフレームと表示時間
+type Frames = ((u32, u32), Vec<(RgbaImage, Duration)>);
+
+fn decode_still(bytes: &[u8], max_size: (u32, u32)) -> Result<Frames, Error> {
+    let image = image::load_from_memory(bytes)?.to_rgba8();
+    let size = image.dimensions();
+    Ok((size, vec![(fit(image, max_size), Duration::ZERO)]))
+}
+
+fn decode_frames(bytes: &[u8], max_size: (u32, u32)) -> Result<Frames, Error> {
+    let frames = match image::guess_format(bytes)? {
+        ImageFormat::Gif => GifDecoder::new(Cursor::new(bytes))?.into_frames(),
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(Cursor::new(bytes))?;
+            if !decoder.has_animation() {
+                return decode_still(bytes, max_size);
+            }
+            decoder.into_frames()
+        }
+        ImageFormat::Png => {
+            let decoder = PngDecoder::new(Cursor::new(bytes))?;
+            if !decoder.is_apng()? {
+                return decode_still(bytes, max_size);
+            }
+            decoder.apng()?.into_frames()
+        }
+        _ => return decode_still(bytes, max_size),
+    };
+    // 元の大きさのフレームをためないよう、一枚ずつ縮めてから次を読む
+    let animated_size = (MAX_ANIMATED_SIDE, MAX_ANIMATED_SIDE);
+    let mut frames = frames.take(MAX_FRAMES);
+    let first = frames.next().ok_or("no frames")??;
+    let delay = Duration::from(first.delay());
+    let first = first.into_buffer();
+    let size = first.dimensions();
+    // 1 枚しかなければ静止画の大きさで出す
+    let still = fit(first, max_size);
+    let mut animation = vec![(fit(still.clone(), animated_size), delay)];
+    let mut total = animation[0].0.len();
+    for frame in frames {
+        let frame = frame?;
+        let delay = Duration::from(frame.delay());
+        let buffer = fit(frame.into_buffer(), animated_size);
+        total += buffer.len();
+        if total > MAX_ANIMATION_BYTES {
+            break;
+        }
+        animation.push((buffer, delay));
+    }
+    if animation.len() == 1 {
+        return Ok((size, vec![(still, delay)]));
+    }
+    Ok((size, animation))
+}
+
+/// Decodes `bytes` to at most `thumbnail_size(kind)`. Only note media is animated.
+fn decode(bytes: &[u8], kind: ImageKind) -> Result<Decoded, Error> {
+    let max_size = thumbnail_size(kind);
+    if is_svg(bytes) {
+        return render_svg(bytes, max_size, kind);
+    }
+    let (size, frames) = match kind {
+        ImageKind::Media => decode_frames(bytes, max_size)?,
+        _ => decode_still(bytes, max_size)?,
+    };
+    let animated = frames.len() > 1;
+    let mut decoded = Decoded { size, frames: Vec::with_capacity(frames.len()), thumbnail: None };
+    for (buffer, delay) in frames {
+        let delay = match delay {
+            delay if delay < MIN_FRAME_DELAY => DEFAULT_FRAME_DELAY,
+            delay => delay,
+        };
+        let color_image = egui::ColorImage::from_rgba_unmultiplied(
+            [buffer.width() as usize, buffer.height() as usize],
+            buffer.as_flat_samples().as_slice(),
+        );
+        decoded.frames.push((color_image, delay));
+        if !animated && buffer.dimensions() != size {
+            decoded.thumbnail = Some(buffer);
+        }
+    }
+    Ok(decoded)
+}
+
+// 不透明なら JPEG、透過があれば PNG にする
+fn encode_thumbnail(image: &RgbaImage) -> Result<Vec<u8>, Error> {
+    let mut bytes = Cursor::new(Vec::new());
+    if image.pixels().all(|pixel| pixel[3] == u8::MAX) {
+        DynamicImage::ImageRgba8(image.clone())
+            .to_rgb8()
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut bytes, 85))?;
+    } else {
+        image.write_to(&mut bytes, ImageFormat::Png)?;
+    }
+    Ok(bytes.into_inner())
+}
+
+/// What goes into the disk cache: the downscaled image, or the original file when
+/// it was not resized (SVG, animations, small images) or is smaller anyway.
+fn stored_bytes(original: Vec<u8>, thumbnail: Option<&RgbaImage>) -> Vec<u8> {
+    match thumbnail.map(encode_thumbnail) {
+        Some(Ok(bytes)) if bytes.len() < original.len() => bytes,
+        _ => original,
+    }
+}
+
+fn fetch(url: &str) -> Result<Vec<u8>, Error> {
+    let response = ehttp::fetch_blocking(&ehttp::Request::get(url))?;
+    if !response.ok {
+        return Err(format!("HTTP {}", response.status).into());
+    }
+    if response.bytes.len() > MAX_BYTES {
+        return Err(format!("{} bytes is too large", response.bytes.len()).into());
+    }
+    Ok(response.bytes)
+}
+
+/// Textures uploaded from a worker thread, handed back to the cache.
+struct Loaded {
+    size: (u32, u32),
+    frames: Vec<(egui::TextureHandle, Duration)>,
+}
+
+// LMDB → ダウンロード → デコードの順に、UI スレッドの外で行う
+async fn load(
+    ctx: &egui::Context,
+    cache_db: &LmdbCache,
+    downloads: &Semaphore,
+    url: &str,
+    kind: ImageKind,
+) -> Result<Loaded, Error> {
+    let cached = {
+        let (cache_db, url) = (cache_db.clone(), url.to_string());
+        tokio::task::spawn_blocking(move || load_from_lmdb(&cache_db, &url, kind)).await?
+    };
+    let downloaded = cached.is_none();
+    let bytes = match cached {
+        Some(bytes) => bytes,
+        None => {
+            let _permit = downloads.acquire().await?;
+            let url = url.to_string();
+            tokio::task::spawn_blocking(move || fetch(&url)).await??
+        }
+    };
+    let (ctx, cache_db, url) = (ctx.clone(), cache_db.clone(), url.to_string());
+    tokio::task::spawn_blocking(move || {
+        let decoded = decode(&bytes, kind)?;
+        if downloaded {
+            // リダイレクト先ではなく頼まれた URL で保存する
+            save_to_lmdb(&cache_db, &url, kind, &stored_bytes(bytes, decoded.thumbnail.as_ref()));
+        }
+        let frames = decoded
+            .frames
+            .into_iter()
+            .enumerate()
+            .map(|(i, (color_image, delay))| (ctx.load_texture(format!("{url}#{i}"), color_image, Default::default()), delay))
+            .collect();
+        Ok(Loaded { size: decoded.size, frames })
+    })
+    .await?
+}
+
+struct Entry {
+    state: ImageState,
+    // 最後に描かれたフレーム番号
+    last_used: Cell<u64>,
+    size: (u32, u32),
+    frames: Vec<(egui::TextureHandle, Duration)>,
+    total: Duration,
+    // テクスチャのバイト数
+    bytes: usize,
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Entry {
+    fn new(state: ImageState, frame: u64) -> Self {
+        Self {
+            state,
+            last_used: Cell::new(frame),
+            size: (0, 0),
+            frames: Vec::new(),
+            total: Duration::ZERO,
+            bytes: 0,
+            failures: 0,
+            retry_at: None,
+        }
+    }
+}
+
+// 種類ごとに縮小する大きさが違うので、同じ URL でも別に持つ
+type Key = (String, ImageKind);
+
+/// Textures of every remote image, with a bounded download queue, retry with
+/// exponential backoff and LRU eviction once `MAX_TEXTURE_BYTES` is exceeded.
+pub struct ImageCache {
+    entries: HashMap<Key, Entry>,
+    frame: u64,
+    texture_bytes: usize,
+    pending: usize,
+    downloads: Arc<Semaphore>,
+}
+
+impl Default for ImageCache {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            frame: 0,
+            texture_bytes: 0,
+            pending: 0,
+            downloads: Arc::new(Semaphore::new(MAX_DOWNLOADS)),
+        }
+    }
+}
+
+impl ImageCache {
+    /// Called once per frame. Textures used in the current frame are never evicted.
+    pub fn begin_frame(&mut self) {
+        self.frame += 1;
+    }
+
+    // 使われたことを記録する。失敗して待ち時間が過ぎたものは None (もう一度頼んでもらう)
+    fn entry(&self, url: &str, kind: ImageKind) -> Option<&Entry> {
+        let entry = self.entries.get(&(url.to_string(), kind))?;
+        if entry.retry_at.is_some_and(|at| Instant::now() >= at) {
+            return None;
+        }
+        entry.last_used.set(self.frame);
+        Some(entry)
+    }
+
+    /// The state of `url`, or `None` when it should be (re)requested through `load_images`.
+    pub fn get(&self, url: &str, kind: ImageKind) -> Option<&ImageState> {
+        self.entry(url, kind).map(|entry| &entry.state)
+    }
+
+    /// The original size of a loaded image.
+    pub fn dimensions(&self, url: &str, kind: ImageKind) -> Option<(u32, u32)> {
+        self.entry(url, kind).filter(|entry| !entry.frames.is_empty()).map(|entry| entry.size)
+    }
+
+    /// The frame of `url` to show at `time` (seconds) and how long until the next one.
+    pub fn current_frame(&self, url: &str, kind: ImageKind, time: f64) -> Option<(egui::TextureHandle, Option<Duration>)> {
+        let entry = self.entry(url, kind)?;
+        let (first, _) = entry.frames.first()?;
+        if entry.frames.len() > 1 && !entry.total.is_zero() {
+            let t = Duration::from_secs_f64(time % entry.total.as_secs_f64());
+            let mut elapsed = Duration::ZERO;
+            for (texture, delay) in &entry.frames {
+                elapsed += *delay;
+                if t < elapsed {
+                    return Some((texture.clone(), Some(elapsed - t)));
+                }
+            }
+        }
+        Some((first.clone(), None))
+    }
+
+    /// A small texture of a NIP-94 blurhash, decoded on the spot.
+    pub fn blurhash(&mut self, ctx: &egui::Context, hash: &str) -> Option<egui::TextureHandle> {
+        // メディアの代わりに出すものなので、種類は Media として持つ
+        let key = (format!("blurhash:{hash}"), ImageKind::Media);
+        if !self.entries.contains_key(&key) {
+            let pixels = blurhash::decode(hash, 32, 32, 1.0).ok()?;
+            let texture = ctx.load_texture(&key.0, egui::ColorImage::from_rgba_unmultiplied([32, 32], &pixels), Default::default());
+            self.store(key.clone(), Loaded { size: (32, 32), frames: vec![(texture, Duration::ZERO)] });
+        }
+        self.current_frame(&key.0, key.1, 0.0).map(|(texture, _)| texture)
+    }
+
+    fn wants(&self, key: &Key) -> bool {
+        match self.entries.get(key) {
+            None => true,
+            Some(entry) => entry.retry_at.is_some_and(|at| Instant::now() >= at),
+        }
+    }
+
+    fn start(&mut self, key: Key) {
+        let frame = self.frame;
+        let entry = self.entries.entry(key).or_insert_with(|| Entry::new(ImageState::Loading, frame));
+        entry.state = ImageState::Loading;
+        entry.retry_at = None;
+        self.pending += 1;
+    }
+
+    fn store(&mut self, key: Key, loaded: Loaded) {
+        let bytes = loaded.frames.iter().map(|(texture, _)| texture.size()[0] * texture.size()[1] * 4).sum();
+        let mut entry = Entry::new(ImageState::Loaded(loaded.frames[0].0.clone()), self.frame);
+        entry.size = loaded.size;
+        entry.total = loaded.frames.iter().map(|(_, delay)| *delay).sum();
+        entry.frames = loaded.frames;
+        entry.bytes = bytes;
+        if let Some(old) = self.entries.insert(key, entry) {
+            self.texture_bytes -= old.bytes;
+        }
+        self.texture_bytes += bytes;
+        self.evict();
+    }
+
+    fn finish(&mut self, key: Key, result: Result<Loaded, Error>) {
+        self.pending = self.pending.saturating_sub(1);
+        match result {
+            Ok(loaded) if !loaded.frames.is_empty() => self.store(key, loaded),
+            _ => {
+                let frame = self.frame;
+                let entry = self.entries.entry(key).or_insert_with(|| Entry::new(ImageState::Failed, frame));
+                entry.state = ImageState::Failed;
+                entry.failures += 1;
+                entry.retry_at = Some(Instant::now() + retry_delay(entry.failures));
+            }
+        }
+    }
+
+    // 合計が上限を超えたら、長く使われていないものから捨てる
+    fn evict(&mut self) {
+        while self.texture_bytes > MAX_TEXTURE_BYTES {
+            let oldest = self
+                .entries
+                .iter()
+                .filter(|(_, entry)| entry.bytes > 0 && entry.last_used.get() < self.frame)
+                .min_by_key(|(_, entry)| entry.last_used.get())
+                .map(|(key, _)| key.clone());
+            let Some(key) = oldest else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&key) {
+                self.texture_bytes -= entry.bytes;
+            }
+        }
+    }
+}
+
+/// Loads the images a view asked for: from the LMDB cache, or downloaded at most
+/// `MAX_DOWNLOADS` at a time. Decoding and texture uploads happen on worker threads.
+pub fn load_images(
+    ctx: &egui::Context,
+    app_data: &mut NostrPostAppInternal,
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+    urls_to_load: Vec<(String, ImageKind)>,
+) {
+    for (url, kind) in urls_to_load {
+        let cache = &mut app_data.image_cache;
+        let key = (url.clone(), kind);
+        if !cache.wants(&key) {
+            continue;
+        }
+        if cache.pending >= MAX_PENDING {
+            break;
+        }
+        cache.start(key.clone());
+        let downloads = cache.downloads.clone();
+        let cache_db = app_data.cache_db.clone();
+        let ctx = ctx.clone();
+        let arc = app_data_arc.clone();
+        runtime_handle.spawn(async move {
+            let result = load(&ctx, &cache_db, &downloads, &url, kind).await;
+            if let Err(e) = &result {
+                eprintln!("Failed to load image {url}: {e}");
+            }
+            arc.lock().unwrap().image_cache.finish(key, result);
+            ctx.request_repaint();
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_images_are_retried_after_a_growing_delay() {
+        assert_eq!(retry_delay(1), Duration::from_secs(5));
+        assert_eq!(retry_delay(3), Duration::from_secs(20));
+        assert_eq!(retry_delay(40), RETRY_MAX);
+
+        let url = "https://example.com/a.png";
+        let key = (url.to_string(), ImageKind::Media);
+        let mut cache = ImageCache::default();
+        cache.start(key.clone());
+        cache.finish(key.clone(), Err("HTTP 503".into()));
+        assert!(matches!(cache.get(url, ImageKind::Media), Some(ImageState::Failed)));
+        assert!(!cache.wants(&key));
+        // 同じ URL でも大きさの違う種類は別に読み込む
+        assert!(cache.get(url, ImageKind::Avatar).is_none());
+        assert!(cache.wants(&(url.to_string(), ImageKind::Avatar)));
+
+        cache.entries.get_mut(&key).unwrap().retry_at = Some(Instant::now());
+        assert!(cache.get(url, ImageKind::Media).is_none());
+        assert!(cache.wants(&key));
+    }
+
+    #[test]
+    fn renders_svg() {
+        let svg = br#"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="20"><rect width="10" height="20" fill="red"/></svg>"#;
+        let decoded = decode(svg, ImageKind::Avatar).unwrap();
+        assert_eq!(decoded.size, (10, 20));
+        let (image, _) = &decoded.frames[0];
+        assert_eq!(image.size, [16, 32]);
+        assert_eq!(image.pixels[0], egui::Color32::RED);
+    }
+
+    #[test]
+    fn stores_downscaled_thumbnails() {
+        let mut original = Cursor::new(Vec::new());
+        RgbaImage::from_fn(400, 200, |x, y| image::Rgba([x as u8, y as u8, 0, 255]))
+            .write_to(&mut original, ImageFormat::Png)
+            .unwrap();
+        let original = original.into_inner();
+
+        let decoded = decode(&original, ImageKind::Avatar).unwrap();
+        assert_eq!(decoded.size, (400, 200));
+        let stored = stored_bytes(original.clone(), decoded.thumbnail.as_ref());
+        assert!(stored.len() < original.len());
+        let thumbnail = image::load_from_memory(&stored).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (32, 16));
+
+        // 縮小しない大きさならそのまま保存する
+        let decoded = decode(&original, ImageKind::Media).unwrap();
+        assert!(decoded.thumbnail.is_none());
+        assert_eq!(stored_bytes(original.clone(), decoded.thumbnail.as_ref()), original);
+    }
+
+    #[test]
+    fn downscales_animation_frames() {
+        let mut gif = Vec::new();
+        {
+            let mut encoder = image::codecs::gif::GifEncoder::new(&mut gif);
+            for shade in [0, 255] {
+                let buffer = RgbaImage::from_pixel(1000, 500, image::Rgba([shade, 0, 0, 255]));
+                encoder
+                    .encode_frame(image::Frame::from_parts(buffer, 0, 0, image::Delay::from_numer_denom_ms(50, 1)))
+                    .unwrap();
+            }
+        }
+
+        let decoded = decode(&gif, ImageKind::Media).unwrap();
+        assert_eq!(decoded.size, (1000, 500));
+        assert_eq!(decoded.frames.len(), 2);
+        for (image, delay) in &decoded.frames {
+            assert_eq!(image.size, [480, 240]);
+            assert_eq!(*delay, Duration::from_millis(50));
+        }
+        assert!(decoded.thumbnail.is_none());
+    }
+}
@@ -0,0 +1,499 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use nostr::nips::nip19::ToBech32;
+use nostr::{Event, Filter, Kind, PublicKey, RelayUrl, SubscriptionId, Timestamp};
+use nostr_sdk::prelude::{FlagCheck, RelayNotification, RelayServiceFlags, RelayStatus};
+use nostr_sdk::{Client, RelayPoolNotification};
+use tokio::runtime::Handle;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+
+use crate::{
+    desktop_notify::{self, AlertKind},
+    notifications::{self, NotificationKind},
+    nostr_client,
+    types::{NostrPostAppInternal, ProfileMetadata, TimelinePost},
+    ui::events::timeline_routes,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LiveTarget {
+    Timeline,
+    Notifications,
+}
+
+struct LiveSubscription {
+    target: LiveTarget,
+    relays: Vec<RelayUrl>,
+    filters: Vec<(SubscriptionId, Filter)>,
+}
+
+// ログイン中に張りっぱなしにする REQ と、それを処理するタスク
+#[derive(Default)]
+pub struct LiveState {
+    subscriptions: Vec<LiveSubscription>,
+    // 対象ごとに受け取った最新の created_at。再接続時の since に使う。
+    last_seen: HashMap<LiveTarget, Timestamp>,
+    // 再接続を見張っているリレー
+    watched: HashSet<RelayUrl>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl LiveState {
+    fn target_of(&self, id: &SubscriptionId) -> Option<LiveTarget> {
+        self.subscriptions
+            .iter()
+            .find(|sub| sub.filters.iter().any(|(sub_id, _)| sub_id == id))
+            .map(|sub| sub.target)
+    }
+
+    // タスクを止めて状態を捨てる。REQ はクライアントの shutdown で閉じられる。
+    pub fn stop(&mut self) {
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+        self.subscriptions.clear();
+        self.last_seen.clear();
+        self.watched.clear();
+    }
+}
+
+/// Inserts `posts` into `target` (newest first), skipping ids already present.
+pub fn merge_posts(target: &mut Vec<TimelinePost>, posts: impl IntoIterator<Item = TimelinePost>) {
+    for post in posts {
+        if target.iter().any(|p| p.id == post.id) {
+            continue;
+        }
+        let index = target.partition_point(|p| p.created_at >= post.created_at);
+        target.insert(index, post);
+    }
+}
+
+// 「N件の新しい投稿」をクリックしたときに、保留中の投稿を一覧に反映する
+pub fn show_new_posts(app_data: &mut NostrPostAppInternal, target: LiveTarget) {
+    let (shown, pending) = match target {
+        LiveTarget::Timeline => (&mut app_data.timeline_posts, &mut app_data.new_timeline_posts),
+        LiveTarget::Notifications => (
+            &mut app_data.notification_posts,
+            &mut app_data.new_notification_posts,
+        ),
+    };
+    merge_posts(shown, pending.drain(..));
+    app_data.should_repaint = true;
+}
+
+// 一覧を取得し直したあと、すでに表示されている投稿を保留分から取り除く
+pub fn drop_shown_pending(app_data: &mut NostrPostAppInternal) {
+    let timeline_posts = &app_data.timeline_posts;
+    app_data
+        .new_timeline_posts
+        .retain(|p| !timeline_posts.iter().any(|shown| shown.id == p.id));
+    let notification_posts = &app_data.notification_posts;
+    app_data
+        .new_notification_posts
+        .retain(|p| !notification_posts.iter().any(|shown| shown.id == p.id));
+}
+
+/// Starts the timeline and notification subscriptions for the logged-in user.
+pub fn start(
+    app_data_arc: Arc<Mutex<NostrPostAppInternal>>,
+    client: Client,
+    pubkey: PublicKey,
+    runtime_handle: &Handle,
+) {
+    let handle = runtime_handle.clone();
+    let arc = app_data_arc.clone();
+    let task = runtime_handle.spawn(async move {
+        // REQ より先に受信側を作っておかないと、直後のイベントを取りこぼす
+        let mut notifications = client.notifications();
+
+        subscribe_timeline(&arc, &client, pubkey).await;
+        subscribe_notifications(&arc, &client, pubkey).await;
+
+        loop {
+            match notifications.recv().await {
+                Ok(RelayPoolNotification::Event { subscription_id, event, .. }) => {
+                    let target = arc.lock().unwrap().live.target_of(&subscription_id);
+                    if let Some(target) = target {
+                        handle_event(&arc, &client, &handle, target, *event);
+                    }
+                }
+                Ok(RelayPoolNotification::Shutdown) => break,
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    eprintln!("Live subscription lagged, skipped {skipped} notifications");
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+        println!("Live subscriptions stopped.");
+    });
+    app_data_arc.lock().unwrap().live.tasks.push(task);
+}
+
+/// Replaces the timeline subscription, e.g. after switching feeds or following someone.
+pub fn resubscribe_timeline(app_data_arc: Arc<Mutex<NostrPostAppInternal>>, runtime_handle: &Handle) {
+    let (client, pubkey) = {
+        let app_data = app_data_arc.lock().unwrap();
+        match (app_data.nostr_client.clone(), app_data.my_pubkey) {
+            (Some(client), Some(pubkey)) => (client, pubkey),
+            _ => return,
+        }
+    };
+    runtime_handle.spawn(async move {
+        subscribe_timeline(&app_data_arc, &client, pubkey).await;
+    });
+}
+
+async fn subscribe_timeline(
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    client: &Client,
+    pubkey: PublicKey,
+) {
+    let (old, followed_pubkeys, feed, cache_db, relay_config, since) = {
+        let mut app_data = app_data_arc.lock().unwrap();
+        let old = take_subscriptions(&mut app_data, LiveTarget::Timeline);
+        app_data.live.last_seen.remove(&LiveTarget::Timeline);
+        let since = app_data
+            .timeline_posts
+            .first()
+            .map(|p| p.created_at)
+            .unwrap_or_else(Timestamp::now);
+        (
+            old,
+            app_data.followed_pubkeys.clone(),
+            app_data.timeline_feed,
+            app_data.cache_db.clone(),
+            app_data.relays.clone(),
+            since,
+        )
+    };
+    for sub in old {
+        for (id, _) in &sub.filters {
+            client.unsubscribe(id).await;
+        }
+    }
+
+    // 取得と同じく、フォロー中は著者ごとの outbox リレーに REQ を張る
+    let routes = timeline_routes(client, pubkey, &followed_pubkeys, feed, &cache_db, &relay_config).await;
+    for route in routes {
+        let relays = parse_relays(route.relays.iter().map(String::as_str));
+        let filters = nostr_client::timeline_filters(route.authors.as_ref());
+        subscribe(app_data_arc, client, LiveTarget::Timeline, relays, filters, since).await;
+    }
+}
+
+/// Replaces the notification subscription, e.g. after the read relays changed.
+pub fn resubscribe_notifications(app_data_arc: Arc<Mutex<NostrPostAppInternal>>, runtime_handle: &Handle) {
+    let (client, pubkey) = {
+        let app_data = app_data_arc.lock().unwrap();
+        match (app_data.nostr_client.clone(), app_data.my_pubkey) {
+            (Some(client), Some(pubkey)) => (client, pubkey),
+            _ => return,
+        }
+    };
+    runtime_handle.spawn(async move {
+        subscribe_notifications(&app_data_arc, &client, pubkey).await;
+    });
+}
+
+async fn subscribe_notifications(
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    client: &Client,
+    pubkey: PublicKey,
+) {
+    let (old, since) = {
+        let mut app_data = app_data_arc.lock().unwrap();
+        let old = take_subscriptions(&mut app_data, LiveTarget::Notifications);
+        app_data.live.last_seen.remove(&LiveTarget::Notifications);
+        let since = app_data
+            .notification_posts
+            .first()
+            .map(|p| p.created_at)
+            .unwrap_or_else(Timestamp::now);
+        (old, since)
+    };
+    for sub in old {
+        for (id, _) in &sub.filters {
+            client.unsubscribe(id).await;
+        }
+    }
+
+    // 通知は自分の読み込みリレーに届く
+    let relays: Vec<RelayUrl> = client
+        .pool()
+        .relays_with_flag(RelayServiceFlags::READ, FlagCheck::All)
+        .await
+        .into_keys()
+        .collect();
+    let filters = vec![
+        nostr_client::notification_filter(pubkey),
+        nostr_client::follower_filter(pubkey),
+    ];
+    subscribe(app_data_arc, client, LiveTarget::Notifications, relays, filters, since).await;
+}
+
+async fn subscribe(
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    client: &Client,
+    target: LiveTarget,
+    relays: Vec<RelayUrl>,
+    filters: Vec<Filter>,
+    since: Timestamp,
+) {
+    // クライアントに追加されていないリレーには REQ を送れない。
+    // outbox のリレーは READ / WRITE なしで追加しているので、client.relays() には出てこない
+    let pool_relays = client.pool().all_relays().await;
+    let relays: Vec<RelayUrl> = relays
+        .into_iter()
+        .filter(|url| pool_relays.contains_key(url))
+        .collect();
+    if relays.is_empty() {
+        println!("No relays for live {target:?} subscription");
+        return;
+    }
+
+    let mut subscription = LiveSubscription {
+        target,
+        relays: relays.clone(),
+        filters: Vec::new(),
+    };
+    for filter in filters {
+        let id = SubscriptionId::generate();
+        let result = client
+            .subscribe_with_id_to(relays.clone(), id.clone(), filter.clone().since(since), None)
+            .await;
+        match result {
+            Ok(_) => subscription.filters.push((id, filter)),
+            Err(e) => eprintln!("Failed to subscribe live {target:?}: {e}"),
+        }
+    }
+    println!(
+        "Live {target:?} subscription: {} REQ on {:?}",
+        subscription.filters.len(),
+        relays
+    );
+
+    {
+        let mut app_data = app_data_arc.lock().unwrap();
+        app_data.live.last_seen.insert(target, since);
+        app_data.live.subscriptions.push(subscription);
+    }
+    watch_relays(app_data_arc, client, &relays);
+}
+
+fn take_subscriptions(app_data: &mut NostrPostAppInternal, target: LiveTarget) -> Vec<LiveSubscription> {
+    let (taken, kept) = std::mem::take(&mut app_data.live.subscriptions)
+        .into_iter()
+        .partition(|sub| sub.target == target);
+    app_data.live.subscriptions = kept;
+    taken
+}
+
+fn parse_relays<'a>(urls: impl Iterator<Item = &'a str>) -> Vec<RelayUrl> {
+    urls.filter_map(|url| RelayUrl::parse(url).ok()).collect()
+}
+
+// 切断から復帰したリレーに、最後に受け取った時刻以降の REQ を送り直す。
+// relay-pool も再接続時に元のフィルターで再購読するが、since が古いままなので上書きする。
+fn watch_relays(app_data_arc: &Arc<Mutex<NostrPostAppInternal>>, client: &Client, relays: &[RelayUrl]) {
+    let mut app_data = app_data_arc.lock().unwrap();
+    for url in relays {
+        if !app_data.live.watched.insert(url.clone()) {
+            continue;
+        }
+        let arc = app_data_arc.clone();
+        let client = client.clone();
+        let url = url.clone();
+        let task = tokio::spawn(async move {
+            let Ok(relay) = client.relay(&url).await else { return };
+            let mut notifications = relay.notifications();
+            let mut disconnected = false;
+            loop {
+                match notifications.recv().await {
+                    Ok(RelayNotification::RelayStatus { status }) => match status {
+                        RelayStatus::Connected if disconnected => {
+                            disconnected = false;
+                            resubscribe_relay(&arc, &client, &url).await;
+                        }
+                        RelayStatus::Disconnected | RelayStatus::Terminated => disconnected = true,
+                        _ => {}
+                    },
+                    Ok(RelayNotification::Shutdown) => break,
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+        app_data.live.tasks.push(task);
+    }
+}
+
+async fn resubscribe_relay(
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    client: &Client,
+    url: &RelayUrl,
+) {
+    let requests: Vec<(SubscriptionId, Filter)> = {
+        let app_data = app_data_arc.lock().unwrap();
+        let live = &app_data.live;
+        live.subscriptions
+            .iter()
+            .filter(|sub| sub.relays.contains(url))
+            .flat_map(|sub| {
+                let since = live.last_seen.get(&sub.target).copied();
+                sub.filters.iter().map(move |(id, filter)| {
+                    let filter = match since {
+                        Some(since) => filter.clone().since(since),
+                        None => filter.clone(),
+                    };
+                    (id.clone(), filter)
+                })
+            })
+            .collect()
+    };
+    if requests.is_empty() {
+        return;
+    }
+
+    println!("Relay {url} reconnected, resubscribing {} live REQ", requests.len());
+    for (id, filter) in requests {
+        if let Err(e) = client.subscribe_with_id_to([url.clone()], id, filter, None).await {
+            eprintln!("Failed to resubscribe on {url}: {e}");
+        }
+    }
+}
+
+fn handle_event(
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    client: &Client,
+    runtime_handle: &Handle,
+    target: LiveTarget,
+    event: Event,
+) {
+    // 通知の送り主。Zap レシートは LNURL サーバーではなく zap した人。
+    let author = match target {
+        LiveTarget::Timeline => event.pubkey,
+        LiveTarget::Notifications => notifications::actor(&event),
+    };
+    let (known_metadata, me, cache_db) = {
+        let app_data = app_data_arc.lock().unwrap();
+        (known_author_metadata(&app_data, &author), app_data.my_pubkey, app_data.cache_db.clone())
+    };
+    let is_follow = event.kind == Kind::ContactList;
+    if target == LiveTarget::Notifications {
+        let Some(me) = me else { return };
+        if !notifications::is_relevant(&event, me, notifications::load_zapper(&cache_db, &me)) {
+            return;
+        }
+        // コンタクトリストは、前から知っているフォロワーの更新なら通知しない
+        if is_follow && notifications::new_followers(&cache_db, &me, [event.clone()]).is_empty() {
+            return;
+        }
+    }
+    let to_post = move |event: &Event, metadata: ProfileMetadata| match target {
+        LiveTarget::Timeline => nostr_client::event_to_timeline_post(event, metadata),
+        LiveTarget::Notifications => {
+            let post = notifications::to_post(event, metadata);
+            if let (true, Some(me)) = (is_follow, me) {
+                notifications::remember_follows(&cache_db, &me, vec![post.clone()]);
+            }
+            post
+        }
+    };
+    match known_metadata {
+        Some(metadata) => {
+            let post = to_post(&event, metadata);
+            push_live_post(&mut app_data_arc.lock().unwrap(), target, post);
+        }
+        None => {
+            // 初めて見る著者はプロフィールを取ってから追加する
+            let arc = app_data_arc.clone();
+            let client = client.clone();
+            runtime_handle.spawn(async move {
+                let metadata = nostr_client::get_profile_metadata(author, &client)
+                    .await
+                    .unwrap_or_default();
+                let post = to_post(&event, metadata.clone());
+                let mut app_data = arc.lock().unwrap();
+                app_data.profile_cache.insert(author, metadata);
+                push_live_post(&mut app_data, target, post);
+            });
+        }
+    }
+}
+
+// メンション・返信・Zap はウィンドウが裏にあるときデスクトップにも出す
+fn alert_notification(app_data: &NostrPostAppInternal, post: &TimelinePost) {
+    let name = if post.author_metadata.name.is_empty() {
+        let npub = post.author_pubkey.to_bech32().unwrap_or_default();
+        format!("{}...{}", &npub[0..8], &npub[npub.len() - 4..])
+    } else {
+        post.author_metadata.name.clone()
+    };
+    let (kind, summary) = match NotificationKind::of(post) {
+        Some(NotificationKind::Mention) => (AlertKind::Mention, format!("{name}からのメンション")),
+        Some(NotificationKind::Reply) => (AlertKind::Mention, format!("{name}からの返信")),
+        Some(NotificationKind::Zap) => (
+            AlertKind::Zap,
+            format!("{name}が {} sats をZapしました", notifications::zap_sats(post)),
+        ),
+        _ => return,
+    };
+    desktop_notify::alert(app_data, kind, post.created_at, summary, &post.content);
+}
+
+fn known_author_metadata(app_data: &NostrPostAppInternal, pubkey: &PublicKey) -> Option<ProfileMetadata> {
+    if app_data.my_pubkey.as_ref() == Some(pubkey) {
+        return Some(app_data.editable_profile.clone());
+    }
+    if let Some(metadata) = app_data.profile_cache.get(pubkey) {
+        return Some(metadata.clone());
+    }
+    app_data
+        .timeline_posts
+        .iter()
+        .chain(app_data.notification_posts.iter())
+        .chain(app_data.new_timeline_posts.iter())
+        .chain(app_data.new_notification_posts.iter())
+        .find(|p| &p.author_pubkey == pubkey)
+        .map(|p| p.author_metadata.clone())
+}
+
+fn push_live_post(app_data: &mut NostrPostAppInternal, target: LiveTarget, post: TimelinePost) {
+    if !app_data.is_logged_in || app_data.mute_list.hides(&post) {
+        return;
+    }
+    let last_seen = app_data.live.last_seen.entry(target).or_insert(post.created_at);
+    *last_seen = (*last_seen).max(post.created_at);
+
+    let (shown, pending) = match target {
+        LiveTarget::Timeline => (&mut app_data.timeline_posts, &mut app_data.new_timeline_posts),
+        LiveTarget::Notifications => (
+            &mut app_data.notification_posts,
+            &mut app_data.new_notification_posts,
+        ),
+    };
+    if shown.iter().any(|p| p.id == post.id) || pending.iter().any(|p| p.id == post.id) {
+        return;
+    }
+    if target == LiveTarget::Notifications {
+        alert_notification(app_data, &post);
+    }
+    let (shown, pending) = match target {
+        LiveTarget::Timeline => (&mut app_data.timeline_posts, &mut app_data.new_timeline_posts),
+        LiveTarget::Notifications => (
+            &mut app_data.notification_posts,
+            &mut app_data.new_notification_posts,
+        ),
+    };
+    // 表示中の一覧が空ならスクロール位置も無いので、そのまま並べる
+    if shown.is_empty() {
+        merge_posts(shown, [post]);
+    } else {
+        merge_posts(pending, [post]);
+    }
+    app_data.should_repaint = true;
+}
@@ -1,25 +1,21 @@
 use eframe::egui;
-use std::fs;
-use std::path::Path;
 use std::sync::{Arc, Mutex};
 use std::collections::HashSet;
-use nostr::{nips::nip47::NostrWalletConnectURI, Keys, PublicKey};
+use nostr::{nips::nip47::NostrWalletConnectURI, Keys, NostrSigner, PublicKey, Timestamp};
 use nostr_sdk::{Client};
 use std::str::FromStr;
 
 use crate::{
-    types::{Config, NostrPostAppInternal, ProfileMetadata, RelayConfig, TimelinePost, AppTab},
-    cache_db::{LmdbCache, DB_FOLLOWED, DB_PROFILES, DB_TIMELINE, DB_NOTIFICATIONS},
-    CONFIG_FILE,
-    ui::events::{refresh_all_data}
+    types::{Account, Config, NostrPostAppInternal, ProfileMetadata, RegisterMode, RelayConfig, RemoteSignerConfig, AppTab},
+    cache_db::{LmdbCache, DB_FOLLOWED, DB_PROFILES},
+    event_store, load_config, nip02, nip51, notifications, nostr_client, write_config,
+    ui::events::{feed_authors, refresh_all_data}
 };
 
 // --- Step 1: キャッシュからデータを読み込む ---
 struct CachedData {
     followed_pubkeys: HashSet<PublicKey>,
     profile_metadata: ProfileMetadata,
-    timeline_posts: Vec<TimelinePost>,
-    notification_posts: Vec<TimelinePost>,
 }
 
 fn load_data_from_cache(
@@ -30,12 +26,6 @@ fn load_data_from_cache(
 
     let followed_cache = cache_db.read_cache::<HashSet<PublicKey>>(DB_FOLLOWED, pubkey_hex)?;
     let profile_cache = cache_db.read_cache::<ProfileMetadata>(DB_PROFILES, pubkey_hex)?;
-    let timeline_cache = cache_db
-        .read_cache::<Vec<TimelinePost>>(DB_TIMELINE, pubkey_hex)
-        .ok();
-    let notification_cache = cache_db
-        .read_cache::<Vec<TimelinePost>>(DB_NOTIFICATIONS, pubkey_hex)
-        .ok();
 
     if followed_cache.is_expired() || profile_cache.is_expired() {
         return Err("Cache expired".into());
@@ -45,11 +35,334 @@ fn load_data_from_cache(
     Ok(CachedData {
         followed_pubkeys: followed_cache.data,
         profile_metadata: profile_cache.data,
-        timeline_posts: timeline_cache.map_or(Vec::new(), |c| c.data),
-        notification_posts: notification_cache.map_or(Vec::new(), |c| c.data),
     })
 }
 
+// --- アカウントの秘密鍵を復号する ---
+// 旧形式 (PBKDF2 + ChaCha20Poly1305) の鍵は NIP-49 形式に書き換える。
+fn unlock_account(
+    index: usize,
+    passphrase: &str,
+) -> Result<(Keys, Option<NostrWalletConnectURI>), Box<dyn std::error::Error + Send + Sync>> {
+    let mut config = load_config();
+    let account = config.accounts.get_mut(index).ok_or("Account not found")?;
+
+    if crate::nip49::is_legacy(&account.encrypted_secret_key) {
+        let decrypted_bytes = crate::nip49::decrypt_legacy(
+            &account.encrypted_secret_key,
+            passphrase,
+            &account.salt,
+        )?;
+        let secret_key: [u8; 32] = decrypted_bytes
+            .as_slice()
+            .try_into()
+            .map_err(|_| "Invalid secret key length")?;
+        let nwc_uri_bytes = match &account.encrypted_nwc_uri {
+            Some(encrypted_nwc) => Some(crate::nip49::decrypt_legacy(encrypted_nwc, passphrase, &account.salt)?),
+            None => None,
+        };
+
+        account.encrypted_secret_key = crate::nip49::encrypt_secret_key(
+            &secret_key,
+            passphrase,
+            crate::nip49::DEFAULT_LOG_N,
+            crate::nip49::KeySecurity::Unknown,
+        )?;
+        account.encrypted_nwc_uri = match nwc_uri_bytes {
+            Some(bytes) => Some(crate::nip49::encrypt(&bytes, passphrase)?),
+            None => None,
+        };
+        account.salt.clear();
+        println!("Migrated account key to NIP-49 (ncryptsec).");
+    }
+
+    let decrypted_bytes = crate::nip49::decrypt_secret_key(&account.encrypted_secret_key, passphrase)?;
+    let keys = Keys::parse(&hex::encode(&decrypted_bytes))?;
+
+    let nwc_uri = if let Some(encrypted_nwc) = &account.encrypted_nwc_uri {
+        let decrypted_nwc_bytes = crate::nip49::decrypt(encrypted_nwc, passphrase)?;
+        let nwc_uri_str = String::from_utf8(decrypted_nwc_bytes)?;
+        Some(NostrWalletConnectURI::from_str(&nwc_uri_str)?)
+    } else {
+        None
+    };
+
+    account.pubkey = keys.public_key().to_hex();
+    config.active_account = index;
+    write_config(&config)?;
+    Ok((keys, nwc_uri))
+}
+
+// --- 登録: nsec/hex または ncryptsec を受け付け、ncryptsec と鍵を返す ---
+// ニーモニックから生成・復元した鍵はアプリ外に出ていないので KeySecurity::Medium で保存する。
+fn encrypt_input_key(
+    secret_key_input: &str,
+    passphrase: &str,
+    key_security: crate::nip49::KeySecurity,
+) -> Result<(Keys, String), Box<dyn std::error::Error + Send + Sync>> {
+    if crate::nip49::is_ncryptsec(secret_key_input) {
+        // Keep the user's ncryptsec (and its log_n / key security byte) as-is.
+        let decrypted_bytes = crate::nip49::decrypt_secret_key(secret_key_input, passphrase)?;
+        let keys = Keys::parse(&hex::encode(&decrypted_bytes))?;
+        Ok((keys, secret_key_input.trim().to_string()))
+    } else {
+        let keys = Keys::parse(secret_key_input.trim())?;
+        let ncryptsec = crate::nip49::encrypt_secret_key(
+            &keys.secret_key().to_secret_bytes(),
+            passphrase,
+            crate::nip49::DEFAULT_LOG_N,
+            key_security,
+        )?;
+        Ok((keys, ncryptsec))
+    }
+}
+
+// --- 登録したアカウントを設定に保存する。秘密鍵のない同じ公開鍵のアカウントは置き換える ---
+fn save_account(account: Account) -> Result<Config, Box<dyn std::error::Error + Send + Sync>> {
+    let mut config = load_config();
+    add_account(&mut config, account)?;
+    if config.theme.is_none() {
+        config.theme = Some(crate::types::AppTheme::Light);
+    }
+    write_config(&config)?;
+    Ok(config)
+}
+
+// 秘密鍵を保存したアカウントは、読み取り専用やリモート署名のログインで上書きしない。
+// 上書きすると暗号化した秘密鍵と NWC の接続先が失われる
+fn add_account(config: &mut Config, account: Account) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    match config.accounts.iter().position(|a| a.pubkey == account.pubkey) {
+        Some(i) if !config.accounts[i].encrypted_secret_key.is_empty() => {
+            return Err(format!(
+                "{} is already saved with its secret key. Log in with that account instead.",
+                config.accounts[i].display_name()
+            )
+            .into());
+        }
+        Some(i) => {
+            config.accounts[i] = account;
+            config.active_account = i;
+        }
+        None => {
+            config.accounts.push(account);
+            config.active_account = config.accounts.len() - 1;
+        }
+    }
+    Ok(())
+}
+
+// --- 署名者が決まった後のセッション開始。ローカル鍵とリモート署名で共通 ---
+async fn start_session(
+    app_data_arc: Arc<Mutex<NostrPostAppInternal>>,
+    signer: Option<Arc<dyn NostrSigner>>,
+    pubkey: PublicKey,
+    nwc_uri: Option<NostrWalletConnectURI>,
+    cache_db: LmdbCache,
+    runtime_handle: tokio::runtime::Handle,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if let Some(uri) = nwc_uri {
+        let app_data_for_nwc_task = app_data_arc.clone();
+        runtime_handle.spawn(async move {
+            if let Err(e) =
+                super::wallet_view::connect_nwc(uri, app_data_for_nwc_task.clone())
+                    .await
+            {
+                eprintln!("Failed to connect to NWC: {}", e);
+                let mut app_data =
+                    app_data_for_nwc_task.lock().unwrap();
+                app_data.nwc_error = Some(format!("NWC auto-connect failed: {}", e));
+            }
+        });
+    }
+
+    let (relay_config, feed, event_db) = {
+        let app_data = app_data_arc.lock().unwrap();
+        (app_data.relays.clone(), app_data.timeline_feed, app_data.event_db.clone())
+    };
+    // 閲覧専用ログインでは署名者なしでクライアントを作る。
+    // 受け取ったイベントと送ったイベントは共有のイベントデータベースに保存される。
+    let read_only = signer.is_none();
+    let client = match &signer {
+        Some(signer) => Client::builder().signer(signer.clone()).database(event_db).build(),
+        None => Client::builder().database(event_db).build(),
+    };
+    // 設定のリレーを読み書きの向きごとに登録して接続する
+    super::relay_panel::apply_relay_config(&client, &relay_config).await;
+    client.connect().await;
+
+    let pubkey_hex = pubkey.to_string();
+    {
+        let mut app_data = app_data_arc.lock().unwrap();
+        if let Ok(cached_data) = load_data_from_cache(&cache_db, &pubkey_hex) {
+            app_data.followed_pubkeys = cached_data.followed_pubkeys;
+            app_data.editable_profile = cached_data.profile_metadata;
+        }
+        app_data.my_signer = signer.clone();
+        app_data.my_pubkey = Some(pubkey);
+        // 初めてのアカウントでは、ログイン時点までの通知を既読として扱う
+        app_data.notifications_read_until = Some(
+            notifications::load_read_until(&cache_db, &pubkey).unwrap_or_else(|| {
+                let now = Timestamp::now();
+                notifications::save_read_until(&cache_db, &pubkey, now);
+                now
+            }),
+        );
+        app_data.nostr_client = Some(client.clone());
+        app_data.is_logged_in = true;
+        app_data.is_loading = true;
+        app_data.current_tab = AppTab::Home;
+    }
+    // ミュートリストはタイムラインより先に読んで、取得結果に反映する
+    let mutes = nip51::load(&client, &cache_db, signer.as_ref(), pubkey).await;
+    // 前回までに保存したイベントから先に表示する
+    let followed_pubkeys = app_data_arc.lock().unwrap().followed_pubkeys.clone();
+    let authors = feed_authors(feed, pubkey, &followed_pubkeys);
+    let (timeline_posts, notification_posts, self_posts) = tokio::join!(
+        nostr_client::stored_timeline_posts(client.database(), authors.as_ref(), &mutes),
+        nostr_client::stored_notification_posts(client.database(), &cache_db, pubkey, &mutes),
+        nostr_client::stored_posts_by_author(client.database(), pubkey),
+    );
+    {
+        let mut app_data = app_data_arc.lock().unwrap();
+        app_data.timeline_posts = timeline_posts;
+        app_data.notification_posts = notification_posts;
+        app_data.profile_posts = self_posts;
+        app_data.mute_list = mutes.clone();
+        app_data.follows.history = nip02::load_history(&cache_db, &pubkey);
+    }
+    let fresh_data_result = refresh_all_data(&client, pubkey, feed, &cache_db, &relay_config, &mutes).await;
+
+    // 閲覧専用では NWC 接続時の自動取得が無いので、ここで Zap 履歴を読み込む。
+    if read_only {
+        let app_data_for_zaps = app_data_arc.clone();
+        runtime_handle.spawn(async move {
+            if let Err(e) = super::wallet_view::get_zap_history(app_data_for_zaps.clone()).await {
+                let mut app_data = app_data_for_zaps.lock().unwrap();
+                app_data.nwc_error = Some(format!("Zap履歴の取得エラー: {}", e));
+            }
+        });
+    }
+
+    // --- Fetch self posts ---
+    let client_clone = client.clone();
+    let app_data_for_self_posts = app_data_arc.clone();
+    runtime_handle.spawn(async move {
+        match nostr_client::fetch_posts_by_author(&client_clone, pubkey, &[], None).await {
+            Ok(page) => {
+                let mut app_data = app_data_for_self_posts.lock().unwrap();
+                event_store::merge_fetched(&mut app_data.profile_posts, page.posts);
+                app_data.should_repaint = true;
+            }
+            Err(e) => eprintln!("Failed to fetch self posts: {}", e),
+        }
+    });
+
+    match fresh_data_result {
+        Ok(fresh_data) => {
+            // Get relay status before updating app_data
+            let relays = client.relays().await;
+            let mut status_log =
+                format!("\n--- 現在接続中のリレー ({}件) ---\n", relays.len());
+            for (url, relay) in relays.iter() {
+                let status = relay.status();
+                status_log.push_str(&format!("  - {}: {:?}\n", url, status));
+            }
+            status_log.push_str("---------------------------------\n");
+
+            let mut app_data = app_data_arc.lock().unwrap();
+            app_data.followed_pubkeys = fresh_data.followed_pubkeys;
+            app_data.timeline_posts = fresh_data.timeline_posts;
+            app_data.timeline_pagination.reset();
+            app_data.notification_posts = fresh_data.notification_posts;
+            app_data.notification_pagination.reset();
+            app_data.connected_relays_display = status_log;
+
+            let my_emojis: std::collections::HashMap<String, String> = fresh_data.profile_metadata.emojis
+                .iter()
+                .map(|emoji_pair| (emoji_pair[0].clone(), emoji_pair[1].clone()))
+                .collect();
+            app_data.my_emojis = my_emojis;
+            app_data.editable_profile = fresh_data.profile_metadata;
+            app_data.nip01_profile_display = fresh_data.profile_json_string;
+            app_data.profile_fetch_status = "Profile loaded.".to_string();
+
+            // --- Fetch NIP-30/51 Emojis with fallback ---
+            let app_data_clone_for_emojis = app_data_arc.clone();
+            runtime_handle.spawn(async move {
+                println!("Spawning emoji fetch task for kind:30030...");
+
+                let custom_emojis = crate::emoji_loader::fetch_emoji_sets(&["wss://yabu.me".to_string()], pubkey).await;
+
+                if !custom_emojis.is_empty() {
+                    println!("Fetched {} custom emojis from kind:30030.", custom_emojis.len());
+                    let mut app_data = app_data_clone_for_emojis.lock().unwrap();
+                    app_data.my_emojis.extend(custom_emojis);
+                    app_data.should_repaint = true;
+                } else {
+                    println!("No custom emojis found from the aggregator relay.");
+                }
+            });
+            // --- End Fetch Emojis ---
+        }
+        Err(e) => {
+            let mut app_data = app_data_arc.lock().unwrap();
+            app_data.profile_fetch_status = format!("Failed to refresh data: {e}");
+        }
+    }
+
+    // 以降の新着はリレーから流れてくるものを取り込む
+    super::relay_panel::start_status_monitor(app_data_arc.clone(), client.clone(), &runtime_handle);
+    if let Some(signer) = signer {
+        super::messages_view::start(app_data_arc.clone(), client.clone(), signer, pubkey, cache_db, &runtime_handle);
+    }
+    super::live::start(app_data_arc.clone(), client, pubkey, &runtime_handle);
+    Ok(())
+}
+
+// --- ログイン処理の後始末。失敗したらクライアントを閉じてエラーを表示する ---
+async fn finish_login(
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    result: Result<(), Box<dyn std::error::Error + Send + Sync>>,
+) {
+    if let Err(e) = result {
+        eprintln!("Login failed: {e}");
+        let client_to_shutdown = {
+            let mut app_data_in_task = app_data_arc.lock().unwrap();
+            app_data_in_task.nostr_client.take()
+        };
+        if let Some(client) = client_to_shutdown { client.shutdown().await; }
+        let mut app_data_in_task = app_data_arc.lock().unwrap();
+        app_data_in_task.profile_fetch_status = format!("Login failed: {e}");
+    }
+    let mut app_data_in_task = app_data_arc.lock().unwrap();
+    app_data_in_task.is_loading = false;
+    app_data_in_task.nip46_auth_url = None;
+    app_data_in_task.should_repaint = true;
+}
+
+fn clear_register_inputs(app_data: &mut NostrPostAppInternal) {
+    app_data.register_mode = RegisterMode::ImportKey;
+    app_data.generated_mnemonic = None;
+    app_data.mnemonic_quiz.clear();
+    app_data.restore_mnemonic_input.clear();
+    app_data.restore_bip39_passphrase_input.clear();
+    app_data.restore_account_index = 0;
+    app_data.bunker_uri_input.clear();
+    app_data.nostrconnect_uri = None;
+    app_data.nostrconnect_app_keys = None;
+    app_data.read_only_input.clear();
+}
+
+// リモート署名から認可 URL が届いたら開けるように表示する。
+fn draw_auth_url(ui: &mut egui::Ui, app_data: &NostrPostAppInternal) {
+    if let Some(auth_url) = &app_data.nip46_auth_url {
+        ui.horizontal(|ui| {
+            ui.label("署名アプリでの承認が必要です:");
+            ui.hyperlink(auth_url);
+        });
+    }
+}
+
 pub fn draw_login_view(
     ui: &mut egui::Ui,
     app_data: &mut NostrPostAppInternal,
@@ -57,212 +370,485 @@ pub fn draw_login_view(
     runtime_handle: tokio::runtime::Handle,
 ) {
     let login_heading_text = "ログインまたは登録";
-    let secret_key_label_text = "秘密鍵 (nsec):";
-    let secret_key_hint_text = "nsec1...";
+    let secret_key_label_text = "秘密鍵 (nsec / ncryptsec):";
+    let secret_key_hint_text = "nsec1... または ncryptsec1...";
     let passphrase_label_text = "パスフレーズ:";
     let passphrase_hint_text = "パスワード";
     let confirm_passphrase_label_text = "パスフレーズの確認:";
     let confirm_passphrase_hint_text = "パスワードを再入力";
     let login_button_text = "ログイン";
     let register_button_text = "登録";
+    let account_label_text = "アカウント:";
+    let add_account_button_text = "＋ アカウントを追加";
+    let account_name_label_text = "アカウント名:";
+    let account_name_hint_text = "例: 個人用";
+    let back_button_text = "戻る";
+    let export_heading_text = "秘密鍵をエクスポート (ncryptsec)";
+    let import_mode_text = "秘密鍵をインポート";
+    let generate_mode_text = "新しい鍵を生成";
+    let restore_mode_text = "ニーモニックから復元";
+    let generate_button_text = "生成";
+    let mnemonic_warning_text = "⚠ この単語を順番通りに紙に書き留めてください。失うと鍵を復元できません。";
+    let quiz_heading_text = "確認のため、次の単語を入力してください:";
+    let restore_words_label_text = "ニーモニック (12 / 24 単語):";
+    let restore_words_hint_text = "単語をスペース区切りで入力";
+    let bip39_passphrase_label_text = "BIP-39 パスフレーズ (任意):";
+    let account_index_label_text = "アカウント番号:";
+    let remote_signer_mode_text = "リモート署名 (NIP-46)";
+    let bunker_uri_label_text = "bunker URI:";
+    let nostrconnect_button_text = "nostrconnect:// URI を生成";
+    let connect_button_text = "接続";
+    let remote_signer_account_text = "リモート署名のアカウントです。署名アプリで接続を承認してください。";
+    let read_only_mode_text = "閲覧のみ (npub)";
+    let read_only_label_text = "npub または NIP-05:";
+    let read_only_hint_text = "npub1... または name@example.com";
+    let read_only_account_text = "閲覧専用のアカウントです。投稿やリアクションはできません。";
 
     ui.group(|ui| {
         ui.heading(login_heading_text);
         ui.add_space(10.0);
-        if Path::new(CONFIG_FILE).exists() {
+        if !app_data.accounts.is_empty() && !app_data.show_add_account {
             // --- ログイン ---
             ui.horizontal(|ui| {
-                ui.label(passphrase_label_text);
-                ui.add(egui::TextEdit::singleline(&mut app_data.passphrase_input)
-                    .password(true)
-                    .hint_text(passphrase_hint_text));
+                ui.label(account_label_text);
+                let selected_text = app_data
+                    .accounts
+                    .get(app_data.active_account)
+                    .map(|a| a.display_name())
+                    .unwrap_or_default();
+                let mut selected = app_data.active_account;
+                egui::ComboBox::from_id_salt("login_account_select")
+                    .selected_text(selected_text)
+                    .show_ui(ui, |ui| {
+                        for (i, account) in app_data.accounts.iter().enumerate() {
+                            ui.selectable_value(&mut selected, i, account.display_name());
+                        }
+                    });
+                if selected != app_data.active_account {
+                    app_data.active_account = selected;
+                    app_data.relays = app_data.accounts[selected].relays.clone();
+                    app_data.passphrase_input.clear();
+                }
+                if ui.button(add_account_button_text).clicked() {
+                    app_data.show_add_account = true;
+                    app_data.passphrase_input.clear();
+                }
             });
+            let remote_signer = app_data
+                .accounts
+                .get(app_data.active_account)
+                .and_then(|a| a.remote_signer.clone());
+            let read_only = app_data
+                .accounts
+                .get(app_data.active_account)
+                .is_some_and(|a| a.read_only);
+            if read_only {
+                ui.label(read_only_account_text);
+            } else if remote_signer.is_some() {
+                ui.label(remote_signer_account_text);
+            } else {
+                ui.horizontal(|ui| {
+                    ui.label(passphrase_label_text);
+                    ui.add(egui::TextEdit::singleline(&mut app_data.passphrase_input)
+                        .password(true)
+                        .hint_text(passphrase_hint_text));
+                });
+            }
+            draw_auth_url(ui, app_data);
 
             if ui.button(egui::RichText::new(login_button_text).strong()).clicked() && !app_data.is_loading {
                 let passphrase = app_data.passphrase_input.clone();
+                let account_index = app_data.active_account;
+                let account_pubkey = app_data.accounts[account_index].pubkey.clone();
                 let cache_db_clone = app_data.cache_db.clone();
                 app_data.is_loading = true;
                 app_data.should_repaint = true;
                 let cloned_app_data_arc = app_data_arc.clone();
                 runtime_handle.clone().spawn(async move {
-                    let app_data_for_login_logic = cloned_app_data_arc.clone();
-                    let login_result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async move {
-                        let (keys, nwc_uri) = (|| -> Result<_, Box<dyn std::error::Error + Send + Sync>> {
-                            let config_str = fs::read_to_string(CONFIG_FILE)?;
-                            let config: Config = serde_json::from_str(&config_str)?;
-                            let decrypted_bytes = crate::nip49::decrypt(
-                                &config.encrypted_secret_key,
-                                &passphrase,
-                                &config.salt,
-                            )?;
-                            let keys = Keys::parse(&hex::encode(&decrypted_bytes))?;
-
-                            let nwc_uri = if let Some(encrypted_nwc) = config.encrypted_nwc_uri {
-                                let decrypted_nwc_bytes = crate::nip49::decrypt(
-                                    &encrypted_nwc,
-                                    &passphrase,
-                                    &config.salt,
-                                )?;
-                                let nwc_uri_str = String::from_utf8(decrypted_nwc_bytes)?;
-                                Some(NostrWalletConnectURI::from_str(&nwc_uri_str)?)
-                            } else {
-                                None
-                            };
-                            Ok((keys, nwc_uri))
-                        })()?;
-
-                        if let Some(uri) = nwc_uri {
-                            let app_data_for_nwc_task = app_data_for_login_logic.clone();
-                            runtime_handle.clone().spawn(async move {
-                                if let Err(e) =
-                                    super::wallet_view::connect_nwc(uri, app_data_for_nwc_task.clone())
-                                        .await
-                                {
-                                    eprintln!("Failed to connect to NWC: {}", e);
-                                    let mut app_data =
-                                        app_data_for_nwc_task.lock().unwrap();
-                                    app_data.nwc_error = Some(format!("NWC auto-connect failed: {}", e));
+                    let login_result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+                        let (signer, pubkey, nwc_uri): (Option<Arc<dyn NostrSigner>>, PublicKey, _) = match remote_signer {
+                            _ if read_only => (None, PublicKey::parse(&account_pubkey)?, None),
+                            Some(remote) => {
+                                let app_keys = Keys::parse(&remote.app_secret_key)?;
+                                let session = crate::nip46::connect(
+                                    &remote.bunker_uri,
+                                    app_keys,
+                                    crate::nip46::REQUEST_TIMEOUT,
+                                    Some(cloned_app_data_arc.clone()),
+                                ).await?;
+                                if session.user_pubkey.to_hex() != account_pubkey {
+                                    return Err("Remote signer returned a different public key".into());
                                 }
-                            });
-                        }
-
-                        let relay_config = {
-                            app_data_for_login_logic.lock().unwrap().relays.clone()
-                        };
-                        let client = Client::new(keys.clone());
-                        // Connect to all relays
-                        let all_relays: Vec<String> = relay_config
-                            .aggregator
-                            .iter()
-                            .chain(relay_config.self_hosted.iter())
-                            .chain(relay_config.search.iter())
-                            .cloned()
-                            .collect::<HashSet<_>>()
-                            .into_iter()
-                            .collect();
-                        for relay_url in &all_relays {
-                            client.add_relay(relay_url.clone()).await?;
-                        }
-                        client.connect().await;
-
-                        let pubkey_hex = keys.public_key().to_string();
-                        if let Ok(cached_data) = load_data_from_cache(&cache_db_clone, &pubkey_hex) {
-                            let mut app_data = app_data_for_login_logic.lock().unwrap();
-                            app_data.my_keys = Some(keys.clone());
-                            app_data.nostr_client = Some(client.clone());
-                            app_data.followed_pubkeys = cached_data.followed_pubkeys;
-                            app_data.timeline_posts = cached_data.timeline_posts;
-                            app_data.notification_posts = cached_data.notification_posts;
-                            app_data.editable_profile = cached_data.profile_metadata;
-                            app_data.is_logged_in = true;
-                            app_data.is_loading = true;
-                        } else {
-                            let mut app_data = app_data_for_login_logic.lock().unwrap();
-                            app_data.my_keys = Some(keys.clone());
-                            app_data.nostr_client = Some(client.clone());
-                            app_data.is_logged_in = true;
-                            app_data.is_loading = true;
-                        }
-                        let fresh_data_result = refresh_all_data(&client, &keys, &cache_db_clone, &relay_config).await;
-
-                        // --- Fetch and cache self posts ---
-                        let client_clone = client.clone();
-                        let keys_clone = keys.clone();
-                        let cache_db_clone_for_self_posts = cache_db_clone.clone();
-                        runtime_handle.clone().spawn(async move {
-                            if let Err(e) = crate::ui::events::fetch_and_cache_self_posts(&client_clone, &keys_clone, &cache_db_clone_for_self_posts).await {
-                                eprintln!("Failed to fetch and cache self posts: {}", e);
+                                (Some(session.signer), session.user_pubkey, None)
                             }
-                        });
-
-                        if let Ok(fresh_data) = fresh_data_result {
-                            // Get relay status before updating app_data
-                            let relays = client.relays().await;
-                            let mut status_log =
-                                format!("\n--- 現在接続中のリレー ({}件) ---\n", relays.len());
-                            for (url, relay) in relays.iter() {
-                                let status = relay.status();
-                                status_log.push_str(&format!("  - {}: {:?}\n", url, status));
+                            None => {
+                                let (keys, nwc_uri) = unlock_account(account_index, &passphrase)?;
+                                let pubkey = keys.public_key();
+                                (Some(Arc::new(keys)), pubkey, nwc_uri)
                             }
-                            status_log.push_str("---------------------------------\n");
-
-                            let mut app_data = app_data_for_login_logic.lock().unwrap();
-                            app_data.followed_pubkeys = fresh_data.followed_pubkeys;
-                            app_data.timeline_posts = fresh_data.timeline_posts;
-                            app_data.notification_posts = fresh_data.notification_posts;
-                            app_data.connected_relays_display = status_log;
-
-                            let my_emojis: std::collections::HashMap<String, String> = fresh_data.profile_metadata.emojis
-                                .iter()
-                                .map(|emoji_pair| (emoji_pair[0].clone(), emoji_pair[1].clone()))
-                                .collect();
-                            app_data.my_emojis = my_emojis;
-                            app_data.editable_profile = fresh_data.profile_metadata;
-                            app_data.nip01_profile_display = fresh_data.profile_json_string;
-                            app_data.profile_fetch_status = "Profile loaded.".to_string();
-
-                            // --- Fetch NIP-30/51 Emojis with fallback ---
-                            let pubkey = keys.public_key();
-                            let app_data_clone_for_emojis = app_data_for_login_logic.clone();
-                            runtime_handle.clone().spawn(async move {
-                                println!("Spawning emoji fetch task for kind:30030...");
-
-                                let custom_emojis = crate::emoji_loader::fetch_emoji_sets(&vec!["wss://yabu.me".to_string()], pubkey).await;
-
-                                if !custom_emojis.is_empty() {
-                                    println!("Fetched {} custom emojis from kind:30030.", custom_emojis.len());
-                                    let mut app_data = app_data_clone_for_emojis.lock().unwrap();
-                                    app_data.my_emojis.extend(custom_emojis);
-                                    app_data.should_repaint = true;
-                                } else {
-                                    println!("No custom emojis found from the aggregator relay.");
-                                }
-                            });
-                            // --- End Fetch Emojis ---
-                        } else if let Err(e) = fresh_data_result {
-                            let mut app_data = app_data_for_login_logic.lock().unwrap();
-                            app_data.profile_fetch_status = format!("Failed to refresh data: {e}");
+                        };
+                        {
+                            let mut app_data = cloned_app_data_arc.lock().unwrap();
+                            app_data.accounts = load_config().accounts;
+                            app_data.passphrase_input.clear();
                         }
-                        Ok(())
+                        start_session(
+                            cloned_app_data_arc.clone(),
+                            signer,
+                            pubkey,
+                            nwc_uri,
+                            cache_db_clone,
+                            runtime_handle.clone(),
+                        ).await
                     }.await;
-                    if let Err(e) = login_result {
-                        let client_to_shutdown = {
-                            let mut app_data_in_task = cloned_app_data_arc.lock().unwrap();
-                            app_data_in_task.nostr_client.take()
-                        };
-                        if let Some(client) = client_to_shutdown { client.shutdown().await; }
-                        let mut app_data_in_task = cloned_app_data_arc.lock().unwrap();
-                        app_data_in_task.profile_fetch_status = format!("Login failed: {e}");
-                    }
-                    let mut app_data_in_task = cloned_app_data_arc.lock().unwrap();
-                    app_data_in_task.is_loading = false;
-                    app_data_in_task.should_repaint = true;
+                    finish_login(&cloned_app_data_arc, login_result).await;
                 });
             }
+
+            ui.add_space(10.0);
+            let has_local_key = app_data
+                .accounts
+                .get(app_data.active_account)
+                .is_some_and(|a| a.remote_signer.is_none() && !a.read_only);
+            egui::CollapsingHeader::new(export_heading_text).show(ui, |ui| {
+                ui.label("暗号化された秘密鍵です。他の NIP-49 対応クライアントにインポートできます。");
+                let ncryptsec = app_data
+                    .accounts
+                    .get(app_data.active_account)
+                    .map(|a| a.encrypted_secret_key.clone())
+                    .filter(|k| crate::nip49::is_ncryptsec(k));
+                match ncryptsec {
+                    Some(ncryptsec) => {
+                        ui.horizontal(|ui| {
+                            ui.add(egui::TextEdit::singleline(&mut ncryptsec.as_str()).desired_width(300.0));
+                            if ui.button("コピー").clicked() {
+                                ui.ctx().copy_text(ncryptsec.clone());
+                            }
+                        });
+                    }
+                    None if !has_local_key => {
+                        ui.label("このアカウントの秘密鍵はこのアプリに保存されていません。");
+                    }
+                    None => {
+                        ui.label("一度ログインすると ncryptsec 形式に移行されます。");
+                    }
+                }
+            });
         } else {
             // --- 新規登録 ---
             ui.horizontal(|ui| {
-                ui.label(secret_key_label_text);
-                ui.add(egui::TextEdit::singleline(&mut app_data.secret_key_input)
-                    .password(true)
-                    .hint_text(secret_key_hint_text));
+                ui.label(account_name_label_text);
+                ui.add(egui::TextEdit::singleline(&mut app_data.account_name_input)
+                    .hint_text(account_name_hint_text));
             });
-
             ui.horizontal(|ui| {
-                ui.label(passphrase_label_text);
-                ui.add(egui::TextEdit::singleline(&mut app_data.passphrase_input)
-                    .password(true)
-                    .hint_text(passphrase_hint_text));
-            });
-            ui.horizontal(|ui| {
-                ui.label(confirm_passphrase_label_text);
-                ui.add(egui::TextEdit::singleline(&mut app_data.confirm_passphrase_input)
-                    .password(true)
-                    .hint_text(confirm_passphrase_hint_text));
+                ui.selectable_value(&mut app_data.register_mode, RegisterMode::ImportKey, import_mode_text);
+                ui.selectable_value(&mut app_data.register_mode, RegisterMode::Generate, generate_mode_text);
+                ui.selectable_value(&mut app_data.register_mode, RegisterMode::Restore, restore_mode_text);
+                ui.selectable_value(&mut app_data.register_mode, RegisterMode::RemoteSigner, remote_signer_mode_text);
+                ui.selectable_value(&mut app_data.register_mode, RegisterMode::ReadOnly, read_only_mode_text);
             });
+            ui.add_space(5.0);
 
-            if ui.button(egui::RichText::new(register_button_text).strong()).clicked() && !app_data.is_loading {
-                let secret_key_input = app_data.secret_key_input.clone();
+            let register_ready = match app_data.register_mode {
+                RegisterMode::ImportKey => {
+                    ui.horizontal(|ui| {
+                        ui.label(secret_key_label_text);
+                        ui.add(egui::TextEdit::singleline(&mut app_data.secret_key_input)
+                            .password(true)
+                            .hint_text(secret_key_hint_text));
+                    });
+                    true
+                }
+                RegisterMode::Generate => {
+                    ui.horizontal(|ui| {
+                        ui.radio_value(&mut app_data.mnemonic_word_count, 12, "12 単語");
+                        ui.radio_value(&mut app_data.mnemonic_word_count, 24, "24 単語");
+                        if ui.button(generate_button_text).clicked() {
+                            match crate::nip06::generate_mnemonic(app_data.mnemonic_word_count) {
+                                Ok(mnemonic) => {
+                                    app_data.generated_mnemonic = Some(mnemonic.to_string());
+                                    app_data.mnemonic_quiz = crate::nip06::quiz_positions(app_data.mnemonic_word_count)
+                                        .into_iter()
+                                        .map(|i| (i, String::new()))
+                                        .collect();
+                                }
+                                Err(e) => {
+                                    app_data.profile_fetch_status = format!("Failed to generate mnemonic: {e}");
+                                }
+                            }
+                        }
+                    });
+
+                    match app_data.generated_mnemonic.clone() {
+                        Some(mnemonic) => {
+                            let words: Vec<&str> = mnemonic.split_whitespace().collect();
+                            ui.colored_label(egui::Color32::from_rgb(200, 120, 0), mnemonic_warning_text);
+                            egui::Grid::new("generated_mnemonic_grid")
+                                .num_columns(4)
+                                .spacing([20.0, 4.0])
+                                .show(ui, |ui| {
+                                    for (i, word) in words.iter().enumerate() {
+                                        ui.monospace(format!("{:>2}. {}", i + 1, word));
+                                        if (i + 1) % 4 == 0 {
+                                            ui.end_row();
+                                        }
+                                    }
+                                });
+                            ui.add_space(5.0);
+                            ui.label(quiz_heading_text);
+                            let mut all_correct = true;
+                            for (position, answer) in app_data.mnemonic_quiz.iter_mut() {
+                                ui.horizontal(|ui| {
+                                    ui.label(format!("{} 番目:", *position + 1));
+                                    ui.add(egui::TextEdit::singleline(answer).desired_width(120.0));
+                                    if answer.trim().to_lowercase() == words[*position] {
+                                        ui.label("✔");
+                                    } else {
+                                        all_correct = false;
+                                    }
+                                });
+                            }
+                            all_correct
+                        }
+                        None => false,
+                    }
+                }
+                RegisterMode::Restore => {
+                    ui.label(restore_words_label_text);
+                    ui.add(egui::TextEdit::multiline(&mut app_data.restore_mnemonic_input)
+                        .desired_rows(3)
+                        .hint_text(restore_words_hint_text));
+                    let valid = if app_data.restore_mnemonic_input.trim().is_empty() {
+                        false
+                    } else {
+                        match crate::nip06::parse_mnemonic(&app_data.restore_mnemonic_input) {
+                            Ok(_) => {
+                                ui.label("✔ 有効なニーモニックです");
+                                true
+                            }
+                            Err(e) => {
+                                ui.colored_label(egui::Color32::RED, format!("無効なニーモニック: {e}"));
+                                false
+                            }
+                        }
+                    };
+                    ui.horizontal(|ui| {
+                        ui.label(bip39_passphrase_label_text);
+                        ui.add(egui::TextEdit::singleline(&mut app_data.restore_bip39_passphrase_input)
+                            .password(true));
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label(account_index_label_text);
+                        ui.add(egui::DragValue::new(&mut app_data.restore_account_index).range(0..=1000));
+                    });
+                    valid
+                }
+                RegisterMode::RemoteSigner => {
+                    ui.horizontal(|ui| {
+                        ui.label(bunker_uri_label_text);
+                        ui.add(egui::TextEdit::singleline(&mut app_data.bunker_uri_input)
+                            .hint_text("bunker://..."));
+                    });
+                    ui.label("または、署名アプリに nostrconnect:// URI を読み込ませます。");
+                    if ui.button(nostrconnect_button_text).clicked() {
+                        let app_keys = Keys::generate();
+                        match crate::nip46::client_uri(&app_keys, &RelayConfig::with_defaults().aggregator) {
+                            Ok(uri) => {
+                                app_data.nostrconnect_uri = Some(uri.to_string());
+                                app_data.nostrconnect_app_keys = Some(app_keys);
+                            }
+                            Err(e) => {
+                                app_data.profile_fetch_status = format!("Failed to create nostrconnect URI: {e}");
+                            }
+                        }
+                    }
+                    if let Some(uri) = app_data.nostrconnect_uri.clone() {
+                        ui.horizontal(|ui| {
+                            ui.add(egui::TextEdit::singleline(&mut uri.as_str()).desired_width(300.0));
+                            if ui.button("コピー").clicked() {
+                                ui.ctx().copy_text(uri.clone());
+                            }
+                        });
+                        ui.label("署名アプリに貼り付けてから「接続」を押してください。");
+                    }
+                    draw_auth_url(ui, app_data);
+                    !app_data.bunker_uri_input.trim().is_empty() || app_data.nostrconnect_uri.is_some()
+                }
+                RegisterMode::ReadOnly => {
+                    ui.horizontal(|ui| {
+                        ui.label(read_only_label_text);
+                        ui.add(egui::TextEdit::singleline(&mut app_data.read_only_input)
+                            .hint_text(read_only_hint_text));
+                    });
+                    ui.label(read_only_account_text);
+                    !app_data.read_only_input.trim().is_empty()
+                }
+            };
+
+            // リモート署名・閲覧専用では秘密鍵を保存しないのでパスフレーズは不要。
+            if !matches!(app_data.register_mode, RegisterMode::RemoteSigner | RegisterMode::ReadOnly) {
+                ui.horizontal(|ui| {
+                    ui.label(passphrase_label_text);
+                    ui.add(egui::TextEdit::singleline(&mut app_data.passphrase_input)
+                        .password(true)
+                        .hint_text(passphrase_hint_text));
+                });
+                ui.horizontal(|ui| {
+                    ui.label(confirm_passphrase_label_text);
+                    ui.add(egui::TextEdit::singleline(&mut app_data.confirm_passphrase_input)
+                        .password(true)
+                        .hint_text(confirm_passphrase_hint_text));
+                });
+            }
+
+            let register_clicked = ui.horizontal(|ui| {
+                let button_text = match app_data.register_mode {
+                    RegisterMode::RemoteSigner => connect_button_text,
+                    RegisterMode::ReadOnly => login_button_text,
+                    _ => register_button_text,
+                };
+                let clicked = ui
+                    .add_enabled(register_ready, egui::Button::new(egui::RichText::new(button_text).strong()))
+                    .clicked();
+                if !app_data.accounts.is_empty() && ui.button(back_button_text).clicked() {
+                    app_data.show_add_account = false;
+                    app_data.secret_key_input.clear();
+                    app_data.account_name_input.clear();
+                    clear_register_inputs(app_data);
+                }
+                clicked
+            }).inner;
+
+            if register_clicked && !app_data.is_loading && app_data.register_mode == RegisterMode::RemoteSigner {
+                // bunker:// が入力されていればそちらを優先し、無ければ生成した nostrconnect:// で待ち受ける。
+                let (uri, app_keys) = if !app_data.bunker_uri_input.trim().is_empty() {
+                    (app_data.bunker_uri_input.trim().to_string(), Keys::generate())
+                } else {
+                    match (app_data.nostrconnect_uri.clone(), app_data.nostrconnect_app_keys.clone()) {
+                        (Some(uri), Some(app_keys)) => (uri, app_keys),
+                        _ => return,
+                    }
+                };
+                let account_name = app_data.account_name_input.trim().to_string();
+                let cache_db_clone = app_data.cache_db.clone();
+                app_data.is_loading = true;
+                app_data.should_repaint = true;
+                let cloned_app_data_arc = app_data_arc.clone();
+                runtime_handle.clone().spawn(async move {
+                    let connect_result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+                        let session = crate::nip46::connect(
+                            &uri,
+                            app_keys.clone(),
+                            crate::nip46::REQUEST_TIMEOUT,
+                            Some(cloned_app_data_arc.clone()),
+                        ).await?;
+                        let relay_config = RelayConfig::with_defaults();
+                        let config = save_account(Account {
+                            name: account_name,
+                            pubkey: session.user_pubkey.to_hex(),
+                            encrypted_secret_key: String::new(),
+                            salt: String::new(),
+                            encrypted_nwc_uri: None,
+                            relays: relay_config.clone(),
+                            remote_signer: Some(RemoteSignerConfig {
+                                bunker_uri: session.bunker_uri,
+                                app_secret_key: app_keys.secret_key().to_secret_hex(),
+                            }),
+                            read_only: false,
+                            blur_unfollowed_media: false,
+                        })?;
+                        {
+                            let mut app_data = cloned_app_data_arc.lock().unwrap();
+                            app_data.accounts = config.accounts;
+                            app_data.active_account = config.active_account;
+                            app_data.relays = relay_config;
+                            app_data.show_add_account = false;
+                            app_data.account_name_input.clear();
+                            clear_register_inputs(&mut app_data);
+                        }
+                        start_session(
+                            cloned_app_data_arc.clone(),
+                            Some(session.signer),
+                            session.user_pubkey,
+                            None,
+                            cache_db_clone,
+                            runtime_handle.clone(),
+                        ).await
+                    }.await;
+                    finish_login(&cloned_app_data_arc, connect_result).await;
+                });
+            } else if register_clicked && !app_data.is_loading && app_data.register_mode == RegisterMode::ReadOnly {
+                let identifier = app_data.read_only_input.trim().to_string();
+                let account_name = app_data.account_name_input.trim().to_string();
+                let cache_db_clone = app_data.cache_db.clone();
+                app_data.is_loading = true;
+                app_data.should_repaint = true;
+                let cloned_app_data_arc = app_data_arc.clone();
+                runtime_handle.clone().spawn(async move {
+                    let watch_result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
+                        let pubkey = crate::nostr_client::resolve_public_key(&identifier).await?;
+                        let relay_config = RelayConfig::with_defaults();
+                        let config = save_account(Account {
+                            name: account_name,
+                            pubkey: pubkey.to_hex(),
+                            encrypted_secret_key: String::new(),
+                            salt: String::new(),
+                            encrypted_nwc_uri: None,
+                            relays: relay_config.clone(),
+                            remote_signer: None,
+                            read_only: true,
+                            blur_unfollowed_media: false,
+                        })?;
+                        {
+                            let mut app_data = cloned_app_data_arc.lock().unwrap();
+                            app_data.accounts = config.accounts;
+                            app_data.active_account = config.active_account;
+                            app_data.relays = relay_config;
+                            app_data.show_add_account = false;
+                            app_data.account_name_input.clear();
+                            app_data.read_only_input.clear();
+                            clear_register_inputs(&mut app_data);
+                        }
+                        start_session(
+                            cloned_app_data_arc.clone(),
+                            None,
+                            pubkey,
+                            None,
+                            cache_db_clone,
+                            runtime_handle.clone(),
+                        ).await
+                    }.await;
+                    finish_login(&cloned_app_data_arc, watch_result).await;
+                });
+            } else if register_clicked && !app_data.is_loading {
+                // ニーモニックからの鍵導出は UI スレッドで行い、以降は秘密鍵のインポートと同じ流れにする。
+                let derived_secret_key = match app_data.register_mode {
+                    RegisterMode::ImportKey | RegisterMode::RemoteSigner | RegisterMode::ReadOnly => Ok(None),
+                    RegisterMode::Generate => app_data
+                        .generated_mnemonic
+                        .as_deref()
+                        .ok_or_else(|| "No mnemonic generated".into())
+                        .and_then(crate::nip06::parse_mnemonic)
+                        .and_then(|m| crate::nip06::derive_keys(&m, "", 0))
+                        .map(Some),
+                    RegisterMode::Restore => crate::nip06::parse_mnemonic(&app_data.restore_mnemonic_input)
+                        .and_then(|m| crate::nip06::derive_keys(
+                            &m,
+                            &app_data.restore_bip39_passphrase_input,
+                            app_data.restore_account_index,
+                        ))
+                        .map(Some),
+                };
+                let (secret_key_input, key_security) = match derived_secret_key {
+                    Ok(Some(keys)) => (keys.secret_key().to_secret_hex(), crate::nip49::KeySecurity::Medium),
+                    Ok(None) => (app_data.secret_key_input.clone(), crate::nip49::KeySecurity::Unknown),
+                    Err(e) => {
+                        app_data.profile_fetch_status = format!("Failed to derive keys: {e}");
+                        return;
+                    }
+                };
+                let account_name = app_data.account_name_input.trim().to_string();
                 let passphrase = app_data.passphrase_input.clone();
                 let confirm_passphrase = app_data.confirm_passphrase_input.clone();
                 let cache_db_clone = app_data.cache_db.clone();
@@ -278,119 +864,81 @@ pub fn draw_login_view(
                         return;
                     }
                     let registration_result: Result<(), Box<dyn std::error::Error + Send + Sync>> = async {
-                        let keys = (|| -> Result<Keys, Box<dyn std::error::Error + Send + Sync>> {
-                            let user_provided_keys = Keys::parse(&secret_key_input)?;
-                            let plaintext_bytes = user_provided_keys.secret_key().to_secret_bytes();
-                            let (nip49_encoded, salt_base64) =
-                                crate::nip49::encrypt(&plaintext_bytes, &passphrase)?;
-                            let config = Config {
-                                encrypted_secret_key: nip49_encoded,
-                                salt: salt_base64,
-                                encrypted_nwc_uri: None,
-                                relays: serde_json::to_value(RelayConfig {
-                                    aggregator: vec!["wss://yabu.me".to_string()],
-                                    ..Default::default()
-                                })?,
-                                theme: Some(crate::types::AppTheme::Light),
-                            };
-                            let config_json = serde_json::to_string_pretty(&config)?;
-                            fs::write(CONFIG_FILE, config_json)?;
-                            Ok(user_provided_keys)
-                        })()?;
-                        let relay_config = {
-                            cloned_app_data_arc.lock().unwrap().relays.clone()
-                        };
-                        let client = Client::new(keys.clone());
-                        // Connect to all relays
-                        let all_relays: Vec<String> = relay_config
-                            .aggregator
-                            .iter()
-                            .chain(relay_config.self_hosted.iter())
-                            .chain(relay_config.search.iter())
-                            .cloned()
-                            .collect::<HashSet<_>>()
-                            .into_iter()
-                            .collect();
-                        for relay_url in &all_relays {
-                            client.add_relay(relay_url.clone()).await?;
-                        }
-                        client.connect().await;
-
-                        let fresh_data_result = refresh_all_data(&client, &keys, &cache_db_clone, &relay_config).await;
-
-                        // --- Fetch and cache self posts ---
-                        let client_clone = client.clone();
-                        let keys_clone = keys.clone();
-                        let cache_db_clone_for_self_posts = cache_db_clone.clone();
-                        runtime_handle.clone().spawn(async move {
-                            if let Err(e) = crate::ui::events::fetch_and_cache_self_posts(&client_clone, &keys_clone, &cache_db_clone_for_self_posts).await {
-                                eprintln!("Failed to fetch and cache self posts: {}", e);
-                            }
-                        });
-
-                        if let Ok(fresh_data) = fresh_data_result {
-                            let relays = client.relays().await;
-                            let mut status_log =
-                                format!("\n--- 現在接続中のリレー ({}件) ---\n", relays.len());
-                            for (url, relay) in relays.iter() {
-                                let status = relay.status();
-                                status_log.push_str(&format!("  - {}: {:?}\n", url, status));
-                            }
-                            status_log.push_str("---------------------------------\n");
-
+                        let (keys, ncryptsec) =
+                            encrypt_input_key(&secret_key_input, &passphrase, key_security)?;
+                        let relay_config = RelayConfig::with_defaults();
+                        let config = save_account(Account {
+                            name: account_name,
+                            pubkey: keys.public_key().to_hex(),
+                            encrypted_secret_key: ncryptsec,
+                            salt: String::new(),
+                            encrypted_nwc_uri: None,
+                            relays: relay_config.clone(),
+                            remote_signer: None,
+                            read_only: false,
+                            blur_unfollowed_media: false,
+                        })?;
+                        {
                             let mut app_data = cloned_app_data_arc.lock().unwrap();
-                            app_data.my_keys = Some(keys.clone());
-                            app_data.nostr_client = Some(client.clone());
-                            app_data.is_logged_in = true;
-                            app_data.current_tab = AppTab::Home;
-                            app_data.followed_pubkeys = fresh_data.followed_pubkeys;
-                            app_data.timeline_posts = fresh_data.timeline_posts;
-                            app_data.connected_relays_display = status_log;
-
-                            let my_emojis: std::collections::HashMap<String, String> = fresh_data.profile_metadata.emojis
-                                .iter()
-                                .map(|emoji_pair| (emoji_pair[0].clone(), emoji_pair[1].clone()))
-                                .collect();
-                            app_data.my_emojis = my_emojis;
-                            app_data.editable_profile = fresh_data.profile_metadata;
-                            app_data.nip01_profile_display = fresh_data.profile_json_string;
-                            app_data.profile_fetch_status = "Profile loaded.".to_string();
-
-                            // --- Fetch NIP-30/51 Emojis with fallback ---
-                            let pubkey = keys.public_key();
-                            let app_data_clone_for_emojis = cloned_app_data_arc.clone();
-                            runtime_handle.clone().spawn(async move {
-                                println!("Spawning emoji fetch task for kind:30030...");
-                                let custom_emojis = crate::emoji_loader::fetch_emoji_sets(&vec!["wss://yabu.me".to_string()], pubkey).await;
-
-                                if !custom_emojis.is_empty() {
-                                    println!("Fetched {} custom emojis from kind:30030.", custom_emojis.len());
-                                    let mut app_data = app_data_clone_for_emojis.lock().unwrap();
-                                    app_data.my_emojis.extend(custom_emojis);
-                                    app_data.should_repaint = true;
-                                } else {
-                                    println!("No custom emojis found from the aggregator relay.");
-                                }
-                            });
-                            // --- End Fetch Emojis ---
-                        } else if let Err(e) = fresh_data_result {
-                            eprintln!("Failed to fetch initial data for registration: {e}");
+                            app_data.accounts = config.accounts;
+                            app_data.active_account = config.active_account;
+                            app_data.relays = relay_config;
+                            app_data.show_add_account = false;
+                            app_data.account_name_input.clear();
+                            app_data.secret_key_input.clear();
+                            app_data.passphrase_input.clear();
+                            app_data.confirm_passphrase_input.clear();
+                            clear_register_inputs(&mut app_data);
                         }
-                        Ok(())
+                        let pubkey = keys.public_key();
+                        start_session(
+                            cloned_app_data_arc.clone(),
+                            Some(Arc::new(keys)),
+                            pubkey,
+                            None,
+                            cache_db_clone,
+                            runtime_handle.clone(),
+                        ).await
                     }.await;
-                    if let Err(e) = registration_result {
-                        eprintln!("Failed to register new key: {e}");
-                        let client_to_shutdown = {
-                            let mut app_data_in_task = cloned_app_data_arc.lock().unwrap();
-                            app_data_in_task.nostr_client.take()
-                        };
-                        if let Some(client) = client_to_shutdown { client.shutdown().await; }
-                    }
-                    let mut app_data_async = cloned_app_data_arc.lock().unwrap();
-                    app_data_async.is_loading = false;
-                    app_data_async.should_repaint = true;
+                    finish_login(&cloned_app_data_arc, registration_result).await;
                 });
             }
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_accounts_with_a_secret_key() {
+        let pubkey = Keys::generate().public_key().to_hex();
+        let watch = |name: &str| Account {
+            name: name.to_string(),
+            pubkey: pubkey.clone(),
+            read_only: true,
+            ..Default::default()
+        };
+        let mut config = Config::default();
+
+        // 読み取り専用どうしなら置き換える
+        add_account(&mut config, watch("a")).unwrap();
+        add_account(&mut config, watch("b")).unwrap();
+        assert_eq!(config.accounts.len(), 1);
+        assert_eq!(config.accounts[0].name, "b");
+
+        config.accounts[0] = Account {
+            encrypted_secret_key: "ncryptsec1...".to_string(),
+            encrypted_nwc_uri: Some("nwc".to_string()),
+            read_only: false,
+            ..watch("key")
+        };
+        assert!(add_account(&mut config, watch("c")).is_err());
+        assert!(add_account(&mut config, Account { read_only: false, ..watch("d") }).is_err());
+        assert_eq!(config.accounts.len(), 1);
+        assert_eq!(config.accounts[0].encrypted_secret_key, "ncryptsec1...");
+        assert_eq!(config.accounts[0].encrypted_nwc_uri.as_deref(), Some("nwc"));
+        assert!(!config.accounts[0].read_only);
+    }
+}
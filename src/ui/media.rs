@@ -0,0 +1,159 @@
+use eframe::egui;
+
+use crate::nip92::{Media, MediaKind};
+use crate::types::{ImageKind, ImageState, NostrPostAppInternal};
+
+// 本文中に出す大きさの上限
+const MAX_WIDTH: f32 = 400.0;
+const MAX_HEIGHT: f32 = 320.0;
+
+// 描画する時点の読み込み状態
+enum Shown {
+    Frame((egui::TextureHandle, Option<std::time::Duration>)),
+    Loading,
+    Failed,
+    NotRequested,
+}
+
+fn display_size(available_width: f32, (width, height): (u32, u32)) -> egui::Vec2 {
+    let (width, height) = (width.max(1) as f32, height.max(1) as f32);
+    let scale = (available_width.min(MAX_WIDTH) / width).min(MAX_HEIGHT / height).min(1.0);
+    egui::vec2(width * scale, height * scale)
+}
+
+fn paint_placeholder(ui: &egui::Ui, app_data: &mut NostrPostAppInternal, rect: egui::Rect, blurhash: Option<&str>) {
+    match blurhash.and_then(|hash| app_data.image_cache.blurhash(ui.ctx(), hash)) {
+        Some(texture) => egui::Image::new(&texture).corner_radius(6.0).paint_at(ui, rect),
+        None => {
+            ui.painter().rect_filled(rect, 6.0, ui.visuals().widgets.inactive.bg_fill);
+        }
+    }
+}
+
+/// Draws an image or a video's poster inline. With `blurred`, only the blurhash is shown
+/// (nothing is downloaded) until it is clicked.
+pub fn draw_media(
+    ui: &mut egui::Ui,
+    app_data: &mut NostrPostAppInternal,
+    media: &Media,
+    blurred: bool,
+    urls_to_load: &mut Vec<(String, ImageKind)>,
+) {
+    // 動画はプレビュー画像があればそれを出す
+    let image_url = match media.kind {
+        MediaKind::Image => Some(media.url.as_str()),
+        MediaKind::Video => media.poster.as_deref(),
+    };
+    let time = ui.input(|i| i.time);
+    let cache = &app_data.image_cache;
+    let (current, dim) = match image_url.map(|url| (url, cache.get(url, ImageKind::Media))) {
+        Some((url, Some(ImageState::Loaded(_)))) => (
+            cache.current_frame(url, ImageKind::Media, time).map_or(Shown::Loading, Shown::Frame),
+            cache.dimensions(url, ImageKind::Media),
+        ),
+        Some((_, Some(ImageState::Failed))) => (Shown::Failed, None),
+        Some((_, None)) => (Shown::NotRequested, None),
+        _ => (Shown::Loading, None),
+    };
+    if media.kind == MediaKind::Image && matches!(current, Shown::Failed) {
+        ui.hyperlink(&media.url);
+        return;
+    }
+    let default_dim = match media.kind {
+        MediaKind::Image => (400, 300),
+        MediaKind::Video => (640, 360),
+    };
+    let dim = dim.or(media.dim).unwrap_or(default_dim);
+    let (rect, response) = ui.allocate_exact_size(display_size(ui.available_width(), dim), egui::Sense::click());
+    let response = response.on_hover_cursor(egui::CursorIcon::PointingHand);
+
+    if blurred && !app_data.revealed_media.contains(&media.url) {
+        paint_placeholder(ui, app_data, rect, media.blurhash.as_deref());
+        ui.painter().text(
+            rect.center(),
+            egui::Align2::CENTER_CENTER,
+            "クリックして表示",
+            egui::FontId::proportional(14.0),
+            egui::Color32::WHITE,
+        );
+        if response.on_hover_text("フォローしていない人のメディアです").clicked() {
+            app_data.revealed_media.insert(media.url.clone());
+        }
+        return;
+    }
+
+    match current {
+        Shown::Frame((texture, next_frame)) => {
+            egui::Image::new(&texture).corner_radius(6.0).paint_at(ui, rect);
+            if let Some(next_frame) = next_frame {
+                ui.ctx().request_repaint_after(next_frame);
+            }
+        }
+        shown => {
+            paint_placeholder(ui, app_data, rect, media.blurhash.as_deref());
+            if let (Some(url), Shown::NotRequested) = (image_url, &shown) {
+                urls_to_load.push((url.to_string(), ImageKind::Media));
+            }
+            if image_url.is_some() && !matches!(shown, Shown::Failed) {
+                ui.put(rect, egui::Spinner::new());
+            }
+        }
+    }
+
+    match media.kind {
+        MediaKind::Image => {
+            let response = match &media.alt {
+                Some(alt) => response.on_hover_text(alt),
+                None => response,
+            };
+            if response.clicked() {
+                app_data.lightbox = Some(media.url.clone());
+            }
+        }
+        MediaKind::Video => {
+            let painter = ui.painter();
+            painter.circle_filled(rect.center(), 22.0, egui::Color32::from_black_alpha(160));
+            painter.text(rect.center(), egui::Align2::CENTER_CENTER, "▶", egui::FontId::proportional(20.0), egui::Color32::WHITE);
+            if response.on_hover_text("外部のプレーヤーで開く").clicked() {
+                ui.ctx().open_url(egui::OpenUrl::new_tab(&media.url));
+            }
+            ui.hyperlink_to(egui::RichText::new("↗ 外部で開く").small(), &media.url);
+        }
+    }
+}
+
+/// The image opened from a note, over everything else. Esc or a click outside closes it.
+pub fn draw_lightbox(ctx: &egui::Context, app_data: &mut NostrPostAppInternal) {
+    let Some(url) = app_data.lightbox.clone() else {
+        return;
+    };
+    let mut close = false;
+    let modal = egui::Modal::new(egui::Id::new("media_lightbox")).show(ctx, |ui| {
+        let cache = &app_data.image_cache;
+        match cache.current_frame(&url, ImageKind::Media, ui.input(|i| i.time)).zip(cache.dimensions(&url, ImageKind::Media)) {
+            Some(((texture, next_frame), size)) => {
+                let max = ctx.screen_rect().size() * 0.85;
+                let (width, height) = (size.0.max(1) as f32, size.1.max(1) as f32);
+                let scale = (max.x / width).min(max.y / height).min(1.0);
+                ui.add(egui::Image::new(&texture).fit_to_exact_size(egui::vec2(width * scale, height * scale)));
+                if let Some(next_frame) = next_frame {
+                    ctx.request_repaint_after(next_frame);
+                }
+            }
+            _ => {
+                ui.spinner();
+            }
+        }
+        ui.horizontal(|ui| {
+            if ui.button("ブラウザで開く").clicked() {
+                ctx.open_url(egui::OpenUrl::new_tab(&url));
+            }
+            if ui.button("閉じる").clicked() {
+                close = true;
+            }
+        });
+    });
+    if close || modal.should_close() {
+        app_data.lightbox = None;
+    }
+}
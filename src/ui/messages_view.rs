@@ -0,0 +1,506 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use eframe::egui;
+use nostr::nips::nip19::ToBech32;
+use nostr::{Event, Filter, Kind, NostrSigner, PublicKey, SubscriptionId};
+use nostr_sdk::prelude::{FlagCheck, RelayServiceFlags};
+use nostr_sdk::{Client, RelayPoolNotification};
+use tokio::runtime::Handle;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+
+use crate::{
+    cache_db::LmdbCache,
+    desktop_notify::{self, AlertKind},
+    nip17::{self, MessageStore},
+    nip65, nostr_client,
+    types::{NostrPostAppInternal, ProfileMetadata},
+};
+
+const SUBSCRIPTION_PREFIX: &str = "direct-messages";
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// メッセージ画面の状態。ログイン中は受信タスクが store を更新し続ける。
+#[derive(Default)]
+pub struct MessagesState {
+    pub store: MessageStore,
+    pub selected: Option<PublicKey>,
+    pub message_input: String,
+    pub peer_input: String,
+    pub error: Option<String>,
+    pub is_loading: bool,
+    pub is_sending: bool,
+    pub is_resolving: bool,
+    // DB_MESSAGES の暗号鍵。署名者で開けるまでは None
+    secret: Option<[u8; 32]>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl MessagesState {
+    // 受信タスクを止めて、復号済みの会話をメモリから消す
+    pub fn stop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        *self = Self::default();
+    }
+}
+
+fn save_store(app_data: &NostrPostAppInternal, me: &PublicKey) {
+    let Some(secret) = app_data.messages.secret else { return };
+    if let Err(e) = app_data.messages.store.save(&app_data.cache_db, me, &secret) {
+        eprintln!("Failed to save messages: {e}");
+    }
+}
+
+/// Opens the encrypted message store and keeps receiving NIP-17 / NIP-04 messages for `pubkey`.
+pub fn start(
+    app_data_arc: Arc<Mutex<NostrPostAppInternal>>,
+    client: Client,
+    signer: Arc<dyn NostrSigner>,
+    pubkey: PublicKey,
+    cache_db: LmdbCache,
+    runtime_handle: &Handle,
+) {
+    let arc = app_data_arc.clone();
+    let task = runtime_handle.spawn(async move {
+        {
+            let mut app_data = arc.lock().unwrap();
+            app_data.messages.is_loading = true;
+            app_data.messages.error = None;
+        }
+        let secret = match nip17::unlock_store_key(&cache_db, &signer, pubkey).await {
+            Ok(secret) => secret,
+            Err(e) => {
+                eprintln!("Failed to unlock message store: {e}");
+                let mut app_data = arc.lock().unwrap();
+                app_data.messages.is_loading = false;
+                app_data.messages.error = Some(format!("メッセージの保存領域を開けませんでした: {e}"));
+                app_data.should_repaint = true;
+                return;
+            }
+        };
+        let store = MessageStore::load(&cache_db, &pubkey, &secret).unwrap_or_else(|e| {
+            eprintln!("Failed to load messages: {e}");
+            MessageStore::default()
+        });
+        let since = store.since();
+        let peers: HashSet<PublicKey> = store.conversations.keys().copied().collect();
+        {
+            let mut app_data = arc.lock().unwrap();
+            app_data.messages.store = store;
+            app_data.messages.secret = Some(secret);
+            app_data.should_repaint = true;
+        }
+        fetch_peer_profiles(&arc, &client, peers).await;
+
+        // 自分の受信リレー (kind 10050) と設定の読み込みリレーの両方を見る
+        let mut relays = match nip17::inbox_relays(&client, &cache_db, pubkey).await {
+            // kind 10050 がないと NIP-17 のクライアントから DM が届かないので、設定から公開する
+            Ok(relays) if relays.is_empty() => {
+                let config = arc.lock().unwrap().relays.clone();
+                match nip17::publish_dm_relays(&client, &cache_db, &signer, &config).await {
+                    Ok(_) => nip17::own_dm_relays(&config),
+                    Err(e) => {
+                        eprintln!("Failed to publish DM relays: {e}");
+                        Vec::new()
+                    }
+                }
+            }
+            Ok(relays) => relays,
+            Err(e) => {
+                eprintln!("Failed to fetch DM relays: {e}");
+                Vec::new()
+            }
+        };
+        let read_relays = client
+            .pool()
+            .relays_with_flag(RelayServiceFlags::READ, FlagCheck::All)
+            .await;
+        for url in read_relays.into_keys().map(|url| url.to_string()) {
+            if !relays.contains(&url) {
+                relays.push(url);
+            }
+        }
+        nip65::ensure_relays(&client, &relays).await;
+
+        // 新着だけを流す REQ を先に張ってから過去分を取る。重なった分は seen で弾く。
+        let mut notifications = client.notifications();
+        let filters = nip17::message_filters(pubkey, since);
+        let mut subscription_ids = Vec::new();
+        for (i, filter) in filters.iter().enumerate() {
+            let id = SubscriptionId::new(format!("{SUBSCRIPTION_PREFIX}-{i}"));
+            let live_filter = filter.clone().limit(0);
+            if let Err(e) = client
+                .subscribe_with_id_to(relays.clone(), id.clone(), live_filter, None)
+                .await
+            {
+                eprintln!("Failed to subscribe to messages: {e}");
+            }
+            subscription_ids.push(id);
+        }
+
+        let mut events = Vec::new();
+        for filter in filters {
+            match client.fetch_events_from(relays.clone(), filter, FETCH_TIMEOUT).await {
+                Ok(fetched) => events.extend(fetched),
+                Err(e) => eprintln!("Failed to fetch messages: {e}"),
+            }
+        }
+        receive(&arc, &client, &signer, pubkey, events, false).await;
+        {
+            let mut app_data = arc.lock().unwrap();
+            app_data.messages.is_loading = false;
+            app_data.should_repaint = true;
+        }
+
+        loop {
+            match notifications.recv().await {
+                Ok(RelayPoolNotification::Event { subscription_id, event, .. }) => {
+                    if subscription_ids.contains(&subscription_id) {
+                        receive(&arc, &client, &signer, pubkey, vec![*event], true).await;
+                    }
+                }
+                Ok(RelayPoolNotification::Shutdown) => break,
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    eprintln!("Message subscription lagged, skipped {skipped} notifications");
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+    app_data_arc.lock().unwrap().messages.task = Some(task);
+}
+
+// 未処理のイベントを復号して会話に加え、暗号化して保存する
+async fn receive(
+    arc: &Arc<Mutex<NostrPostAppInternal>>,
+    client: &Client,
+    signer: &Arc<dyn NostrSigner>,
+    me: PublicKey,
+    events: Vec<Event>,
+    // ライブで届いたものだけデスクトップ通知する。履歴の取得では出さない。
+    alert: bool,
+) {
+    let events: Vec<Event> = {
+        let app_data = arc.lock().unwrap();
+        events
+            .into_iter()
+            .filter(|event| !app_data.messages.store.is_seen(event))
+            .collect()
+    };
+    if events.is_empty() {
+        return;
+    }
+
+    let mut processed = Vec::new();
+    let mut opened = Vec::new();
+    for event in &events {
+        match nip17::open_event(signer, me, event).await {
+            Ok(found) => {
+                processed.push(event);
+                opened.extend(found);
+            }
+            // 失敗したものは seen にしない。次回の起動でもう一度試す。
+            Err(e) => eprintln!("Failed to decrypt message {}: {e}", event.id),
+        }
+    }
+
+    let new_peers: HashSet<PublicKey> = {
+        let mut app_data = arc.lock().unwrap();
+        for event in processed {
+            app_data.messages.store.mark_seen(event);
+        }
+        let mut new_peers = HashSet::new();
+        for (peer, message) in opened {
+            let (sender, created_at, content) = (message.sender, message.created_at, message.content.clone());
+            if !app_data.messages.store.insert(peer, message) {
+                continue;
+            }
+            if alert && sender != me {
+                let summary = format!("{}からのメッセージ", peer_name(&app_data, &sender));
+                desktop_notify::alert(&app_data, AlertKind::Message, created_at, summary, &content);
+            }
+            if !app_data.profile_cache.contains_key(&peer) {
+                new_peers.insert(peer);
+            }
+        }
+        save_store(&app_data, &me);
+        app_data.should_repaint = true;
+        new_peers
+    };
+    fetch_peer_profiles(arc, client, new_peers).await;
+}
+
+// 会話相手のうち、名前がまだわからない人のプロフィールをまとめて取る
+async fn fetch_peer_profiles(
+    arc: &Arc<Mutex<NostrPostAppInternal>>,
+    client: &Client,
+    peers: HashSet<PublicKey>,
+) {
+    let missing: Vec<PublicKey> = {
+        let app_data = arc.lock().unwrap();
+        peers
+            .into_iter()
+            .filter(|pk| !app_data.profile_cache.contains_key(pk))
+            .collect()
+    };
+    if missing.is_empty() {
+        return;
+    }
+    let filter = Filter::new().authors(missing).kind(Kind::Metadata);
+    let profiles: HashMap<PublicKey, ProfileMetadata> = match client.fetch_events(filter, FETCH_TIMEOUT).await {
+        Ok(events) => events
+            .into_iter()
+            .filter_map(|event| {
+                serde_json::from_str::<ProfileMetadata>(&event.content)
+                    .ok()
+                    .map(|metadata| (event.pubkey, metadata))
+            })
+            .collect(),
+        Err(e) => {
+            eprintln!("Failed to fetch message peer profiles: {e}");
+            return;
+        }
+    };
+    let mut app_data = arc.lock().unwrap();
+    app_data.profile_cache.extend(profiles);
+    app_data.should_repaint = true;
+}
+
+fn peer_name(app_data: &NostrPostAppInternal, peer: &PublicKey) -> String {
+    match app_data.profile_cache.get(peer) {
+        Some(metadata) if !metadata.name.is_empty() => metadata.name.clone(),
+        _ => {
+            let npub = peer.to_bech32().unwrap_or_default();
+            format!("{}...{}", &npub[0..8], &npub[npub.len() - 4..])
+        }
+    }
+}
+
+fn format_time(created_at: nostr::Timestamp) -> String {
+    let datetime = chrono::DateTime::from_timestamp(created_at.as_u64() as i64, 0).unwrap_or_default();
+    datetime.with_timezone(&chrono::Local).format("%m/%d %H:%M").to_string()
+}
+
+pub fn draw_messages_view(
+    ui: &mut egui::Ui,
+    app_data: &mut NostrPostAppInternal,
+    app_data_arc: Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    ui.horizontal(|ui| {
+        ui.heading("メッセージ");
+        if app_data.messages.is_loading {
+            ui.add_space(10.0);
+            ui.spinner();
+            ui.label("メッセージを読み込み中...");
+        }
+    });
+    if app_data.my_signer.is_none() {
+        ui.add_space(10.0);
+        ui.label(crate::READ_ONLY_HINT);
+        return;
+    }
+    if let Some(error) = &app_data.messages.error {
+        ui.colored_label(ui.visuals().error_fg_color, error);
+    }
+    ui.add_space(10.0);
+
+    egui::SidePanel::left("conversation_list")
+        .frame(egui::Frame::NONE)
+        .resizable(false)
+        .exact_width(220.0)
+        .show_inside(ui, |ui| {
+            draw_conversation_list(ui, app_data, &app_data_arc, runtime_handle);
+        });
+    egui::CentralPanel::default()
+        .frame(egui::Frame::NONE.inner_margin(egui::Margin { left: 12, ..Default::default() }))
+        .show_inside(ui, |ui| {
+            draw_conversation(ui, app_data, &app_data_arc, runtime_handle);
+        });
+}
+
+fn draw_conversation_list(
+    ui: &mut egui::Ui,
+    app_data: &mut NostrPostAppInternal,
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    // --- 新しい会話 ---
+    ui.horizontal(|ui| {
+        ui.add(
+            egui::TextEdit::singleline(&mut app_data.messages.peer_input)
+                .hint_text("npub / NIP-05")
+                .desired_width(150.0),
+        );
+        let can_open = !app_data.messages.is_resolving && !app_data.messages.peer_input.trim().is_empty();
+        if ui.add_enabled(can_open, egui::Button::new("開く")).clicked() {
+            let input = app_data.messages.peer_input.trim().to_string();
+            app_data.messages.is_resolving = true;
+            let arc = app_data_arc.clone();
+            let client = app_data.nostr_client.clone();
+            runtime_handle.spawn(async move {
+                let result = nostr_client::resolve_public_key(&input).await;
+                let peer = {
+                    let mut app_data = arc.lock().unwrap();
+                    app_data.messages.is_resolving = false;
+                    app_data.should_repaint = true;
+                    match result {
+                        Ok(peer) => {
+                            app_data.messages.selected = Some(peer);
+                            app_data.messages.peer_input.clear();
+                            app_data.messages.error = None;
+                            peer
+                        }
+                        Err(e) => {
+                            app_data.messages.error = Some(format!("相手が見つかりませんでした: {e}"));
+                            return;
+                        }
+                    }
+                };
+                if let Some(client) = client {
+                    fetch_peer_profiles(&arc, &client, HashSet::from([peer])).await;
+                }
+            });
+        }
+    });
+    ui.separator();
+
+    // 最後のメッセージが新しい順
+    let mut peers: Vec<(PublicKey, nostr::Timestamp)> = app_data
+        .messages
+        .store
+        .conversations
+        .iter()
+        .filter_map(|(peer, messages)| messages.last().map(|m| (*peer, m.created_at)))
+        .collect();
+    peers.sort_by_key(|(_, last)| std::cmp::Reverse(*last));
+
+    egui::ScrollArea::vertical()
+        .id_salt("conversation_list_scroll")
+        .auto_shrink([false, false])
+        .show(ui, |ui| {
+            if peers.is_empty() && !app_data.messages.is_loading {
+                ui.label("まだメッセージはありません。");
+            }
+            for (peer, _) in peers {
+                let name = peer_name(app_data, &peer);
+                let preview: String = app_data.messages.store.conversations[&peer]
+                    .last()
+                    .map(|m| m.content.lines().next().unwrap_or_default().chars().take(20).collect())
+                    .unwrap_or_default();
+                let selected = app_data.messages.selected == Some(peer);
+                let text = format!("{name}\n{preview}");
+                if ui.selectable_label(selected, text).clicked() {
+                    app_data.messages.selected = Some(peer);
+                }
+            }
+        });
+}
+
+fn draw_conversation(
+    ui: &mut egui::Ui,
+    app_data: &mut NostrPostAppInternal,
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    let Some(peer) = app_data.messages.selected else {
+        ui.label("左の一覧から会話を選ぶか、相手の npub / NIP-05 を入力してください。");
+        return;
+    };
+    let me = app_data.my_pubkey;
+    ui.heading(peer_name(app_data, &peer));
+    ui.separator();
+
+    egui::ScrollArea::vertical()
+        .id_salt("conversation_scroll")
+        .stick_to_bottom(true)
+        .auto_shrink([false, false])
+        .max_height(ui.available_height() - 70.0)
+        .show(ui, |ui| {
+            let Some(messages) = app_data.messages.store.conversations.get(&peer) else { return };
+            let bubble_width = ui.available_width() * 0.7;
+            for message in messages {
+                let mine = Some(message.sender) == me;
+                let layout = if mine {
+                    egui::Layout::right_to_left(egui::Align::TOP)
+                } else {
+                    egui::Layout::left_to_right(egui::Align::TOP)
+                };
+                ui.with_layout(layout, |ui| {
+                    let fill = if mine {
+                        ui.visuals().selection.bg_fill
+                    } else {
+                        ui.visuals().faint_bg_color
+                    };
+                    egui::Frame::new()
+                        .fill(fill)
+                        .corner_radius(8.0)
+                        .inner_margin(8.0)
+                        .show(ui, |ui| {
+                            ui.set_max_width(bubble_width);
+                            ui.vertical(|ui| {
+                                ui.label(&message.content);
+                                let mut meta = format_time(message.created_at);
+                                if message.legacy {
+                                    meta.push_str(" (NIP-04)");
+                                }
+                                ui.label(egui::RichText::new(meta).small().weak());
+                            });
+                        });
+                });
+                ui.add_space(4.0);
+            }
+        });
+
+    // --- 送信 ---
+    ui.separator();
+    ui.horizontal(|ui| {
+        ui.add(
+            egui::TextEdit::multiline(&mut app_data.messages.message_input)
+                .hint_text("メッセージを入力")
+                .desired_rows(2)
+                .desired_width(ui.available_width() - 80.0),
+        );
+        let is_sending = app_data.messages.is_sending;
+        let can_send = !is_sending && !app_data.messages.message_input.trim().is_empty();
+        let label = if is_sending { "送信中..." } else { "送信" };
+        if ui.add_enabled(can_send, egui::Button::new(label)).clicked() {
+            let (Some(client), Some(signer), Some(me)) =
+                (app_data.nostr_client.clone(), app_data.my_signer.clone(), me)
+            else {
+                return;
+            };
+            let content = std::mem::take(&mut app_data.messages.message_input);
+            app_data.messages.is_sending = true;
+            app_data.messages.error = None;
+            let arc = app_data_arc.clone();
+            let cache_db = app_data.cache_db.clone();
+            runtime_handle.spawn(async move {
+                let result =
+                    nip17::send_message(&client, &cache_db, &signer, me, peer, content.clone()).await;
+                let mut app_data = arc.lock().unwrap();
+                app_data.messages.is_sending = false;
+                match result {
+                    Ok(message) => {
+                        app_data.messages.store.insert(peer, message);
+                        save_store(&app_data, &me);
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to send message: {e}");
+                        app_data.messages.error = Some(format!("送信に失敗しました: {e}"));
+                        // 入力し直さなくて済むように戻す
+                        if app_data.messages.message_input.is_empty() {
+                            app_data.messages.message_input = content;
+                        }
+                    }
+                }
+                app_data.should_repaint = true;
+            });
+        }
+    });
+}
@@ -0,0 +1,260 @@
+use std::sync::{Arc, Mutex};
+
+use eframe::egui;
+use nostr::nips::nip19::{FromBech32, Nip19Event, Nip19Profile, ToBech32};
+use nostr::{EventId, PublicKey};
+use tokio::runtime::Handle;
+
+use crate::{
+    nip10::ThreadRefs,
+    nip51::{self, MuteChange, MuteItem},
+    types::{NostrPostAppInternal, TimelinePost},
+    READ_ONLY_HINT,
+};
+
+// 新しいミュートで隠れる投稿を、表示中の一覧から取り除く
+fn hide_muted(app_data: &mut NostrPostAppInternal) {
+    let mutes = &app_data.mute_list;
+    mutes.retain_visible(&mut app_data.timeline_posts);
+    mutes.retain_visible(&mut app_data.new_timeline_posts);
+    mutes.retain_visible(&mut app_data.notification_posts);
+    mutes.retain_visible(&mut app_data.new_notification_posts);
+    mutes.retain_visible(&mut app_data.search_results);
+    app_data.should_repaint = true;
+}
+
+pub fn can_edit(app_data: &NostrPostAppInternal) -> bool {
+    app_data.my_signer.is_some() && !app_data.mute_list.private_locked
+}
+
+/// Applies `change` right away, then applies it to the newest list on the relays and
+/// publishes that as the new kind 10000 event. The previous list comes back if publishing fails.
+fn update_mute_list(
+    app_data: &mut NostrPostAppInternal,
+    change: MuteChange,
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    let (Some(client), Some(signer), Some(me)) =
+        (app_data.nostr_client.clone(), app_data.my_signer.clone(), app_data.my_pubkey)
+    else {
+        return;
+    };
+    let mut list = app_data.mute_list.clone();
+    list.apply(&change);
+    let previous = std::mem::replace(&mut app_data.mute_list, list.clone());
+    hide_muted(app_data);
+    app_data.mute_status = "ミュートリストを公開中...".to_string();
+    let cache_db = app_data.cache_db.clone();
+    let arc = app_data_arc.clone();
+    runtime_handle.spawn(async move {
+        let result = nip51::publish_change(&client, &cache_db, &signer, me, &previous, &change).await;
+        let mut app_data = arc.lock().unwrap();
+        match result {
+            Ok(published) => {
+                // 他のクライアントでの変更も取り込む
+                if app_data.mute_list == list {
+                    app_data.mute_list = published;
+                    hide_muted(&mut app_data);
+                }
+                app_data.mute_status = "ミュートリストを更新しました".to_string();
+            }
+            Err(e) => {
+                eprintln!("Failed to publish mute list: {e}");
+                // 後から別の変更が入っていなければ元に戻す
+                if app_data.mute_list == list {
+                    app_data.mute_list = previous;
+                }
+                app_data.mute_status = format!("ミュートリストを更新できませんでした: {e}");
+            }
+        }
+        app_data.should_repaint = true;
+    });
+}
+
+/// Mutes `item`, or unmutes it if it is already in the list.
+pub fn toggle(
+    app_data: &mut NostrPostAppInternal,
+    item: MuteItem,
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    let change = if app_data.mute_list.contains(&item) {
+        MuteChange::Remove(item)
+    } else {
+        MuteChange::Add(item, app_data.mute_privately)
+    };
+    update_mute_list(app_data, change, app_data_arc, runtime_handle);
+}
+
+fn short_npub(pubkey: &PublicKey) -> String {
+    let npub = pubkey.to_bech32().unwrap_or_default();
+    format!("{}...{}", &npub[0..8], &npub[npub.len() - 4..])
+}
+
+fn pubkey_name(app_data: &NostrPostAppInternal, pubkey: &PublicKey) -> String {
+    match app_data.profile_cache.get(pubkey) {
+        Some(metadata) if !metadata.name.is_empty() => metadata.name.clone(),
+        _ => short_npub(pubkey),
+    }
+}
+
+fn item_label(app_data: &NostrPostAppInternal, item: &MuteItem) -> String {
+    match item {
+        MuteItem::Pubkey(pubkey) => format!("👤 {}", pubkey_name(app_data, pubkey)),
+        MuteItem::Hashtag(hashtag) => format!("# {hashtag}"),
+        MuteItem::Word(word) => format!("💬 {word}"),
+        MuteItem::Thread(id) => {
+            let note = id.to_bech32().unwrap_or_default();
+            format!("🧵 {}...{}", &note[0..9], &note[note.len() - 4..])
+        }
+    }
+}
+
+// 入力欄の文字列を項目にする。#タグ、npub/nprofile、note/nevent 以外は語句として扱う。
+fn parse_input(input: &str) -> Option<MuteItem> {
+    let input = input.trim();
+    let bech32 = input.strip_prefix("nostr:").unwrap_or(input);
+    if let Some(hashtag) = input.strip_prefix('#') {
+        let hashtag = hashtag.trim().to_lowercase();
+        return (!hashtag.is_empty()).then_some(MuteItem::Hashtag(hashtag));
+    }
+    if let Ok(pubkey) = PublicKey::from_bech32(bech32) {
+        return Some(MuteItem::Pubkey(pubkey));
+    }
+    if let Ok(profile) = Nip19Profile::from_bech32(bech32) {
+        return Some(MuteItem::Pubkey(profile.public_key));
+    }
+    if let Ok(id) = EventId::from_bech32(bech32) {
+        return Some(MuteItem::Thread(id));
+    }
+    if let Ok(event) = Nip19Event::from_bech32(bech32) {
+        return Some(MuteItem::Thread(event.event_id));
+    }
+    (!input.is_empty()).then(|| MuteItem::Word(input.to_lowercase()))
+}
+
+/// Mute actions for one post, shown in its "⋯" menu and on right click.
+pub fn draw_post_menu(
+    ui: &mut egui::Ui,
+    app_data: &mut NostrPostAppInternal,
+    post: &TimelinePost,
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    let mut items = Vec::new();
+    if app_data.my_pubkey != Some(post.author_pubkey) {
+        items.push(MuteItem::Pubkey(post.author_pubkey));
+    }
+    let thread = ThreadRefs::parse(&post.tags).root.map_or(post.id, |root| root.id);
+    items.push(MuteItem::Thread(thread));
+    for tag in &post.tags {
+        if let [name, hashtag, ..] = tag.as_slice() {
+            let item = MuteItem::Hashtag(hashtag.to_lowercase());
+            if name == "t" && !hashtag.is_empty() && !items.contains(&item) {
+                items.push(item);
+            }
+        }
+    }
+
+    let enabled = can_edit(app_data);
+    for item in items {
+        let target = match &item {
+            MuteItem::Pubkey(pubkey) => pubkey_name(app_data, pubkey),
+            MuteItem::Thread(_) => "このスレッド".to_string(),
+            MuteItem::Hashtag(hashtag) => format!("#{hashtag}"),
+            MuteItem::Word(word) => word.clone(),
+        };
+        let text = if app_data.mute_list.contains(&item) {
+            format!("{target} のミュートを解除")
+        } else {
+            format!("{target} をミュート")
+        };
+        if ui
+            .add_enabled(enabled, egui::Button::new(text))
+            .on_disabled_hover_text(READ_ONLY_HINT)
+            .clicked()
+        {
+            toggle(app_data, item, app_data_arc, runtime_handle);
+            ui.close();
+        }
+    }
+    ui.separator();
+    ui.checkbox(&mut app_data.mute_privately, "非公開でミュートする");
+}
+
+/// The muted-items manager in the settings.
+pub fn draw_mute_panel(
+    ui: &mut egui::Ui,
+    app_data: &mut NostrPostAppInternal,
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    ui.heading("ミュート (NIP-51)");
+    ui.add_space(10.0);
+    ui.label("ミュートしたユーザー・ハッシュタグ・語句・スレッドは、タイムラインや通知、検索に表示されません。🔒 の項目は暗号化して公開します。");
+    if app_data.mute_list.private_locked {
+        ui.colored_label(
+            ui.visuals().error_fg_color,
+            "非公開のミュートを復号できないため、ここでは編集できません。",
+        );
+    }
+    let enabled = can_edit(app_data);
+    ui.add_space(10.0);
+
+    let mut removed = None;
+    let mut toggled = None;
+    let entries: Vec<(MuteItem, bool)> = app_data
+        .mute_list
+        .public
+        .iter()
+        .map(|item| (item.clone(), false))
+        .chain(app_data.mute_list.private.iter().map(|item| (item.clone(), true)))
+        .collect();
+    if entries.is_empty() {
+        ui.label("ミュートしている項目はありません。");
+    }
+    egui::Grid::new("mute_list").num_columns(3).show(ui, |ui| {
+        for (item, private) in entries {
+            ui.label(item_label(app_data, &item));
+            let lock = if private { "🔒" } else { "🔓" };
+            let lock_hint = if private { "公開する" } else { "非公開にする" };
+            if ui.add_enabled(enabled, egui::Button::new(lock)).on_hover_text(lock_hint).clicked() {
+                toggled = Some((item.clone(), !private));
+            }
+            if ui.add_enabled(enabled, egui::Button::new("解除")).clicked() {
+                removed = Some(item);
+            }
+            ui.end_row();
+        }
+    });
+
+    ui.add_space(10.0);
+    ui.horizontal(|ui| {
+        ui.add(
+            egui::TextEdit::singleline(&mut app_data.mute_input)
+                .hint_text("語句、#ハッシュタグ、npub、note")
+                .desired_width(260.0),
+        );
+        ui.checkbox(&mut app_data.mute_privately, "非公開");
+        if ui
+            .add_enabled(enabled, egui::Button::new("追加"))
+            .on_disabled_hover_text(READ_ONLY_HINT)
+            .clicked()
+            && let Some(item) = parse_input(&app_data.mute_input)
+        {
+            let change = MuteChange::Add(item, app_data.mute_privately);
+            app_data.mute_input.clear();
+            update_mute_list(app_data, change, app_data_arc, runtime_handle);
+        }
+    });
+    if !app_data.mute_status.is_empty() {
+        ui.label(&app_data.mute_status);
+    }
+
+    if let Some(item) = removed {
+        update_mute_list(app_data, MuteChange::Remove(item), app_data_arc, runtime_handle);
+    } else if let Some((item, private)) = toggled {
+        update_mute_list(app_data, MuteChange::Add(item, private), app_data_arc, runtime_handle);
+    }
+}
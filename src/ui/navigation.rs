@@ -0,0 +1,88 @@
+use std::sync::{Arc, Mutex};
+
+use tokio::runtime::Handle;
+
+use crate::{
+    types::{AppTab, NostrPostAppInternal},
+    ui::{thread_view::{self, ThreadState}, user_profile_view::{self, UserProfile}},
+};
+
+// 戻る履歴に残すページの数
+const MAX_HISTORY: usize = 50;
+
+/// A page we navigated away from: its tab and the thread or profile that was open on it.
+pub struct NavEntry {
+    tab: AppTab,
+    thread: Option<ThreadState>,
+    profile: Option<UserProfile>,
+}
+
+/// Moves the current page onto the back stack. The caller opens the next page.
+pub fn push(app_data: &mut NostrPostAppInternal) {
+    let entry = NavEntry {
+        tab: app_data.current_tab,
+        thread: app_data.thread.take(),
+        profile: app_data.user_profile.take(),
+    };
+    app_data.nav_history.push(entry);
+    if app_data.nav_history.len() > MAX_HISTORY {
+        app_data.nav_history.remove(0);
+    }
+    app_data.should_repaint = true;
+}
+
+/// Puts back a thread that was taken out of `app_data` for drawing. If one of its posts
+/// opened another page meanwhile, the thread goes into the entry pushed for that page.
+pub fn restore_thread(app_data: &mut NostrPostAppInternal, thread: ThreadState) {
+    let navigated = app_data.thread.is_some() || app_data.user_profile.is_some();
+    match app_data.nav_history.last_mut() {
+        Some(entry) if navigated && entry.thread.is_none() => entry.thread = Some(thread),
+        _ => app_data.thread = Some(thread),
+    }
+}
+
+/// Switches to `tab` from the side panel, remembering the page we leave.
+pub fn open_tab(app_data: &mut NostrPostAppInternal, tab: AppTab) {
+    if app_data.current_tab == tab && app_data.thread.is_none() && app_data.user_profile.is_none() {
+        return;
+    }
+    push(app_data);
+    app_data.current_tab = tab;
+}
+
+pub fn can_go_back(app_data: &NostrPostAppInternal) -> bool {
+    !app_data.nav_history.is_empty()
+}
+
+/// Returns to the previous page. Pages left while loading are loaded again.
+pub fn back(
+    app_data: &mut NostrPostAppInternal,
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    let Some(entry) = app_data.nav_history.pop() else {
+        // 履歴がなければ開いているページを閉じるだけ
+        app_data.thread = None;
+        app_data.user_profile = None;
+        app_data.should_repaint = true;
+        return;
+    };
+    let reload_thread = entry.thread.as_ref().is_some_and(|thread| thread.is_loading);
+    let reload_profile = entry.profile.as_ref().is_some_and(|profile| profile.is_loading);
+    app_data.current_tab = entry.tab;
+    app_data.thread = entry.thread;
+    app_data.user_profile = entry.profile;
+    app_data.should_repaint = true;
+    if reload_thread {
+        thread_view::reload(app_data, app_data_arc.clone(), runtime_handle);
+    }
+    if reload_profile {
+        user_profile_view::reload(app_data, app_data_arc, runtime_handle);
+    }
+}
+
+pub fn clear(app_data: &mut NostrPostAppInternal) {
+    app_data.nav_history.clear();
+    app_data.thread = None;
+    app_data.user_profile = None;
+}
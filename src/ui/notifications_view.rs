@@ -3,8 +3,10 @@ use nostr::{nips::nip19::ToBech32};
 use std::sync::{Arc, Mutex};
 
 use crate::{
+    notifications::{self, NotificationGroup, NotificationKind},
+    nostr_client::fetch_notification_events,
     types::*,
-    ui::{image_cache, post, zap, events},
+    ui::{image_cache, post, events, live, pagination, home_view},
 };
 
 pub fn draw_notifications_view(
@@ -18,99 +20,11 @@ pub fn draw_notifications_view(
     let timeline_heading_text = "通知";
     let fetch_latest_button_text = "通知を更新";
     let no_timeline_message_text = "通知はまだありません。";
+    let new_notifications_banner_text = "件の新しい通知";
+    let mark_all_read_button_text = "すべて既読にする";
 
-    // --- ZAP Dialog ---
-    if app_data.show_zap_dialog {
-        if let Some(post_to_zap) = app_data.zap_target_post.clone() {
-            let mut close_dialog = false;
-            egui::Window::new("ZAPを送る")
-                .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0.0, 0.0))
-                .collapsible(false)
-                .resizable(false)
-                .show(ctx, |ui| {
-                    ui.vertical_centered_justified(|ui| {
-                        ui.add_space(10.0);
-                        let display_name = if !post_to_zap.author_metadata.name.is_empty() {
-                            post_to_zap.author_metadata.name.clone()
-                        } else {
-                            let pubkey = post_to_zap.author_pubkey.to_bech32().unwrap_or_default();
-                            format!("{}...{}", &pubkey[0..8], &pubkey[pubkey.len()-4..])
-                        };
-                        ui.label(format!("{} にZAPします", display_name));
-                        ui.add_space(10.0);
-                        ui.horizontal(|ui| {
-                            ui.label("金額 (sats):");
-                            ui.add(egui::TextEdit::singleline(&mut app_data.zap_amount_input)
-                                .desired_width(120.0));
-                        });
-                        ui.add_space(10.0);
-                    });
-
-                    ui.separator();
-                    ui.add_space(5.0);
-
-                    ui.horizontal(|ui| {
-                        if ui.button("キャンセル").clicked() {
-                           close_dialog = true;
-                        }
-                        ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
-                            if ui.button("ZAP").clicked() {
-                                if let (Some(nwc), Some(nwc_client), Some(my_keys)) =
-                                    (app_data.nwc.as_ref(), app_data.nwc_client.as_ref(), app_data.my_keys.as_ref())
-                                {
-                                    if let Ok(amount_sats) = app_data.zap_amount_input.parse::<u64>() {
-                                        let nwc_clone = nwc.clone();
-                                        let nwc_client_clone = nwc_client.clone();
-                                        let my_keys_clone = my_keys.clone();
-                                        let app_data_clone = app_data_arc.clone();
-
-                                        runtime_handle.spawn(async move {
-                                            {
-                                                let mut data = app_data_clone.lock().unwrap();
-                                                data.should_repaint = true;
-                                            } // Lock is dropped here
-
-                                            let result = zap::send_zap_request(
-                                                &nwc_clone,
-                                                &nwc_client_clone,
-                                                &my_keys_clone,
-                                                post_to_zap.author_pubkey,
-                                                &post_to_zap.author_metadata.lud16,
-                                                amount_sats,
-                                                Some(post_to_zap.id),
-                                                Some(post_to_zap.kind),
-                                            ).await;
-
-                                            let mut data = app_data_clone.lock().unwrap();
-                                            match result {
-                                                Ok(_) => {
-                                                    // ZAPリクエストを送信しました。ウォレットの確認を待っています...
-                                                }
-                                                Err(e) => {
-                                                    eprintln!("ZAPエラー: {}", e);
-                                                }
-                                            }
-                                            data.should_repaint = true;
-                                        });
-
-                                        close_dialog = true;
-
-                                    } else {
-                                        eprintln!("無効な金額です");
-                                    }
-                                } else {
-                                    eprintln!("ZAPにはNWCの接続が必要です");
-                                }
-                            }
-                        });
-                    });
-                });
-            if close_dialog {
-                app_data.show_zap_dialog = false;
-                app_data.zap_target_post = None;
-            }
-        }
-    }
+    // 通知の対象の投稿を取りに行く
+    home_view::fetch_queued_posts(app_data, &app_data_arc, &runtime_handle);
 
     let card_frame = egui::Frame {
         inner_margin: egui::Margin::same(12),
@@ -124,38 +38,52 @@ pub fn draw_notifications_view(
             ui.heading(timeline_heading_text);
 
             let fetch_button = egui::Button::new(egui::RichText::new(fetch_latest_button_text).strong());
-            if ui.add_enabled(!app_data.is_loading, fetch_button).clicked() {
-                if let (Some(client), Some(keys)) = (
+            if ui.add_enabled(!app_data.is_loading, fetch_button).clicked()
+                && let (Some(client), Some(pubkey)) = (
                     app_data.nostr_client.as_ref(),
-                    app_data.my_keys.as_ref(),
+                    app_data.my_pubkey,
                 ) {
-                    let client = client.clone();
-                    let keys = keys.clone();
-                    let cache_db = app_data.cache_db.clone();
-                    let relay_config = app_data.relays.clone();
-                    let cloned_app_data_arc = app_data_arc.clone();
+                let client = client.clone();
+                let cache_db = app_data.cache_db.clone();
+                let relay_config = app_data.relays.clone();
+                let feed = app_data.timeline_feed;
+                let mutes = app_data.mute_list.clone();
+                let cloned_app_data_arc = app_data_arc.clone();
+                let handle = runtime_handle.clone();
 
-                    app_data.is_loading = true;
-                    app_data.should_repaint = true;
+                app_data.is_loading = true;
+                app_data.should_repaint = true;
 
-                    runtime_handle.spawn(async move {
-                        match events::refresh_all_data(&client, &keys, &cache_db, &relay_config).await {
-                            Ok(fresh_data) => {
-                                let mut app_data = cloned_app_data_arc.lock().unwrap();
+                runtime_handle.spawn(async move {
+                    let mut follows_changed = false;
+                    match events::refresh_all_data(&client, pubkey, feed, &cache_db, &relay_config, &mutes).await {
+                        Ok(fresh_data) => {
+                            let mut app_data = cloned_app_data_arc.lock().unwrap();
+                            follows_changed = app_data.followed_pubkeys != fresh_data.followed_pubkeys;
+                            app_data.followed_pubkeys = fresh_data.followed_pubkeys;
+                            if app_data.timeline_feed == feed {
                                 app_data.timeline_posts = fresh_data.timeline_posts;
-                                app_data.notification_posts = fresh_data.notification_posts;
-                                app_data.editable_profile = fresh_data.profile_metadata;
-                                println!("Refreshed all data from notifications view.");
-                            }
-                            Err(e) => {
-                                eprintln!("Failed to refresh data: {}", e);
+                                app_data.timeline_pagination.reset();
                             }
+                            app_data.notification_posts = fresh_data.notification_posts;
+                            app_data.notification_pagination.reset();
+                            app_data.editable_profile = fresh_data.profile_metadata;
+                            live::drop_shown_pending(&mut app_data);
+                            println!("Refreshed all data from notifications view.");
                         }
+                        Err(e) => {
+                            eprintln!("Failed to refresh data: {}", e);
+                        }
+                    }
+                    {
                         let mut app_data = cloned_app_data_arc.lock().unwrap();
                         app_data.is_loading = false;
                         app_data.should_repaint = true;
-                    });
-                }
+                    }
+                    if follows_changed {
+                        live::resubscribe_timeline(cloned_app_data_arc, &handle);
+                    }
+                });
             }
 
             if app_data.is_loading {
@@ -163,14 +91,33 @@ pub fn draw_notifications_view(
                 ui.spinner();
                 ui.label("更新中...");
             }
+
+            ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                if ui.button(mark_all_read_button_text).clicked() {
+                    mark_all_read(app_data);
+                }
+            });
         });
         ui.add_space(10.0);
 
+        let mut scroll_to_top = false;
+        let new_notifications_count = app_data.new_notification_posts.len();
+        if new_notifications_count > 0 {
+            let banner = egui::Button::new(
+                egui::RichText::new(format!("↑ {new_notifications_count}{new_notifications_banner_text}")).strong(),
+            );
+            if ui.add_sized([ui.available_width(), 28.0], banner).clicked() {
+                live::show_new_posts(app_data, live::LiveTarget::Notifications);
+                scroll_to_top = true;
+            }
+            ui.add_space(5.0);
+        }
+
         if app_data.notification_posts.is_empty() {
             ui.label(no_timeline_message_text);
         } else {
-            let num_posts = app_data.notification_posts.len();
             let row_height = 90.0;
+            let groups = notifications::group(&app_data.notification_posts);
 
             let card_frame = egui::Frame {
                 inner_margin: egui::Margin::same(0),
@@ -180,114 +127,156 @@ pub fn draw_notifications_view(
                 ..Default::default()
             };
             card_frame.show(ui, |ui| {
-                egui::ScrollArea::vertical()
+                let mut scroll_area = egui::ScrollArea::vertical()
                     .id_salt("notification_scroll_area")
-                    .max_height(ui.available_height() - 100.0)
-                    .show_rows(ui, row_height, num_posts, |ui, row_range| {
-                        for i in row_range {
-                            let post_data = app_data.notification_posts[i].clone();
-                            post::render_post(
-                                ui,
-                                app_data,
-                                &post_data,
-                                &mut urls_to_load,
-                                app_data_arc.clone(),
-                                runtime_handle.clone(),
-                            );
-                            ui.add_space(5.0);
-                        }
-                    });
+                    .max_height(ui.available_height() - 100.0);
+                if scroll_to_top {
+                    scroll_area = scroll_area.vertical_scroll_offset(0.0);
+                }
+                let output = scroll_area.show(ui, |ui| {
+                    for group in &groups {
+                        draw_group(ui, app_data, group, &mut urls_to_load, app_data_arc.clone(), &runtime_handle);
+                        ui.add_space(5.0);
+                    }
+                });
+                if pagination::near_bottom(&output, row_height) {
+                    load_older_notifications(app_data, app_data_arc.clone(), &runtime_handle);
+                }
+                pagination::draw_footer(ui, &mut app_data.notification_pagination);
             });
         }
 
-        // --- Image Loading Logic ---
+        image_cache::load_images(ctx, app_data, &app_data_arc, &runtime_handle, urls_to_load);
+    });
 
-        // First, try to load images from the LMDB cache for URLs not in memory.
-        let cache_db = app_data.cache_db.clone();
-        let mut still_to_load = Vec::new();
-        for (url_key, kind) in urls_to_load {
-            if let Some(image_bytes) = image_cache::load_from_lmdb(&cache_db, &url_key) {
-                // Image found in cache, process it directly.
-                // This is a simplification; for a smoother UI, this should be async.
-                if let Ok(mut dynamic_image) = image::load_from_memory(&image_bytes) {
-                    let (width, height) = match kind {
-                        ImageKind::Avatar => (32, 32),
-                        ImageKind::Emoji => (20, 20),
-                    _ => (32, 32), // Default for Banner, ProfilePicture, etc.
-                    };
-                    dynamic_image = dynamic_image.thumbnail(width, height);
-                    let color_image = egui::ColorImage::from_rgba_unmultiplied(
-                        [dynamic_image.width() as usize, dynamic_image.height() as usize],
-                        dynamic_image.to_rgba8().as_flat_samples().as_slice(),
-                    );
-                    let texture_handle = ctx.load_texture(
-                        &url_key,
-                        color_image,
-                        Default::default()
-                    );
-                    app_data.image_cache.insert(url_key, ImageState::Loaded(texture_handle));
-                } else {
-                    // Failed to decode, mark as failed.
-                    app_data.image_cache.insert(url_key, ImageState::Failed);
-                }
-            } else {
-                // Not on disk, queue for network download.
-                still_to_load.push((url_key, kind));
-            }
-        }
+}
 
-        // Fetch remaining images from the network.
-        let data_clone = app_data_arc.clone();
-        for (url_key, kind) in still_to_load {
-            app_data.image_cache.insert(url_key.clone(), ImageState::Loading);
-            app_data.should_repaint = true;
+fn load_older_notifications(
+    app_data: &mut NostrPostAppInternal,
+    app_data_arc: Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &tokio::runtime::Handle,
+) {
+    let (Some(client), Some(pubkey)) = (app_data.nostr_client.clone(), app_data.my_pubkey) else {
+        return;
+    };
+    let Some((until, generation)) = app_data.notification_pagination.begin(&app_data.notification_posts) else {
+        return;
+    };
+    let cache_db = app_data.cache_db.clone();
+    let mutes = app_data.mute_list.clone();
 
-            let app_data_clone = data_clone.clone();
-            let ctx_clone = ctx.clone();
-            let cache_db_for_fetch = app_data.cache_db.clone();
-            let request = ehttp::Request::get(&url_key);
+    runtime_handle.spawn(async move {
+        let result = fetch_notification_events(&client, &cache_db, pubkey, Some(until), &mutes).await;
+        let mut guard = app_data_arc.lock().unwrap();
+        let app_data = &mut *guard;
+        app_data.notification_pagination.finish(generation, &mut app_data.notification_posts, result);
+        app_data.should_repaint = true;
+    });
+}
 
-            ehttp::fetch(request, move |result| {
-                let new_state = match result {
-                    Ok(response) => {
-                        if response.ok {
-                            // Save to LMDB cache first.
-                            image_cache::save_to_lmdb(&cache_db_for_fetch, &response.url, &response.bytes);
+/// Marks every notification shown in the list as read.
+pub fn mark_all_read(app_data: &mut NostrPostAppInternal) {
+    let (Some(pubkey), Some(newest)) = (app_data.my_pubkey, app_data.notification_posts.first()) else {
+        return;
+    };
+    if app_data.notifications_read_until.is_some_and(|read_until| read_until >= newest.created_at) {
+        return;
+    }
+    app_data.notifications_read_until = Some(newest.created_at);
+    notifications::save_read_until(&app_data.cache_db, &pubkey, newest.created_at);
+    app_data.should_repaint = true;
+}
 
-                            match image::load_from_memory(&response.bytes) {
-                                Ok(mut dynamic_image) => {
-                                    let (width, height) = match kind {
-                                        ImageKind::Avatar => (32, 32),
-                                        ImageKind::Emoji => (20, 20),
-                                    _ => (32, 32), // Default for Banner, ProfilePicture, etc.
-                                    };
-                                    dynamic_image = dynamic_image.thumbnail(width, height);
+/// Number of unread notification rows, including ones waiting above the list.
+pub fn unread_count(app_data: &NostrPostAppInternal) -> usize {
+    let read_until = app_data.notifications_read_until;
+    let shown = notifications::group(&app_data.notification_posts)
+        .iter()
+        .filter(|group| read_until.is_none_or(|read_until| group.latest > read_until))
+        .count();
+    shown + notifications::group(&app_data.new_notification_posts).len()
+}
 
-                                    let color_image = egui::ColorImage::from_rgba_unmultiplied(
-                                        [dynamic_image.width() as usize, dynamic_image.height() as usize],
-                                        dynamic_image.to_rgba8().as_flat_samples().as_slice(),
-                                    );
-                                    let texture_handle = ctx_clone.load_texture(
-                                        &response.url,
-                                        color_image,
-                                        Default::default()
-                                    );
-                                    ImageState::Loaded(texture_handle)
-                                }
-                                Err(_) => ImageState::Failed,
-                            }
-                        } else {
-                            ImageState::Failed
-                        }
-                    }
-                    Err(_) => ImageState::Failed,
-                };
+fn display_name(post: &TimelinePost) -> String {
+    if !post.author_metadata.name.is_empty() {
+        post.author_metadata.name.clone()
+    } else {
+        let pubkey = post.author_pubkey.to_bech32().unwrap_or_default();
+        format!("{}...{}", &pubkey[0..8], &pubkey[pubkey.len() - 4..])
+    }
+}
 
-                let mut app_data = app_data_clone.lock().unwrap();
-                app_data.image_cache.insert(url_key, new_state);
-                ctx_clone.request_repaint();
-            });
+// 「A、B、他5人」の形にする
+fn actor_names(names: &[String]) -> String {
+    match names {
+        [] => String::new(),
+        [a] => a.clone(),
+        [a, b] => format!("{a}、{b}"),
+        [a, b, rest @ ..] => format!("{a}、{b}、他{}人", rest.len()),
+    }
+}
+
+fn summary_text(group: &NotificationGroup, names: &str) -> String {
+    match group.kind {
+        NotificationKind::Mention => format!("{names}がメンションしました"),
+        NotificationKind::Reply => format!("{names}が返信しました"),
+        NotificationKind::Reaction => format!("{names}がリアクションしました {}", group.reactions.join(" ")),
+        NotificationKind::Repost => format!("{names}がリポストしました"),
+        NotificationKind::Zap if group.sats > 0 => format!("{names}が {} sats をZapしました", group.sats),
+        NotificationKind::Zap => format!("{names}がZapしました"),
+        NotificationKind::Follow => format!("{names}にフォローされました"),
+    }
+}
+
+fn draw_group(
+    ui: &mut egui::Ui,
+    app_data: &mut NostrPostAppInternal,
+    group: &NotificationGroup,
+    urls_to_load: &mut Vec<(String, ImageKind)>,
+    app_data_arc: Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &tokio::runtime::Handle,
+) {
+    let posts: Vec<TimelinePost> = group
+        .actors
+        .iter()
+        .map(|&i| app_data.notification_posts[i].clone())
+        .collect();
+    let names: Vec<String> = posts.iter().map(display_name).collect();
+    let unread = app_data
+        .notifications_read_until
+        .is_none_or(|read_until| group.latest > read_until);
+
+    ui.horizontal(|ui| {
+        if unread {
+            ui.label(egui::RichText::new("●").small().color(ui.visuals().selection.bg_fill));
         }
+        ui.label(egui::RichText::new(group.kind.icon()).size(16.0));
+        ui.label(egui::RichText::new(summary_text(group, &actor_names(&names))).strong());
+        let datetime = chrono::DateTime::from_timestamp(group.latest.as_u64() as i64, 0).unwrap_or_default();
+        ui.label(
+            egui::RichText::new(datetime.with_timezone(&chrono::Local).format("%m/%d %H:%M").to_string())
+                .small()
+                .color(egui::Color32::GRAY),
+        );
     });
 
+    match group.kind {
+        NotificationKind::Mention | NotificationKind::Reply => {
+            if let Some(post) = posts.first() {
+                post::render_post(ui, app_data, post, urls_to_load, app_data_arc, runtime_handle.clone());
+            }
+        }
+        _ => {
+            // 1 人だけの Zap ならコメントも出す
+            if let [zap] = posts.as_slice()
+                && group.kind == NotificationKind::Zap && !zap.content.is_empty()
+            {
+                ui.label(&zap.content);
+            }
+            if let Some(target) = group.target {
+                post::render_notification_target(ui, app_data, target, urls_to_load, app_data_arc, runtime_handle);
+            }
+        }
+    }
+    ui.separator();
 }
@@ -0,0 +1,153 @@
+use eframe::egui;
+use nostr::Timestamp;
+
+use crate::{nostr_client::Page, types::TimelinePost, ui::live::merge_posts};
+
+// 末尾からこの行数以内までスクロールしたら次のページを読む
+const PREFETCH_ROWS: f32 = 3.0;
+
+// 一覧ごとの「さらに古い投稿」の読み込み状態
+#[derive(Default)]
+pub struct Pagination {
+    pub is_loading: bool,
+    // リレーが 1 ページ分を返さなくなったら true
+    pub reached_end: bool,
+    pub error: Option<String>,
+    // 読み込み中のページの until と、前のページがリレーから返ってきた最古の時刻。
+    // ミュートで全部隠れたページがあっても、その先から続けて読める。
+    until: Option<Timestamp>,
+    next_until: Option<Timestamp>,
+    // 一覧を取り直すたびに進める。古い読み込み結果を捨てるのに使う。
+    generation: u64,
+}
+
+impl Pagination {
+    /// Marks the next page as loading and returns its `until` cursor and generation.
+    pub fn begin(&mut self, posts: &[TimelinePost]) -> Option<(Timestamp, u64)> {
+        if self.is_loading || self.reached_end || self.error.is_some() {
+            return None;
+        }
+        // 同じ秒の投稿を取りこぼさないよう、最古の created_at をそのまま until にする。
+        // 重なった分は merge_posts で除かれる。
+        let oldest = posts.last()?.created_at;
+        let until = self.next_until.map_or(oldest, |next| next.min(oldest));
+        self.until = Some(until);
+        self.is_loading = true;
+        Some((until, self.generation))
+    }
+
+    /// Appends a loaded page. Returns true if any new post was added.
+    pub fn finish(
+        &mut self,
+        generation: u64,
+        posts: &mut Vec<TimelinePost>,
+        result: Result<Page, Box<dyn std::error::Error + Send + Sync>>,
+    ) -> bool {
+        if generation != self.generation {
+            return false;
+        }
+        self.is_loading = false;
+        match result {
+            Ok(page) => {
+                let before = posts.len();
+                merge_posts(posts, page.posts);
+                let added = posts.len() > before;
+                self.next_until = match page.next_until {
+                    // 1 ページ全部が until と同じ秒だと先へ進めないので、その秒を飛ばす
+                    Some(next) if !added && Some(next) == self.until => Some(next - 1),
+                    next => next,
+                };
+                self.reached_end = self.next_until.is_none();
+                added
+            }
+            Err(e) => {
+                eprintln!("Failed to load older posts: {e}");
+                self.error = Some(e.to_string());
+                false
+            }
+        }
+    }
+
+    // 一覧を先頭から取り直したとき、読み込み中の結果ごと状態を捨てる
+    pub fn reset(&mut self) {
+        *self = Pagination {
+            generation: self.generation + 1,
+            ..Default::default()
+        };
+    }
+}
+
+pub fn near_bottom<R>(output: &egui::scroll_area::ScrollAreaOutput<R>, row_height: f32) -> bool {
+    let visible_bottom = output.state.offset.y + output.inner_rect.height();
+    visible_bottom >= output.content_size.y - row_height * PREFETCH_ROWS
+}
+
+// 一覧の下に読み込み中 / 終端 / エラーを表示する
+pub fn draw_footer(ui: &mut egui::Ui, pagination: &mut Pagination) {
+    ui.add_space(5.0);
+    ui.horizontal(|ui| {
+        if pagination.is_loading {
+            ui.spinner();
+            ui.label("過去の投稿を読み込み中...");
+        } else if let Some(error) = &pagination.error {
+            ui.colored_label(ui.visuals().error_fg_color, format!("読み込みに失敗しました: {error}"));
+            if ui.button("再試行").clicked() {
+                pagination.error = None;
+            }
+        } else if pagination.reached_end {
+            ui.weak("これ以上古い投稿はありません。");
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr::{EventId, Keys, Kind};
+    use std::collections::HashMap;
+
+    fn post(n: u8, created_at: u64) -> TimelinePost {
+        TimelinePost {
+            id: EventId::from_byte_array([n; 32]),
+            kind: Kind::TextNote,
+            author_pubkey: Keys::generate().public_key(),
+            author_metadata: Default::default(),
+            content: String::new(),
+            created_at: Timestamp::from(created_at),
+            emojis: HashMap::new(),
+            tags: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn end_of_feed_follows_the_fetched_page() {
+        let mut pagination = Pagination::default();
+        let mut posts = vec![post(1, 1000)];
+
+        // ページ全体がミュートで隠れても、リレーが 1 ページ分返したなら続きがある
+        let (until, generation) = pagination.begin(&posts).unwrap();
+        assert_eq!(until, Timestamp::from(1000));
+        let page = Page { posts: Vec::new(), next_until: Some(Timestamp::from(900)) };
+        assert!(!pagination.finish(generation, &mut posts, Ok(page)));
+        assert!(!pagination.reached_end);
+
+        let (until, generation) = pagination.begin(&posts).unwrap();
+        assert_eq!(until, Timestamp::from(900));
+        let page = Page { posts: vec![post(2, 800)], next_until: None };
+        assert!(pagination.finish(generation, &mut posts, Ok(page)));
+        assert!(pagination.reached_end);
+        assert_eq!(posts.len(), 2);
+    }
+
+    #[test]
+    fn a_full_page_within_one_second_moves_past_it() {
+        let mut pagination = Pagination::default();
+        let mut posts = vec![post(1, 1000)];
+
+        let (_, generation) = pagination.begin(&posts).unwrap();
+        let page = Page { posts: vec![post(1, 1000)], next_until: Some(Timestamp::from(1000)) };
+        pagination.finish(generation, &mut posts, Ok(page));
+
+        assert_eq!(pagination.begin(&posts).map(|(until, _)| until), Some(Timestamp::from(999)));
+    }
+}
@@ -1,11 +1,22 @@
 use eframe::egui;
 use nostr::nips::nip19::{ToBech32, FromBech32, Nip19Event};
-use nostr::{EventBuilder, Kind, Tag, EventId, RelayUrl};
+use nostr::{EventBuilder, Filter, Kind, Tag, EventId, PublicKey, RelayUrl};
 use regex::Regex;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::runtime::Handle;
-use crate::types::{ImageKind, ImageState, NostrPostAppInternal, TimelinePost, AppTheme};
+use crate::types::{ImageKind, ImageState, NostrPostAppInternal, ProfileMetadata, TimelinePost, AppTheme};
+use crate::nip05::{self, Nip05Status};
+use crate::nip18;
+use crate::nip92::{self, Segment};
+use crate::nip65;
+use crate::nostr_client::event_to_timeline_post;
+use crate::ui::{follow_panel, media, mute_panel, thread_view, user_profile_view, zap};
+use crate::READ_ONLY_HINT;
+
+// 引用の nevent に入れるリレーの数
+const QUOTE_RELAY_HINTS: usize = 3;
 
 fn find_post_by_id(app_data: &NostrPostAppInternal, event_id: EventId) -> Option<Arc<TimelinePost>> {
     if let Some(post) = app_data.quoted_posts_cache.get(&event_id) {
@@ -20,15 +31,61 @@ fn find_post_by_id(app_data: &NostrPostAppInternal, event_id: EventId) -> Option
     if let Some(post) = app_data.search_results.iter().find(|p| p.id == event_id) {
         return Some(Arc::new(post.clone()));
     }
+    // 通知の対象になる自分の投稿
+    if let Some(post) = app_data.profile_posts.iter().find(|p| p.id == event_id) {
+        return Some(Arc::new(post.clone()));
+    }
     None
 }
 
+// リポストの元投稿。取得済みならそれを、なければ content に埋め込まれたイベントを使う。
+// 埋め込みの検証は重いので、一度読んだらプロフィールがなくても引用のキャッシュに入れておく
+fn reposted_post(app_data: &mut NostrPostAppInternal, repost: &TimelinePost) -> Option<TimelinePost> {
+    let reposted_id = nip18::reposted_id(&repost.tags);
+    if let Some(post) = reposted_id.and_then(|id| find_post_by_id(app_data, id)) {
+        // 著者のプロフィールが後から届いていたら埋める
+        if post.author_metadata == ProfileMetadata::default()
+            && let Some(metadata) = app_data.profile_cache.get(&post.author_pubkey)
+        {
+            return Some(TimelinePost { author_metadata: metadata.clone(), ..(*post).clone() });
+        }
+        return Some((*post).clone());
+    }
+    let Some(event) = nip18::embedded_event(&repost.content, &repost.tags) else {
+        // 中身が空の古い形式や、中身が e タグと食い違うリポストは e タグから取りに行く
+        if let (Some(id), Ok(mut posts_to_fetch)) = (reposted_id, app_data.posts_to_fetch.lock())
+            && posts_to_fetch.insert(id)
+        {
+            app_data.should_repaint = true;
+        }
+        return None;
+    };
+    let metadata = app_data.profile_cache.get(&event.pubkey).cloned();
+    // 著者のプロフィールは引用の取得処理にまとめて取ってもらう
+    if metadata.is_none()
+        && event.kind == Kind::TextNote
+        && let Ok(mut posts_to_fetch) = app_data.posts_to_fetch.lock()
+        && posts_to_fetch.insert(event.id)
+    {
+        app_data.should_repaint = true;
+    }
+    let post = event_to_timeline_post(&event, metadata.unwrap_or_default());
+    app_data.quoted_posts_cache.insert(post.id, Arc::new(post.clone()));
+    Some(post)
+}
+
+// truncate が false なら本文を省略せずに出す (リポスト)
 fn render_quoted_post(
     ui: &mut egui::Ui,
     app_data: &NostrPostAppInternal,
     post: &TimelinePost,
     urls_to_load: &mut Vec<(String, ImageKind)>,
+    truncate: bool,
 ) {
+    if app_data.mute_list.hides(post) {
+        ui.label(egui::RichText::new("ミュートしている投稿です").small().color(egui::Color32::GRAY));
+        return;
+    }
     let (fill_color, stroke_color) = match app_data.current_theme {
         AppTheme::Light => (egui::Color32::from_gray(240), egui::Color32::from_gray(220)),
         AppTheme::Dark => (egui::Color32::from_rgb(30, 30, 32), egui::Color32::from_rgb(60, 60, 62)),
@@ -52,7 +109,7 @@ fn render_quoted_post(
 
                 if !url.is_empty() {
                     let url_key = url.to_string();
-                     match app_data.image_cache.get(&url_key) {
+                     match app_data.image_cache.get(&url_key, ImageKind::Avatar) {
                         Some(ImageState::Loaded(texture_handle)) => {
                             let image_widget = egui::Image::new(texture_handle)
                                 .corner_radius(corner_radius)
@@ -101,6 +158,10 @@ fn render_quoted_post(
 
             ui.add_space(4.0);
 
+            if !truncate {
+                ui.label(egui::RichText::new(&post.content).color(app_data.current_theme.text_color()));
+                return;
+            }
             let mut truncated_content = post.content.replace('\n', " ");
             let max_len = 120;
             if truncated_content.chars().count() > max_len {
@@ -111,6 +172,33 @@ fn render_quoted_post(
     });
 }
 
+/// Shows the post a grouped notification refers to. Clicking it opens the thread.
+pub fn render_notification_target(
+    ui: &mut egui::Ui,
+    app_data: &mut NostrPostAppInternal,
+    event_id: EventId,
+    urls_to_load: &mut Vec<(String, ImageKind)>,
+    app_data_arc: Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    let Some(post) = find_post_by_id(app_data, event_id) else {
+        if let Ok(mut posts_to_fetch) = app_data.posts_to_fetch.lock()
+            && posts_to_fetch.insert(event_id)
+        {
+            app_data.should_repaint = true;
+        }
+        ui.label(egui::RichText::new("投稿を読み込み中...").small().color(egui::Color32::GRAY));
+        return;
+    };
+    let response = ui
+        .scope(|ui| render_quoted_post(ui, app_data, &post, urls_to_load, true))
+        .response
+        .interact(egui::Sense::click())
+        .on_hover_cursor(egui::CursorIcon::PointingHand);
+    if response.clicked() {
+        thread_view::open_thread(app_data, &post, app_data_arc, runtime_handle);
+    }
+}
 
 fn render_post_content(
     ui: &mut egui::Ui,
@@ -122,19 +210,19 @@ fn render_post_content(
     let text_color = app_data.current_theme.text_color();
 
     // Music/Podcast status check
-    if let Some(d_tag) = post.tags.iter().find(|t| (*t).clone().to_vec().get(0).map(|s| s.as_str()) == Some("d")) {
-        if d_tag.clone().to_vec().get(1).map(|s| s.as_str()) == Some("music") {
-            ui.horizontal(|ui| {
-                ui.label("🎵");
-                ui.vertical(|ui| {
-                    ui.label(egui::RichText::new(&post.content).color(text_color));
-                    if let Some(r_tag_value) = post.tags.iter().find(|t| (*t).clone().to_vec().get(0).map(|s| s.as_str()) == Some("r")).and_then(|t| t.clone().to_vec().get(1).cloned()) {
-                        ui.hyperlink_to(egui::RichText::new(&r_tag_value).small().color(egui::Color32::GRAY), r_tag_value);
-                    }
-                });
+    if let Some(d_tag) = post.tags.iter().find(|t| (*t).clone().to_vec().first().map(|s| s.as_str()) == Some("d"))
+        && d_tag.clone().to_vec().get(1).map(|s| s.as_str()) == Some("music")
+    {
+        ui.horizontal(|ui| {
+            ui.label("🎵");
+            ui.vertical(|ui| {
+                ui.label(egui::RichText::new(&post.content).color(text_color));
+                if let Some(r_tag_value) = post.tags.iter().find(|t| (*t).clone().to_vec().first().map(|s| s.as_str()) == Some("r")).and_then(|t| t.clone().to_vec().get(1).cloned()) {
+                    ui.hyperlink_to(egui::RichText::new(&r_tag_value).small().color(egui::Color32::GRAY), r_tag_value);
+                }
             });
-            return;
-        }
+        });
+        return;
     }
 
     // Refactored logic to handle quotes and text separately
@@ -143,7 +231,7 @@ fn render_post_content(
 
     for mat in re_nostr.find_iter(&post.content) {
         let pre_text = &post.content[last_end..mat.start()];
-        render_text_with_emojis(ui, pre_text, text_color, app_data, post, urls_to_load, my_emojis);
+        render_text_with_media(ui, pre_text, text_color, app_data, post, urls_to_load, my_emojis);
 
         let bech32_uri = mat.as_str();
         let event_id = EventId::from_bech32(bech32_uri).ok()
@@ -151,13 +239,13 @@ fn render_post_content(
 
         if let Some(id) = event_id {
             if let Some(quoted_post) = app_data.quoted_posts_cache.get(&id) {
-                render_quoted_post(ui, app_data, quoted_post, urls_to_load);
+                render_quoted_post(ui, app_data, quoted_post, urls_to_load, true);
             } else {
-                if let Ok(mut posts_to_fetch) = app_data.posts_to_fetch.lock() {
-                    if !posts_to_fetch.contains(&id) {
-                        posts_to_fetch.insert(id);
-                        app_data.should_repaint = true;
-                    }
+                if let Ok(mut posts_to_fetch) = app_data.posts_to_fetch.lock()
+                    && !posts_to_fetch.contains(&id)
+                {
+                    posts_to_fetch.insert(id);
+                    app_data.should_repaint = true;
                 }
                 let (_rect, _) = ui.allocate_exact_size(egui::vec2(ui.available_width(), 30.0), egui::Sense::hover());
                 let spinner_frame = egui::Frame {
@@ -179,7 +267,36 @@ fn render_post_content(
     }
 
     let remaining_text = &post.content[last_end..];
-    render_text_with_emojis(ui, remaining_text, text_color, app_data, post, urls_to_load, my_emojis);
+    render_text_with_media(ui, remaining_text, text_color, app_data, post, urls_to_load, my_emojis);
+}
+
+// 設定が有効なら、フォローしていない人のメディアはクリックするまでぼかす
+fn blurs_media(app_data: &NostrPostAppInternal, post: &TimelinePost) -> bool {
+    app_data.accounts.get(app_data.active_account).is_some_and(|account| account.blur_unfollowed_media)
+        && app_data.my_pubkey != Some(post.author_pubkey)
+        && !app_data.followed_pubkeys.contains(&post.author_pubkey)
+}
+
+// 画像・動画の URL は本文から抜き出して埋め込む
+fn render_text_with_media(
+    ui: &mut egui::Ui,
+    text: &str,
+    text_color: egui::Color32,
+    app_data: &mut NostrPostAppInternal,
+    post: &TimelinePost,
+    urls_to_load: &mut Vec<(String, ImageKind)>,
+    my_emojis: &HashMap<String, String>,
+) {
+    let blurred = blurs_media(app_data, post);
+    for segment in nip92::split_media(text, &post.tags) {
+        match segment {
+            Segment::Text(text) if text.trim().is_empty() => {}
+            Segment::Text(text) => {
+                render_text_with_emojis(ui, text.trim_matches('\n'), text_color, app_data, post, urls_to_load, my_emojis);
+            }
+            Segment::Media(item) => media::draw_media(ui, app_data, &item, blurred, urls_to_load),
+        }
+    }
 }
 
 fn render_text_with_emojis(
@@ -213,7 +330,7 @@ fn render_text_with_emojis(
                 let emoji_size = egui::vec2(20.0, 20.0);
                 let url_key = url.to_string();
 
-                match app_data.image_cache.get(&url_key) {
+                match app_data.image_cache.get(&url_key, ImageKind::Emoji) {
                     Some(ImageState::Loaded(texture_handle)) => {
                         let image_widget = egui::Image::new(texture_handle).fit_to_exact_size(emoji_size);
                         ui.add(image_widget);
@@ -248,6 +365,13 @@ fn render_text_with_emojis(
     });
 }
 
+// 一覧では返信先を引用表示する。スレッドでは木で親がわかるので出さない。
+#[derive(Clone, Copy, PartialEq)]
+enum PostLayout {
+    List,
+    Thread { focused: bool },
+}
+
 pub fn render_post(
     ui: &mut egui::Ui,
     app_data: &mut NostrPostAppInternal,
@@ -256,68 +380,139 @@ pub fn render_post(
     app_data_arc: Arc<Mutex<NostrPostAppInternal>>,
     runtime_handle: Handle,
 ) {
+    draw_post(ui, app_data, post, urls_to_load, app_data_arc, runtime_handle, PostLayout::List);
+}
+
+/// A checkmark when `identifier` (the profile's `nip05`) resolves to `pubkey`, a warning
+/// when it resolves elsewhere. Nothing while it is being checked or could not be fetched.
+pub fn draw_nip05_badge(
+    ui: &mut egui::Ui,
+    app_data: &mut NostrPostAppInternal,
+    pubkey: PublicKey,
+    identifier: &str,
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    if identifier.is_empty() {
+        return;
+    }
+    match nip05::status(app_data, pubkey, identifier, app_data_arc, runtime_handle) {
+        Nip05Status::Verified => {
+            ui.colored_label(egui::Color32::from_rgb(0, 160, 80), "✔")
+                .on_hover_text(identifier);
+        }
+        Nip05Status::Mismatch => {
+            ui.colored_label(ui.visuals().warn_fg_color, "⚠")
+                .on_hover_text(format!("{identifier} はこのユーザーを指していません"));
+        }
+        Nip05Status::Pending | Nip05Status::Unreachable => {}
+    }
+}
+
+/// Renders a post inside the thread tree. `focused` marks the post the thread was opened from.
+pub fn render_thread_post(
+    ui: &mut egui::Ui,
+    app_data: &mut NostrPostAppInternal,
+    post: &TimelinePost,
+    urls_to_load: &mut Vec<(String, ImageKind)>,
+    app_data_arc: Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: Handle,
+    focused: bool,
+) {
+    draw_post(ui, app_data, post, urls_to_load, app_data_arc, runtime_handle, PostLayout::Thread { focused });
+}
+
+fn draw_post(
+    ui: &mut egui::Ui,
+    app_data: &mut NostrPostAppInternal,
+    post: &TimelinePost,
+    urls_to_load: &mut Vec<(String, ImageKind)>,
+    app_data_arc: Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: Handle,
+    layout: PostLayout,
+) {
+    let stroke = match layout {
+        PostLayout::Thread { focused: true } => egui::Stroke::new(1.5, ui.visuals().selection.stroke.color),
+        _ => egui::Stroke::NONE,
+    };
     let card_frame = egui::Frame {
         inner_margin: egui::Margin::same(12),
         corner_radius: 8.0.into(),
         shadow: eframe::epaint::Shadow::NONE,
         fill: app_data.current_theme.card_background_color(),
+        stroke,
         ..Default::default()
     };
 
+    let reposted = if nip18::is_repost(post.kind) {
+        reposted_post(app_data, post)
+    } else {
+        None
+    };
+    let mut open_thread = false;
+    let mut open_profile = false;
     card_frame.show(ui, |ui| {
         ui.horizontal(|ui| {
             let avatar_size = egui::vec2(32.0, 32.0);
             let corner_radius = 4.0;
             let url = &post.author_metadata.picture;
 
-            if !url.is_empty() {
+            let avatar_response = if !url.is_empty() {
                 let url_key = url.to_string();
-                let image_state = app_data.image_cache.get(&url_key).cloned();
+                let image_state = app_data.image_cache.get(&url_key, ImageKind::Avatar).cloned();
 
                 match image_state {
                     Some(ImageState::Loaded(texture_handle)) => {
                         let image_widget = egui::Image::new(&texture_handle)
                             .corner_radius(corner_radius)
-                            .fit_to_exact_size(avatar_size);
-                        ui.add(image_widget);
+                            .fit_to_exact_size(avatar_size)
+                            .sense(egui::Sense::click());
+                        ui.add(image_widget)
                     }
                     Some(ImageState::Loading) => {
-                        let (rect, _) = ui.allocate_exact_size(avatar_size, egui::Sense::hover());
+                        let (rect, response) = ui.allocate_exact_size(avatar_size, egui::Sense::click());
                         ui.painter().rect_filled(
                             rect,
                             corner_radius,
                             ui.style().visuals.widgets.inactive.bg_fill,
                         );
                         ui.put(rect, egui::Spinner::new());
+                        response
                     }
                     Some(ImageState::Failed) => {
-                        let (rect, _) = ui.allocate_exact_size(avatar_size, egui::Sense::hover());
+                        let (rect, response) = ui.allocate_exact_size(avatar_size, egui::Sense::click());
                         ui.painter().rect_filled(
                             rect,
                             corner_radius,
                             ui.style().visuals.error_fg_color.linear_multiply(0.2),
                         );
+                        response
                     }
                     None => {
                         if !urls_to_load.iter().any(|(u, _)| u == &url_key) {
                             urls_to_load.push((url_key.clone(), ImageKind::Avatar));
                         }
-                        let (rect, _) = ui.allocate_exact_size(avatar_size, egui::Sense::hover());
+                        let (rect, response) = ui.allocate_exact_size(avatar_size, egui::Sense::click());
                         ui.painter().rect_filled(
                             rect,
                             corner_radius,
                             ui.style().visuals.widgets.inactive.bg_fill,
                         );
                         ui.put(rect, egui::Spinner::new());
+                        response
                     }
                 }
             } else {
-                let (rect, _) = ui.allocate_exact_size(avatar_size, egui::Sense::hover());
+                let (rect, response) = ui.allocate_exact_size(avatar_size, egui::Sense::click());
                 ui.painter().rect_filled(
                     rect,
                     corner_radius,
                     ui.style().visuals.widgets.inactive.bg_fill,
                 );
+                response
+            };
+            if avatar_response.on_hover_cursor(egui::CursorIcon::PointingHand).clicked() {
+                open_profile = true;
             }
 
             ui.add_space(8.0);
@@ -328,23 +523,48 @@ pub fn render_post(
                 let pubkey = post.author_pubkey.to_bech32().unwrap_or_default();
                 format!("{}...{}", &pubkey[0..8], &pubkey[pubkey.len() - 4..])
             };
-            ui.label(
+            let name_label = egui::Label::new(
                 egui::RichText::new(display_name)
                     .strong()
                     .color(app_data.current_theme.text_color()),
-            );
+            )
+            .sense(egui::Sense::click());
+            if ui.add(name_label).on_hover_cursor(egui::CursorIcon::PointingHand).clicked() {
+                open_profile = true;
+            }
+            draw_nip05_badge(ui, app_data, post.author_pubkey, &post.author_metadata.nip05, &app_data_arc, &runtime_handle);
 
             let created_at_datetime =
                 chrono::DateTime::from_timestamp(post.created_at.as_u64() as i64, 0).unwrap();
             let local_datetime = created_at_datetime.with_timezone(&chrono::Local);
-            ui.label(
+            let timestamp = egui::Label::new(
                 egui::RichText::new(local_datetime.format("%Y-%m-%d %H:%M:%S").to_string())
                     .color(egui::Color32::GRAY)
                     .small(),
-            );
+            )
+            .sense(egui::Sense::click());
+            if ui.add(timestamp).on_hover_text("スレッドを表示").clicked() {
+                open_thread = true;
+            }
         });
         ui.add_space(5.0);
-        if post.kind == Kind::Reaction {
+        if nip18::is_repost(post.kind) {
+            ui.label(
+                egui::RichText::new("🔁 リポストしました")
+                    .color(egui::Color32::GRAY)
+                    .small(),
+            );
+            ui.add_space(4.0);
+            match &reposted {
+                Some(original) => render_quoted_post(ui, app_data, original, urls_to_load, false),
+                None => {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.label("リポストを読み込み中...");
+                    });
+                }
+            }
+        } else if post.kind == Kind::Reaction {
             let reacted_event_id = post.tags.iter().find_map(|tag| {
                 if let Some(nostr::TagStandard::Event { event_id, .. }) = tag.as_standardized() {
                     Some(*event_id)
@@ -359,14 +579,14 @@ pub fn render_post(
                         let reaction_emoji = &post.content;
                         ui.label(format!("あなたの投稿に {} しました", reaction_emoji));
                         ui.add_space(4.0);
-                        render_quoted_post(ui, app_data, &reacted_post, urls_to_load);
+                        render_quoted_post(ui, app_data, &reacted_post, urls_to_load, true);
                     });
                 } else {
-                    if let Ok(mut posts_to_fetch) = app_data.posts_to_fetch.lock() {
-                        if !posts_to_fetch.contains(&event_id) {
-                            posts_to_fetch.insert(event_id);
-                            app_data.should_repaint = true;
-                        }
+                    if let Ok(mut posts_to_fetch) = app_data.posts_to_fetch.lock()
+                        && !posts_to_fetch.contains(&event_id)
+                    {
+                        posts_to_fetch.insert(event_id);
+                        app_data.should_repaint = true;
                     }
                     ui.horizontal(|ui|{
                         ui.spinner();
@@ -396,7 +616,7 @@ pub fn render_post(
             let re_nostr = Regex::new(r"nostr:(?:note|nevent)1[a-z0-9]+").unwrap();
             let is_quote_in_content = re_nostr.is_match(&post.content);
 
-            if let Some(event_id) = event_tag_id {
+            if let (Some(event_id), PostLayout::List) = (event_tag_id, layout) {
                 if is_quote_in_content {
                     // This is a quote post, render_post_content will handle the preview.
                     render_post_content(ui, app_data, post, urls_to_load, &app_data.my_emojis.clone());
@@ -411,13 +631,13 @@ pub fn render_post(
                         ui.label(reply_label);
 
                         if let Some(replied_post) = find_post_by_id(app_data, event_id) {
-                            render_quoted_post(ui, app_data, &replied_post, urls_to_load);
+                            render_quoted_post(ui, app_data, &replied_post, urls_to_load, true);
                         } else {
-                            if let Ok(mut posts_to_fetch) = app_data.posts_to_fetch.lock() {
-                                if !posts_to_fetch.contains(&event_id) {
-                                    posts_to_fetch.insert(event_id);
-                                    app_data.should_repaint = true;
-                                }
+                            if let Ok(mut posts_to_fetch) = app_data.posts_to_fetch.lock()
+                                && !posts_to_fetch.contains(&event_id)
+                            {
+                                posts_to_fetch.insert(event_id);
+                                app_data.should_repaint = true;
                             }
                             let spinner_frame = egui::Frame {
                                 inner_margin: egui::Margin::same(8),
@@ -433,7 +653,7 @@ pub fn render_post(
                     });
                 }
             } else {
-                // Not a reply or quote, just a regular text note.
+                // Not a reply or quote (or already shown in a thread), just a regular text note.
                 render_post_content(ui, app_data, post, urls_to_load, &app_data.my_emojis.clone());
             }
         }
@@ -451,8 +671,18 @@ pub fn render_post(
         ui.separator();
         ui.add_space(5.0);
 
+        // リポストへの操作は元の投稿に対して行う
+        let post = reposted.as_ref().unwrap_or(post);
+
+        // 閲覧専用 (npub) ログインでは署名が必要な操作を無効にする。
+        let can_sign = app_data.my_signer.is_some();
         ui.horizontal(|ui| {
-            if ui.button("💬").on_hover_text("Reply").clicked() {
+            if ui
+                .add_enabled(can_sign, egui::Button::new("💬"))
+                .on_hover_text("Reply")
+                .on_disabled_hover_text(READ_ONLY_HINT)
+                .clicked()
+            {
                 app_data.show_reply_dialog = true;
                 app_data.reply_target_post = Some(post.clone());
                 app_data.reply_input.clear();
@@ -460,46 +690,77 @@ pub fn render_post(
 
             ui.add_space(15.0);
 
-            if ui.button("🔁").on_hover_text("Repost").clicked() {
-                if let (Some(client), Some(keys)) =
-                    (app_data.nostr_client.as_ref(), app_data.my_keys.as_ref())
-                {
-                    let client = client.clone();
-                    let keys = keys.clone();
-                    let reposted_event_id = post.id;
-                    let reposted_author_pubkey = post.author_pubkey;
-                    let cloned_app_data_arc = app_data_arc.clone();
-
-                    runtime_handle.spawn(async move {
-                        let tags = vec![
-                            Tag::event(reposted_event_id),
-                            Tag::public_key(reposted_author_pubkey),
-                        ];
-                        let event_result = EventBuilder::new(Kind::Repost, "").tags(tags).sign(&keys).await;
-
-                        match event_result {
-                            Ok(event) => match client.send_event(&event).await {
-                                Ok(event_id) => {
-                                    println!("Repost published with event id: {:?}", event_id);
-                                }
-                                Err(e) => eprintln!("Failed to publish repost: {}", e),
-                            },
-                            Err(e) => eprintln!("Failed to create repost event: {}", e),
+            if ui
+                .add_enabled(can_sign, egui::Button::new("🔁"))
+                .on_hover_text("Repost")
+                .on_disabled_hover_text(READ_ONLY_HINT)
+                .clicked()
+                && let (Some(client), Some(signer)) =
+                    (app_data.nostr_client.as_ref(), app_data.my_signer.as_ref())
+            {
+                let client = client.clone();
+                let signer = signer.clone();
+                let reposted_event_id = post.id;
+                let cache_db = app_data.cache_db.clone();
+                let cloned_app_data_arc = app_data_arc.clone();
+
+                runtime_handle.spawn(async move {
+                    // NIP-18 では元のイベントを JSON のまま content に入れるので、署名付きで取り直す
+                    let filter = Filter::new().id(reposted_event_id).limit(1);
+                    let original = match client.fetch_events(filter, Duration::from_secs(10)).await {
+                        Ok(events) => events.into_iter().next(),
+                        Err(e) => {
+                            eprintln!("Failed to fetch event to repost: {}", e);
+                            None
                         }
-                        cloned_app_data_arc.lock().unwrap().should_repaint = true;
-                    });
-                }
+                    };
+                    let Some(original) = original else {
+                        eprintln!("Event to repost was not found: {}", reposted_event_id);
+                        return;
+                    };
+                    let relay_hint = nip65::relay_hint(&client, &cache_db, &original.pubkey).await;
+                    // kind 1 以外は kind 16 (汎用リポスト) になる
+                    let event_result = EventBuilder::repost(&original, relay_hint).sign(&signer).await;
+
+                    match event_result {
+                        Ok(event) => match nip65::publish(&client, &cache_db, &event).await {
+                            Ok(event_id) => {
+                                println!("Repost published with event id: {:?}", event_id);
+                            }
+                            Err(e) => eprintln!("Failed to publish repost: {}", e),
+                        },
+                        Err(e) => eprintln!("Failed to create repost event: {}", e),
+                    }
+                    cloned_app_data_arc.lock().unwrap().should_repaint = true;
+                });
             }
 
             ui.add_space(15.0);
 
-            if ui.button("✏️").on_hover_text("Quote").clicked() {
+            if ui
+                .add_enabled(can_sign, egui::Button::new("✏️"))
+                .on_hover_text("Quote")
+                .on_disabled_hover_text(READ_ONLY_HINT)
+                .clicked()
+            {
                 app_data.show_post_dialog = true;
                 app_data.post_input.clear();
 
+                // 著者の書き込みリレーを優先し、足りなければ自分のアグリゲーターを添える
+                let mut relays = nip65::cached_write_relays(&app_data.cache_db, &post.author_pubkey);
+                relays.truncate(QUOTE_RELAY_HINTS - 1);
+                for url in app_data.relays.aggregator.iter().filter_map(|s| RelayUrl::parse(s).ok()) {
+                    if relays.len() >= QUOTE_RELAY_HINTS {
+                        break;
+                    }
+                    if !relays.contains(&url) {
+                        relays.push(url);
+                    }
+                }
                 let mut nip19_event = Nip19Event::new(post.id);
                 nip19_event.author = Some(post.author_pubkey);
-                nip19_event.relays = app_data.relays.aggregator.iter().filter_map(|s| RelayUrl::parse(s).ok()).collect();
+                nip19_event.kind = Some(post.kind);
+                nip19_event.relays = relays;
 
                 if let Ok(nevent) = nip19_event.to_bech32() {
                     app_data.post_input = format!("nostr:{}\n\n", nevent);
@@ -518,53 +779,78 @@ pub fn render_post(
 
             ui.add_space(15.0);
 
-            if ui.button("❤️").on_hover_text("React").clicked() {
-                if let (Some(client), Some(keys)) =
-                    (app_data.nostr_client.as_ref(), app_data.my_keys.as_ref())
-                {
-                    let client = client.clone();
-                    let keys = keys.clone();
-                    let reacted_event_id = post.id;
-                    let reacted_author_pubkey = post.author_pubkey;
-                    let cloned_app_data_arc = app_data_arc.clone();
-
-                    runtime_handle.spawn(async move {
-                        let tags = vec![
-                            Tag::event(reacted_event_id),
-                            Tag::public_key(reacted_author_pubkey),
-                        ];
-                        let event_result = EventBuilder::new(Kind::Reaction, "+")
-                            .tags(tags)
-                            .sign(&keys)
-                            .await;
-
-                        match event_result {
-                            Ok(event) => match client.send_event(&event).await {
-                                Ok(event_id) => {
-                                    println!("Reaction published with event id: {:?}", event_id);
-                                }
-                                Err(e) => eprintln!("Failed to publish reaction: {}", e),
-                            },
-                            Err(e) => eprintln!("Failed to create reaction event: {}", e),
-                        }
-                        cloned_app_data_arc.lock().unwrap().should_repaint = true;
-                    });
-                }
+            if ui
+                .add_enabled(can_sign, egui::Button::new("❤️"))
+                .on_hover_text("React")
+                .on_disabled_hover_text(READ_ONLY_HINT)
+                .clicked()
+                && let (Some(client), Some(signer)) =
+                    (app_data.nostr_client.as_ref(), app_data.my_signer.as_ref())
+            {
+                let client = client.clone();
+                let signer = signer.clone();
+                let reacted_event_id = post.id;
+                let reacted_author_pubkey = post.author_pubkey;
+                let cache_db = app_data.cache_db.clone();
+                let cloned_app_data_arc = app_data_arc.clone();
+
+                runtime_handle.spawn(async move {
+                    let tags = vec![
+                        Tag::event(reacted_event_id),
+                        Tag::public_key(reacted_author_pubkey),
+                    ];
+                    let event_result = EventBuilder::new(Kind::Reaction, "+")
+                        .tags(tags)
+                        .sign(&signer)
+                        .await;
+
+                    match event_result {
+                        Ok(event) => match nip65::publish(&client, &cache_db, &event).await {
+                            Ok(event_id) => {
+                                println!("Reaction published with event id: {:?}", event_id);
+                            }
+                            Err(e) => eprintln!("Failed to publish reaction: {}", e),
+                        },
+                        Err(e) => eprintln!("Failed to create reaction event: {}", e),
+                    }
+                    cloned_app_data_arc.lock().unwrap().should_repaint = true;
+                });
             }
 
             ui.add_space(15.0);
 
-            if let Some(my_keys) = &app_data.my_keys {
-                if post.author_pubkey != my_keys.public_key() {
-                    if !post.author_metadata.lud16.is_empty() {
-                        if ui.button("⚡").on_hover_text("Zap").clicked() {
-                            app_data.zap_target_post = Some(post.clone());
-                            app_data.show_zap_dialog = true;
-                            app_data.zap_amount_input = "21".to_string();
-                        }
-                    }
-                }
+            if post.kind == Kind::TextNote && ui.button("🧵").on_hover_text("スレッドを表示").clicked() {
+                open_thread = true;
+            }
+
+            ui.add_space(15.0);
+
+            if let Some(my_pubkey) = app_data.my_pubkey
+                && post.author_pubkey != my_pubkey
+                && !post.author_metadata.lud16.is_empty()
+                && ui
+                    .add_enabled(can_sign, egui::Button::new("⚡"))
+                    .on_hover_text("Zap")
+                    .on_disabled_hover_text(READ_ONLY_HINT)
+                    .clicked()
+            {
+                zap::open_dialog(app_data, zap::ZapTarget::post(post));
             }
+
+            ui.add_space(15.0);
+
+            ui.menu_button("⋯", |ui| {
+                if follow_panel::follow_button(ui, app_data, post.author_pubkey, &app_data_arc, &runtime_handle) {
+                    ui.close();
+                }
+                mute_panel::draw_post_menu(ui, app_data, post, &app_data_arc, &runtime_handle);
+            });
         });
     });
+
+    if open_thread {
+        thread_view::open_thread(app_data, post, app_data_arc, &runtime_handle);
+    } else if open_profile {
+        user_profile_view::open_profile(app_data, post.author_pubkey, &app_data_arc, &runtime_handle);
+    }
 }
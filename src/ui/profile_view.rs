@@ -1,12 +1,14 @@
 use eframe::egui::{self, Sense};
 use std::sync::{Arc, Mutex};
 
-use nostr::{EventBuilder, Kind, Filter, TagStandard};
+use nostr::{EventBuilder, Kind, Filter};
 
 use crate::{
     cache_db::DB_PROFILES,
+    event_store,
+    nostr_client::{event_to_timeline_post, next_until, Page, AUTHOR_POSTS_LIMIT},
     types::*,
-    ui::{image_cache, post},
+    ui::{image_cache, pagination, post},
 };
 
 pub fn draw_profile_view(
@@ -42,7 +44,7 @@ pub fn draw_profile_view(
 
                 let picture_url = &app_data.editable_profile.picture;
                 if !picture_url.is_empty() {
-                    let image_state = app_data.image_cache.get(picture_url).cloned();
+                    let image_state = app_data.image_cache.get(picture_url, ImageKind::ProfilePicture).cloned();
                     match image_state {
                         Some(ImageState::Loaded(texture_handle)) => {
                             let image_widget = egui::Image::new(&texture_handle)
@@ -112,9 +114,15 @@ pub fn draw_profile_view(
                         ui.horizontal(|ui| {
                             ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
                                  let save_button = egui::Button::new(egui::RichText::new(save_profile_button_text).strong());
-                                 if ui.add_enabled(!app_data.is_loading, save_button).clicked() {
+                                 let can_sign = app_data.my_signer.is_some();
+                                 let mut save_response = ui.add_enabled(!app_data.is_loading && can_sign, save_button);
+                                 if !can_sign {
+                                    save_response = save_response.on_disabled_hover_text(crate::READ_ONLY_HINT);
+                                 }
+                                 if save_response.clicked() {
                                     let client_clone = app_data.nostr_client.as_ref().unwrap().clone();
-                                    let keys_clone = app_data.my_keys.clone().unwrap();
+                                    let signer_clone = app_data.my_signer.clone().unwrap();
+                                    let my_pubkey = app_data.my_pubkey.unwrap();
                                     let editable_profile_clone = app_data.editable_profile.clone();
                                     let cache_db_clone = app_data.cache_db.clone();
 
@@ -127,13 +135,13 @@ pub fn draw_profile_view(
                                             let profile_content = serde_json::to_string(&editable_profile_clone)?;
 
                                             let event = EventBuilder::new(Kind::Metadata, profile_content.clone())
-                                                .sign(&keys_clone)
+                                                .sign(&signer_clone)
                                                 .await?;
 
                                             match client_clone.send_event(&event).await {
                                                 Ok(event_id) => {
                                                     println!("NIP-01 profile published with event id: {event_id:?}");
-                                                    let pubkey_hex = keys_clone.public_key().to_string();
+                                                    let pubkey_hex = my_pubkey.to_string();
                                                     if let Err(e) = cache_db_clone.write_cache(DB_PROFILES, &pubkey_hex, &editable_profile_clone) {
                                                         eprintln!("Failed to write profile cache: {e}");
                                                     }
@@ -180,58 +188,32 @@ pub fn draw_profile_view(
                 ui.heading("最新の投稿");
                 ui.add_space(10.0);
                 let refresh_button = egui::Button::new("🔄 更新");
-                if ui.add_enabled(!app_data.is_fetching_profile_posts, refresh_button).clicked() {
-                    if let (Some(client), Some(keys)) = (app_data.nostr_client.as_ref(), app_data.my_keys.as_ref()) {
-                        let client = client.clone();
-                        let pubkey = keys.public_key();
-                        let cloned_app_data_arc = app_data_arc.clone();
-
-                        app_data.is_fetching_profile_posts = true;
-                        app_data.should_repaint = true;
-
-                        runtime_handle.spawn(async move {
-                            let filter = Filter::new().author(pubkey).kind(Kind::TextNote).limit(20);
-                            match client.fetch_events(filter, std::time::Duration::from_secs(10)).await {
-                                Ok(events) => {
-                                    let mut posts = Vec::new();
-                                    let author_metadata = cloned_app_data_arc.lock().unwrap().editable_profile.clone();
-                                    for event in events {
-                                        let emojis = event
-                                            .tags
-                                            .iter()
-                                            .filter_map(|tag| {
-                                                if let Some(TagStandard::Emoji { shortcode, url }) = tag.as_standardized() {
-                                                    Some((shortcode.to_string(), url.to_string()))
-                                                } else {
-                                                    None
-                                                }
-                                            })
-                                            .collect();
-
-                                        let post = TimelinePost {
-                                            id: event.id,
-                                            kind: event.kind,
-                                            author_pubkey: event.pubkey,
-                                            author_metadata: author_metadata.clone(),
-                                            content: event.content.clone(),
-                                            created_at: event.created_at,
-                                            emojis,
-                                            tags: event.tags.to_vec(),
-                                        };
-                                        posts.push(post);
-                                    }
-                                    let mut app_data = cloned_app_data_arc.lock().unwrap();
-                                    app_data.profile_posts = posts;
-                                }
-                                Err(e) => {
-                                    eprintln!("Failed to fetch profile posts: {}", e);
-                                }
+                if ui.add_enabled(!app_data.is_fetching_profile_posts, refresh_button).clicked()
+                    && let (Some(client), Some(pubkey)) = (app_data.nostr_client.as_ref(), app_data.my_pubkey)
+                {
+                    let client = client.clone();
+                    let cloned_app_data_arc = app_data_arc.clone();
+
+                    app_data.is_fetching_profile_posts = true;
+                    app_data.should_repaint = true;
+
+                    let author_metadata = app_data.editable_profile.clone();
+
+                    runtime_handle.spawn(async move {
+                        match fetch_own_posts(&client, pubkey, author_metadata, None).await {
+                            Ok(page) => {
+                                let mut app_data = cloned_app_data_arc.lock().unwrap();
+                                event_store::merge_fetched(&mut app_data.profile_posts, page.posts);
+                                app_data.profile_posts_pagination.reset();
                             }
-                            let mut app_data = cloned_app_data_arc.lock().unwrap();
-                            app_data.is_fetching_profile_posts = false;
-                            app_data.should_repaint = true;
-                        });
-                    }
+                            Err(e) => {
+                                eprintln!("Failed to fetch profile posts: {}", e);
+                            }
+                        }
+                        let mut app_data = cloned_app_data_arc.lock().unwrap();
+                        app_data.is_fetching_profile_posts = false;
+                        app_data.should_repaint = true;
+                    });
                 }
                 if app_data.is_fetching_profile_posts {
                     ui.spinner();
@@ -255,7 +237,7 @@ pub fn draw_profile_view(
                 };
 
                 card_frame.show(ui, |ui| {
-                    egui::ScrollArea::vertical()
+                    let output = egui::ScrollArea::vertical()
                         .id_salt("profile_posts_scroll_area")
                         .max_height(ui.available_height() - 50.0)
                         .show_rows(ui, row_height, num_posts, |ui, row_range| {
@@ -272,89 +254,56 @@ pub fn draw_profile_view(
                                 ui.add_space(5.0);
                             }
                         });
+                    if pagination::near_bottom(&output, row_height) {
+                        load_older_posts(app_data, app_data_arc.clone(), &runtime_handle);
+                    }
+                    pagination::draw_footer(ui, &mut app_data.profile_posts_pagination);
                 });
             }
         });
 
 
-    // --- Image Loading Logic ---
-    let cache_db = app_data.cache_db.clone();
-    let mut still_to_load = Vec::new();
-    for (url_key, kind) in urls_to_load {
-        if let Some(image_bytes) = image_cache::load_from_lmdb(&cache_db, &url_key) {
-            if let Ok(mut dynamic_image) = image::load_from_memory(&image_bytes) {
-                let (width, height) = match kind {
-                    ImageKind::Avatar => (32, 32),
-                    ImageKind::Emoji => (20, 20),
-                    ImageKind::ProfilePicture => (100, 100),
-                };
-                dynamic_image = dynamic_image.thumbnail(width, height);
-                let color_image = egui::ColorImage::from_rgba_unmultiplied(
-                    [dynamic_image.width() as usize, dynamic_image.height() as usize],
-                    dynamic_image.to_rgba8().as_flat_samples().as_slice(),
-                );
-                let texture_handle = ctx.load_texture(
-                    &url_key,
-                    color_image,
-                    Default::default()
-                );
-                app_data.image_cache.insert(url_key, ImageState::Loaded(texture_handle));
-            } else {
-                app_data.image_cache.insert(url_key, ImageState::Failed);
-            }
-        } else {
-            still_to_load.push((url_key, kind));
-        }
-    }
+    image_cache::load_images(ctx, app_data, &app_data_arc, &runtime_handle, urls_to_load);
+}
 
-    let data_clone = app_data_arc.clone();
-    for (url_key, kind) in still_to_load {
-        app_data.image_cache.insert(url_key.clone(), ImageState::Loading);
-        app_data.should_repaint = true;
+// 自分の投稿を until 以前から 1 ページ分取得する
+async fn fetch_own_posts(
+    client: &nostr_sdk::Client,
+    pubkey: nostr::PublicKey,
+    author_metadata: ProfileMetadata,
+    until: Option<nostr::Timestamp>,
+) -> Result<Page, Box<dyn std::error::Error + Send + Sync>> {
+    let mut filter = Filter::new().author(pubkey).kind(Kind::TextNote).limit(AUTHOR_POSTS_LIMIT);
+    if let Some(until) = until {
+        filter = filter.until(until);
+    }
+    let events = client.fetch_events(filter, std::time::Duration::from_secs(10)).await?;
+    let mut posts: Vec<TimelinePost> = events
+        .iter()
+        .map(|event| event_to_timeline_post(event, author_metadata.clone()))
+        .collect();
+    posts.sort_by_key(|p| std::cmp::Reverse(p.created_at));
+    Ok(Page { posts, next_until: next_until(events.iter(), AUTHOR_POSTS_LIMIT) })
+}
 
-        let app_data_clone = data_clone.clone();
-        let ctx_clone = ctx.clone();
-        let cache_db_for_fetch = app_data.cache_db.clone();
-        let request = ehttp::Request::get(&url_key);
-
-        ehttp::fetch(request, move |result| {
-            let new_state = match result {
-                Ok(response) => {
-                    if response.ok {
-                        image_cache::save_to_lmdb(&cache_db_for_fetch, &response.url, &response.bytes);
-
-                        match image::load_from_memory(&response.bytes) {
-                            Ok(mut dynamic_image) => {
-                                let (width, height) = match kind {
-                                    ImageKind::Avatar => (32, 32),
-                                    ImageKind::Emoji => (20, 20),
-                                    ImageKind::ProfilePicture => (100, 100),
-                                };
-                                dynamic_image = dynamic_image.thumbnail(width, height);
-
-                                let color_image = egui::ColorImage::from_rgba_unmultiplied(
-                                    [dynamic_image.width() as usize, dynamic_image.height() as usize],
-                                    dynamic_image.to_rgba8().as_flat_samples().as_slice(),
-                                );
-                                let texture_handle = ctx_clone.load_texture(
-                                    &response.url,
-                                    color_image,
-                                    Default::default()
-                                );
-                                ImageState::Loaded(texture_handle)
-                            }
-                            Err(_) => ImageState::Failed,
-                        }
-                    } else {
-                        ImageState::Failed
-                    }
-                }
-                Err(_) => ImageState::Failed,
-            };
+fn load_older_posts(
+    app_data: &mut NostrPostAppInternal,
+    app_data_arc: Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &tokio::runtime::Handle,
+) {
+    let (Some(client), Some(pubkey)) = (app_data.nostr_client.clone(), app_data.my_pubkey) else {
+        return;
+    };
+    let Some((until, generation)) = app_data.profile_posts_pagination.begin(&app_data.profile_posts) else {
+        return;
+    };
+    let author_metadata = app_data.editable_profile.clone();
 
-            let mut app_data = app_data_clone.lock().unwrap();
-            app_data.image_cache.insert(url_key, new_state);
-            ctx_clone.request_repaint();
-        });
-    }
+    runtime_handle.spawn(async move {
+        let result = fetch_own_posts(&client, pubkey, author_metadata, Some(until)).await;
+        let mut guard = app_data_arc.lock().unwrap();
+        let app_data = &mut *guard;
+        app_data.profile_posts_pagination.finish(generation, &mut app_data.profile_posts, result);
+        app_data.should_repaint = true;
+    });
 }
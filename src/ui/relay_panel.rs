@@ -0,0 +1,393 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use eframe::egui;
+use nostr::{RelayMessage, RelayUrl};
+use nostr_sdk::prelude::{RelayNotification, RelayOptions, RelayServiceFlags, RelayStatus};
+use nostr_sdk::{Client, Relay};
+use tokio::runtime::Handle;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    save_config,
+    types::{NostrPostAppInternal, RelayAccess, RelayConfig},
+    ui::live,
+};
+
+// client.relays() を見に行く間隔
+const STATUS_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Connection state of one relay in the pool, shown in the relay panel.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RelayStatusInfo {
+    pub status: RelayStatus,
+    pub latency: Option<Duration>,
+    pub last_error: Option<String>,
+}
+
+impl Default for RelayStatusInfo {
+    fn default() -> Self {
+        Self {
+            status: RelayStatus::Initialized,
+            latency: None,
+            last_error: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum RelayCategory {
+    Aggregator,
+    SelfHosted,
+    Search,
+}
+
+impl RelayCategory {
+    const ALL: [RelayCategory; 3] = [Self::Aggregator, Self::SelfHosted, Self::Search];
+
+    fn title(self) -> &'static str {
+        match self {
+            Self::Aggregator => "アグリゲーター",
+            Self::SelfHosted => "自前のリレー",
+            Self::Search => "検索 (NIP-50)",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Self::Aggregator => "グローバルフィードと、リレーリストのない人の投稿を読むリレーです。",
+            Self::SelfHosted => "自分で運用しているリレーやバックアップ用のリレーです。",
+            Self::Search => "検索タブで使うリレーです。既定では投稿を送りません。",
+        }
+    }
+
+    fn urls_mut(self, config: &mut RelayConfig) -> &mut Vec<String> {
+        match self {
+            Self::Aggregator => &mut config.aggregator,
+            Self::SelfHosted => &mut config.self_hosted,
+            Self::Search => &mut config.search,
+        }
+    }
+
+    fn input_mut(self, app_data: &mut NostrPostAppInternal) -> &mut String {
+        match self {
+            Self::Aggregator => &mut app_data.aggregator_relay_input,
+            Self::SelfHosted => &mut app_data.self_hosted_relay_input,
+            Self::Search => &mut app_data.search_relay_input,
+        }
+    }
+}
+
+enum RelayEdit {
+    Add,
+    Remove(usize),
+    MoveUp(usize),
+    MoveDown(usize),
+    SetAccess(String, RelayAccess),
+}
+
+// relay_statuses のキー。設定の URL は末尾の / などが揃っていないことがある。
+fn status_key(url: &str) -> String {
+    RelayUrl::parse(url).map(|u| u.to_string()).unwrap_or_else(|_| url.to_string())
+}
+
+fn status_label(status: Option<RelayStatus>) -> (egui::Color32, &'static str) {
+    match status {
+        Some(RelayStatus::Connected) => (egui::Color32::from_rgb(60, 180, 90), "接続中"),
+        Some(RelayStatus::Pending | RelayStatus::Connecting) => {
+            (egui::Color32::from_rgb(230, 170, 40), "接続しています")
+        }
+        Some(RelayStatus::Disconnected) => (egui::Color32::from_rgb(220, 80, 60), "切断 (再接続待ち)"),
+        Some(RelayStatus::Terminated) => (egui::Color32::from_rgb(220, 80, 60), "停止"),
+        Some(RelayStatus::Banned) => (egui::Color32::from_rgb(220, 80, 60), "拒否されました"),
+        Some(RelayStatus::Sleeping) => (egui::Color32::GRAY, "休止中"),
+        Some(RelayStatus::Initialized) | None => (egui::Color32::GRAY, "未接続"),
+    }
+}
+
+/// Brings the running client's pool in line with `config` without reconnecting
+/// relays that did not change.
+///
+/// Relays added for the outbox model (no READ / WRITE flag) are left alone, unless
+/// `config` lists them, in which case they get the flags from `config`.
+pub async fn apply_relay_config(client: &Client, config: &RelayConfig) {
+    let mut desired: HashMap<RelayUrl, RelayServiceFlags> = HashMap::new();
+    for url in config.all_urls() {
+        let Ok(relay_url) = RelayUrl::parse(&url) else {
+            eprintln!("Skipping invalid relay URL: {url}");
+            continue;
+        };
+        let access = config.access(&url);
+        let mut flags = RelayServiceFlags::PING;
+        if access.read {
+            flags |= RelayServiceFlags::READ;
+        }
+        if access.write {
+            flags |= RelayServiceFlags::WRITE;
+        }
+        desired.insert(relay_url, flags);
+    }
+
+    // outbox で追加済みのリレーには add_relay が効かないので、それも含めて見る
+    let current = client.pool().all_relays().await;
+    for (url, relay) in &current {
+        let flags = relay.flags();
+        if !desired.contains_key(url) && (flags.has_read() || flags.has_write()) {
+            println!("Removing relay {url}");
+            // GOSSIP 付きのリレーは外されず、READ / WRITE だけが落ちる
+            if let Err(e) = client.remove_relay(url).await {
+                eprintln!("Failed to remove relay {url}: {e}");
+            }
+        }
+    }
+
+    for (url, flags) in desired {
+        match current.get(&url) {
+            Some(relay) => {
+                relay.flags().remove(RelayServiceFlags::READ | RelayServiceFlags::WRITE);
+                relay.flags().add(flags);
+            }
+            None => {
+                println!("Adding relay {url}");
+                match client.pool().add_relay(url.clone(), RelayOptions::new().flags(flags)).await {
+                    Ok(_) => {
+                        if let Err(e) = client.connect_relay(url.clone()).await {
+                            eprintln!("Failed to connect to {url}: {e}");
+                        }
+                    }
+                    Err(e) => eprintln!("Failed to add relay {url}: {e}"),
+                }
+            }
+        }
+    }
+}
+
+/// Polls `client.relays()` for status and latency, and listens to every relay for errors.
+pub fn start_status_monitor(
+    app_data_arc: Arc<Mutex<NostrPostAppInternal>>,
+    client: Client,
+    runtime_handle: &Handle,
+) {
+    let cloned_app_data_arc = app_data_arc.clone();
+    let task = runtime_handle.spawn(async move {
+        let mut watched: HashSet<RelayUrl> = HashSet::new();
+        loop {
+            let relays = client.relays().await;
+            for (url, relay) in &relays {
+                if watched.insert(url.clone()) {
+                    watch_errors(cloned_app_data_arc.clone(), url.to_string(), relay.clone());
+                }
+            }
+            watched.retain(|url| relays.contains_key(url));
+
+            {
+                let mut app_data = cloned_app_data_arc.lock().unwrap();
+                let mut changed = false;
+                for (url, relay) in &relays {
+                    let info = app_data.relay_statuses.entry(url.to_string()).or_default();
+                    let status = relay.status();
+                    let latency = relay.stats().latency();
+                    if info.status != status || info.latency != latency {
+                        info.status = status;
+                        info.latency = latency;
+                        changed = true;
+                    }
+                }
+                let before = app_data.relay_statuses.len();
+                let in_pool: HashSet<String> = relays.keys().map(|url| url.to_string()).collect();
+                app_data.relay_statuses.retain(|url, _| in_pool.contains(url));
+                if changed || app_data.relay_statuses.len() != before {
+                    app_data.should_repaint = true;
+                }
+            }
+            tokio::time::sleep(STATUS_POLL_INTERVAL).await;
+        }
+    });
+    app_data_arc.lock().unwrap().relay_monitor = Some(task);
+}
+
+// リレーからの NOTICE / CLOSED / OK false や切断を、最後のエラーとして残す
+fn watch_errors(app_data_arc: Arc<Mutex<NostrPostAppInternal>>, url: String, relay: Relay) {
+    tokio::spawn(async move {
+        let mut notifications = relay.notifications();
+        loop {
+            let error = match notifications.recv().await {
+                Ok(RelayNotification::RelayStatus { status }) => match status {
+                    RelayStatus::Disconnected | RelayStatus::Terminated | RelayStatus::Banned => {
+                        Some(format!("切断されました ({status})"))
+                    }
+                    _ => None,
+                },
+                Ok(RelayNotification::Message { message }) => match message {
+                    RelayMessage::Notice(notice) => Some(format!("NOTICE: {notice}")),
+                    RelayMessage::Closed { message, .. } if !message.is_empty() => {
+                        Some(format!("CLOSED: {message}"))
+                    }
+                    RelayMessage::Ok { status: false, message, .. } => Some(format!("拒否: {message}")),
+                    _ => None,
+                },
+                Ok(RelayNotification::AuthenticationFailed) => Some("認証 (NIP-42) に失敗しました".to_string()),
+                Ok(RelayNotification::Shutdown) => break,
+                Ok(_) => None,
+                Err(RecvError::Lagged(_)) => None,
+                Err(RecvError::Closed) => break,
+            };
+            if let Some(error) = error {
+                let time = chrono::Local::now().format("%H:%M:%S");
+                let mut app_data = app_data_arc.lock().unwrap();
+                app_data.relay_statuses.entry(url.clone()).or_default().last_error =
+                    Some(format!("{time} {error}"));
+                app_data.should_repaint = true;
+            }
+        }
+    });
+}
+
+/// Relay management: add, remove and reorder relays per category, toggle read/write,
+/// and show each relay's live status.
+pub fn draw_relay_panel(
+    ui: &mut egui::Ui,
+    app_data: &mut NostrPostAppInternal,
+    app_data_arc: Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    ui.heading("リレー");
+    ui.add_space(10.0);
+    ui.label("変更はすぐに接続中のリレーへ反映され、設定に保存されます。");
+
+    let mut changed = false;
+    for category in RelayCategory::ALL {
+        ui.add_space(15.0);
+        ui.label(egui::RichText::new(category.title()).strong());
+        ui.weak(category.description());
+        ui.add_space(5.0);
+
+        let urls = category.urls_mut(&mut app_data.relays).clone();
+        let mut edit = None;
+        egui::Grid::new(("relay_panel", category.title()))
+            .num_columns(5)
+            .striped(true)
+            .show(ui, |ui| {
+                for (i, url) in urls.iter().enumerate() {
+                    let info = app_data.relay_statuses.get(&status_key(url));
+                    let (color, text) = status_label(info.map(|info| info.status));
+                    ui.colored_label(color, "●").on_hover_text(text);
+                    ui.label(url).on_hover_text(text);
+                    match info.and_then(|info| info.latency) {
+                        Some(latency) => ui.weak(format!("{} ms", latency.as_millis())),
+                        None => ui.weak("-"),
+                    };
+
+                    let before = app_data.relays.access(url);
+                    let mut access = before;
+                    ui.horizontal(|ui| {
+                        ui.checkbox(&mut access.read, "読み込み");
+                        ui.checkbox(&mut access.write, "書き込み");
+                    });
+                    if access != before {
+                        edit = Some(RelayEdit::SetAccess(url.clone(), access));
+                    }
+
+                    ui.horizontal(|ui| {
+                        if ui.add_enabled(i > 0, egui::Button::new("⬆")).clicked() {
+                            edit = Some(RelayEdit::MoveUp(i));
+                        }
+                        if ui.add_enabled(i + 1 < urls.len(), egui::Button::new("⬇")).clicked() {
+                            edit = Some(RelayEdit::MoveDown(i));
+                        }
+                        if ui.button("🗑").on_hover_text("削除").clicked() {
+                            edit = Some(RelayEdit::Remove(i));
+                        }
+                    });
+                    ui.end_row();
+
+                    if let Some(error) = info.and_then(|info| info.last_error.as_ref()) {
+                        ui.label("");
+                        ui.label(
+                            egui::RichText::new(error)
+                                .small()
+                                .color(ui.visuals().error_fg_color),
+                        );
+                        ui.end_row();
+                    }
+                }
+            });
+        if urls.is_empty() {
+            ui.weak("リレーがありません。");
+        }
+
+        ui.horizontal(|ui| {
+            let input = category.input_mut(app_data);
+            let response = ui.add(
+                egui::TextEdit::singleline(input)
+                    .hint_text("wss://...")
+                    .desired_width(250.0),
+            );
+            let submitted = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+            if ui.button("追加").clicked() || submitted {
+                edit = Some(RelayEdit::Add);
+            }
+        });
+
+        let Some(edit) = edit else { continue };
+        match edit {
+            RelayEdit::Add => {
+                let input = category.input_mut(app_data).trim().to_string();
+                match RelayUrl::parse(&input) {
+                    Ok(relay_url) => {
+                        let url = relay_url.to_string();
+                        let list = category.urls_mut(&mut app_data.relays);
+                        if list.iter().any(|u| status_key(u) == url) {
+                            app_data.relay_panel_error = Some(format!("{url} は追加済みです"));
+                        } else {
+                            list.push(url);
+                            category.input_mut(app_data).clear();
+                            app_data.relay_panel_error = None;
+                            changed = true;
+                        }
+                    }
+                    Err(e) => {
+                        app_data.relay_panel_error = Some(format!("URL が正しくありません: {e}"));
+                    }
+                }
+            }
+            RelayEdit::Remove(i) => {
+                category.urls_mut(&mut app_data.relays).remove(i);
+                changed = true;
+            }
+            RelayEdit::MoveUp(i) => {
+                category.urls_mut(&mut app_data.relays).swap(i - 1, i);
+                changed = true;
+            }
+            RelayEdit::MoveDown(i) => {
+                category.urls_mut(&mut app_data.relays).swap(i, i + 1);
+                changed = true;
+            }
+            RelayEdit::SetAccess(url, access) => {
+                app_data.relays.set_access(&url, access);
+                changed = true;
+            }
+        }
+    }
+
+    if let Some(error) = &app_data.relay_panel_error {
+        ui.add_space(5.0);
+        ui.colored_label(ui.visuals().error_fg_color, error);
+    }
+
+    if changed {
+        app_data.relays.prune_access();
+        save_config(app_data);
+        if let Some(client) = app_data.nostr_client.clone() {
+            let relay_config = app_data.relays.clone();
+            let cloned_runtime_handle = runtime_handle.clone();
+            runtime_handle.spawn(async move {
+                apply_relay_config(&client, &relay_config).await;
+                // タイムラインと通知の REQ を新しいリレー構成で張り直す
+                live::resubscribe_timeline(app_data_arc.clone(), &cloned_runtime_handle);
+                live::resubscribe_notifications(app_data_arc, &cloned_runtime_handle);
+            });
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use crate::{
     nostr_client::search_events,
-    types::{ImageKind, ImageState, NostrPostAppInternal},
-    ui::{image_cache, post},
+    types::{ImageKind, NostrPostAppInternal},
+    ui::{image_cache, pagination, post, user_profile_view},
 };
 use eframe::egui;
 use std::sync::{Arc, Mutex};
@@ -21,18 +21,24 @@ pub fn draw_search_view(
             ui.label("検索:");
             ui.add(
                 egui::TextEdit::singleline(&mut app_data.search_input)
-                    .hint_text("キーワードを入力..."),
+                    .hint_text("キーワード / user@domain"),
             );
             if ui.button("検索").clicked() {
                 let query = app_data.search_input.clone();
-                if !query.is_empty() {
+                app_data.search_error = None;
+                if is_nip05_identifier(&query) {
+                    open_nip05_profile(app_data, query.trim().to_string(), &app_data_arc, &runtime_handle);
+                } else if !query.is_empty() {
                     app_data.is_loading = true;
                     app_data.search_results.clear();
+                    app_data.search_query = query.clone();
+                    app_data.search_pagination.reset();
                     let search_relays = app_data.relays.search.clone();
+                    let mutes = app_data.mute_list.clone();
                     let app_data_clone = app_data_arc.clone();
                     runtime_handle.spawn(async move {
-                        let results = match search_events(search_relays, query).await {
-                            Ok(posts) => posts,
+                        let results = match search_events(search_relays, query, None, &mutes).await {
+                            Ok(page) => page.posts,
                             Err(e) => {
                                 eprintln!("Search failed: {}", e);
                                 // Optionally, set an error message in app_data to show in the UI
@@ -50,6 +56,9 @@ pub fn draw_search_view(
     });
 
     ui.add_space(10.0);
+    if let Some(error) = &app_data.search_error {
+        ui.colored_label(ui.visuals().error_fg_color, error);
+    }
     if app_data.is_loading {
         ui.spinner();
     } else if app_data.search_results.is_empty() {
@@ -66,7 +75,7 @@ pub fn draw_search_view(
         };
 
         card_frame.show(ui, |ui| {
-            egui::ScrollArea::vertical()
+            let output = egui::ScrollArea::vertical()
                 .id_salt("search_scroll_area")
                 .max_height(ui.available_height() - 50.0)
                 .show_rows(ui, row_height, num_posts, |ui, row_range| {
@@ -84,98 +93,73 @@ pub fn draw_search_view(
                         }
                     }
                 });
+            if pagination::near_bottom(&output, row_height) {
+                load_older_results(app_data, app_data_arc.clone(), &runtime_handle);
+            }
+            pagination::draw_footer(ui, &mut app_data.search_pagination);
         });
     }
 
-    // --- Image Loading Logic ---
-    let cache_db = app_data.cache_db.clone();
-    let mut still_to_load = Vec::new();
-    for (url_key, kind) in urls_to_load {
-        if let Some(image_bytes) = image_cache::load_from_lmdb(&cache_db, &url_key) {
-            if let Ok(mut dynamic_image) = image::load_from_memory(&image_bytes) {
-                let (width, height) = match kind {
-                    ImageKind::Avatar => (32, 32),
-                    ImageKind::Emoji => (20, 20),
-                    _ => (32, 32),
-                };
-                dynamic_image = dynamic_image.thumbnail(width, height);
-                let color_image = egui::ColorImage::from_rgba_unmultiplied(
-                    [
-                        dynamic_image.width() as usize,
-                        dynamic_image.height() as usize,
-                    ],
-                    dynamic_image.to_rgba8().as_flat_samples().as_slice(),
-                );
-                let texture_handle =
-                    ctx.load_texture(&url_key, color_image, Default::default());
-                app_data
-                    .image_cache
-                    .insert(url_key, ImageState::Loaded(texture_handle));
-            } else {
-                app_data.image_cache.insert(url_key, ImageState::Failed);
-            }
-        } else {
-            still_to_load.push((url_key, kind));
+    image_cache::load_images(ctx, app_data, &app_data_arc, &runtime_handle, urls_to_load);
+}
+
+// 空白を含まない name@domain (または @domain) を NIP-05 の識別子とみなす
+fn is_nip05_identifier(query: &str) -> bool {
+    let query = query.trim();
+    match query.split_once('@') {
+        Some((name, domain)) => {
+            !query.contains(char::is_whitespace) && !name.contains('@') && !domain.contains('@') && domain.contains('.')
         }
+        None => false,
     }
+}
 
-    let data_clone = app_data_arc.clone();
-    for (url_key, kind) in still_to_load {
-        app_data
-            .image_cache
-            .insert(url_key.clone(), ImageState::Loading);
+// nostr.json で公開鍵とリレーを調べて、その人のプロフィールを開く
+fn open_nip05_profile(
+    app_data: &mut NostrPostAppInternal,
+    identifier: String,
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    app_data.is_loading = true;
+    let resolver = app_data.nip05_resolver.clone();
+    let arc = app_data_arc.clone();
+    let handle = runtime_handle.clone();
+    runtime_handle.spawn(async move {
+        let result = resolver.lookup(&identifier).await;
+        let mut app_data = arc.lock().unwrap();
+        app_data.is_loading = false;
         app_data.should_repaint = true;
+        match result {
+            Ok(profile) => {
+                let relays = profile.relays.iter().map(|url| url.to_string()).collect();
+                user_profile_view::open_profile_with_relays(&mut app_data, profile.public_key, relays, &arc, &handle);
+            }
+            Err(e) => {
+                eprintln!("Failed to resolve {identifier}: {e}");
+                app_data.search_error = Some(format!("{identifier} を見つけられませんでした: {e}"));
+            }
+        }
+    });
+}
 
-        let app_data_clone = data_clone.clone();
-        let ctx_clone = ctx.clone();
-        let cache_db_for_fetch = app_data.cache_db.clone();
-        let request = ehttp::Request::get(&url_key);
-
-        ehttp::fetch(request, move |result| {
-            let new_state = match result {
-                Ok(response) => {
-                    if response.ok {
-                        image_cache::save_to_lmdb(
-                            &cache_db_for_fetch,
-                            &response.url,
-                            &response.bytes,
-                        );
-
-                        match image::load_from_memory(&response.bytes) {
-                            Ok(mut dynamic_image) => {
-                                let (width, height) = match kind {
-                                    ImageKind::Avatar => (32, 32),
-                                    ImageKind::Emoji => (20, 20),
-                                    _ => (32, 32),
-                                };
-                                dynamic_image = dynamic_image.thumbnail(width, height);
-
-                                let color_image = egui::ColorImage::from_rgba_unmultiplied(
-                                    [
-                                        dynamic_image.width() as usize,
-                                        dynamic_image.height() as usize,
-                                    ],
-                                    dynamic_image.to_rgba8().as_flat_samples().as_slice(),
-                                );
-                                let texture_handle = ctx_clone.load_texture(
-                                    &response.url,
-                                    color_image,
-                                    Default::default(),
-                                );
-                                ImageState::Loaded(texture_handle)
-                            }
-                            Err(_) => ImageState::Failed,
-                        }
-                    } else {
-                        ImageState::Failed
-                    }
-                }
-                Err(_) => ImageState::Failed,
-            };
+fn load_older_results(
+    app_data: &mut NostrPostAppInternal,
+    app_data_arc: Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    let Some((until, generation)) = app_data.search_pagination.begin(&app_data.search_results) else {
+        return;
+    };
+    let search_relays = app_data.relays.search.clone();
+    let query = app_data.search_query.clone();
+    let mutes = app_data.mute_list.clone();
 
-            let mut app_data = app_data_clone.lock().unwrap();
-            app_data.image_cache.insert(url_key, new_state);
-            ctx_clone.request_repaint();
-        });
-    }
+    runtime_handle.spawn(async move {
+        let result = search_events(search_relays, query, Some(until), &mutes).await;
+        let mut guard = app_data_arc.lock().unwrap();
+        let app_data = &mut *guard;
+        app_data.search_pagination.finish(generation, &mut app_data.search_results, result);
+        app_data.should_repaint = true;
+    });
 }
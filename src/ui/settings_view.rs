@@ -1,21 +1,28 @@
 use crate::{
-    cache_db::{DB_FOLLOWED, DB_PROFILES, DB_RELAYS, DB_TIMELINE},
+    cache_db::{DB_FOLLOWED, DB_PROFILES},
+    nip17,
+    nip65::{self, RelayList},
     save_config,
-    types::{AppTab, AppTheme, NostrPostAppInternal, ProfileMetadata, RelayConfig, TimelinePost, UserBackup},
+    types::{AppTheme, MediaServerKind, NostrPostAppInternal, ProfileMetadata, UserBackup},
+    ui::{accounts, follow_panel, mute_panel, relay_panel},
+    READ_ONLY_HINT,
 };
 use eframe::egui;
-use nostr::{nips::nip19::ToBech32, PublicKey};
+use nostr::{nips::nip19::ToBech32, PublicKey, RelayUrl};
 use rfd::FileDialog;
 use std::collections::HashSet;
 use std::fs;
 use std::sync::{Arc, Mutex};
 use tokio::runtime::Handle;
 
+// 画像キャッシュの上限の選択肢 (MB)。LMDB 全体は 1 GB まで
+const IMAGE_CACHE_BUDGETS_MB: [u64; 4] = [64, 128, 256, 512];
+
 pub fn draw_settings_view(
     ui: &mut egui::Ui,
     ctx: &egui::Context,
     app_data: &mut NostrPostAppInternal,
-    _app_data_arc: Arc<Mutex<NostrPostAppInternal>>,
+    app_data_arc: Arc<Mutex<NostrPostAppInternal>>,
     runtime_handle: Handle,
 ) {
     let logout_button_text = "ログアウト";
@@ -40,6 +47,289 @@ pub fn draw_settings_view(
     ui.separator();
     ui.add_space(20.0);
 
+    // --- デスクトップ通知 ---
+    ui.heading("デスクトップ通知");
+    ui.add_space(10.0);
+    ui.label("ウィンドウが裏にあるときに届いたものを、OS の通知で知らせます。");
+    let config = &mut app_data.desktop_notifications;
+    let mut changed = ui.checkbox(&mut config.enabled, "デスクトップ通知を使う").changed();
+    ui.add_enabled_ui(config.enabled, |ui| {
+        changed |= ui.checkbox(&mut config.mentions, "メンション・返信").changed();
+        changed |= ui.checkbox(&mut config.messages, "ダイレクトメッセージ").changed();
+        changed |= ui.checkbox(&mut config.zaps, "Zap").changed();
+    });
+    if changed {
+        save_config(app_data);
+    }
+    if ui.button("テスト通知を送る").clicked() {
+        let notifier = app_data.notifier.clone();
+        runtime_handle.spawn(async move {
+            if let Err(e) = notifier.notify("rustr", "テスト通知です").await {
+                eprintln!("Failed to show desktop notification: {e}");
+            }
+        });
+    }
+
+    ui.add_space(20.0);
+    ui.separator();
+    ui.add_space(20.0);
+
+    // --- メディアの表示 ---
+    ui.heading("メディアの表示");
+    ui.add_space(10.0);
+    ui.label("本文の画像・動画の URL はその場に表示します。ぼかしはアカウントごとの設定です。");
+    let active_account = app_data.active_account;
+    let blur_changed = app_data.accounts.get_mut(active_account).is_some_and(|account| {
+        ui.checkbox(&mut account.blur_unfollowed_media, "フォローしていない人の画像・動画をクリックするまでぼかす")
+            .changed()
+    });
+    if blur_changed {
+        save_config(app_data);
+    }
+
+    ui.add_space(20.0);
+    ui.separator();
+    ui.add_space(20.0);
+
+    // --- 画像キャッシュ ---
+    ui.heading("画像キャッシュ");
+    ui.add_space(10.0);
+    ui.label("表示した画像は縮小してディスクに保存します。上限を超えると、長く表示していないものから削除します。");
+    match app_data.cache_db.image_cache_usage() {
+        Ok(usage) => {
+            ui.label(format!(
+                "使用量: {:.1} MB / {} MB ({}件)",
+                usage.bytes as f64 / (1024.0 * 1024.0),
+                app_data.image_cache_config.budget_mb,
+                usage.entries
+            ));
+        }
+        Err(e) => {
+            ui.label(format!("使用量を読み取れませんでした: {e}"));
+        }
+    }
+    let previous_budget = app_data.image_cache_config.budget_mb;
+    ui.horizontal(|ui| {
+        ui.label("上限:");
+        egui::ComboBox::from_id_salt("image_cache_budget")
+            .selected_text(format!("{} MB", app_data.image_cache_config.budget_mb))
+            .show_ui(ui, |ui| {
+                for budget_mb in IMAGE_CACHE_BUDGETS_MB {
+                    ui.selectable_value(&mut app_data.image_cache_config.budget_mb, budget_mb, format!("{budget_mb} MB"));
+                }
+            });
+    });
+    if app_data.image_cache_config.budget_mb != previous_budget {
+        if let Err(e) = app_data.cache_db.set_image_budget(app_data.image_cache_config.budget_bytes()) {
+            eprintln!("Failed to trim image cache: {e}");
+        }
+        save_config(app_data);
+    }
+    if ui.button("画像キャッシュを消去").clicked()
+        && let Err(e) = app_data.cache_db.clear_image_cache()
+    {
+        eprintln!("Failed to clear image cache: {e}");
+    }
+
+    ui.add_space(20.0);
+    ui.separator();
+    ui.add_space(20.0);
+
+    // --- メディアのアップロード (NIP-96 / Blossom) ---
+    ui.heading("メディアのアップロード");
+    ui.add_space(10.0);
+    ui.label("投稿に添付した画像や動画のアップロード先です。");
+    let server = &mut app_data.media_server;
+    let previous_kind = server.kind;
+    let mut changed = false;
+    ui.horizontal(|ui| {
+        changed |= ui.radio_value(&mut server.kind, MediaServerKind::Nip96, "NIP-96").changed();
+        changed |= ui.radio_value(&mut server.kind, MediaServerKind::Blossom, "Blossom").changed();
+    });
+    // 既定のサーバーのままなら、種類に合わせて切り替える
+    if server.kind != previous_kind && server.url == previous_kind.default_url() {
+        server.url = server.kind.default_url().to_string();
+    }
+    ui.horizontal(|ui| {
+        ui.label("サーバー:");
+        let response = ui.add(
+            egui::TextEdit::singleline(&mut server.url)
+                .hint_text(server.kind.default_url())
+                .desired_width(300.0),
+        );
+        changed |= response.lost_focus();
+    });
+    if changed {
+        save_config(app_data);
+    }
+
+    ui.add_space(20.0);
+    ui.separator();
+    ui.add_space(20.0);
+
+    // --- リレー ---
+    relay_panel::draw_relay_panel(ui, app_data, app_data_arc.clone(), &runtime_handle);
+
+    ui.add_space(20.0);
+    ui.separator();
+    ui.add_space(20.0);
+
+    // --- フォロー (NIP-02) ---
+    follow_panel::draw_follow_panel(ui, app_data, &app_data_arc, &runtime_handle);
+
+    ui.add_space(20.0);
+    ui.separator();
+    ui.add_space(20.0);
+
+    // --- ミュート (NIP-51) ---
+    mute_panel::draw_mute_panel(ui, app_data, &app_data_arc, &runtime_handle);
+
+    ui.add_space(20.0);
+    ui.separator();
+    ui.add_space(20.0);
+
+    // --- リレーリスト (NIP-65) ---
+    ui.heading("リレーリスト (NIP-65)");
+    ui.add_space(10.0);
+    ui.label("他のクライアントがあなたの投稿を探したり、リプライを届けたりするときに使うリレーです。");
+    let relay_list = RelayList::from_config(&app_data.relays);
+    let mut listed: Vec<&String> = relay_list.write.iter().collect();
+    listed.extend(relay_list.read.iter().filter(|url| !relay_list.write.contains(url)));
+    egui::Grid::new("nip65_relay_list").num_columns(2).show(ui, |ui| {
+        for url in listed {
+            ui.label(url);
+            match (relay_list.read.contains(url), relay_list.write.contains(url)) {
+                (true, true) => ui.weak("読み書き"),
+                (true, false) => ui.weak("読み込み"),
+                _ => ui.weak("書き込み"),
+            };
+            ui.end_row();
+        }
+    });
+    ui.add_space(5.0);
+    let can_publish = app_data.my_signer.is_some() && !app_data.is_loading;
+    let publish_button = ui
+        .add_enabled(can_publish, egui::Button::new("リレーリストを公開 (kind 10002)"));
+    let publish_button = if app_data.my_signer.is_none() {
+        publish_button.on_disabled_hover_text(READ_ONLY_HINT)
+    } else {
+        publish_button
+    };
+    if publish_button.clicked()
+        && let (Some(client), Some(signer)) = (app_data.nostr_client.clone(), app_data.my_signer.clone())
+    {
+        let cache_db = app_data.cache_db.clone();
+        let relay_config = app_data.relays.clone();
+        let cloned_app_data_arc = app_data_arc.clone();
+        app_data.relay_list_status = "公開中...".to_string();
+        runtime_handle.spawn(async move {
+            let status = match nip65::publish_own_relay_list(&client, &cache_db, &signer, &relay_config).await {
+                Ok(event_id) => {
+                    println!("Relay list published with event id: {event_id:?}");
+                    "リレーリストを公開しました。".to_string()
+                }
+                Err(e) => {
+                    eprintln!("Failed to publish relay list: {e}");
+                    format!("公開に失敗しました: {e}")
+                }
+            };
+            let mut app_data = cloned_app_data_arc.lock().unwrap();
+            app_data.relay_list_status = status;
+            app_data.should_repaint = true;
+        });
+    }
+    if !app_data.relay_list_status.is_empty() {
+        ui.label(&app_data.relay_list_status);
+    }
+
+    ui.add_space(20.0);
+    ui.separator();
+    ui.add_space(20.0);
+
+    // --- DM リレー (NIP-17) ---
+    ui.heading("DM リレー (NIP-17)");
+    ui.add_space(10.0);
+    ui.label("他のクライアントがあなたに DM を届けるリレーです。設定しなければ読み込みリレーを使います。");
+    let dm_relays = nip17::own_dm_relays(&app_data.relays);
+    let mut removed_dm_relay = None;
+    egui::Grid::new("dm_relay_list").num_columns(2).show(ui, |ui| {
+        for url in &dm_relays {
+            ui.label(url);
+            if app_data.relays.dm.is_empty() {
+                ui.weak("読み込みリレー");
+            } else if ui.button("削除").clicked() {
+                removed_dm_relay = Some(url.clone());
+            }
+            ui.end_row();
+        }
+    });
+    ui.horizontal(|ui| {
+        ui.add(
+            egui::TextEdit::singleline(&mut app_data.dm_relay_input)
+                .hint_text("wss://...")
+                .desired_width(250.0),
+        );
+        if ui.button("追加").clicked() {
+            match RelayUrl::parse(app_data.dm_relay_input.trim()) {
+                Ok(relay_url) => {
+                    // 読み込みリレーを使っていたなら、それを引き継いでから足す
+                    let mut relays = dm_relays.clone();
+                    let url = relay_url.to_string();
+                    if !relays.contains(&url) {
+                        relays.push(url);
+                    }
+                    app_data.relays.dm = relays;
+                    app_data.dm_relay_input.clear();
+                    app_data.dm_relays_status.clear();
+                    save_config(app_data);
+                }
+                Err(e) => app_data.dm_relays_status = format!("URL が正しくありません: {e}"),
+            }
+        }
+    });
+    if let Some(url) = removed_dm_relay {
+        app_data.relays.dm.retain(|u| *u != url);
+        save_config(app_data);
+    }
+    ui.add_space(5.0);
+    let publish_button = ui
+        .add_enabled(can_publish, egui::Button::new("DM リレーを公開 (kind 10050)"));
+    let publish_button = if app_data.my_signer.is_none() {
+        publish_button.on_disabled_hover_text(READ_ONLY_HINT)
+    } else {
+        publish_button
+    };
+    if publish_button.clicked()
+        && let (Some(client), Some(signer)) = (app_data.nostr_client.clone(), app_data.my_signer.clone())
+    {
+        let cache_db = app_data.cache_db.clone();
+        let relay_config = app_data.relays.clone();
+        let cloned_app_data_arc = app_data_arc.clone();
+        app_data.dm_relays_status = "公開中...".to_string();
+        runtime_handle.spawn(async move {
+            let status = match nip17::publish_dm_relays(&client, &cache_db, &signer, &relay_config).await {
+                Ok(event_id) => {
+                    println!("DM relay list published with event id: {event_id:?}");
+                    "DM リレーを公開しました。".to_string()
+                }
+                Err(e) => {
+                    eprintln!("Failed to publish DM relay list: {e}");
+                    format!("公開に失敗しました: {e}")
+                }
+            };
+            let mut app_data = cloned_app_data_arc.lock().unwrap();
+            app_data.dm_relays_status = status;
+            app_data.should_repaint = true;
+        });
+    }
+    if !app_data.dm_relays_status.is_empty() {
+        ui.label(&app_data.dm_relays_status);
+    }
+
+    ui.add_space(20.0);
+    ui.separator();
+    ui.add_space(20.0);
+
     // --- イベントデータのバックアップ ---
     ui.heading("データのバックアップ");
     ui.add_space(10.0);
@@ -47,8 +337,8 @@ pub fn draw_settings_view(
     ui.add_space(10.0);
 
     if ui.button("バックアップをダウンロード").clicked() {
-        if let Some(keys) = &app_data.my_keys {
-            let pubkey_hex = keys.public_key().to_string();
+        if let Some(pubkey) = app_data.my_pubkey {
+            let pubkey_hex = pubkey.to_string();
             let mut backup = UserBackup::default();
 
             // Fetch data from cache
@@ -64,17 +354,10 @@ pub fn draw_settings_view(
             {
                 backup.followed_pubkeys = Some(cache.data);
             }
-            if let Ok(cache) = app_data
-                .cache_db
-                .read_cache::<RelayConfig>(DB_RELAYS, &pubkey_hex)
-            {
-                backup.relays = Some(cache.data);
-            }
-            if let Ok(cache) = app_data
-                .cache_db
-                .read_cache::<Vec<TimelinePost>>(DB_TIMELINE, &pubkey_hex)
-            {
-                backup.timeline = Some(cache.data);
+            backup.relays = Some(app_data.relays.clone());
+            // タイムラインは表示中のものを書き出す (元のイベントはイベントデータベースにある)
+            if !app_data.timeline_posts.is_empty() {
+                backup.timeline = Some(app_data.timeline_posts.clone());
             }
 
             match serde_json::to_string_pretty(&backup) {
@@ -84,10 +367,10 @@ pub fn draw_settings_view(
                         .add_filter("JSON", &["json"])
                         .save_file();
 
-                    if let Some(path) = file_path {
-                        if let Err(e) = fs::write(path, json_str) {
-                            eprintln!("Failed to write backup file: {}", e);
-                        }
+                    if let Some(path) = file_path
+                        && let Err(e) = fs::write(path, json_str)
+                    {
+                        eprintln!("Failed to write backup file: {}", e);
                     }
                 }
                 Err(e) => {
@@ -120,10 +403,9 @@ pub fn draw_settings_view(
 
         ui.label("あなたの公開鍵 (npub)");
         let public_key_bech32 = app_data
-            .my_keys
-            .as_ref()
-            .map_or("N/A".to_string(), |k| {
-                k.public_key().to_bech32().unwrap_or_default()
+            .my_pubkey
+            .map_or("N/A".to_string(), |pk| {
+                pk.to_bech32().unwrap_or_default()
             });
         ui.horizontal(|ui| {
             ui.text_edit_singleline(&mut public_key_bech32.clone())
@@ -145,29 +427,8 @@ pub fn draw_settings_view(
             )
             .clicked()
         {
-            let client_to_shutdown = app_data.nostr_client.take();
-
-            app_data.is_logged_in = false;
-            app_data.my_keys = None;
-            app_data.followed_pubkeys.clear();
-            app_data.followed_pubkeys_display.clear();
-            app_data.timeline_posts.clear();
-            app_data.post_input.clear();
-            app_data.passphrase_input.clear();
-            app_data.confirm_passphrase_input.clear();
-            app_data.secret_key_input.clear();
-            app_data.current_tab = AppTab::Home;
-            app_data.nip01_profile_display.clear();
-            app_data.editable_profile = ProfileMetadata::default();
-            app_data.profile_fetch_status = "ログインしてください".to_string();
-            app_data.should_repaint = true;
+            accounts::end_session(app_data, &runtime_handle);
             println!("Logged out.");
-
-            if let Some(client) = client_to_shutdown {
-                runtime_handle.spawn(async move {
-                    client.shutdown().await;
-                });
-            }
         }
     });
 }
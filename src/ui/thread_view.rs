@@ -0,0 +1,383 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use eframe::egui;
+use nostr::{Event, EventId, Filter, Kind, PublicKey};
+use nostr_sdk::Client;
+use tokio::runtime::Handle;
+
+use crate::{
+    nip10::ThreadRefs,
+    nip65,
+    nostr_client::event_to_timeline_post,
+    types::{AppTab, ImageKind, NostrPostAppInternal, ProfileMetadata, TimelinePost},
+    ui::{navigation, post},
+};
+
+// 親をたどる回数の上限
+const MAX_ANCESTORS: usize = 30;
+// これより深い返信は字下げを増やさない
+const MAX_INDENT_DEPTH: usize = 8;
+const INDENT_WIDTH: f32 = 16.0;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The open conversation, laid out as a reply tree.
+pub struct ThreadState {
+    pub focus: EventId,
+    posts: HashMap<EventId, TimelinePost>,
+    children: HashMap<EventId, Vec<EventId>>,
+    // 親が見つからなかった投稿 (普通はルートだけ)
+    top_level: Vec<EventId>,
+    collapsed: HashSet<EventId>,
+    pub is_loading: bool,
+    pub error: Option<String>,
+}
+
+impl ThreadState {
+    fn new(focus: TimelinePost) -> Self {
+        let mut thread = Self {
+            focus: focus.id,
+            posts: HashMap::new(),
+            children: HashMap::new(),
+            top_level: Vec::new(),
+            collapsed: HashSet::new(),
+            is_loading: false,
+            error: None,
+        };
+        thread.set_posts(vec![focus]);
+        thread
+    }
+
+    // 返信先 (NIP-10) をもとに木を組み直す。兄弟は古い順に並べる。
+    fn set_posts(&mut self, posts: Vec<TimelinePost>) {
+        self.posts = posts.into_iter().map(|p| (p.id, p)).collect();
+        self.children.clear();
+        self.top_level.clear();
+
+        let mut sorted: Vec<&TimelinePost> = self.posts.values().collect();
+        sorted.sort_by_key(|p| (p.created_at, p.id));
+        for post in sorted {
+            let parent = ThreadRefs::parse(&post.tags)
+                .parent()
+                .map(|r| r.id)
+                .filter(|id| *id != post.id && self.posts.contains_key(id));
+            match parent {
+                Some(parent) => self.children.entry(parent).or_default().push(post.id),
+                None => self.top_level.push(post.id),
+            }
+        }
+    }
+
+    fn descendant_count(&self, id: &EventId) -> usize {
+        self.children
+            .get(id)
+            .map(|children| children.iter().map(|c| 1 + self.descendant_count(c)).sum())
+            .unwrap_or(0)
+    }
+}
+
+/// Opens the thread of `post` in the home tab and starts loading it.
+pub fn open_thread(
+    app_data: &mut NostrPostAppInternal,
+    post: &TimelinePost,
+    app_data_arc: Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    // 開いていたページは戻るボタンで戻れるように履歴へ積む
+    navigation::push(app_data);
+    app_data.thread = Some(ThreadState::new(post.clone()));
+    app_data.current_tab = AppTab::Home;
+    load(app_data, app_data_arc, runtime_handle);
+}
+
+/// Loads the open thread again, e.g. after coming back to it while it was still loading.
+pub fn reload(
+    app_data: &mut NostrPostAppInternal,
+    app_data_arc: Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    load(app_data, app_data_arc, runtime_handle);
+}
+
+fn load(
+    app_data: &mut NostrPostAppInternal,
+    app_data_arc: Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    let Some(client) = app_data.nostr_client.clone() else { return };
+    let Some(thread) = app_data.thread.as_mut() else { return };
+    let Some(focus) = thread.posts.get(&thread.focus).cloned() else { return };
+    thread.is_loading = true;
+    thread.error = None;
+    let known = app_data.quoted_posts_cache.clone();
+    let profiles = app_data.profile_cache.clone();
+
+    runtime_handle.spawn(async move {
+        let focus_id = focus.id;
+        // 保存済みのイベントだけで先に組んで表示し、リレーの結果で組み直す
+        if let Ok((posts, new_profiles)) = load_thread(&client, focus.clone(), &known, &profiles, true).await {
+            let mut app_data = app_data_arc.lock().unwrap();
+            app_data.profile_cache.extend(new_profiles);
+            if let Some(thread) = app_data.thread.as_mut().filter(|t| t.focus == focus_id) {
+                thread.set_posts(posts);
+            }
+            app_data.should_repaint = true;
+        }
+        let result = load_thread(&client, focus, &known, &profiles, false).await;
+
+        let mut app_data = app_data_arc.lock().unwrap();
+        let (posts, new_profiles) = match result {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                eprintln!("Failed to load thread: {e}");
+                if let Some(thread) = app_data.thread.as_mut().filter(|t| t.focus == focus_id) {
+                    thread.is_loading = false;
+                    thread.error = Some(e.to_string());
+                }
+                app_data.should_repaint = true;
+                return;
+            }
+        };
+        // 取得した投稿は引用・返信先の表示でも使い回す
+        for post in &posts {
+            app_data.quoted_posts_cache.insert(post.id, Arc::new(post.clone()));
+        }
+        app_data.profile_cache.extend(new_profiles);
+        // 読み込み中に別のスレッドへ移っていたら結果は捨てる
+        if let Some(thread) = app_data.thread.as_mut().filter(|t| t.focus == focus_id) {
+            thread.set_posts(posts);
+            thread.is_loading = false;
+        }
+        app_data.should_repaint = true;
+    });
+}
+
+// 保存済みのイベントに、relays があればそこから取ったものを加える
+async fn fetch_events(
+    client: &Client,
+    relays: Option<&[String]>,
+    filter: Filter,
+    timeout: Duration,
+) -> Result<Vec<Event>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut events: Vec<Event> = client.database().query(filter.clone()).await?.into_iter().collect();
+    if let Some(relays) = relays {
+        for event in client.fetch_events_from(relays.to_vec(), filter, timeout).await? {
+            if !events.iter().any(|e| e.id == event.id) {
+                events.push(event);
+            }
+        }
+    }
+    Ok(events)
+}
+
+/// Fetches the ancestors of `focus` up to the root and every reply under the root.
+/// With `local_only`, only the event database is searched.
+///
+/// Returns the thread's posts and the author profiles that were not in `profiles`.
+async fn load_thread(
+    client: &Client,
+    focus: TimelinePost,
+    known: &HashMap<EventId, Arc<TimelinePost>>,
+    profiles: &HashMap<PublicKey, ProfileMetadata>,
+    local_only: bool,
+) -> Result<(Vec<TimelinePost>, HashMap<PublicKey, ProfileMetadata>), Box<dyn std::error::Error + Send + Sync>> {
+    let refs = ThreadRefs::parse(&focus.tags);
+    let root_id = refs.root.as_ref().map(|r| r.id).unwrap_or(focus.id);
+    let hints: Vec<String> = refs
+        .root
+        .iter()
+        .chain(refs.reply.iter())
+        .filter_map(|r| r.relay.as_ref().map(|url| url.to_string()))
+        .collect();
+    let relays = if local_only { None } else { Some(nip65::read_relays_with_hints(client, &hints).await) };
+    let relays = relays.as_deref();
+
+    // 返信は NIP-10 ではルートを必ず参照する。旧形式で返信先しか指していないものも拾うため、
+    // 開いた投稿への返信も合わせて取る。
+    let replies_filter = Filter::new().kind(Kind::TextNote).events([root_id, focus.id]);
+    let wanted: Vec<EventId> = std::iter::once(root_id)
+        .chain(refs.parent().map(|r| r.id))
+        .filter(|id| *id != focus.id && !known.contains_key(id))
+        .collect();
+    let ancestors_fetch = async {
+        if wanted.is_empty() {
+            return Ok(Default::default());
+        }
+        fetch_events(client, relays, Filter::new().ids(wanted.clone()), FETCH_TIMEOUT).await
+    };
+    let (replies, ancestors) = futures::join!(
+        fetch_events(client, relays, replies_filter, FETCH_TIMEOUT),
+        ancestors_fetch
+    );
+
+    let mut events: HashMap<EventId, Event> = HashMap::new();
+    match ancestors {
+        Ok(found) => events.extend(found.into_iter().map(|e| (e.id, e))),
+        Err(e) => eprintln!("Failed to fetch thread ancestors: {e}"),
+    }
+    for event in replies? {
+        let event_refs = ThreadRefs::parse(&event.tags.clone().to_vec());
+        let in_thread = event_refs.root.as_ref().is_some_and(|r| r.id == root_id)
+            || event_refs.parent().is_some_and(|r| r.id == root_id || r.id == focus.id);
+        if in_thread {
+            events.insert(event.id, event);
+        }
+    }
+
+    // 返信先を 1 つずつたどってルートまでの祖先をそろえる
+    let mut posts: Vec<TimelinePost> = vec![focus.clone()];
+    let mut parent = refs.parent().map(|r| r.id);
+    for _ in 0..MAX_ANCESTORS {
+        let Some(id) = parent else { break };
+        let tags = if let Some(post) = known.get(&id) {
+            posts.push((**post).clone());
+            post.tags.clone()
+        } else if let Some(event) = events.get(&id) {
+            event.tags.clone().to_vec()
+        } else {
+            let filter = Filter::new().id(id);
+            match fetch_events(client, relays, filter, FETCH_TIMEOUT).await {
+                Ok(found) => match found.into_iter().next() {
+                    Some(event) => {
+                        let tags = event.tags.clone().to_vec();
+                        events.insert(event.id, event);
+                        tags
+                    }
+                    None => break,
+                },
+                Err(e) => {
+                    eprintln!("Failed to fetch parent {id}: {e}");
+                    break;
+                }
+            }
+        };
+        parent = ThreadRefs::parse(&tags).parent().map(|r| r.id).filter(|p| *p != id);
+    }
+
+    // 知らない著者のプロフィールだけまとめて取る
+    let missing: HashSet<PublicKey> = events
+        .values()
+        .map(|e| e.pubkey)
+        .filter(|pk| !profiles.contains_key(pk))
+        .collect();
+    let mut new_profiles: HashMap<PublicKey, ProfileMetadata> = HashMap::new();
+    if !missing.is_empty() {
+        let filter = Filter::new().authors(missing).kind(Kind::Metadata);
+        match fetch_events(client, relays, filter, Duration::from_secs(5)).await {
+            Ok(metadata_events) => {
+                for event in metadata_events {
+                    if let Ok(metadata) = serde_json::from_str::<ProfileMetadata>(&event.content) {
+                        new_profiles.insert(event.pubkey, metadata);
+                    }
+                }
+            }
+            Err(e) => eprintln!("Failed to fetch thread profiles: {e}"),
+        }
+    }
+
+    for event in events.values() {
+        if posts.iter().any(|p| p.id == event.id) {
+            continue;
+        }
+        let metadata = new_profiles
+            .get(&event.pubkey)
+            .or_else(|| profiles.get(&event.pubkey))
+            .cloned()
+            .unwrap_or_default();
+        posts.push(event_to_timeline_post(event, metadata));
+    }
+    Ok((posts, new_profiles))
+}
+
+pub fn draw_thread_view(
+    ui: &mut egui::Ui,
+    app_data: &mut NostrPostAppInternal,
+    urls_to_load: &mut Vec<(String, ImageKind)>,
+    app_data_arc: Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    let Some(is_loading) = app_data.thread.as_ref().map(|thread| thread.is_loading) else { return };
+    ui.horizontal(|ui| {
+        if ui.button("← 戻る").clicked() {
+            navigation::back(app_data, &app_data_arc, runtime_handle);
+        }
+        ui.heading("スレッド");
+        if is_loading {
+            ui.add_space(10.0);
+            ui.spinner();
+            ui.label("会話を読み込み中...");
+        } else if ui.button("再読み込み").clicked() {
+            load(app_data, app_data_arc.clone(), runtime_handle);
+        }
+    });
+    // 描画中に投稿から別のスレッドを開けるよう、状態を取り出しておく
+    let Some(mut thread) = app_data.thread.take() else { return };
+    if let Some(error) = &thread.error {
+        ui.colored_label(ui.visuals().error_fg_color, format!("読み込みに失敗しました: {error}"));
+    }
+    ui.add_space(10.0);
+
+    egui::ScrollArea::vertical()
+        .id_salt("thread_scroll_area")
+        .max_height(ui.available_height() - 100.0)
+        .show(ui, |ui| {
+            for id in thread.top_level.clone() {
+                draw_node(ui, app_data, &mut thread, id, 0, urls_to_load, &app_data_arc, runtime_handle);
+            }
+        });
+
+    navigation::restore_thread(app_data, thread);
+}
+
+#[allow(clippy::too_many_arguments)]
+fn draw_node(
+    ui: &mut egui::Ui,
+    app_data: &mut NostrPostAppInternal,
+    thread: &mut ThreadState,
+    id: EventId,
+    depth: usize,
+    urls_to_load: &mut Vec<(String, ImageKind)>,
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    let Some(post) = thread.posts.get(&id).cloned() else { return };
+    let children = thread.children.get(&id).cloned().unwrap_or_default();
+    let collapsed = thread.collapsed.contains(&id);
+
+    ui.horizontal(|ui| {
+        ui.add_space(INDENT_WIDTH * depth.min(MAX_INDENT_DEPTH) as f32);
+        ui.vertical(|ui| {
+            post::render_thread_post(
+                ui,
+                app_data,
+                &post,
+                urls_to_load,
+                app_data_arc.clone(),
+                runtime_handle.clone(),
+                id == thread.focus,
+            );
+            if !children.is_empty() {
+                let label = if collapsed {
+                    format!("▶ {}件の返信を表示", thread.descendant_count(&id))
+                } else {
+                    "▼ 返信を隠す".to_string()
+                };
+                if ui.small_button(label).clicked() {
+                    if collapsed {
+                        thread.collapsed.remove(&id);
+                    } else {
+                        thread.collapsed.insert(id);
+                    }
+                }
+            }
+            ui.add_space(5.0);
+        });
+    });
+
+    if !collapsed {
+        for child in children {
+            draw_node(ui, app_data, thread, child, depth + 1, urls_to_load, app_data_arc, runtime_handle);
+        }
+    }
+}
@@ -0,0 +1,387 @@
+use std::sync::{Arc, Mutex};
+
+use eframe::egui::{self, Sense};
+use nostr::nips::nip19::ToBech32;
+use nostr::PublicKey;
+use tokio::runtime::Handle;
+
+use crate::{
+    event_store,
+    nip51::MuteItem,
+    nostr_client::{fetch_follow_counts, fetch_posts_by_author, get_profile_metadata, stored_posts_by_author, FOLLOWER_LIMIT},
+    types::{AppTab, ImageKind, ImageState, NostrPostAppInternal, ProfileMetadata, TimelinePost},
+    ui::{follow_panel, image_cache, mute_panel, navigation, pagination::{self, Pagination}, post, zap},
+    READ_ONLY_HINT,
+};
+
+const AVATAR_SIZE: f32 = 80.0;
+const BANNER_HEIGHT: f32 = 150.0;
+
+/// Another user's profile page.
+pub struct UserProfile {
+    pub pubkey: PublicKey,
+    metadata: ProfileMetadata,
+    // NIP-05 の nostr.json などで教えられた、この人の投稿がありそうなリレー
+    relay_hints: Vec<String>,
+    following_count: Option<usize>,
+    follower_count: Option<usize>,
+    posts: Vec<TimelinePost>,
+    pagination: Pagination,
+    pub is_loading: bool,
+    error: Option<String>,
+}
+
+impl UserProfile {
+    fn new(pubkey: PublicKey, metadata: ProfileMetadata, relay_hints: Vec<String>) -> Self {
+        Self {
+            pubkey,
+            metadata,
+            relay_hints,
+            following_count: None,
+            follower_count: None,
+            posts: Vec::new(),
+            pagination: Pagination::default(),
+            is_loading: false,
+            error: None,
+        }
+    }
+}
+
+/// Opens the profile page of `pubkey` on top of the current page and starts loading it.
+pub fn open_profile(
+    app_data: &mut NostrPostAppInternal,
+    pubkey: PublicKey,
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    open_profile_with_relays(app_data, pubkey, Vec::new(), app_data_arc, runtime_handle);
+}
+
+/// Same as `open_profile`, also reading from `relay_hints`.
+pub fn open_profile_with_relays(
+    app_data: &mut NostrPostAppInternal,
+    pubkey: PublicKey,
+    relay_hints: Vec<String>,
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    if app_data.user_profile.as_ref().is_some_and(|profile| profile.pubkey == pubkey) {
+        return;
+    }
+    navigation::push(app_data);
+    let metadata = app_data.profile_cache.get(&pubkey).cloned().unwrap_or_default();
+    app_data.user_profile = Some(UserProfile::new(pubkey, metadata, relay_hints));
+    reload(app_data, app_data_arc, runtime_handle);
+}
+
+/// Fetches the open profile, its follow counts and its newest posts again.
+pub fn reload(
+    app_data: &mut NostrPostAppInternal,
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    let Some(client) = app_data.nostr_client.clone() else { return };
+    let Some(profile) = app_data.user_profile.as_mut() else { return };
+    profile.is_loading = true;
+    profile.error = None;
+    profile.pagination.reset();
+    let pubkey = profile.pubkey;
+    let relay_hints = profile.relay_hints.clone();
+    let mutes = app_data.mute_list.clone();
+    let arc = app_data_arc.clone();
+
+    runtime_handle.spawn(async move {
+        // 保存済みの投稿を先に出す
+        let mut stored = stored_posts_by_author(client.database(), pubkey).await;
+        mutes.retain_visible(&mut stored);
+        {
+            let mut app_data = arc.lock().unwrap();
+            if let Some(profile) = app_data.user_profile.as_mut().filter(|p| p.pubkey == pubkey && p.posts.is_empty()) {
+                profile.posts = stored.clone();
+                app_data.should_repaint = true;
+            }
+        }
+        let (metadata, counts, posts) = futures::join!(
+            get_profile_metadata(pubkey, &client),
+            fetch_follow_counts(&client, pubkey),
+            fetch_posts_by_author(&client, pubkey, &relay_hints, None),
+        );
+
+        let mut guard = arc.lock().unwrap();
+        let app_data = &mut *guard;
+        app_data.should_repaint = true;
+        // 読み込み中に別のページへ移っていたら結果は捨てる (戻ったときに読み込み直す)
+        let Some(profile) = app_data.user_profile.as_mut().filter(|p| p.pubkey == pubkey) else {
+            return;
+        };
+        profile.is_loading = false;
+        match metadata {
+            Ok(metadata) => {
+                if metadata != ProfileMetadata::default() {
+                    app_data.profile_cache.insert(pubkey, metadata.clone());
+                }
+                profile.metadata = metadata;
+            }
+            Err(e) => eprintln!("Failed to fetch profile of {pubkey}: {e}"),
+        }
+        match counts {
+            Ok((following, followers)) => {
+                profile.following_count = following;
+                profile.follower_count = Some(followers);
+            }
+            Err(e) => eprintln!("Failed to count follows of {pubkey}: {e}"),
+        }
+        match posts {
+            Ok(mut page) => {
+                mutes.retain_visible(&mut page.posts);
+                event_store::merge_fetched(&mut stored, page.posts);
+                profile.posts = stored;
+            }
+            Err(e) => {
+                eprintln!("Failed to fetch posts of {pubkey}: {e}");
+                profile.error = Some(e.to_string());
+            }
+        }
+    });
+}
+
+fn load_older_posts(
+    app_data: &mut NostrPostAppInternal,
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    let Some(client) = app_data.nostr_client.clone() else { return };
+    let Some(profile) = app_data.user_profile.as_mut() else { return };
+    let Some((until, generation)) = profile.pagination.begin(&profile.posts) else {
+        return;
+    };
+    let pubkey = profile.pubkey;
+    let relay_hints = profile.relay_hints.clone();
+    let mutes = app_data.mute_list.clone();
+    let arc = app_data_arc.clone();
+
+    runtime_handle.spawn(async move {
+        let result = fetch_posts_by_author(&client, pubkey, &relay_hints, Some(until)).await.map(|mut page| {
+            mutes.retain_visible(&mut page.posts);
+            page
+        });
+        let mut app_data = arc.lock().unwrap();
+        if let Some(profile) = app_data.user_profile.as_mut().filter(|p| p.pubkey == pubkey) {
+            profile.pagination.finish(generation, &mut profile.posts, result);
+        }
+        app_data.should_repaint = true;
+    });
+}
+
+fn display_name(metadata: &ProfileMetadata, pubkey: &PublicKey) -> String {
+    if !metadata.name.is_empty() {
+        return metadata.name.clone();
+    }
+    let npub = pubkey.to_bech32().unwrap_or_default();
+    format!("{}...{}", &npub[0..8], &npub[npub.len() - 4..])
+}
+
+// 読み込み済みなら画像を、まだなら読み込みを頼んで下地だけを描く
+fn draw_image(
+    ui: &mut egui::Ui,
+    app_data: &NostrPostAppInternal,
+    url: &str,
+    kind: ImageKind,
+    size: egui::Vec2,
+    urls_to_load: &mut Vec<(String, ImageKind)>,
+) {
+    let (rect, _) = ui.allocate_exact_size(size, Sense::hover());
+    match app_data.image_cache.get(url, kind) {
+        Some(ImageState::Loaded(texture)) => {
+            let image = egui::Image::new(texture)
+                .fit_to_exact_size(size)
+                .maintain_aspect_ratio(false)
+                .corner_radius(8.0);
+            ui.put(rect, image);
+        }
+        state => {
+            ui.painter().rect_filled(rect, 8.0, ui.style().visuals.extreme_bg_color);
+            if url.is_empty() {
+                return;
+            }
+            if state.is_none() && !urls_to_load.iter().any(|(u, _)| u == url) {
+                urls_to_load.push((url.to_string(), kind));
+            }
+            if !matches!(state, Some(ImageState::Failed)) {
+                ui.put(rect.shrink(size.min_elem() * 0.35), egui::Spinner::new());
+            }
+        }
+    }
+}
+
+pub fn draw_user_profile_view(
+    ui: &mut egui::Ui,
+    ctx: &egui::Context,
+    app_data: &mut NostrPostAppInternal,
+    app_data_arc: Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    let Some(profile) = app_data.user_profile.as_ref() else { return };
+    let pubkey = profile.pubkey;
+    let metadata = profile.metadata.clone();
+    let is_loading = profile.is_loading;
+    let counts = (profile.following_count, profile.follower_count);
+    let error = profile.error.clone();
+    let name = display_name(&metadata, &pubkey);
+    let mut urls_to_load: Vec<(String, ImageKind)> = Vec::new();
+
+    ui.horizontal(|ui| {
+        if ui.button("← 戻る").clicked() {
+            navigation::back(app_data, &app_data_arc, runtime_handle);
+        }
+        ui.heading(&name);
+        if is_loading {
+            ui.add_space(10.0);
+            ui.spinner();
+            ui.label("プロフィールを読み込み中...");
+        } else if ui.button("再読み込み").clicked() {
+            reload(app_data, &app_data_arc, runtime_handle);
+        }
+    });
+    // 戻るで別のページに移った
+    if app_data.user_profile.as_ref().is_none_or(|p| p.pubkey != pubkey) {
+        return;
+    }
+    ui.add_space(10.0);
+
+    let output = egui::ScrollArea::vertical()
+        .id_salt(("user_profile_scroll_area", pubkey))
+        .show(ui, |ui| {
+            let banner = metadata.extra.get("banner").and_then(|v| v.as_str()).unwrap_or_default();
+            if !banner.is_empty() {
+                let size = egui::vec2(ui.available_width(), BANNER_HEIGHT);
+                draw_image(ui, app_data, banner, ImageKind::Banner, size, &mut urls_to_load);
+                ui.add_space(10.0);
+            }
+
+            ui.horizontal_top(|ui| {
+                draw_image(
+                    ui,
+                    app_data,
+                    &metadata.picture,
+                    ImageKind::ProfilePicture,
+                    egui::vec2(AVATAR_SIZE, AVATAR_SIZE),
+                    &mut urls_to_load,
+                );
+                ui.add_space(15.0);
+                ui.vertical(|ui| {
+                    ui.label(egui::RichText::new(&name).strong().size(18.0));
+                    let npub = pubkey.to_bech32().unwrap_or_default();
+                    ui.horizontal(|ui| {
+                        ui.weak(format!("{}...{}", &npub[0..12], &npub[npub.len() - 6..]));
+                        if ui.small_button("📋").on_hover_text("npub をコピー").clicked() {
+                            ui.ctx().copy_text(npub.clone());
+                        }
+                    });
+                    if !metadata.nip05.is_empty() {
+                        ui.horizontal(|ui| {
+                            ui.label(&metadata.nip05);
+                            post::draw_nip05_badge(ui, app_data, pubkey, &metadata.nip05, &app_data_arc, runtime_handle);
+                        });
+                    }
+                    if !metadata.lud16.is_empty() {
+                        ui.label(format!("⚡ {}", metadata.lud16));
+                    }
+                    let following = counts.0.map_or("-".to_string(), |n| n.to_string());
+                    let followers = match counts.1 {
+                        Some(n) if n >= FOLLOWER_LIMIT => format!("{n}+"),
+                        Some(n) => n.to_string(),
+                        None => "-".to_string(),
+                    };
+                    ui.label(format!("フォロー {following}　フォロワー {followers}"));
+                });
+            });
+
+            if !metadata.about.is_empty() {
+                ui.add_space(10.0);
+                ui.label(&metadata.about);
+            }
+
+            ui.add_space(10.0);
+            draw_actions(ui, app_data, pubkey, &metadata, &app_data_arc, runtime_handle);
+            if let Some(error) = &error {
+                ui.colored_label(ui.visuals().error_fg_color, format!("読み込みに失敗しました: {error}"));
+            }
+
+            ui.add_space(10.0);
+            ui.separator();
+            ui.heading("投稿");
+            ui.add_space(10.0);
+
+            if app_data.mute_list.contains(&MuteItem::Pubkey(pubkey)) {
+                ui.label("このユーザーをミュートしています。");
+                return;
+            }
+            let num_posts = app_data.user_profile.as_ref().map_or(0, |p| p.posts.len());
+            if num_posts == 0 && !is_loading {
+                ui.label("投稿はまだありません。");
+            }
+            for i in 0..num_posts {
+                // 投稿からほかのページを開いたら、そこで描くのをやめる
+                let Some(post_data) = app_data.user_profile.as_ref().and_then(|p| p.posts.get(i).cloned()) else {
+                    break;
+                };
+                post::render_post(ui, app_data, &post_data, &mut urls_to_load, app_data_arc.clone(), runtime_handle.clone());
+                ui.add_space(5.0);
+            }
+            if let Some(profile) = app_data.user_profile.as_mut() {
+                pagination::draw_footer(ui, &mut profile.pagination);
+            }
+        });
+    if pagination::near_bottom(&output, 90.0) && app_data.user_profile.as_ref().is_some_and(|p| p.pubkey == pubkey) {
+        load_older_posts(app_data, &app_data_arc, runtime_handle);
+    }
+
+    image_cache::load_images(ctx, app_data, &app_data_arc, runtime_handle, urls_to_load);
+}
+
+fn draw_actions(
+    ui: &mut egui::Ui,
+    app_data: &mut NostrPostAppInternal,
+    pubkey: PublicKey,
+    metadata: &ProfileMetadata,
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    if app_data.my_pubkey == Some(pubkey) {
+        return;
+    }
+    let can_sign = app_data.my_signer.is_some();
+    ui.horizontal(|ui| {
+        follow_panel::follow_button(ui, app_data, pubkey, app_data_arc, runtime_handle);
+
+        let muted = app_data.mute_list.contains(&MuteItem::Pubkey(pubkey));
+        let mute_text = if muted { "ミュート解除" } else { "ミュート" };
+        if ui
+            .add_enabled(mute_panel::can_edit(app_data), egui::Button::new(mute_text))
+            .on_disabled_hover_text(READ_ONLY_HINT)
+            .clicked()
+        {
+            mute_panel::toggle(app_data, MuteItem::Pubkey(pubkey), app_data_arc, runtime_handle);
+        }
+
+        if !metadata.lud16.is_empty()
+            && ui
+                .add_enabled(can_sign, egui::Button::new("⚡ Zap"))
+                .on_disabled_hover_text(READ_ONLY_HINT)
+                .clicked()
+        {
+            zap::open_dialog(app_data, zap::ZapTarget::profile(pubkey, metadata.clone()));
+        }
+
+        if ui
+            .add_enabled(can_sign, egui::Button::new("✉ メッセージ"))
+            .on_disabled_hover_text(READ_ONLY_HINT)
+            .clicked()
+        {
+            navigation::push(app_data);
+            app_data.messages.selected = Some(pubkey);
+            app_data.current_tab = AppTab::Messages;
+        }
+    });
+}
@@ -2,15 +2,13 @@ use eframe::egui;
 use nostr::nips::nip47::{NostrWalletConnectURI, Response};
 use nostr::{Event, Filter, JsonUtil, Kind, Keys, SingleLetterTag, TagKind};
 use nostr_sdk::Client;
-use serde_json;
-use std::fs;
 use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use tokio::runtime::Handle;
 
 use crate::nostr_client::get_profile_metadata;
-use crate::types::{Config, NostrPostAppInternal, ProfileMetadata, ZapReceipt};
-use crate::{nip49, CONFIG_FILE};
+use crate::types::{NostrPostAppInternal, ProfileMetadata, ZapReceipt};
+use crate::{load_config, nip49, write_config};
 use chrono::{DateTime, Utc};
 use lightning_invoice::Bolt11Invoice;
 
@@ -28,7 +26,8 @@ pub fn draw_wallet_view(
         return;
     }
 
-    if app_data.nwc.is_some() {
+    // 閲覧専用ログインではウォレットに接続できないので Zap 履歴だけを表示する。
+    if app_data.nwc.is_some() || app_data.my_signer.is_none() {
         draw_wallet_details(ui, app_data, app_data_arc.clone(), runtime_handle);
     } else {
         draw_setup_view(ui, app_data, app_data_arc.clone(), runtime_handle);
@@ -46,7 +45,11 @@ fn draw_wallet_details(
     app_data_arc: Arc<Mutex<NostrPostAppInternal>>,
     runtime_handle: Handle,
 ) {
-    ui.label("ウォレット接続済み");
+    if app_data.nwc.is_some() {
+        ui.label("ウォレット接続済み");
+    } else {
+        ui.label("閲覧専用モード: Zap 履歴のみ表示しています。");
+    }
     ui.add_space(10.0);
 
     ui.horizontal(|ui| {
@@ -148,21 +151,23 @@ async fn save_and_connect(
     }
     let nwc_uri = NostrWalletConnectURI::from_str(&nwc_uri_str)?;
 
-    // Read existing config to get the salt
-    let config_str = fs::read_to_string(CONFIG_FILE)?;
-    let mut config: Config = serde_json::from_str(&config_str)?;
+    let mut config = load_config();
+    let account = config.active_mut().ok_or("アカウントが見つかりません")?;
+    if account.remote_signer.is_some() || account.read_only {
+        // There is no passphrase to protect the URI with, and it holds a wallet secret.
+        return Err("秘密鍵を保存していないアカウントでは NWC を保存できません".into());
+    }
 
-    // Verify passphrase by trying to decrypt the main secret key
-    let _ = nip49::decrypt(&config.encrypted_secret_key, &passphrase, &config.salt)?;
+    // Verify passphrase by trying to decrypt the account's secret key
+    let _ = nip49::decrypt_secret_key(&account.encrypted_secret_key, &passphrase)?;
 
-    // Encrypt NWC URI with the same salt and passphrase
-    let encrypted_nwc_uri =
-        nip49::encrypt_with_salt(nwc_uri_str.as_bytes(), &passphrase, &config.salt)?;
-    config.encrypted_nwc_uri = Some(encrypted_nwc_uri);
+    // Encrypt NWC URI with the same passphrase
+    let encrypted_nwc_uri = nip49::encrypt(nwc_uri_str.as_bytes(), &passphrase)?;
+    account.encrypted_nwc_uri = Some(encrypted_nwc_uri);
 
     // Save updated config
-    let config_json = serde_json::to_string_pretty(&config)?;
-    fs::write(CONFIG_FILE, config_json)?;
+    write_config(&config)?;
+    app_data_arc.lock().unwrap().accounts = config.accounts.clone();
 
     connect_nwc(nwc_uri, app_data_arc).await?;
 
@@ -223,24 +228,23 @@ async fn listen_for_nwc_responses(
                 // Keep the loop from being too tight
             }
             Ok(notification) = notifications.recv() => {
-                if let nostr_sdk::RelayPoolNotification::Event { event, .. } = notification {
-                    if event.kind == Kind::WalletConnectResponse {
-                         if let Ok(decrypted_response) = Response::from_event(&_nwc, &event) {
-                            let mut app_data = app_data_arc.lock().unwrap();
-                            if let Some(res) = decrypted_response.result {
-                                match res {
-                                    nostr::nips::nip47::ResponseResult::PayInvoice(_pay_invoice_res) => {
-                                        println!("ZAP成功！");
-                                        // Here you might want to trigger a refresh of the zap history
-                                    },
-                                    _ => {
-                                        // Other responses are ignored for now
-                                    }
-                                }
-                            } else if let Some(error) = decrypted_response.error {
-                                app_data.nwc_error = Some(format!("NWCエラー: {}", error.message));
+                if let nostr_sdk::RelayPoolNotification::Event { event, .. } = notification
+                    && event.kind == Kind::WalletConnectResponse
+                    && let Ok(decrypted_response) = Response::from_event(&_nwc, &event)
+                {
+                    let mut app_data = app_data_arc.lock().unwrap();
+                    if let Some(res) = decrypted_response.result {
+                        match res {
+                            nostr::nips::nip47::ResponseResult::PayInvoice(_pay_invoice_res) => {
+                                println!("ZAP成功！");
+                                // Here you might want to trigger a refresh of the zap history
+                            },
+                            _ => {
+                                // Other responses are ignored for now
                             }
                         }
+                    } else if let Some(error) = decrypted_response.error {
+                        app_data.nwc_error = Some(format!("NWCエラー: {}", error.message));
                     }
                 }
             }
@@ -248,7 +252,7 @@ async fn listen_for_nwc_responses(
     }
 }
 
-async fn get_zap_history(
+pub async fn get_zap_history(
     app_data_arc: Arc<Mutex<NostrPostAppInternal>>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let (client, my_pubkey) = {
@@ -261,9 +265,7 @@ async fn get_zap_history(
             .cloned()
             .ok_or("Nostrクライアントが接続されていません")?;
         let my_pubkey = app_data
-            .my_keys
-            .as_ref()
-            .map(|k| k.public_key())
+            .my_pubkey
             .ok_or("ログインしていません")?;
         (client, my_pubkey)
     };
@@ -298,7 +300,7 @@ async fn get_zap_history(
             }
 
             // Sort by creation date, newest first
-            zap_receipts.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+            zap_receipts.sort_by_key(|z| std::cmp::Reverse(z.created_at));
 
             {
                 let mut app_data = app_data_arc.lock().unwrap();
@@ -324,7 +326,6 @@ async fn parse_zap_receipt(
     let mut zapper_pubkey = None; // This is us, but we get it from the 'P' tag
     let mut zapped_event_id = None;
     let mut amount_msats = 0;
-    let note: String;
 
     let description_tag = event
         .tags
@@ -334,7 +335,7 @@ async fn parse_zap_receipt(
         .ok_or("Descriptionタグが見つかりません")?;
 
     let zap_request_event = Event::from_json(description_tag)?;
-    note = zap_request_event.content.clone();
+    let note: String = zap_request_event.content.clone();
 
     // Check for the specific k tag ("30315")
     let required_k_tag_found =
@@ -343,10 +344,9 @@ async fn parse_zap_receipt(
             .iter()
             .any(|t| {
                 if t.kind() == TagKind::SingleLetter(SingleLetterTag::from_char('k').unwrap())
+                    && let Some(val) = t.as_slice().get(1)
                 {
-                    if let Some(val) = t.as_slice().get(1) {
-                        return val == "30315";
-                    }
+                    return val == "30315";
                 }
                 false
             });
@@ -365,20 +365,18 @@ async fn parse_zap_receipt(
 
     let recipient_pubkey = recipient_pubkey.ok_or("受信者の公開鍵が見つかりません")?;
 
-    if let Some(bolt11_tag) = event.tags.iter().find(|t| t.kind() == TagKind::Bolt11) {
-        if let Some(invoice_str) = bolt11_tag.as_slice().get(1) {
-             if let Ok(invoice) = Bolt11Invoice::from_str(invoice_str) {
-                if let Some(amount) = invoice.amount_milli_satoshis() {
-                    amount_msats = amount;
-                }
-            }
-        }
+    if let Some(bolt11_tag) = event.tags.iter().find(|t| t.kind() == TagKind::Bolt11)
+        && let Some(invoice_str) = bolt11_tag.as_slice().get(1)
+        && let Ok(invoice) = Bolt11Invoice::from_str(invoice_str)
+        && let Some(amount) = invoice.amount_milli_satoshis()
+    {
+        amount_msats = amount;
     }
 
-    if let Some(p_tag) = event.tags.iter().find(|t| t.kind() == TagKind::SingleLetter(SingleLetterTag::from_char('P').unwrap())) {
-        if let Some(pk_str) = p_tag.as_slice().get(1) {
-            zapper_pubkey = Some(nostr::PublicKey::from_str(pk_str)?);
-        }
+    if let Some(p_tag) = event.tags.iter().find(|t| t.kind() == TagKind::SingleLetter(SingleLetterTag::from_char('P').unwrap()))
+        && let Some(pk_str) = p_tag.as_slice().get(1)
+    {
+        zapper_pubkey = Some(nostr::PublicKey::from_str(pk_str)?);
     }
 
 
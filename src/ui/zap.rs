@@ -1,16 +1,51 @@
 use anyhow::{anyhow, Result};
+use eframe::egui;
 use nostr::{
     nips::{
         nip04,
+        nip19::ToBech32,
         nip47::{NostrWalletConnectURI, PayInvoiceRequest, Request, RequestParams},
     },
-    EventBuilder, Keys, Kind, PublicKey, RelayUrl, Tag,
+    EventBuilder, EventId, Keys, Kind, NostrSigner, PublicKey, RelayUrl, Tag,
 };
 use nostr_sdk::Client;
 use serde::{Deserialize, Serialize};
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::runtime::Handle;
 use ureq;
 
+use crate::types::{NostrPostAppInternal, ProfileMetadata, TimelinePost};
+
+/// Who the zap dialog pays: a post's author for a zap on that post, or a profile.
+#[derive(Clone)]
+pub struct ZapTarget {
+    pub pubkey: PublicKey,
+    pub metadata: ProfileMetadata,
+    pub note: Option<(EventId, Kind)>,
+}
+
+impl ZapTarget {
+    pub fn post(post: &TimelinePost) -> Self {
+        Self {
+            pubkey: post.author_pubkey,
+            metadata: post.author_metadata.clone(),
+            note: Some((post.id, post.kind)),
+        }
+    }
+
+    pub fn profile(pubkey: PublicKey, metadata: ProfileMetadata) -> Self {
+        Self { pubkey, metadata, note: None }
+    }
+}
+
+/// Opens the zap dialog for `target` with the default amount.
+pub fn open_dialog(app_data: &mut NostrPostAppInternal, target: ZapTarget) {
+    app_data.zap_target = Some(target);
+    app_data.show_zap_dialog = true;
+    app_data.zap_amount_input = "21".to_string();
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct LnurlPayResponse {
     callback: String,
@@ -43,10 +78,34 @@ fn lud16_to_lnurl(lud16: &str) -> Result<String> {
     Ok(format!("https://{}/.well-known/lnurlp/{}", domain, name))
 }
 
+async fn fetch_pay_params(lnurl: String) -> Result<LnurlPayResponse> {
+    tokio::task::spawn_blocking(move || -> anyhow::Result<LnurlPayResponse> {
+        let agent = ureq::agent();
+        let res = agent.get(&lnurl).call().map_err(|e| anyhow!(e))?;
+        let text = res.into_string().map_err(|e| anyhow!(e))?;
+        serde_json::from_str(&text).map_err(|e| anyhow!(e))
+    })
+    .await?
+}
+
+/// The key that signs zap receipts for payments to `lud16` (NIP-57 `nostrPubkey`).
+/// `None` if there is no lightning address or its server does not support zaps.
+pub async fn lnurl_nostr_pubkey(lud16: &str) -> Result<Option<PublicKey>> {
+    if lud16.is_empty() {
+        return Ok(None);
+    }
+    let pay_params = fetch_pay_params(lud16_to_lnurl(lud16)?).await?;
+    if !pay_params.allows_nostr {
+        return Ok(None);
+    }
+    Ok(pay_params.nostr_pubkey.and_then(|pk| PublicKey::from_hex(&pk).ok()))
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn send_zap_request(
     nwc: &NostrWalletConnectURI,
     nwc_client: &Client,
-    from_keys: &Keys,
+    signer: &Arc<dyn NostrSigner>,
     to_pubkey: PublicKey,
     lud16: &str,
     amount_sats: u64,
@@ -57,14 +116,7 @@ pub async fn send_zap_request(
     let lnurl = lud16_to_lnurl(lud16)?;
 
     // 1. Fetch LNURL pay parameters
-    let lnurl_clone = lnurl.clone();
-    let pay_params: LnurlPayResponse = tokio::task::spawn_blocking(move || -> anyhow::Result<LnurlPayResponse> {
-        let agent = ureq::agent();
-        let res = agent.get(&lnurl_clone).call().map_err(|e| anyhow!(e))?;
-        let text = res.into_string().map_err(|e| anyhow!(e))?;
-        serde_json::from_str(&text).map_err(|e| anyhow!(e))
-    })
-    .await??;
+    let pay_params = fetch_pay_params(lnurl.clone()).await?;
 
     if amount_msats < pay_params.min_sendable || amount_msats > pay_params.max_sendable {
         return Err(anyhow!(
@@ -91,7 +143,7 @@ pub async fn send_zap_request(
     }
     let zap_request = EventBuilder::new(Kind::ZapRequest, "")
         .tags(tags)
-        .sign(from_keys)
+        .sign(signer)
         .await?;
     let zap_request_str = serde_json::to_string(&zap_request)?;
 
@@ -139,3 +191,104 @@ pub async fn send_zap_request(
 
     Ok(())
 }
+
+// どのタブからでも開けるように、ZAP ダイアログは画面全体に 1 つだけ描く
+pub fn draw_zap_dialog(
+    ctx: &egui::Context,
+    app_data: &mut NostrPostAppInternal,
+    app_data_arc: &Arc<Mutex<NostrPostAppInternal>>,
+    runtime_handle: &Handle,
+) {
+    if !app_data.show_zap_dialog {
+        return;
+    }
+    let Some(target) = app_data.zap_target.clone() else { return };
+    let mut close_dialog = false;
+    egui::Window::new("ZAPを送る")
+        .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0.0, 0.0))
+        .collapsible(false)
+        .resizable(false)
+        .show(ctx, |ui| {
+            ui.vertical_centered_justified(|ui| {
+                ui.add_space(10.0);
+                let display_name = if !target.metadata.name.is_empty() {
+                    target.metadata.name.clone()
+                } else {
+                    let pubkey = target.pubkey.to_bech32().unwrap_or_default();
+                    format!("{}...{}", &pubkey[0..8], &pubkey[pubkey.len()-4..])
+                };
+                ui.label(format!("{} にZAPします", display_name));
+                ui.add_space(10.0);
+                ui.horizontal(|ui| {
+                    ui.label("金額 (sats):");
+                    ui.add(egui::TextEdit::singleline(&mut app_data.zap_amount_input)
+                        .desired_width(120.0));
+                });
+                ui.add_space(10.0);
+            });
+
+            ui.separator();
+            ui.add_space(5.0);
+
+            ui.horizontal(|ui| {
+                if ui.button("キャンセル").clicked() {
+                   close_dialog = true;
+                }
+                ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                    if ui.button("ZAP").clicked() {
+                        if let (Some(nwc), Some(nwc_client), Some(signer)) =
+                            (app_data.nwc.as_ref(), app_data.nwc_client.as_ref(), app_data.my_signer.as_ref())
+                        {
+                            if let Ok(amount_sats) = app_data.zap_amount_input.parse::<u64>() {
+                                let nwc_clone = nwc.clone();
+                                let nwc_client_clone = nwc_client.clone();
+                                let signer_clone = signer.clone();
+                                let app_data_clone = app_data_arc.clone();
+                                let target = target.clone();
+
+                                runtime_handle.spawn(async move {
+                                    {
+                                        let mut data = app_data_clone.lock().unwrap();
+                                        data.should_repaint = true;
+                                    } // Lock is dropped here
+
+                                    let result = send_zap_request(
+                                        &nwc_clone,
+                                        &nwc_client_clone,
+                                        &signer_clone,
+                                        target.pubkey,
+                                        &target.metadata.lud16,
+                                        amount_sats,
+                                        target.note.map(|(id, _)| id),
+                                        target.note.map(|(_, kind)| kind),
+                                    ).await;
+
+                                    let mut data = app_data_clone.lock().unwrap();
+                                    match result {
+                                        Ok(_) => {
+                                            // ZAPリクエストを送信しました。ウォレットの確認を待っています...
+                                        }
+                                        Err(e) => {
+                                            eprintln!("ZAPエラー: {}", e);
+                                        }
+                                    }
+                                    data.should_repaint = true;
+                                });
+
+                                close_dialog = true;
+
+                            } else {
+                                eprintln!("無効な金額です");
+                            }
+                        } else {
+                            eprintln!("ZAPにはNWCの接続が必要です");
+                        }
+                    }
+                });
+            });
+        });
+    if close_dialog {
+        app_data.show_zap_dialog = false;
+        app_data.zap_target = None;
+    }
+}
@@ -0,0 +1,408 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::{Engine as _, engine::general_purpose};
+use nostr::nips::nip96::{self, ServerConfig, UploadRequest, UploadResponse};
+use nostr::{EventBuilder, JsonUtil, Kind, NostrSigner, Tag, Timestamp, Url};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::types::{MediaServerConfig, MediaServerKind};
+
+const CONFIG_TIMEOUT: Duration = Duration::from_secs(10);
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(120);
+// Blossom の認可イベントの有効期限
+const AUTH_EXPIRATION_SECS: u64 = 5 * 60;
+// blurhash の成分数 (横 x 縦) と、計算に使う縮小画像の大きさ
+const BLURHASH_COMPONENTS: (u32, u32) = (4, 3);
+const BLURHASH_THUMBNAIL: u32 = 32;
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// A file picked, pasted or dropped into the composer.
+pub struct MediaFile {
+    pub name: String,
+    pub bytes: Vec<u8>,
+}
+
+impl MediaFile {
+    pub fn read(path: &Path) -> std::io::Result<Self> {
+        Ok(Self {
+            name: path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default(),
+            bytes: std::fs::read(path)?,
+        })
+    }
+}
+
+/// What we know about a file before uploading it, for its `imeta` tag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileInfo {
+    pub mime: String,
+    pub sha256: String,
+    pub size: usize,
+    pub dim: Option<(u32, u32)>,
+    pub blurhash: Option<String>,
+}
+
+/// An uploaded file: the URL to put in the note and the NIP-92 `imeta` tag describing it.
+#[derive(Debug, Clone)]
+pub struct Uploaded {
+    pub url: String,
+    pub imeta: Tag,
+}
+
+fn mime_from_name(name: &str) -> &'static str {
+    let ext = name.rsplit_once('.').map(|(_, ext)| ext.to_lowercase()).unwrap_or_default();
+    match ext.as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "svg" => "image/svg+xml",
+        "mp4" | "m4v" => "video/mp4",
+        "webm" => "video/webm",
+        "mov" => "video/quicktime",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Works out the mime type, hash, dimensions and blurhash of `bytes`.
+pub fn inspect(name: &str, bytes: &[u8]) -> FileInfo {
+    let format = image::guess_format(bytes).ok();
+    let mime = format.map(|f| f.to_mime_type()).unwrap_or_else(|| mime_from_name(name));
+    // 動画などデコードできないものは寸法と blurhash を付けない
+    let decoded = format.and_then(|_| image::load_from_memory(bytes).ok());
+    FileInfo {
+        mime: mime.to_string(),
+        sha256: hex::encode(Sha256::digest(bytes)),
+        size: bytes.len(),
+        dim: decoded.as_ref().map(|image| (image.width(), image.height())),
+        blurhash: decoded.and_then(|image| {
+            let thumbnail = image.thumbnail(BLURHASH_THUMBNAIL, BLURHASH_THUMBNAIL).to_rgba8();
+            let (x, y) = BLURHASH_COMPONENTS;
+            blurhash::encode(x, y, thumbnail.width(), thumbnail.height(), thumbnail.as_raw()).ok()
+        }),
+    }
+}
+
+// --- imeta (NIP-92) ---
+
+/// `["imeta", "url ...", "m ...", "x ...", "size ...", "dim WxH", "blurhash ..."]`
+pub fn imeta_tag(url: &str, info: &FileInfo) -> Tag {
+    let mut fields = vec![
+        "imeta".to_string(),
+        format!("url {url}"),
+        format!("m {}", info.mime),
+        format!("x {}", info.sha256),
+        format!("size {}", info.size),
+    ];
+    if let Some((width, height)) = info.dim {
+        fields.push(format!("dim {width}x{height}"));
+    }
+    if let Some(blurhash) = &info.blurhash {
+        fields.push(format!("blurhash {blurhash}"));
+    }
+    Tag::parse(fields).expect("imeta has a tag name")
+}
+
+// --- HTTP ---
+
+// エラーのときはサーバーが返した理由も見せる
+fn http_error(e: ureq::Error) -> Error {
+    match e {
+        ureq::Error::Status(code, res) => {
+            let reason = res.header("X-Reason").map(str::to_string);
+            let body = res.into_string().unwrap_or_default();
+            format!("HTTP {code}: {}", reason.unwrap_or(body)).into()
+        }
+        e => e.into(),
+    }
+}
+
+fn multipart_body(boundary: &str, file: &MediaFile, mime: &str) -> Vec<u8> {
+    let mut body = Vec::with_capacity(file.bytes.len() + 512);
+    for (name, value) in [("content_type", mime.to_string()), ("size", file.bytes.len().to_string())] {
+        body.extend_from_slice(
+            format!("--{boundary}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n").as_bytes(),
+        );
+    }
+    let filename = file.name.replace('"', "");
+    body.extend_from_slice(
+        format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{filename}\"\r\nContent-Type: {mime}\r\n\r\n"
+        )
+        .as_bytes(),
+    );
+    body.extend_from_slice(&file.bytes);
+    body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+    body
+}
+
+async fn upload_nip96(
+    server: &str,
+    signer: &Arc<dyn NostrSigner>,
+    file: MediaFile,
+    info: FileInfo,
+) -> Result<Uploaded, Error> {
+    let config_url = nip96::get_server_config_url(&Url::parse(server.trim())?)?;
+    let config: ServerConfig = tokio::task::spawn_blocking(move || -> Result<ServerConfig, Error> {
+        let res = ureq::get(config_url.as_str()).timeout(CONFIG_TIMEOUT).call().map_err(http_error)?;
+        Ok(ServerConfig::from_json(res.into_string()?)?)
+    })
+    .await??;
+
+    let request = UploadRequest::new(signer, &config, &file.bytes).await?;
+    let boundary = format!("rustr-{}", &info.sha256[..16]);
+    let body = multipart_body(&boundary, &file, &info.mime);
+    let response: UploadResponse = tokio::task::spawn_blocking(move || -> Result<UploadResponse, Error> {
+        let res = ureq::post(request.url.as_str())
+            .timeout(UPLOAD_TIMEOUT)
+            .set("Authorization", &request.authorization)
+            .set("Content-Type", &format!("multipart/form-data; boundary={boundary}"))
+            .send_bytes(&body)
+            .map_err(http_error)?;
+        Ok(UploadResponse::from_json(res.into_string()?)?)
+    })
+    .await??;
+    let url = response.download_url()?.to_string();
+
+    // サーバーが変換した場合に備えて、返ってきた NIP-94 のタグを優先する
+    let mut info = info;
+    for tag in response.nip94_event.iter().flat_map(|event| event.tags.iter()) {
+        match tag.as_slice() {
+            [name, value, ..] if name == "x" => info.sha256 = value.clone(),
+            [name, value, ..] if name == "m" => info.mime = value.clone(),
+            [name, value, ..] if name == "size" => info.size = value.parse().unwrap_or(info.size),
+            [name, value, ..] if name == "blurhash" => info.blurhash = Some(value.clone()),
+            [name, value, ..] if name == "dim" => {
+                if let Some((w, h)) = value.split_once('x')
+                    && let (Ok(w), Ok(h)) = (w.parse(), h.parse())
+                {
+                    info.dim = Some((w, h));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(Uploaded { imeta: imeta_tag(&url, &info), url })
+}
+
+// BUD-02 の blob descriptor
+#[derive(Deserialize)]
+struct BlobDescriptor {
+    url: String,
+    sha256: String,
+    size: usize,
+    #[serde(rename = "type")]
+    mime: Option<String>,
+}
+
+async fn upload_blossom(
+    server: &str,
+    signer: &Arc<dyn NostrSigner>,
+    file: MediaFile,
+    info: FileInfo,
+) -> Result<Uploaded, Error> {
+    let upload_url = Url::parse(server.trim())?.join("/upload")?;
+    let auth = EventBuilder::new(Kind::BlossomAuth, format!("Upload {}", file.name))
+        .tags([
+            Tag::parse(["t", "upload"])?,
+            Tag::parse(["x", &info.sha256])?,
+            Tag::expiration(Timestamp::now() + AUTH_EXPIRATION_SECS),
+        ])
+        .sign(signer)
+        .await?;
+    let authorization = format!("Nostr {}", general_purpose::STANDARD.encode(auth.as_json()));
+    let mime = info.mime.clone();
+    let blob: BlobDescriptor = tokio::task::spawn_blocking(move || -> Result<BlobDescriptor, Error> {
+        let res = ureq::put(upload_url.as_str())
+            .timeout(UPLOAD_TIMEOUT)
+            .set("Authorization", &authorization)
+            .set("Content-Type", &mime)
+            .send_bytes(&file.bytes)
+            .map_err(http_error)?;
+        Ok(serde_json::from_str(&res.into_string()?)?)
+    })
+    .await??;
+    if blob.sha256 != info.sha256 {
+        return Err(format!("サーバーが返したハッシュが一致しません ({})", blob.sha256).into());
+    }
+
+    let mut info = info;
+    info.size = blob.size;
+    if let Some(mime) = blob.mime.filter(|m| !m.is_empty()) {
+        info.mime = mime;
+    }
+    Ok(Uploaded { imeta: imeta_tag(&blob.url, &info), url: blob.url })
+}
+
+/// Uploads `file` to the configured NIP-96 or Blossom server.
+pub async fn upload(
+    server: &MediaServerConfig,
+    signer: &Arc<dyn NostrSigner>,
+    file: MediaFile,
+) -> Result<Uploaded, Error> {
+    if file.bytes.is_empty() {
+        return Err(format!("{} は空のファイルです", file.name).into());
+    }
+    let (name, bytes) = (file.name, file.bytes);
+    let (info, bytes) = tokio::task::spawn_blocking(move || (inspect(&name, &bytes), MediaFile { name, bytes })).await?;
+    match server.kind {
+        MediaServerKind::Nip96 => upload_nip96(&server.url, signer, bytes, info).await,
+        MediaServerKind::Blossom => upload_blossom(&server.url, signer, bytes, info).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nostr::{Event, Keys};
+    use std::sync::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    struct Request {
+        method: String,
+        path: String,
+        authorization: Option<String>,
+        body: Vec<u8>,
+    }
+
+    // NIP-96 と Blossom の代わりをするローカルサーバー。受けたリクエストを記録する
+    async fn serve_media(respond: fn(&str, &Request) -> String) -> (String, Arc<Mutex<Vec<Request>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let log = requests.clone();
+        let base = base_url.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut data = Vec::new();
+                let mut buf = [0u8; 4096];
+                let (head, body_start) = loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    data.extend_from_slice(&buf[..n]);
+                    if let Some(pos) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break (String::from_utf8_lossy(&data[..pos]).into_owned(), pos + 4);
+                    }
+                };
+                let header = |name: &str| {
+                    head.lines()
+                        .find_map(|line| line.split_once(": ").filter(|(k, _)| k.eq_ignore_ascii_case(name)))
+                        .map(|(_, v)| v.to_string())
+                };
+                let length: usize = header("Content-Length").and_then(|v| v.parse().ok()).unwrap_or(0);
+                while data.len() < body_start + length {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    data.extend_from_slice(&buf[..n]);
+                }
+                let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+                let request = Request {
+                    method: request_line.next().unwrap_or_default().to_string(),
+                    path: request_line.next().unwrap_or_default().to_string(),
+                    authorization: header("Authorization"),
+                    body: data[body_start..].to_vec(),
+                };
+                let json = respond(&base, &request);
+                log.lock().unwrap().push(request);
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{json}",
+                    json.len()
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        (base_url, requests)
+    }
+
+    fn auth_event(authorization: &Option<String>) -> Event {
+        let encoded = authorization.as_deref().unwrap().strip_prefix("Nostr ").unwrap();
+        Event::from_json(general_purpose::STANDARD.decode(encoded).unwrap()).unwrap()
+    }
+
+    fn red_png() -> Vec<u8> {
+        let image = image::RgbImage::from_pixel(8, 6, image::Rgb([255, 0, 0]));
+        let mut bytes = std::io::Cursor::new(Vec::new());
+        image.write_to(&mut bytes, image::ImageFormat::Png).unwrap();
+        bytes.into_inner()
+    }
+
+    #[tokio::test]
+    async fn nip96_upload_builds_imeta() {
+        let (base_url, requests) = serve_media(|base, request| match request.path.as_str() {
+            "/.well-known/nostr/nip96.json" => {
+                format!(r#"{{"api_url":"{base}/api/v2/media","download_url":"{base}/media"}}"#)
+            }
+            _ => format!(
+                r#"{{"status":"success","message":"ok","nip94_event":{{"tags":[["url","{base}/media/red.png"],["m","image/png"]]}}}}"#
+            ),
+        })
+        .await;
+        let signer: Arc<dyn NostrSigner> = Arc::new(Keys::generate());
+        let server = MediaServerConfig { kind: MediaServerKind::Nip96, url: base_url.clone() };
+        let bytes = red_png();
+        let sha256 = hex::encode(Sha256::digest(&bytes));
+
+        let uploaded = upload(&server, &signer, MediaFile { name: "red.png".into(), bytes: bytes.clone() }).await.unwrap();
+
+        assert_eq!(uploaded.url, format!("{base_url}/media/red.png"));
+        let imeta = uploaded.imeta.as_slice();
+        assert_eq!(
+            imeta[..6],
+            [
+                "imeta".to_string(),
+                format!("url {base_url}/media/red.png"),
+                "m image/png".to_string(),
+                format!("x {sha256}"),
+                format!("size {}", bytes.len()),
+                "dim 8x6".to_string(),
+            ]
+        );
+        // blurhash を戻すと元の色になる
+        let blurhash = imeta[6].strip_prefix("blurhash ").unwrap();
+        let pixels = blurhash::decode(blurhash, 4, 3, 1.0).unwrap();
+        assert!(pixels[0] > 240 && pixels[1] < 16 && pixels[2] < 16);
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!((requests[1].method.as_str(), requests[1].path.as_str()), ("POST", "/api/v2/media"));
+        assert_eq!(auth_event(&requests[1].authorization).kind, Kind::HttpAuth);
+        assert!(requests[1].body.windows(bytes.len()).any(|w| w == bytes.as_slice()));
+    }
+
+    #[tokio::test]
+    async fn blossom_upload_signs_auth_event() {
+        let (base_url, requests) = serve_media(|base, request| {
+            format!(
+                r#"{{"url":"{base}/{sha}.mp4","sha256":"{sha}","size":{size},"type":"video/mp4","uploaded":0}}"#,
+                sha = hex::encode(Sha256::digest(&request.body)),
+                size = request.body.len()
+            )
+        })
+        .await;
+        let keys = Keys::generate();
+        let signer: Arc<dyn NostrSigner> = Arc::new(keys.clone());
+        let server = MediaServerConfig { kind: MediaServerKind::Blossom, url: base_url.clone() };
+        let bytes = b"not really a video".to_vec();
+        let sha256 = hex::encode(Sha256::digest(&bytes));
+
+        let uploaded = upload(&server, &signer, MediaFile { name: "clip.mp4".into(), bytes }).await.unwrap();
+
+        assert_eq!(uploaded.url, format!("{base_url}/{sha256}.mp4"));
+        assert_eq!(uploaded.imeta.as_slice()[2], "m video/mp4");
+        // 動画には寸法も blurhash も付けない
+        assert_eq!(uploaded.imeta.as_slice().len(), 5);
+
+        let requests = requests.lock().unwrap();
+        assert_eq!((requests[0].method.as_str(), requests[0].path.as_str()), ("PUT", "/upload"));
+        let auth = auth_event(&requests[0].authorization);
+        assert_eq!(auth.kind, Kind::BlossomAuth);
+        assert_eq!(auth.pubkey, keys.public_key());
+        assert!(auth.tags.iter().any(|tag| tag.as_slice() == ["x", sha256.as_str()]));
+    }
+}